@@ -0,0 +1,445 @@
+// gzip (RFC 1952) around DEFLATE (RFC 1951), as much of it as compressing
+// retired segments and reading them back takes. Compression finds repeats
+// with hash chains and codes them with the fixed Huffman tables, which does
+// well enough on the repetitive text of segments. Decompression reads any
+// DEFLATE stream, so segments compressed by other tools load as well.
+use crate::crc32;
+
+const MAGIC: [u8; 2] = [0x1f, 0x8b];
+const METHOD_DEFLATE: u8 = 8;
+// header flags of optional fields that are skipped when reading
+const FLAG_HEADER_CRC: u8 = 2;
+const FLAG_EXTRA: u8 = 4;
+const FLAG_NAME: u8 = 8;
+const FLAG_COMMENT: u8 = 16;
+const OS_UNKNOWN: u8 = 255;
+
+const WINDOW_SIZE: usize = 32 * 1024;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const HASH_BITS: u32 = 15;
+// candidates looked at per position, more finds longer matches a bit more slowly
+const MAX_CHAIN: usize = 128;
+const END_OF_BLOCK: usize = 256;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+// order the code length code lengths of a dynamic block are stored in
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+// Whether `bytes`, the start of a file, is the start of a gzip stream. Segment
+// text never starts like that: 0x8b cannot follow 0x1f in UTF-8.
+pub(crate) fn is_compressed(bytes: &[u8]) -> bool {
+    bytes.starts_with(&MAGIC)
+}
+
+pub(crate) fn compress(data: &[u8]) -> Vec<u8> {
+    let mut writer = BitWriter::default();
+    writer.bytes.extend_from_slice(&MAGIC);
+    // no flags, modification time or extra flags
+    writer
+        .bytes
+        .extend_from_slice(&[METHOD_DEFLATE, 0, 0, 0, 0, 0, 0]);
+    writer.bytes.push(OS_UNKNOWN);
+    deflate(data, &mut writer);
+    writer.flush();
+    let mut bytes = writer.bytes;
+    bytes.extend_from_slice(&crc32(data).to_le_bytes());
+    bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    bytes
+}
+
+fn corrupt(message: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("corrupt gzip stream: {}", message),
+    )
+}
+
+pub(crate) fn decompress(bytes: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+    if bytes.len() < 18 || !is_compressed(bytes) || bytes[2] != METHOD_DEFLATE {
+        return Err(corrupt("not a gzip header"));
+    }
+    let flags = bytes[3];
+    let mut position = 10;
+    if flags & FLAG_EXTRA != 0 {
+        let length = bytes
+            .get(position..position + 2)
+            .ok_or_else(|| corrupt("truncated header"))?;
+        position += 2 + u16::from_le_bytes([length[0], length[1]]) as usize;
+    }
+    for flag in [FLAG_NAME, FLAG_COMMENT] {
+        if flags & flag != 0 {
+            let end = bytes
+                .get(position..)
+                .and_then(|rest| rest.iter().position(|byte| *byte == 0))
+                .ok_or_else(|| corrupt("truncated header"))?;
+            position += end + 1;
+        }
+    }
+    if flags & FLAG_HEADER_CRC != 0 {
+        position += 2;
+    }
+    let mut reader = BitReader {
+        bytes: bytes
+            .get(position..)
+            .ok_or_else(|| corrupt("truncated header"))?,
+        position: 0,
+        bits: 0,
+        bit_count: 0,
+    };
+    let data = inflate(&mut reader)?;
+    let trailer = reader
+        .bytes
+        .get(reader.position..reader.position + 8)
+        .ok_or_else(|| corrupt("truncated trailer"))?;
+    let expected_crc = u32::from_le_bytes(trailer[..4].try_into().unwrap());
+    let expected_size = u32::from_le_bytes(trailer[4..].try_into().unwrap());
+    if crc32(&data) != expected_crc || data.len() as u32 != expected_size {
+        return Err(corrupt("checksum mismatch"));
+    }
+    Ok(data)
+}
+
+// Bits are packed starting from the least significant bit of each byte.
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    bits: u64,
+    bit_count: u32,
+}
+
+impl BitWriter {
+    fn write_bits(&mut self, value: u32, count: u32) {
+        self.bits |= (value as u64) << self.bit_count;
+        self.bit_count += count;
+        while self.bit_count >= 8 {
+            self.bytes.push(self.bits as u8);
+            self.bits >>= 8;
+            self.bit_count -= 8;
+        }
+    }
+
+    // Huffman codes go out starting from their most significant bit.
+    fn write_code(&mut self, code: u32, length: u32) {
+        self.write_bits(code.reverse_bits() >> (32 - length), length);
+    }
+
+    fn flush(&mut self) {
+        if self.bit_count > 0 {
+            self.bytes.push(self.bits as u8);
+            self.bits = 0;
+            self.bit_count = 0;
+        }
+    }
+
+    // a literal or length symbol in the fixed code
+    fn write_fixed_literal(&mut self, symbol: usize) {
+        let symbol = symbol as u32;
+        match symbol {
+            0..=143 => self.write_code(0x30 + symbol, 8),
+            144..=255 => self.write_code(0x190 + symbol - 144, 9),
+            256..=279 => self.write_code(symbol - 256, 7),
+            _ => self.write_code(0xc0 + symbol - 280, 8),
+        }
+    }
+
+    fn write_match(&mut self, length: usize, distance: usize) {
+        let index = LENGTH_BASE
+            .iter()
+            .rposition(|base| *base as usize <= length)
+            .unwrap();
+        self.write_fixed_literal(257 + index);
+        self.write_bits(
+            (length - LENGTH_BASE[index] as usize) as u32,
+            LENGTH_EXTRA[index] as u32,
+        );
+        let index = DISTANCE_BASE
+            .iter()
+            .rposition(|base| *base as usize <= distance)
+            .unwrap();
+        self.write_code(index as u32, 5);
+        self.write_bits(
+            (distance - DISTANCE_BASE[index] as usize) as u32,
+            DISTANCE_EXTRA[index] as u32,
+        );
+    }
+}
+
+fn hash(data: &[u8], position: usize) -> usize {
+    let value = u32::from_le_bytes([data[position], data[position + 1], data[position + 2], 0]);
+    (value.wrapping_mul(0x9e3779b1) >> (32 - HASH_BITS)) as usize
+}
+
+// Positions by the hash of the three bytes starting there: the last one of
+// each hash, and for every position in the window the one before it.
+struct HashChains {
+    head: Vec<usize>,
+    previous: Vec<usize>,
+}
+
+impl HashChains {
+    fn insert(&mut self, data: &[u8], position: usize) {
+        if position + MIN_MATCH <= data.len() {
+            let hash = hash(data, position);
+            self.previous[position % WINDOW_SIZE] = self.head[hash];
+            self.head[hash] = position;
+        }
+    }
+}
+
+// Codes all of `data` as a single block with the fixed Huffman tables.
+fn deflate(data: &[u8], writer: &mut BitWriter) {
+    let mut chains = HashChains {
+        head: vec![usize::MAX; 1 << HASH_BITS],
+        previous: vec![usize::MAX; WINDOW_SIZE],
+    };
+    writer.write_bits(1, 1); // last block
+    writer.write_bits(1, 2); // fixed Huffman codes
+    let mut position = 0;
+    while position < data.len() {
+        let (mut best_length, mut best_distance) = (0, 0);
+        if position + MIN_MATCH <= data.len() {
+            let max_length = MAX_MATCH.min(data.len() - position);
+            let mut candidate = chains.head[hash(data, position)];
+            let mut chain = 0;
+            while candidate != usize::MAX
+                && position - candidate <= WINDOW_SIZE
+                && chain < MAX_CHAIN
+            {
+                let length = data[candidate..]
+                    .iter()
+                    .zip(data[position..position + max_length].iter())
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                if length > best_length {
+                    (best_length, best_distance) = (length, position - candidate);
+                    if length == max_length {
+                        break;
+                    }
+                }
+                let next = chains.previous[candidate % WINDOW_SIZE];
+                if next == usize::MAX {
+                    break;
+                }
+                candidate = next;
+                chain += 1;
+            }
+        }
+        if best_length >= MIN_MATCH {
+            writer.write_match(best_length, best_distance);
+            for skipped in position..position + best_length {
+                chains.insert(data, skipped);
+            }
+            position += best_length;
+        } else {
+            writer.write_fixed_literal(data[position] as usize);
+            chains.insert(data, position);
+            position += 1;
+        }
+    }
+    writer.write_fixed_literal(END_OF_BLOCK);
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    // next byte to load into `bits`
+    position: usize,
+    bits: u64,
+    bit_count: u32,
+}
+
+impl BitReader<'_> {
+    fn read_bits(&mut self, count: u32) -> Result<u32, std::io::Error> {
+        while self.bit_count < count {
+            let byte = *self
+                .bytes
+                .get(self.position)
+                .ok_or_else(|| corrupt("unexpected end of data"))?;
+            self.bits |= (byte as u64) << self.bit_count;
+            self.bit_count += 8;
+            self.position += 1;
+        }
+        let value = (self.bits & ((1u64 << count) - 1)) as u32;
+        self.bits >>= count;
+        self.bit_count -= count;
+        Ok(value)
+    }
+
+    // drops the bits left of the current byte, stored blocks start on a byte
+    fn align(&mut self) {
+        self.bits = 0;
+        self.bit_count = 0;
+    }
+}
+
+// A canonical Huffman code: how many codes there are of each length, and the
+// symbols ordered by code.
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Self {
+        let mut counts = [0u16; 16];
+        for length in lengths {
+            counts[*length as usize] += 1;
+        }
+        counts[0] = 0;
+        let mut offsets = [0u16; 16];
+        for length in 1..16 {
+            offsets[length] = offsets[length - 1] + counts[length - 1];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, length) in lengths.iter().enumerate() {
+            if *length != 0 {
+                symbols[offsets[*length as usize] as usize] = symbol as u16;
+                offsets[*length as usize] += 1;
+            }
+        }
+        Huffman { counts, symbols }
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> Result<usize, std::io::Error> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for length in 1..16 {
+            code |= reader.read_bits(1)? as i32;
+            let count = self.counts[length] as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize] as usize);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(corrupt("invalid Huffman code"))
+    }
+}
+
+fn fixed_codes() -> (Huffman, Huffman) {
+    let mut lengths = [8u8; 288];
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    (Huffman::new(&lengths), Huffman::new(&[5; 30]))
+}
+
+fn dynamic_codes(reader: &mut BitReader) -> Result<(Huffman, Huffman), std::io::Error> {
+    let literal_count = reader.read_bits(5)? as usize + 257;
+    let distance_count = reader.read_bits(5)? as usize + 1;
+    let code_length_count = reader.read_bits(4)? as usize + 4;
+    let mut code_lengths = [0u8; 19];
+    for symbol in CODE_LENGTH_ORDER.iter().take(code_length_count) {
+        code_lengths[*symbol] = reader.read_bits(3)? as u8;
+    }
+    let code_length_code = Huffman::new(&code_lengths);
+    let mut lengths = Vec::with_capacity(literal_count + distance_count);
+    while lengths.len() < literal_count + distance_count {
+        let (length, repeat) = match code_length_code.decode(reader)? {
+            symbol @ 0..=15 => (symbol as u8, 1),
+            16 => {
+                let previous = *lengths
+                    .last()
+                    .ok_or_else(|| corrupt("repeat without a previous length"))?;
+                (previous, 3 + reader.read_bits(2)?)
+            }
+            17 => (0, 3 + reader.read_bits(3)?),
+            _ => (0, 11 + reader.read_bits(7)?),
+        };
+        for _ in 0..repeat {
+            lengths.push(length);
+        }
+    }
+    if lengths.len() > literal_count + distance_count {
+        return Err(corrupt("too many code lengths"));
+    }
+    let (literal_lengths, distance_lengths) = lengths.split_at(literal_count);
+    Ok((
+        Huffman::new(literal_lengths),
+        Huffman::new(distance_lengths),
+    ))
+}
+
+fn inflate(reader: &mut BitReader) -> Result<Vec<u8>, std::io::Error> {
+    let mut data = Vec::new();
+    loop {
+        let last = reader.read_bits(1)? == 1;
+        let (literals, distances) = match reader.read_bits(2)? {
+            0 => {
+                reader.align();
+                let header = reader
+                    .bytes
+                    .get(reader.position..reader.position + 4)
+                    .ok_or_else(|| corrupt("truncated stored block"))?;
+                let length = u16::from_le_bytes([header[0], header[1]]) as usize;
+                if length != !u16::from_le_bytes([header[2], header[3]]) as usize {
+                    return Err(corrupt("stored block length mismatch"));
+                }
+                let start = reader.position + 4;
+                let stored = reader
+                    .bytes
+                    .get(start..start + length)
+                    .ok_or_else(|| corrupt("truncated stored block"))?;
+                data.extend_from_slice(stored);
+                reader.position = start + length;
+                if last {
+                    return Ok(data);
+                }
+                continue;
+            }
+            1 => fixed_codes(),
+            2 => dynamic_codes(reader)?,
+            _ => return Err(corrupt("invalid block type")),
+        };
+        loop {
+            let symbol = literals.decode(reader)?;
+            if symbol < END_OF_BLOCK {
+                data.push(symbol as u8);
+                continue;
+            }
+            if symbol == END_OF_BLOCK {
+                break;
+            }
+            let index = symbol - 257;
+            if index >= LENGTH_BASE.len() {
+                return Err(corrupt("invalid length symbol"));
+            }
+            let length = LENGTH_BASE[index] as usize
+                + reader.read_bits(LENGTH_EXTRA[index] as u32)? as usize;
+            let index = distances.decode(reader)?;
+            if index >= DISTANCE_BASE.len() {
+                return Err(corrupt("invalid distance symbol"));
+            }
+            let distance = DISTANCE_BASE[index] as usize
+                + reader.read_bits(DISTANCE_EXTRA[index] as u32)? as usize;
+            if distance > data.len() {
+                return Err(corrupt("distance before the start of the data"));
+            }
+            // the copy may overlap what it appends, so it goes byte by byte
+            let start = data.len() - distance;
+            for offset in 0..length {
+                data.push(data[start + offset]);
+            }
+        }
+        if last {
+            // the trailer starts on the byte after the last bits
+            reader.align();
+            return Ok(data);
+        }
+    }
+}
@@ -0,0 +1,12386 @@
+mod gzip;
+mod mmap;
+pub mod resp;
+mod storage;
+
+pub use storage::{Appender, FileStorage, MemoryStorage, Storage, StorageFile};
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Seek};
+use std::io::{SeekFrom, prelude::*};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, mpsc};
+
+#[cfg(any(test, feature = "dev"))]
+mod scratch;
+
+// size past which the write segment is retired, unless set by --segment-size
+const SEGMENT_THRESHOLD: u64 = 256;
+// each size tier holds segments up to this many times larger than the one below
+const TIER_GROWTH: u64 = 4;
+// adjacent segments of a tier that size-tiered compaction waits for
+const TIER_MIN_SEGMENTS: usize = 4;
+// records the write segment holds before its utilization is judged
+const UTILIZATION_MIN_RECORDS: u64 = 8;
+const CURRENT_SEGMENT_SUFFIX: &str = "current";
+// starts the block lines of an SSTable, no key may start with it
+const BLOCK_LINE_MARKER: &[u8] = b"\x01\x01";
+// follows the marker in the last line of an SSTable, see `BlockIndex`
+const SSTABLE_FOOTER: &str = "sstable";
+const CHECKPOINT_SUFFIX: &str = "checkpoint";
+// suffix of the file holding the number of the newest segment ever named
+const MANIFEST_SUFFIX: &str = "manifest";
+// Records are stored one per line as `key,value`. A record carrying flags or
+// typed fields starts with a header enclosed in two markers:
+// `\u{1}<flags in hex>[;<field id>=<value>]*\u{1}key,value`
+const HEADER_MARKER: u8 = 0x01;
+// header field holding the unix time in milliseconds the record expires at
+const FIELD_EXPIRY: char = 'e';
+// header field holding the CRC32 of the `key,value` text as stored
+const FIELD_CHECKSUM: char = 'c';
+// header flag of a tombstone, `key,` without a header is one as well
+const FLAG_TOMBSTONE: u8 = 1;
+// header flag of a record whose key and value are backslash escaped, set only
+// when one of them holds a character the line format cannot carry as is
+const FLAG_ESCAPED: u8 = 2;
+// header field of every record of a batch, holding the number of records of
+// the batch that follow it
+const FIELD_BATCH: char = 'b';
+// header field holding the write order of the record, compaction keeps the
+// record with the highest one whatever segment it is stored in
+const FIELD_SEQUENCE: char = 's';
+// Start of a record written by the binary codec, followed by the header, the
+// key and the value, each after its length as a little endian u32, and then
+// the CRC32 of all of the frame before it. No line of the text format starts
+// with it unless a key does, and those never share a segment.
+const FRAME_MARKER: u8 = 0x02;
+// header field holding the type tag an application gave the value, left out
+// for tag 0 so that untagged records read the same as before
+const FIELD_TYPE: char = 't';
+const CRC32_POLYNOMIAL: u32 = 0xedb88320;
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+// suffix of compacted segments kept around for the snapshots still reading them
+const OBSOLETE_SUFFIX: &str = "compacted";
+// suffix of the saved index of a retired segment, `offset,key` per line
+const HINT_SUFFIX: &str = "hint";
+// suffix of the saved Bloom filter of a retired segment
+const FILTER_SUFFIX: &str = "filter";
+// suffix --compress adds to the name of a retired segment
+const COMPRESSED_SUFFIX: &str = "gz";
+// records SORTEDEXPORT sorts in memory before spilling them to a run file
+const EXPORT_RUN_SIZE: usize = 1024;
+// about 1% false positives with the matching number of hashes
+const BLOOM_BITS_PER_KEY: usize = 10;
+const BLOOM_HASHES: u32 = 7;
+// keys FILTERSTATS probes each filter with unless asked otherwise
+const FILTER_SAMPLE_COUNT: usize = 10_000;
+// mutations kept for RECENT
+const RECENT_BUFFER_SIZE: usize = 64;
+// records SCAN returns per call unless asked otherwise
+const SCAN_DEFAULT_COUNT: usize = 10;
+// levels below the root MERKLE computes unless asked otherwise
+const MERKLE_DEFAULT_DEPTH: u32 = 4;
+const MERKLE_MAX_DEPTH: u32 = 16;
+// fewest and most arguments, the command included, of the commands whose
+// arguments are not all optional, with their usage
+const COMMAND_ARITY: [(&str, usize, usize, &str); 22] = [
+    ("SET", 3, 5, "SET <key> <value> [--type <tag>]"),
+    ("GET", 2, 3, "GET <key> [--include-tombstone]"),
+    ("DELETE", 2, 2, "DELETE <key>"),
+    ("GETSET", 3, 3, "GETSET <key> <value>"),
+    ("GETDEL", 2, 2, "GETDEL <key>"),
+    (
+        "MSET",
+        3,
+        usize::MAX,
+        "MSET <key> <value> [<key> <value> ...]",
+    ),
+    ("MGET", 2, usize::MAX, "MGET <key> [<key> ...]"),
+    ("INCR", 2, 2, "INCR <key>"),
+    ("DECR", 2, 2, "DECR <key>"),
+    ("APPEND", 3, 3, "APPEND <key> <suffix>"),
+    (
+        "CAS",
+        4,
+        4,
+        "CAS <key> <expected value>|--absent <new value>",
+    ),
+    ("SETNX", 3, 3, "SETNX <key> <value>"),
+    ("SETEX", 4, 4, "SETEX <key> <seconds> <value>"),
+    ("EXPIRE", 3, 3, "EXPIRE <key> <seconds>"),
+    ("SWAP", 3, 3, "SWAP <key> <key>"),
+    ("RENAME", 3, 3, "RENAME <old key> <new key>"),
+    ("EXISTS", 2, 2, "EXISTS <key>"),
+    ("TYPE", 2, 2, "TYPE <key>"),
+    ("DUPES", 2, 2, "DUPES <segment file name>"),
+    ("GREP", 2, 4, "GREP <substring> [--limit N]"),
+    (
+        "REPLAY",
+        2,
+        3,
+        "REPLAY <trace file> [--as-fast-as-possible]",
+    ),
+    (
+        "SCAN",
+        1,
+        3,
+        "SCAN [<cursor> [<count>]] | SCAN --prefix <prefix>",
+    ),
+];
+
+// Offsets of the records of a segment by key. Hashed is the default: lookups
+// are O(1) and entries only cost their key and offset plus spare capacity.
+// Ordered keeps the keys sorted for range scans, at O(log n) per lookup and a
+// node allocation per handful of entries.
+#[derive(Debug, PartialEq)]
+enum SegmentIndex {
+    Hashed(HashMap<Vec<u8>, u64>),
+    Ordered(BTreeMap<Vec<u8>, u64>),
+}
+
+impl SegmentIndex {
+    fn get(&self, key: &[u8]) -> Option<&u64> {
+        match self {
+            SegmentIndex::Hashed(index) => index.get(key),
+            SegmentIndex::Ordered(index) => index.get(key),
+        }
+    }
+
+    fn insert(&mut self, key: Vec<u8>, offset: u64) {
+        match self {
+            SegmentIndex::Hashed(index) => index.insert(key, offset),
+            SegmentIndex::Ordered(index) => index.insert(key, offset),
+        };
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<u64> {
+        match self {
+            SegmentIndex::Hashed(index) => index.remove(key),
+            SegmentIndex::Ordered(index) => index.remove(key),
+        }
+    }
+
+    fn extend(&mut self, entries: impl IntoIterator<Item = (Vec<u8>, u64)>) {
+        match self {
+            SegmentIndex::Hashed(index) => index.extend(entries),
+            SegmentIndex::Ordered(index) => index.extend(entries),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            SegmentIndex::Hashed(index) => index.len(),
+            SegmentIndex::Ordered(index) => index.len(),
+        }
+    }
+
+    fn iter(&self) -> Box<dyn ExactSizeIterator<Item = (&Vec<u8>, &u64)> + '_> {
+        match self {
+            SegmentIndex::Hashed(index) => Box::new(index.iter()),
+            SegmentIndex::Ordered(index) => Box::new(index.iter()),
+        }
+    }
+
+    fn keys(&self) -> Box<dyn ExactSizeIterator<Item = &Vec<u8>> + '_> {
+        match self {
+            SegmentIndex::Hashed(index) => Box::new(index.keys()),
+            SegmentIndex::Ordered(index) => Box::new(index.keys()),
+        }
+    }
+
+    // Keys from `start` up to but excluding `end`, sorted.
+    fn keys_in(&self, start: &[u8], end: &[u8]) -> Vec<Vec<u8>> {
+        if start >= end {
+            return Vec::new();
+        }
+        match self {
+            SegmentIndex::Hashed(index) => {
+                let mut keys: Vec<Vec<u8>> = index
+                    .keys()
+                    .filter(|key| key.as_slice() >= start && key.as_slice() < end)
+                    .cloned()
+                    .collect();
+                keys.sort();
+                keys
+            }
+            SegmentIndex::Ordered(index) => index
+                .range::<[u8], _>((
+                    std::ops::Bound::Included(start),
+                    std::ops::Bound::Excluded(end),
+                ))
+                .map(|(key, _)| key.clone())
+                .collect(),
+        }
+    }
+
+    // Switches between the hashed and the ordered representation.
+    fn set_ordered(&mut self, ordered: bool) {
+        *self = match std::mem::replace(self, SegmentIndex::Hashed(HashMap::new())) {
+            SegmentIndex::Hashed(index) if ordered => {
+                SegmentIndex::Ordered(index.into_iter().collect())
+            }
+            SegmentIndex::Ordered(index) if !ordered => {
+                SegmentIndex::Hashed(index.into_iter().collect())
+            }
+            index => index,
+        };
+    }
+
+    fn shrink_to_fit(&mut self) {
+        if let SegmentIndex::Hashed(index) = self {
+            index.shrink_to_fit();
+        }
+    }
+
+    // Estimated bytes held by the entries, keys included.
+    fn memory_usage(&self) -> usize {
+        let entry_size = std::mem::size_of::<Vec<u8>>() + std::mem::size_of::<u64>();
+        let key_bytes: usize = self.keys().map(|key| key.len()).sum();
+        match self {
+            SegmentIndex::Hashed(index) => index.capacity() * entry_size + key_bytes,
+            // nodes are between half and fully used, count them as 3/4 full
+            SegmentIndex::Ordered(index) => index.len() * entry_size * 4 / 3 + key_bytes,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Segment {
+    file_path: String,
+    // where the file of the segment is kept, that of its store
+    storage: Arc<dyn Storage>,
+    index: SegmentIndex,
+    size: u64,
+    // the block index of an SSTable, whose `index` then stays empty
+    blocks: Option<BlockIndex>,
+    // where appends go instead of the file opened for each, set by tests
+    appender: Option<Box<dyn Appender>>,
+    // most index entries kept by `trim_index`, None keeps all of them
+    index_cap: Option<usize>,
+    // lowest offset of an evicted entry, keys missing from the index are
+    // looked up by scanning the segment forward from here
+    trimmed_from: Option<u64>,
+    // entries found again by such a scan, folded back into the index on the next trim
+    recovered: Mutex<HashMap<Vec<u8>, u64>>,
+    access_clock: AtomicU64,
+    last_access: Mutex<HashMap<Vec<u8>, u64>>,
+    // time spent building the index when the segment was opened
+    build_time: std::time::Duration,
+    // the index was loaded from the hint file instead of scanning the segment
+    from_hint: bool,
+    // records written to the segment carry a checksum
+    checksums: bool,
+    // how the records of the segment are laid out, that of its store
+    codec: RecordCodec,
+    // the text of a compressed segment, inflated when it was opened
+    inflated: Option<Arc<[u8]>>,
+    // the file of an uncompressed retired segment, see `Environment::map_segments`
+    mapped: Option<mmap::Mmap>,
+    // keys of a retired segment, None for the write segment
+    filter: Option<BloomFilter>,
+    // records appended since the segment was opened, plus one per key indexed
+    // then; against the keys of the index it tells how much of it is shadowed
+    record_count: u64,
+    // highest sequence number among its records, 0 if none carries one
+    max_sequence: u64,
+}
+
+// The sparse index of a segment written as an SSTable. Its records are sorted
+// by key into blocks of about the same size, and the file ends with a line per
+// block holding the offset and the first key of the block, then a footer
+// pointing at those lines:
+// `\u{1}\u{1}<offset in hex>,<first key>` per block
+// `\u{1}\u{1}sstable <offset of the first block line> <block count>`
+// Only the block lines are kept in memory, a key is looked up by reading the
+// one block that can hold it.
+#[derive(Debug)]
+struct BlockIndex {
+    // first key and offset of each block, in key order
+    blocks: Vec<(Vec<u8>, u64)>,
+    // where the records end and the block lines start
+    data_end: u64,
+    // times each block was read
+    reads: Vec<AtomicU64>,
+}
+
+impl BlockIndex {
+    fn new(blocks: Vec<(Vec<u8>, u64)>, data_end: u64) -> Self {
+        let reads = blocks.iter().map(|_| AtomicU64::new(0)).collect();
+        BlockIndex {
+            blocks,
+            data_end,
+            reads,
+        }
+    }
+
+    // The block holding `key` if any does, the last one starting at or before it.
+    fn block_of(&self, key: &[u8]) -> Option<usize> {
+        self.blocks
+            .partition_point(|(first, _)| first.as_slice() <= key)
+            .checked_sub(1)
+    }
+
+    // The blocks that may hold keys from `start` up to but excluding `end`.
+    fn blocks_in(&self, start: &[u8], end: &[u8]) -> std::ops::Range<usize> {
+        if start >= end {
+            return 0..0;
+        }
+        let first = self.block_of(start).unwrap_or(0);
+        let last = self
+            .blocks
+            .partition_point(|(first, _)| first.as_slice() < end);
+        first..last.max(first)
+    }
+
+    // the offsets the records of `block` take
+    fn span(&self, block: usize) -> std::ops::Range<u64> {
+        let end = self
+            .blocks
+            .get(block + 1)
+            .map_or(self.data_end, |(_, offset)| *offset);
+        self.blocks[block].1..end
+    }
+
+    // The block lines and the footer.
+    fn encode(&self) -> Vec<u8> {
+        let mut lines = Vec::new();
+        for (key, offset) in self.blocks.iter() {
+            lines.extend_from_slice(BLOCK_LINE_MARKER);
+            lines.extend_from_slice(format!("{:x},", offset).as_bytes());
+            lines.extend_from_slice(&escape_field(key));
+            lines.push(b'\n');
+        }
+        lines.extend_from_slice(&encode_footer(self.data_end, self.blocks.len()));
+        lines
+    }
+}
+
+// The last line of an SSTable, always the same length so that it can be read
+// from the end of the file.
+fn encode_footer(data_end: u64, blocks: usize) -> Vec<u8> {
+    let mut footer = BLOCK_LINE_MARKER.to_vec();
+    footer.extend_from_slice(
+        format!("{} {:016x} {:016x}\n", SSTABLE_FOOTER, data_end, blocks).as_bytes(),
+    );
+    footer
+}
+
+// Reads the block index at the end of a segment, None if it is no SSTable.
+fn read_block_index(
+    file: &mut SegmentFile,
+    file_path: &str,
+) -> Result<Option<BlockIndex>, std::io::Error> {
+    let corrupt = |line: &[u8]| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "Corrupt block index line [{}] in [{}]",
+                String::from_utf8_lossy(line),
+                file_path
+            ),
+        )
+    };
+    let footer_len = encode_footer(0, 0).len() as u64;
+    let file_len = file.len()?;
+    if file_len < footer_len {
+        return Ok(None);
+    }
+    let footer_start = file_len - footer_len;
+    file.seek(SeekFrom::Start(footer_start))?;
+    let mut footer = vec![0u8; footer_len as usize];
+    file.read_exact(&mut footer)?;
+    let fields = footer
+        .strip_prefix(BLOCK_LINE_MARKER)
+        .and_then(|footer| std::str::from_utf8(footer).ok())
+        .and_then(|footer| footer.strip_prefix(SSTABLE_FOOTER));
+    let fields = match fields {
+        Some(fields) => fields.trim(),
+        None => return Ok(None),
+    };
+    let (data_end, count) = fields
+        .split_once(' ')
+        .and_then(|(data_end, count)| {
+            Some((
+                u64::from_str_radix(data_end, 16).ok()?,
+                usize::from_str_radix(count, 16).ok()?,
+            ))
+        })
+        .filter(|(data_end, _)| *data_end <= footer_start)
+        .ok_or_else(|| corrupt(fields.as_bytes()))?;
+    file.seek(SeekFrom::Start(data_end))?;
+    let mut blocks: Vec<(Vec<u8>, u64)> = Vec::new();
+    for line in byte_lines(BufReader::new(file.take(footer_start - data_end))) {
+        let line = line?;
+        let block = line
+            .strip_prefix(BLOCK_LINE_MARKER)
+            .and_then(split_line)
+            .and_then(|(block_offset, key)| {
+                let block_offset = std::str::from_utf8(block_offset).ok()?;
+                Some((
+                    unescape_field(key)?,
+                    u64::from_str_radix(block_offset, 16).ok()?,
+                ))
+            })
+            // blocks start inside the records, in order
+            .filter(|(_, block_offset)| {
+                *block_offset < data_end
+                    && blocks.last().is_none_or(|(_, last)| last < block_offset)
+            });
+        match block {
+            Some(block) => blocks.push(block),
+            None => return Err(corrupt(&line)),
+        }
+    }
+    if blocks.len() != count {
+        return Err(corrupt(fields.as_bytes()));
+    }
+    Ok(Some(BlockIndex::new(blocks, data_end)))
+}
+
+#[derive(Debug)]
+pub enum KvError {
+    Io(std::io::Error),
+    // the newest record of the key is a tombstone in this segment
+    KeyDeleted {
+        file_path: String,
+    },
+    // the segment was truncated after its index or hint was built, `file_len`
+    // being the length of its text when read
+    OffsetBeyondEof {
+        file_path: String,
+        offset: u64,
+        file_len: u64,
+    },
+    // a line that does not decode, or not to the key the index expects there
+    Corrupt {
+        file_path: String,
+        offset: u64,
+        line: String,
+    },
+    // the record at `offset` does not match its checksum
+    ChecksumMismatch {
+        file_path: String,
+        offset: u64,
+    },
+    // INCR or DECR on a key whose value does not parse as an i64
+    NotAnInteger {
+        key: String,
+        value: String,
+    },
+    // INCR or DECR that would take the value outside of the i64 range
+    IntegerOverflow {
+        key: String,
+    },
+    // a write with a key over `max_key_len` bytes
+    KeyTooLong {
+        limit: usize,
+    },
+    // a write with a value over `max_value_len` bytes
+    ValueTooLarge {
+        limit: usize,
+    },
+    // a write to a store opened with `open_read_only`
+    ReadOnly,
+    // the segment files still take more than `max_disk_bytes` after a full compaction
+    DiskLimitExceeded {
+        disk_bytes: u64,
+        limit: u64,
+    },
+    // a value read as a string that is not UTF-8, see `KvStore::get_bytes`
+    NotUtf8 {
+        key: String,
+    },
+    // a change of the record codec of a store that already holds records
+    CodecInUse {
+        codec: RecordCodec,
+    },
+}
+
+impl fmt::Display for KvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KvError::Io(e) => write!(f, "{}", e),
+            KvError::KeyDeleted { .. } => write!(f, "key deleted"),
+            KvError::OffsetBeyondEof {
+                file_path,
+                offset,
+                file_len,
+            } => write!(
+                f,
+                "index of [{}] points to offset {} beyond the end of the file at {}",
+                file_path, offset, file_len
+            ),
+            KvError::Corrupt {
+                file_path,
+                offset,
+                line,
+            } => write!(
+                f,
+                "corrupt record [{}] at offset {} of [{}]",
+                line, offset, file_path
+            ),
+            KvError::ChecksumMismatch { file_path, offset } => write!(
+                f,
+                "checksum mismatch at offset {} of [{}]",
+                offset, file_path
+            ),
+            KvError::NotAnInteger { key, value } => {
+                write!(f, "value [{}] of key [{}] is not an integer", value, key)
+            }
+            KvError::IntegerOverflow { key } => {
+                write!(f, "value of key [{}] would overflow", key)
+            }
+            KvError::KeyTooLong { limit } => {
+                write!(f, "key longer than the limit of {} bytes", limit)
+            }
+            KvError::ValueTooLarge { limit } => {
+                write!(f, "value larger than the limit of {} bytes", limit)
+            }
+            KvError::ReadOnly => write!(f, "store is opened read-only"),
+            KvError::DiskLimitExceeded { disk_bytes, limit } => write!(
+                f,
+                "segments take {} bytes on disk even compacted, over the limit of {} bytes",
+                disk_bytes, limit
+            ),
+            KvError::NotUtf8 { key } => write!(f, "value of [{}] is not UTF-8", key),
+            KvError::CodecInUse { codec } => write!(
+                f,
+                "the store holds records written as {}, its record format cannot change",
+                codec.name()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for KvError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            KvError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for KvError {
+    fn from(err: std::io::Error) -> KvError {
+        // an error that went through an io::Result comes back as itself
+        if err.get_ref().is_some_and(|inner| inner.is::<KvError>()) {
+            return *err.into_inner().unwrap().downcast::<KvError>().unwrap();
+        }
+        KvError::Io(err)
+    }
+}
+
+// lets the io::Result based commands propagate storage errors with `?`
+impl From<KvError> for std::io::Error {
+    fn from(err: KvError) -> std::io::Error {
+        let kind = match err {
+            KvError::Io(e) => return e,
+            KvError::OffsetBeyondEof { .. } => std::io::ErrorKind::UnexpectedEof,
+            _ => std::io::ErrorKind::InvalidData,
+        };
+        std::io::Error::new(kind, err)
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+struct RecordHeader {
+    flags: u8,
+    // optional typed fields keyed by a one character id
+    fields: BTreeMap<char, u64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Record {
+    header: RecordHeader,
+    key: Vec<u8>,
+    value: Vec<u8>,
+}
+
+impl Record {
+    pub fn new(key: &[u8], value: &[u8]) -> Self {
+        Record {
+            header: RecordHeader::default(),
+            key: key.to_vec(),
+            value: value.to_vec(),
+        }
+    }
+
+    // A delete of `key`, told apart from a value by its flag alone. Its value
+    // stays empty and is never read.
+    pub fn tombstone(key: &[u8]) -> Self {
+        let mut record = Record::new(key, b"");
+        record.header.flags |= FLAG_TOMBSTONE;
+        record
+    }
+
+    fn is_tombstone(&self) -> bool {
+        self.header.flags & FLAG_TOMBSTONE != 0
+    }
+
+    // the value the record writes, None for a delete
+    fn written_value(&self) -> Option<&[u8]> {
+        match self.is_tombstone() {
+            true => None,
+            false => Some(&self.value),
+        }
+    }
+}
+
+// How the records of a compacted segment are written, see `Environment::compact_to`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RecordFormat {
+    // headerless `key,value` lines unless a record has fields besides a checksum
+    Plain,
+    // every record carries a checksum of its key and value
+    Checksummed,
+}
+
+// CRC-32 (IEEE), computed bitwise to stay free of dependencies.
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (CRC32_POLYNOMIAL & mask);
+        }
+    }
+    !crc
+}
+
+fn needs_escaping(field: &[u8]) -> bool {
+    field
+        .iter()
+        .any(|byte| matches!(byte, b',' | b'\\' | b'\n' | b'\r'))
+}
+
+// `\r` is escaped along with the separators since reading lines drops it
+fn escape_field(field: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(field.len());
+    for byte in field {
+        match byte {
+            b',' => escaped.extend_from_slice(b"\\,"),
+            b'\\' => escaped.extend_from_slice(b"\\\\"),
+            b'\n' => escaped.extend_from_slice(b"\\n"),
+            b'\r' => escaped.extend_from_slice(b"\\r"),
+            byte => escaped.push(*byte),
+        }
+    }
+    escaped
+}
+
+// None on a dangling backslash or an unknown escape
+fn unescape_field(field: &[u8]) -> Option<Vec<u8>> {
+    let mut unescaped = Vec::with_capacity(field.len());
+    let mut bytes = field.iter();
+    while let Some(byte) = bytes.next() {
+        if *byte != b'\\' {
+            unescaped.push(*byte);
+            continue;
+        }
+        match bytes.next()? {
+            b',' => unescaped.push(b','),
+            b'\\' => unescaped.push(b'\\'),
+            b'n' => unescaped.push(b'\n'),
+            b'r' => unescaped.push(b'\r'),
+            _ => return None,
+        }
+    }
+    Some(unescaped)
+}
+
+// splits an escaped body at its first comma that is not escaped
+fn split_escaped(body: &[u8]) -> Option<(&[u8], &[u8])> {
+    let mut escaped = false;
+    for (i, byte) in body.iter().enumerate() {
+        match byte {
+            _ if escaped => escaped = false,
+            b'\\' => escaped = true,
+            b',' => return Some((&body[..i], &body[i + 1..])),
+            _ => {}
+        }
+    }
+    None
+}
+
+// The header flags, key and value of a record as they are stored. A checksum
+// field, if the record has one, is set to the checksum of that key and value.
+fn encode_fields(record: &Record) -> (RecordHeader, Vec<u8>, Vec<u8>) {
+    let mut header = record.header.clone();
+    let mut key = record.key.clone();
+    let mut value = record.value.clone();
+    header.flags &= !FLAG_ESCAPED;
+    if record.is_tombstone() {
+        value.clear();
+    }
+    if needs_escaping(&key) || needs_escaping(&value) {
+        header.flags |= FLAG_ESCAPED;
+        key = escape_field(&key);
+        value = escape_field(&value);
+    }
+    if let Some(checksum) = header.fields.get_mut(&FIELD_CHECKSUM) {
+        *checksum = crc32(&[key.as_slice(), b",", value.as_slice()].concat()) as u64;
+    }
+    (header, key, value)
+}
+
+// False if the record carries a checksum that its key and value do not match.
+fn checksum_matches(record: &Record) -> bool {
+    match record.header.fields.get(&FIELD_CHECKSUM) {
+        Some(stored) => encode_fields(record).0.fields.get(&FIELD_CHECKSUM) == Some(stored),
+        None => true,
+    }
+}
+
+// Encodes a record without its line terminator. Plain records keep the
+// headerless `key,value` form so existing segments stay readable.
+fn encode_record(record: &Record) -> Vec<u8> {
+    let (header, key, value) = encode_fields(record);
+    let is_plain_tombstone = header.flags == FLAG_TOMBSTONE && header.fields.is_empty();
+    // a headerless empty value would read back as a tombstone
+    let is_plain = (header == RecordHeader::default() && !value.is_empty()) || is_plain_tombstone;
+    let mut line = Vec::with_capacity(key.len() + value.len() + 1);
+    if !is_plain || key.first() == Some(&HEADER_MARKER) {
+        line.push(HEADER_MARKER);
+        line.extend_from_slice(encode_header(&header).as_bytes());
+        line.push(HEADER_MARKER);
+    }
+    line.extend_from_slice(&key);
+    line.push(b',');
+    line.extend_from_slice(&value);
+    line
+}
+
+// The part of a header between its markers, `decode_header` reads it back.
+fn encode_header(header: &RecordHeader) -> String {
+    let mut encoded = format!("{:x}", header.flags);
+    for (id, value) in header.fields.iter() {
+        encoded.push_str(&format!(";{}={}", id, value));
+    }
+    encoded
+}
+
+// Parses the part of a header between its markers.
+fn decode_header(encoded: &str) -> Option<RecordHeader> {
+    let mut header = RecordHeader::default();
+    let mut parts = encoded.split(';');
+    header.flags = u8::from_str_radix(parts.next()?, 16).ok()?;
+    for field in parts {
+        let (id, value) = field.split_once('=')?;
+        let mut id_chars = id.chars();
+        let id = match (id_chars.next(), id_chars.next()) {
+            (Some(id), None) => id,
+            _ => return None,
+        };
+        header.fields.insert(id, value.parse::<u64>().ok()?);
+    }
+    Some(header)
+}
+
+// The one parser for stored records, None if the line is not a valid record.
+fn decode_record(line: &[u8]) -> Option<Record> {
+    let mut header = RecordHeader::default();
+    let mut body = line;
+    let has_header = line.first() == Some(&HEADER_MARKER);
+    if let Some(rest) = line.strip_prefix(&[HEADER_MARKER]) {
+        let end = rest.iter().position(|byte| *byte == HEADER_MARKER)?;
+        header = decode_header(std::str::from_utf8(&rest[..end]).ok()?)?;
+        body = &rest[end + 1..];
+    }
+    let (key, value) = match header.flags & FLAG_ESCAPED != 0 {
+        true => {
+            let (key, value) = split_escaped(body)?;
+            (unescape_field(key)?, unescape_field(value)?)
+        }
+        false => {
+            let (key, value) = split_line(body)?;
+            (key.to_vec(), value.to_vec())
+        }
+    };
+    // on disk a headerless tombstone is written as `key,`
+    if !has_header && value.is_empty() {
+        header.flags |= FLAG_TOMBSTONE;
+    }
+    Some(Record { header, key, value })
+}
+
+// Encodes a record as a frame of the binary codec, see `FRAME_MARKER`. Keys
+// and values are stored as they are, and a tombstone is told by its flag alone.
+fn encode_frame(record: &Record) -> Vec<u8> {
+    let mut header = record.header.clone();
+    // the frame carries a checksum of its own
+    header.fields.remove(&FIELD_CHECKSUM);
+    header.flags &= !FLAG_ESCAPED;
+    let value = record.written_value().unwrap_or_default();
+    let header = encode_header(&header);
+    let mut frame = vec![FRAME_MARKER];
+    for part in [header.as_bytes(), &record.key, value] {
+        frame.extend_from_slice(&(part.len() as u32).to_le_bytes());
+        frame.extend_from_slice(part);
+    }
+    let crc = crc32(&frame);
+    frame.extend_from_slice(&crc.to_le_bytes());
+    frame
+}
+
+// None if the frame is cut short, has bytes left over or fails its checksum.
+fn decode_frame(frame: &[u8]) -> Option<Record> {
+    let (body, crc) = frame.split_last_chunk::<4>()?;
+    if body.first() != Some(&FRAME_MARKER) || crc32(body) != u32::from_le_bytes(*crc) {
+        return None;
+    }
+    let mut rest = &body[1..];
+    let mut parts = Vec::with_capacity(3);
+    for _ in 0..3 {
+        let (len, tail) = rest.split_first_chunk::<4>()?;
+        let (part, tail) = tail.split_at_checked(u32::from_le_bytes(*len) as usize)?;
+        parts.push(part);
+        rest = tail;
+    }
+    if !rest.is_empty() {
+        return None;
+    }
+    // a delete is read from the flag, whatever bytes the value holds
+    Some(Record {
+        header: decode_header(std::str::from_utf8(parts[0]).ok()?)?,
+        key: parts[1].to_vec(),
+        value: parts[2].to_vec(),
+    })
+}
+
+// How the records of a store are laid out in its segments. The codec is
+// recorded in the manifest, a store without one is read as text.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum RecordCodec {
+    // `key,value` lines, escaped when a key or value holds a separator
+    #[default]
+    Text,
+    // length prefixed frames holding keys and values as they are, see `FRAME_MARKER`
+    Binary,
+}
+
+// `text` or `binary`
+pub fn record_codec(name: &str) -> Option<RecordCodec> {
+    match name {
+        "text" => Some(RecordCodec::Text),
+        "binary" => Some(RecordCodec::Binary),
+        _ => None,
+    }
+}
+
+impl RecordCodec {
+    pub fn name(self) -> &'static str {
+        match self {
+            RecordCodec::Text => "text",
+            RecordCodec::Binary => "binary",
+        }
+    }
+
+    // A record as it is appended to a segment, its newline included.
+    fn encode(self, record: &Record) -> Vec<u8> {
+        match self {
+            RecordCodec::Text => {
+                let mut line = encode_record(record);
+                line.push(b'\n');
+                line
+            }
+            RecordCodec::Binary => encode_frame(record),
+        }
+    }
+
+    // The record in what `read_frame` read, None if it is not a valid one.
+    fn decode(self, frame: &[u8]) -> Option<Record> {
+        match self {
+            RecordCodec::Text => decode_record(frame),
+            RecordCodec::Binary => decode_frame(frame),
+        }
+    }
+}
+
+// A line that `decode_record` rejects, as an error naming it.
+fn corrupt_line(line: &[u8]) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("Failed to split line [{}]", String::from_utf8_lossy(line)),
+    )
+}
+
+impl Segment {
+    pub fn new(
+        storage: &Arc<dyn Storage>,
+        file_path: String,
+        codec: RecordCodec,
+    ) -> Result<Self, KvError> {
+        if !storage.exists(&file_path) {
+            storage.write(&file_path, b"")?;
+        }
+        let started = std::time::Instant::now();
+        let mut file = open_segment(&**storage, &file_path)?;
+        let size = file.len()?;
+        let inflated = match &file {
+            SegmentFile::Inflated(text) => Some(text.get_ref().clone()),
+            SegmentFile::Plain(_) | SegmentFile::Mapped(_) => None,
+        };
+        let blocks = read_block_index(&mut file, &file_path)?;
+        let ((index, record_count, max_sequence), from_hint) =
+            match (&blocks, read_hint(&**storage, &file_path)) {
+                // only the sequence numbers are needed from the records
+                (Some(_), _) => {
+                    let (_, _, max_sequence) = index_records(file, &file_path, 0, codec)?;
+                    ((HashMap::new(), 0, max_sequence), false)
+                }
+                (None, Some(hint)) => (hint, true),
+                (None, None) => (index_records(file, &file_path, 0, codec)?, false),
+            };
+        Ok(Segment {
+            file_path: file_path.clone(),
+            storage: storage.clone(),
+            index: SegmentIndex::Hashed(index),
+            build_time: started.elapsed(),
+            from_hint,
+            checksums: false,
+            record_count,
+            max_sequence,
+            codec,
+            filter: None,
+            size,
+            blocks,
+            appender: None,
+            inflated,
+            mapped: None,
+            index_cap: None,
+            trimmed_from: None,
+            recovered: Mutex::new(HashMap::new()),
+            access_clock: AtomicU64::new(0),
+            last_access: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn reader(&self) -> Result<SegmentFile, std::io::Error> {
+        if let Some(text) = &self.inflated {
+            return Ok(SegmentFile::Inflated(std::io::Cursor::new(text.clone())));
+        }
+        if let Some(mapped) = &self.mapped {
+            return Ok(SegmentFile::Mapped(std::io::Cursor::new(mapped.clone())));
+        }
+        open_segment(&*self.storage, &self.file_path)
+    }
+
+    // the whole text when it is in memory, inflated or mapped
+    fn text(&self) -> Option<&[u8]> {
+        match (&self.inflated, &self.mapped) {
+            (Some(text), _) => Some(text),
+            (None, Some(mapped)) => Some(mapped),
+            (None, None) => None,
+        }
+    }
+
+    // Maps the file of a retired segment, or drops the mapping. Compressed
+    // segments are in memory anyway and the write segment is never mapped.
+    fn map(&mut self, enabled: bool) -> Result<(), std::io::Error> {
+        let local_path = self.storage.local_path(&self.file_path);
+        self.mapped = match (enabled && self.inflated.is_none(), local_path) {
+            (true, _) if self.mapped.is_some() => return Ok(()),
+            (true, Some(local_path)) => mmap::map(&File::open(local_path)?)?,
+            (true, None) | (false, _) => None,
+        };
+        Ok(())
+    }
+
+    // The record at `offset` as `read_frame` reads it. A segment in memory is
+    // read without touching the file, otherwise through the reader kept in `open`.
+    fn line_at(&self, offset: u64, open: &mut OpenSegments) -> Result<Vec<u8>, KvError> {
+        let beyond_eof = |file_len: u64| KvError::OffsetBeyondEof {
+            file_path: self.file_path.clone(),
+            offset,
+            file_len,
+        };
+        if let Some(text) = self.text() {
+            let rest = text.get(offset as usize..).filter(|rest| !rest.is_empty());
+            let mut rest = rest.ok_or_else(|| beyond_eof(text.len() as u64))?;
+            let frame = read_frame(&mut rest, self.codec)?;
+            return Ok(frame.map(|frame| frame.bytes).unwrap_or_default());
+        }
+        let buf_reader = match open.0.entry(self.file_path.clone()) {
+            std::collections::hash_map::Entry::Occupied(entry) => entry.into_mut(),
+            std::collections::hash_map::Entry::Vacant(entry) => {
+                entry.insert(BufReader::new(self.reader()?))
+            }
+        };
+        let file_len = buf_reader.get_ref().len()?;
+        if offset >= file_len {
+            return Err(beyond_eof(file_len));
+        }
+        buf_reader.seek(SeekFrom::Start(offset))?;
+        let frame = read_frame(buf_reader, self.codec)?;
+        Ok(frame.map(|frame| frame.bytes).unwrap_or_default())
+    }
+
+    // Replaces the file of a sealed segment with a gzip compressed copy named
+    // `<file>.gz`. Offsets stay those of the text, which is kept in memory.
+    fn compress(&mut self) -> Result<(), std::io::Error> {
+        let text = self.storage.read(&self.file_path)?;
+        let compressed_path = format!("{}.{}", self.file_path, COMPRESSED_SUFFIX);
+        let tmp_path = format!("{}.tmp", compressed_path);
+        self.storage.write(&tmp_path, &gzip::compress(&text))?;
+        self.storage.rename(&tmp_path, &compressed_path)?;
+        self.storage.remove(&self.file_path)?;
+        remove_index_files(&*self.storage, &self.file_path)?;
+        self.file_path = compressed_path;
+        self.inflated = Some(text.into());
+        self.mapped = None;
+        Ok(())
+    }
+
+    // Opens a retired segment with its Bloom filter, built from the index when
+    // the saved one is missing or older than the segment.
+    pub fn open_retired(
+        storage: &Arc<dyn Storage>,
+        file_path: String,
+        codec: RecordCodec,
+    ) -> Result<Self, KvError> {
+        let mut segment = Segment::new(storage, file_path, codec)?;
+        let filter = match read_filter(&**storage, &segment.file_path) {
+            Some(filter) => filter,
+            None if segment.blocks.is_some() => BloomFilter::with_keys(segment.keys()?.iter()),
+            None => BloomFilter::with_keys(segment.index.keys()),
+        };
+        segment.filter = Some(filter);
+        Ok(segment)
+    }
+
+    // False only if the segment surely holds no record of `key`.
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        self.filter
+            .as_ref()
+            .is_none_or(|filter| filter.contains(key))
+    }
+
+    // Saves the index of a segment that was just retired or written by
+    // compaction, and builds and saves its Bloom filter.
+    fn persist_index(&mut self) -> Result<(), std::io::Error> {
+        // an SSTable carries its block index, it needs no hint
+        let filter = match self.blocks {
+            Some(_) => BloomFilter::with_keys(self.keys()?.iter()),
+            None => {
+                write_hint(
+                    &*self.storage,
+                    &self.file_path,
+                    &self.index,
+                    self.record_count,
+                    self.max_sequence,
+                )?;
+                BloomFilter::with_keys(self.index.keys())
+            }
+        };
+        write_filter(&*self.storage, &self.file_path, &filter)?;
+        self.filter = Some(filter);
+        Ok(())
+    }
+
+    // a segment that is never written to and has no file behind it
+    pub fn empty(storage: &Arc<dyn Storage>, file_path: String) -> Self {
+        Segment {
+            file_path,
+            storage: storage.clone(),
+            index: SegmentIndex::Hashed(HashMap::new()),
+            size: 0,
+            blocks: None,
+            appender: None,
+            index_cap: None,
+            trimmed_from: None,
+            recovered: Mutex::new(HashMap::new()),
+            access_clock: AtomicU64::new(0),
+            last_access: Mutex::new(HashMap::new()),
+            build_time: std::time::Duration::ZERO,
+            from_hint: false,
+            checksums: false,
+            codec: RecordCodec::Text,
+            inflated: None,
+            mapped: None,
+            filter: None,
+            record_count: 0,
+            max_sequence: 0,
+        }
+    }
+
+    // Where the record of `key` starts. An SSTable reads the one block that
+    // can hold the key.
+    fn offset_of(&self, key: &[u8]) -> Result<Option<u64>, std::io::Error> {
+        let blocks = match &self.blocks {
+            Some(blocks) => blocks,
+            None => {
+                return match self.index.get(key) {
+                    Some(offset) => Ok(Some(*offset)),
+                    None => self.recover_offset(key),
+                };
+            }
+        };
+        let found = match blocks.block_of(key) {
+            Some(block) => self.read_block(blocks, block)?,
+            None => Vec::new(),
+        };
+        Ok(found
+            .into_iter()
+            .find(|(_, record)| record.key == key)
+            .map(|(offset, _)| offset))
+    }
+
+    // The records of `block` of an SSTable with their offsets.
+    fn read_block(
+        &self,
+        blocks: &BlockIndex,
+        block: usize,
+    ) -> Result<Vec<(u64, Record)>, std::io::Error> {
+        blocks.reads[block].fetch_add(1, Ordering::Relaxed);
+        let span = blocks.span(block);
+        let mut file = self.reader()?;
+        file.seek(SeekFrom::Start(span.start))?;
+        let mut records = Vec::new();
+        let mut offset = span.start;
+        let reader = BufReader::new(file.take(span.end - span.start));
+        for line in segment_frames(reader, self.codec) {
+            let (line, line_len) = line?;
+            let record = self
+                .codec
+                .decode(&line)
+                .ok_or_else(|| corrupt_line(&line))?;
+            records.push((offset, record));
+            offset += line_len;
+        }
+        Ok(records)
+    }
+
+    // Every key with a record in this segment. The keys of an SSTable, and
+    // those evicted from a trimmed index, are read from its file.
+    pub fn keys(&self) -> Result<HashSet<Vec<u8>>, std::io::Error> {
+        match (&self.blocks, self.trimmed_from) {
+            (None, None) => Ok(self.index.keys().cloned().collect()),
+            _ => Ok(build_index(&*self.storage, &self.file_path, self.codec)?
+                .into_keys()
+                .collect()),
+        }
+    }
+
+    // Keys from `start` up to but excluding `end` with a record in this segment,
+    // sorted. An SSTable reads only the blocks the range overlaps, evicted
+    // index entries are found by scanning the segment.
+    pub fn keys_in(&self, start: &[u8], end: &[u8]) -> Result<Vec<Vec<u8>>, std::io::Error> {
+        let blocks = match (&self.blocks, self.trimmed_from) {
+            (Some(blocks), _) => blocks,
+            (None, None) => return Ok(self.index.keys_in(start, end)),
+            (None, Some(_)) => {
+                let index = build_index(&*self.storage, &self.file_path, self.codec)?;
+                return Ok(SegmentIndex::Hashed(index).keys_in(start, end));
+            }
+        };
+        let mut keys = Vec::new();
+        for block in blocks.blocks_in(start, end) {
+            keys.extend(
+                self.read_block(blocks, block)?
+                    .into_iter()
+                    .map(|(_, record)| record.key)
+                    .filter(|key| key.as_slice() >= start && key.as_slice() < end),
+            );
+        }
+        Ok(keys)
+    }
+
+    // The record of `key` with its header, tombstones included. None if the
+    // segment holds no record of the key.
+    pub fn get_record(
+        &self,
+        key: &[u8],
+        open: &mut OpenSegments,
+    ) -> Result<Option<Record>, KvError> {
+        let offset = match self.offset_of(key)? {
+            Some(offset) => offset,
+            None => return Ok(None),
+        };
+        self.record_access(key);
+        let real_line = self.line_at(offset, open)?;
+        match self.codec.decode(&real_line) {
+            Some(record) if !checksum_matches(&record) => Err(KvError::ChecksumMismatch {
+                file_path: self.file_path.clone(),
+                offset,
+            }),
+            Some(record) if record.key == key => Ok(Some(record)),
+            _ => Err(KvError::Corrupt {
+                file_path: self.file_path.clone(),
+                offset,
+                line: String::from_utf8_lossy(&real_line).into_owned(),
+            }),
+        }
+    }
+
+    // Whether the newest record of `key` here is a live value, None if there is
+    // no record. Only reads the header, or the key of a headerless record, and
+    // never the value, so unlike `get_data` it cannot verify a checksum. A
+    // binary frame has its header behind a length and is read whole.
+    pub fn contains(&self, key: &[u8], now: u64) -> Result<Option<bool>, KvError> {
+        let offset = match self.offset_of(key)? {
+            Some(offset) => offset,
+            None => return Ok(None),
+        };
+        self.record_access(key);
+        let mut file = self.reader()?;
+        // past the end there is nothing to read, as in `line_at`
+        let file_len = file.len()?;
+        if offset >= file_len {
+            return Err(KvError::OffsetBeyondEof {
+                file_path: self.file_path.clone(),
+                offset,
+                file_len,
+            });
+        }
+        file.seek(SeekFrom::Start(offset))?;
+        let mut reader = BufReader::new(file);
+        let corrupt = |prefix: &[u8]| KvError::Corrupt {
+            file_path: self.file_path.clone(),
+            offset,
+            line: String::from_utf8_lossy(prefix).to_string(),
+        };
+        if self.codec == RecordCodec::Binary {
+            let frame = read_frame(&mut reader, self.codec)?;
+            let frame = frame.map(|frame| frame.bytes).unwrap_or_default();
+            let record = self.codec.decode(&frame).ok_or_else(|| corrupt(&frame))?;
+            return Ok(Some(
+                !record.is_tombstone() && !is_expired(&record.header, now),
+            ));
+        }
+        let mut prefix = Vec::new();
+        reader.read_until(b',', &mut prefix)?;
+        if prefix.first() == Some(&HEADER_MARKER) {
+            let end = prefix[1..]
+                .iter()
+                .position(|byte| *byte == HEADER_MARKER)
+                .ok_or_else(|| corrupt(&prefix))?;
+            let header = std::str::from_utf8(&prefix[1..end + 1])
+                .ok()
+                .and_then(decode_header)
+                .ok_or_else(|| corrupt(&prefix))?;
+            return Ok(Some(
+                header.flags & FLAG_TOMBSTONE == 0 && !is_expired(&header, now),
+            ));
+        }
+        if prefix.last() != Some(&b',') {
+            return Err(corrupt(&prefix));
+        }
+        // a headerless record with an empty value is a tombstone, its line
+        // ending right after the delimiter with `\n` or `\r\n`; a `\r` in a
+        // value is escaped
+        let mut next = [0u8; 1];
+        reader.read_exact(&mut next)?;
+        Ok(Some(next[0] != b'\n' && next[0] != b'\r'))
+    }
+
+    // Finds the offset of a key evicted from the index. Every miss on a trimmed
+    // segment pays for a scan of its tail, so the cap trades reads for memory.
+    fn recover_offset(&self, key: &[u8]) -> Result<Option<u64>, std::io::Error> {
+        let trimmed_from = match self.trimmed_from {
+            Some(trimmed_from) => trimmed_from,
+            None => return Ok(None),
+        };
+        if let Some(offset) = self.recovered.lock().unwrap().get(key) {
+            return Ok(Some(*offset));
+        }
+        let offset = build_index_from(&*self.storage, &self.file_path, trimmed_from, self.codec)?
+            .remove(key);
+        if let Some(offset) = offset {
+            self.recovered.lock().unwrap().insert(key.to_vec(), offset);
+        }
+        Ok(offset)
+    }
+
+    fn record_access(&self, key: &[u8]) {
+        if self.index_cap.is_some() {
+            let tick = self.access_clock.fetch_add(1, Ordering::Relaxed) + 1;
+            self.last_access.lock().unwrap().insert(key.to_vec(), tick);
+        }
+    }
+
+    // Keeps at most `cap` index entries, evicting the least recently read keys.
+    // Returns the number of evicted entries.
+    pub fn trim_index(&mut self, cap: usize) -> usize {
+        self.index_cap = Some(cap);
+        let recovered = std::mem::take(self.recovered.get_mut().unwrap());
+        self.index.extend(recovered);
+        if self.index.len() <= cap {
+            return 0;
+        }
+        let last_access = self.last_access.get_mut().unwrap();
+        let mut by_access: Vec<(u64, Vec<u8>)> = self
+            .index
+            .keys()
+            .map(|key| (last_access.get(key).copied().unwrap_or(0), key.clone()))
+            .collect();
+        by_access.sort();
+        let evicted = self.index.len() - cap;
+        for (_, key) in by_access.into_iter().take(evicted) {
+            let offset = self.index.remove(&key).unwrap();
+            last_access.remove(&key);
+            self.trimmed_from = Some(self.trimmed_from.map_or(offset, |from| from.min(offset)));
+        }
+        evicted
+    }
+
+    // Estimated bytes held by the index and the maps around it.
+    pub fn memory_usage(&self) -> usize {
+        let entry_size = std::mem::size_of::<Vec<u8>>() + std::mem::size_of::<u64>();
+        let maps: usize = [
+            &*self.recovered.lock().unwrap(),
+            &*self.last_access.lock().unwrap(),
+        ]
+        .iter()
+        .map(|map| map.capacity() * entry_size + map.keys().map(|key| key.len()).sum::<usize>())
+        .sum();
+        self.index.memory_usage() + maps
+    }
+
+    // Drops the entries recovered by scans and gives unused capacity back.
+    pub fn release_memory(&mut self) {
+        let recovered = self.recovered.get_mut().unwrap();
+        recovered.clear();
+        recovered.shrink_to_fit();
+        if let Some(cap) = self.index_cap {
+            self.trim_index(cap);
+        }
+        self.index.shrink_to_fit();
+        self.last_access.get_mut().unwrap().shrink_to_fit();
+    }
+
+    pub fn records(&self) -> Result<SegmentRecords, std::io::Error> {
+        Ok(SegmentRecords::new(self.reader()?, self.codec))
+    }
+
+    // Every record of this segment in file order, one line read at a time.
+    // Deletes come back with an empty value and the tombstone flag set.
+    pub fn iter(&self) -> Result<SegmentEntries, std::io::Error> {
+        Ok(SegmentEntries {
+            records: self.records()?,
+        })
+    }
+
+    // Appends `buffer` with a single write and returns the offset it starts at,
+    // which is the size of the segment: it is only ever written through here.
+    // If the write fails the file is truncated back, so no torn record is left.
+    fn append(&mut self, buffer: &[u8]) -> Result<u64, std::io::Error> {
+        let offset = self.size;
+        let file = match self.appender.as_mut() {
+            Some(file) => file,
+            None => self.appender.insert(self.storage.append(&self.file_path)?),
+        };
+        if let Err(e) = file.write_all(buffer).and_then(|_| file.flush()) {
+            file.set_len(offset)?;
+            return Err(e);
+        }
+        Ok(offset)
+    }
+
+    // Closes the append handle of a segment that is done being written.
+    pub fn seal(&mut self) {
+        self.appender = None;
+    }
+
+    // the line of a record as this segment writes it, or its binary frame
+    fn encode_line(&self, record: &Record) -> Vec<u8> {
+        // a binary frame always carries a checksum
+        let as_is = !self.checksums
+            || self.codec == RecordCodec::Binary
+            || record.header.fields.contains_key(&FIELD_CHECKSUM);
+        match as_is {
+            true => self.codec.encode(record),
+            false => {
+                let mut record = record.clone();
+                // the value is filled in by encode_record
+                record.header.fields.insert(FIELD_CHECKSUM, 0);
+                self.codec.encode(&record)
+            }
+        }
+    }
+
+    // the line `save_record` writes for `record`
+    fn saved_line(&self, record: &Record) -> Vec<u8> {
+        // framing only holds within the append that wrote the batch
+        let mut record = record.clone();
+        record.header.fields.remove(&FIELD_BATCH);
+        self.encode_line(&record)
+    }
+
+    pub fn save_record(&mut self, record: &Record) -> Result<(), std::io::Error> {
+        let line = self.saved_line(record);
+        let offset = self.append(&line)?;
+        self.index.insert(record.key.clone(), offset);
+        self.size = offset + line.len() as u64;
+        self.record_count += 1;
+        self.note_sequence(record);
+        Ok(())
+    }
+
+    fn note_sequence(&mut self, record: &Record) {
+        if let Some(sequence) = record.header.fields.get(&FIELD_SEQUENCE) {
+            self.max_sequence = self.max_sequence.max(*sequence);
+        }
+    }
+
+    // Rewrites the record of `key` in place when the new record is no longer
+    // than the old one, padding the rest of the old record with a filler line.
+    // Returns the number of bytes rewritten, or None if the record has to be
+    // appended, as records written by a batch always are: rewriting one would
+    // change how many records its batch is read to have. Binary frames are
+    // always appended as well.
+    pub fn overwrite_in_place(&mut self, record: &Record) -> Result<Option<u64>, std::io::Error> {
+        let offset = match self.index.get(&record.key) {
+            Some(offset) if self.codec == RecordCodec::Text => *offset,
+            _ => return Ok(None),
+        };
+        let mut file = self.storage.open_writable(&self.file_path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut old_line = Vec::new();
+        BufReader::new(&mut file).read_until(b'\n', &mut old_line)?;
+        let is_batched = decode_record(old_line.strip_suffix(b"\n").unwrap_or(&old_line))
+            .is_none_or(|old| old.header.fields.contains_key(&FIELD_BATCH));
+        let mut line = self.encode_line(record);
+        if is_batched || line.len() > old_line.len() {
+            return Ok(None);
+        }
+        let filler = old_line.len() - line.len();
+        if filler > 0 {
+            line.extend_from_slice(" ".repeat(filler - 1).as_bytes());
+            line.push(b'\n');
+        }
+        overwrite_or_restore(&mut file, offset, &old_line, &line)?;
+        self.note_sequence(record);
+        Ok(Some(line.len() as u64))
+    }
+
+    // Appends the records with a single write. Every record carries the number
+    // of records still to follow, 0 for the last one, so an append cut short by
+    // a crash is recognized on open and dropped as a whole.
+    pub fn save_batch(&mut self, records: &[Record]) -> Result<(), std::io::Error> {
+        let mut buffer = Vec::new();
+        let mut offsets = Vec::new();
+        for (position, record) in records.iter().enumerate() {
+            offsets.push((record.key.clone(), buffer.len() as u64));
+            let mut record = record.clone();
+            let following = (records.len() - position - 1) as u64;
+            record.header.fields.insert(FIELD_BATCH, following);
+            buffer.extend_from_slice(&self.encode_line(&record));
+        }
+        // the whole batch goes out in a single write
+        let offset = self.append(&buffer)?;
+        self.index.extend(
+            offsets
+                .into_iter()
+                .map(|(key, relative)| (key, offset + relative)),
+        );
+        self.size = offset + buffer.len() as u64;
+        self.record_count += records.len() as u64;
+        for record in records.iter() {
+            self.note_sequence(record);
+        }
+        Ok(())
+    }
+
+    // Percentage of the records that are the newest of their key, 100 while empty.
+    fn utilization(&self) -> u64 {
+        match self.record_count {
+            0 => 100,
+            records => self.index.len() as u64 * 100 / records,
+        }
+    }
+
+    // Writes `records` to this empty segment as an SSTable, see `BlockIndex`,
+    // starting a block once the current one holds `block_size` bytes. The
+    // records are sorted in byte order, which the block index is searched by
+    // whatever the comparator. Only the block index is kept in memory.
+    fn save_sstable(
+        &mut self,
+        mut records: Vec<Record>,
+        block_size: u64,
+    ) -> Result<(), std::io::Error> {
+        records.sort_by(|a, b| a.key.cmp(&b.key));
+        let (blocks, offset) = self.sstable_blocks(&records, block_size);
+        let mut writer = BufWriter::new(self.storage.append(&self.file_path)?);
+        for record in records.iter() {
+            writer.write_all(&self.saved_line(record))?;
+        }
+        let blocks = BlockIndex::new(blocks, offset);
+        let lines = blocks.encode();
+        writer.write_all(&lines)?;
+        writer.flush()?;
+        self.size = offset + lines.len() as u64;
+        self.blocks = Some(blocks);
+        Ok(())
+    }
+
+    // The first key and offset of each block `save_sstable` splits `records`,
+    // sorted by key, into, and the offset the records end at.
+    fn sstable_blocks(&self, records: &[Record], block_size: u64) -> (Vec<(Vec<u8>, u64)>, u64) {
+        let mut blocks: Vec<(Vec<u8>, u64)> = Vec::new();
+        let mut offset = 0;
+        for record in records {
+            let block_full = blocks
+                .last()
+                .is_none_or(|(_, start)| offset - start >= block_size);
+            if block_full {
+                blocks.push((record.key.clone(), offset));
+            }
+            offset += self.saved_line(record).len() as u64;
+        }
+        (blocks, offset)
+    }
+}
+
+// Values of recently read keys, evicting the least recently read one beyond
+// `capacity`. A capacity of 0 disables it.
+#[derive(Debug, Default)]
+struct ValueCache {
+    capacity: usize,
+    // value, when its record expires and when it was last read
+    entries: HashMap<Vec<u8>, (Vec<u8>, Option<u64>, u64)>,
+    by_last_read: BTreeMap<u64, Vec<u8>>,
+    clock: u64,
+}
+
+impl ValueCache {
+    fn get(&mut self, key: &[u8], now: u64) -> Option<Vec<u8>> {
+        let (value, expires_at, last_read) = self.entries.get_mut(key)?;
+        if expires_at.is_some_and(|expires_at| expires_at <= now) {
+            self.invalidate(key);
+            return None;
+        }
+        self.clock += 1;
+        let key = self.by_last_read.remove(last_read).unwrap();
+        *last_read = self.clock;
+        let value = value.clone();
+        self.by_last_read.insert(self.clock, key);
+        Some(value)
+    }
+
+    fn insert(&mut self, key: &[u8], value: &[u8], expires_at: Option<u64>) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.invalidate(key);
+        self.clock += 1;
+        self.entries
+            .insert(key.to_vec(), (value.to_vec(), expires_at, self.clock));
+        self.by_last_read.insert(self.clock, key.to_vec());
+        while self.entries.len() > self.capacity {
+            let (_, evicted) = self.by_last_read.pop_first().unwrap();
+            self.entries.remove(&evicted);
+        }
+    }
+
+    fn invalidate(&mut self, key: &[u8]) {
+        if let Some((_, _, last_read)) = self.entries.remove(key) {
+            self.by_last_read.remove(&last_read);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.by_last_read.clear();
+    }
+}
+
+const LATENCY_BUCKETS: usize = 32;
+const LATENCY_TRACKED_COMMANDS: [&str; 4] = ["GET", "SET", "DELETE", "COMPACT"];
+
+// Fixed-bucket latency histogram, bucket `i` counts durations below 2^i microseconds.
+#[derive(Debug, Default)]
+struct LatencyHistogram {
+    buckets: [u64; LATENCY_BUCKETS],
+    count: u64,
+}
+
+impl LatencyHistogram {
+    pub fn record(&mut self, elapsed: std::time::Duration) {
+        let micros = elapsed.as_micros().max(1) as u64;
+        let bucket = (u64::BITS - micros.leading_zeros()) as usize;
+        self.buckets[bucket.min(LATENCY_BUCKETS - 1)] += 1;
+        self.count += 1;
+    }
+
+    // upper bound in microseconds of the bucket holding the given percentile
+    pub fn percentile(&self, percentile: u64) -> u64 {
+        let target = (self.count * percentile).div_ceil(100);
+        let mut seen = 0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= target {
+                return 1 << bucket;
+            }
+        }
+        1 << (LATENCY_BUCKETS - 1)
+    }
+}
+
+// What a sweep of the expiry index did, see `Environment::sweep_expired`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SweepSummary {
+    // entries taken off the index, including those of keys written again since
+    examined: usize,
+    // keys tombstoned because their newest record had expired
+    removed: usize,
+}
+
+#[derive(Debug, Default)]
+struct Metrics {
+    gets: AtomicU64,
+    sets: AtomicU64,
+    deletes: AtomicU64,
+    compactions: AtomicU64,
+    read_fanout_exceeded: AtomicU64,
+    // every byte appended to segment files, by writes and by compaction
+    bytes_written: AtomicU64,
+    keys_evicted: AtomicU64,
+    // segments a read skipped because their Bloom filter ruled the key out
+    filter_negatives: AtomicU64,
+}
+
+impl Metrics {
+    // Current value of every counter, zeroing each one as it is read when `reset` is set.
+    pub fn snapshot(&self, reset: bool) -> Vec<(&'static str, u64)> {
+        let counters = [
+            ("gets", &self.gets),
+            ("sets", &self.sets),
+            ("deletes", &self.deletes),
+            ("compactions", &self.compactions),
+            ("read_fanout_exceeded", &self.read_fanout_exceeded),
+            ("bytes_written", &self.bytes_written),
+            ("keys_evicted", &self.keys_evicted),
+            ("filter_negatives", &self.filter_negatives),
+        ];
+        counters
+            .into_iter()
+            .map(|(name, counter)| match reset {
+                true => (name, counter.swap(0, Ordering::Relaxed)),
+                false => (name, counter.load(Ordering::Relaxed)),
+            })
+            .collect()
+    }
+}
+
+// Streams the records of a segment in file order.
+struct SegmentRecords {
+    reader: BufReader<SegmentFile>,
+    codec: RecordCodec,
+}
+
+impl SegmentRecords {
+    fn new(file: SegmentFile, codec: RecordCodec) -> Self {
+        SegmentRecords {
+            reader: BufReader::new(file),
+            codec,
+        }
+    }
+}
+
+impl Iterator for SegmentRecords {
+    type Item = Result<Record, std::io::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let real_line = loop {
+            match read_frame(&mut self.reader, self.codec) {
+                Ok(Some(frame))
+                    if frame.bytes.starts_with(BLOCK_LINE_MARKER) || is_padding(&frame.bytes) =>
+                {
+                    continue;
+                }
+                Ok(Some(frame)) => break frame.bytes,
+                Ok(None) => return None,
+                Err(e) => return Some(Err(e)),
+            }
+        };
+        match self.codec.decode(&real_line) {
+            Some(record) if !checksum_matches(&record) => Some(Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "Checksum mismatch in line [{}]",
+                    String::from_utf8_lossy(&real_line)
+                ),
+            ))),
+            Some(record) => Some(Ok(record)),
+            None => Some(Err(corrupt_line(&real_line))),
+        }
+    }
+}
+
+// Writes `new` over `old` at `offset`. If the write fails partway the old
+// bytes are put back, so the file never holds half of each record.
+fn overwrite_or_restore(
+    file: &mut (impl Write + Seek),
+    offset: u64,
+    old: &[u8],
+    new: &[u8],
+) -> Result<(), std::io::Error> {
+    file.seek(SeekFrom::Start(offset))?;
+    if let Err(e) = file.write_all(new).and_then(|_| file.flush()) {
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(old)?;
+        return Err(e);
+    }
+    Ok(())
+}
+
+// The lines of `reader` as bytes, without their line break. Keys may be any
+// bytes, so a segment is not read as text.
+fn byte_lines<R: BufRead>(reader: R) -> ByteLines<R> {
+    ByteLines { reader }
+}
+
+struct ByteLines<R> {
+    reader: R,
+}
+
+impl<R: BufRead> Iterator for ByteLines<R> {
+    type Item = Result<Vec<u8>, std::io::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut line = Vec::new();
+        match self.reader.read_until(b'\n', &mut line) {
+            Ok(0) => None,
+            Ok(_) => {
+                if line.last() == Some(&b'\n') {
+                    line.pop();
+                }
+                Some(Ok(line))
+            }
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+// Splits a line at its first comma.
+fn split_line(line: &[u8]) -> Option<(&[u8], &[u8])> {
+    let comma = line.iter().position(|byte| *byte == b',')?;
+    Some((&line[..comma], &line[comma + 1..]))
+}
+
+// `(key, value, is_tombstone)` for each record of a segment.
+pub struct SegmentEntries {
+    records: SegmentRecords,
+}
+
+impl Iterator for SegmentEntries {
+    type Item = Result<(Vec<u8>, Vec<u8>, bool), std::io::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let record = match self.records.next()? {
+            Ok(record) => record,
+            Err(e) => return Some(Err(e)),
+        };
+        Some(Ok(match record.is_tombstone() {
+            true => (record.key, Vec::new(), true),
+            false => (record.key, record.value, false),
+        }))
+    }
+}
+
+// Retired segments held by snapshots. Compaction cannot delete a held segment,
+// it moves it out of the way and the last snapshot releasing it deletes it.
+#[derive(Debug)]
+struct SegmentPins {
+    storage: Arc<dyn Storage>,
+    counts: HashMap<String, usize>,
+    // held segments dropped by compaction, with the name they were moved to
+    obsolete: HashMap<String, String>,
+}
+
+impl SegmentPins {
+    fn new(storage: Arc<dyn Storage>) -> Self {
+        SegmentPins {
+            storage,
+            counts: HashMap::new(),
+            obsolete: HashMap::new(),
+        }
+    }
+
+    fn pin(&mut self, file_path: &str) {
+        *self.counts.entry(file_path.to_string()).or_default() += 1;
+    }
+
+    fn unpin(&mut self, file_path: &str) -> Result<(), std::io::Error> {
+        let count = self.counts.get_mut(file_path).unwrap();
+        *count -= 1;
+        if *count == 0 {
+            self.counts.remove(file_path);
+            if let Some(moved_to) = self.obsolete.remove(file_path) {
+                self.storage.remove(&moved_to)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Deletes a segment that is no longer part of the store, or defers that while it is held.
+    fn remove(&mut self, file_path: &str) -> Result<(), std::io::Error> {
+        remove_index_files(&*self.storage, file_path)?;
+        if !self.counts.contains_key(file_path) {
+            return self.storage.remove(file_path);
+        }
+        // renamed so reopening the store does not load it as a live segment
+        let moved_to = format!("{}.{}", file_path, OBSOLETE_SUFFIX);
+        self.storage.rename(file_path, &moved_to)?;
+        self.obsolete.insert(file_path.to_string(), moved_to);
+        Ok(())
+    }
+
+    // where a held segment can be read now
+    fn resolve(&self, file_path: &str) -> String {
+        self.obsolete
+            .get(file_path)
+            .cloned()
+            .unwrap_or_else(|| file_path.to_string())
+    }
+}
+
+// The store as it was when the snapshot was taken. Retired segments are pinned
+// and read lazily, the write segment keeps changing so its records are copied.
+pub struct Snapshot {
+    storage: Arc<dyn Storage>,
+    pins: Arc<Mutex<SegmentPins>>,
+    comparator: Arc<dyn KeyComparator>,
+    // oldest first
+    segment_paths: Vec<String>,
+    write_records: HashMap<Vec<u8>, Option<Vec<u8>>>,
+    // records expired by then read as tombstones
+    now: u64,
+    codec: RecordCodec,
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        let mut pins = self.pins.lock().unwrap();
+        for file_path in self.segment_paths.iter() {
+            if let Err(e) = pins.unpin(file_path) {
+                eprintln!(
+                    "Could not remove compacted segment [{}]: [{}]",
+                    file_path, e
+                );
+            }
+        }
+    }
+}
+
+// Live records of a snapshot, segment by segment from the newest one. Only the
+// keys already returned are kept in memory, besides the segment being read.
+pub struct SnapshotIter {
+    snapshot: Snapshot,
+    // segments not read yet, counted from the oldest
+    remaining: usize,
+    write_segment_read: bool,
+    seen: HashSet<Vec<u8>>,
+    pending: VecDeque<(Vec<u8>, Vec<u8>)>,
+}
+
+impl SnapshotIter {
+    fn new(snapshot: Snapshot) -> Self {
+        SnapshotIter {
+            remaining: snapshot.segment_paths.len(),
+            snapshot,
+            write_segment_read: false,
+            seen: HashSet::new(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    // Queues the records of the next segment that no newer segment shadows.
+    fn read_next_segment(&mut self) -> Result<bool, std::io::Error> {
+        let records = if !self.write_segment_read {
+            self.write_segment_read = true;
+            std::mem::take(&mut self.snapshot.write_records)
+        } else if self.remaining > 0 {
+            self.remaining -= 1;
+            let file_path = &self.snapshot.segment_paths[self.remaining];
+            let file_path = self.snapshot.pins.lock().unwrap().resolve(file_path);
+            let mut records = HashMap::new();
+            let file = open_segment(&*self.snapshot.storage, &file_path)?;
+            for record in SegmentRecords::new(file, self.snapshot.codec) {
+                let record = record?;
+                records.insert(record.key.clone(), visible_value(record, self.snapshot.now));
+            }
+            records
+        } else {
+            return Ok(false);
+        };
+        let mut records: Vec<(Vec<u8>, Vec<u8>)> = records
+            .into_iter()
+            .filter(|(key, _)| self.seen.insert(key.clone()))
+            .filter_map(|(key, value)| Some((key, value?)))
+            .collect();
+        let comparator = &self.snapshot.comparator;
+        records.sort_by(|(a, _), (b, _)| comparator.compare(a, b));
+        self.pending.extend(records);
+        Ok(true)
+    }
+}
+
+impl Iterator for SnapshotIter {
+    type Item = Result<(Vec<u8>, Vec<u8>), std::io::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(record) = self.pending.pop_front() {
+                return Some(Ok(record));
+            }
+            match self.read_next_segment() {
+                Ok(true) => (),
+                Ok(false) => return None,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+// Live keys of a snapshot, read one segment at a time.
+pub struct Keys {
+    records: SnapshotIter,
+}
+
+impl Iterator for Keys {
+    type Item = Result<Vec<u8>, KvError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let record = self.records.next()?;
+        Some(record.map(|(key, _)| key).map_err(KvError::from))
+    }
+}
+
+// Live records of a snapshot whose keys start with `prefix`. The index is not
+// sorted, so every live key is visited: O(n) in the keys of the store.
+pub struct PrefixScan {
+    records: SnapshotIter,
+    prefix: Vec<u8>,
+}
+
+impl Iterator for PrefixScan {
+    type Item = Result<(Vec<u8>, String), KvError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.records.next()? {
+                Ok((key, value)) if key.starts_with(&self.prefix) => {
+                    return Some(utf8_value(&key, value).map(|value| (key, value)));
+                }
+                Ok(_) => continue,
+                Err(e) => return Some(Err(e.into())),
+            }
+        }
+    }
+}
+
+// Live records with keys from a start up to but excluding an end, in byte order
+// whatever the comparator. The keys are gathered up front from the indexes,
+// sorted ones answer in O(log n) per segment, hashed ones visit every key.
+pub struct RangeScan<'a> {
+    // the newest segment holding each key
+    keys: std::collections::btree_map::IntoIter<Vec<u8>, &'a Segment>,
+    now: u64,
+    open: OpenSegments,
+}
+
+impl<'a> RangeScan<'a> {
+    fn new(env: &'a Environment, start: &[u8], end: &[u8]) -> Result<Self, KvError> {
+        let mut newest: BTreeMap<Vec<u8>, &Segment> = BTreeMap::new();
+        for segment in std::iter::once(&env.write_segment).chain(env.segments.iter().rev()) {
+            for key in segment.keys_in(start, end)? {
+                newest.entry(key).or_insert(segment);
+            }
+        }
+        Ok(RangeScan {
+            keys: newest.into_iter(),
+            now: (env.clock)(),
+            open: OpenSegments::default(),
+        })
+    }
+}
+
+impl Iterator for RangeScan<'_> {
+    type Item = Result<(Vec<u8>, String), KvError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (key, segment) = self.keys.next()?;
+            match segment.get_record(&key, &mut self.open) {
+                Ok(Some(record)) => {
+                    if let Some(value) = visible_value(record, self.now) {
+                        return Some(utf8_value(&key, value).map(|value| (key, value)));
+                    }
+                }
+                Ok(None) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+// Names retired segment files and reads their recency back from a name.
+pub trait SegmentNamer: Send + Sync {
+    // Recency of a retired segment of `prefix`, None if the file is not one.
+    fn sequence(&self, prefix: &str, file_name: &str) -> Option<u64>;
+    // Name for the segment retired after the one with sequence `last`.
+    fn next_name(&self, prefix: &str, last: u64) -> String;
+}
+
+// `db.00001`, `db.00002`, ...
+struct NumericNamer;
+
+impl SegmentNamer for NumericNamer {
+    fn sequence(&self, prefix: &str, file_name: &str) -> Option<u64> {
+        let suffix = file_name.strip_prefix(prefix)?.strip_prefix('.')?;
+        if suffix.is_empty() || !suffix.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        suffix.parse::<u64>().ok()
+    }
+
+    fn next_name(&self, prefix: &str, last: u64) -> String {
+        format!("{}.{:05}", prefix, last + 1)
+    }
+}
+
+// `db.<unix time in milliseconds>`, for tools that expect time-stamped files.
+struct TimestampNamer;
+
+impl SegmentNamer for TimestampNamer {
+    fn sequence(&self, prefix: &str, file_name: &str) -> Option<u64> {
+        NumericNamer.sequence(prefix, file_name)
+    }
+
+    fn next_name(&self, prefix: &str, last: u64) -> String {
+        let now = unix_millis();
+        // never go backwards, even if several segments retire within a millisecond
+        format!("{}.{}", prefix, now.max(last + 1))
+    }
+}
+
+// Order of keys wherever they are listed sorted.
+pub trait KeyComparator: Send + Sync {
+    fn compare(&self, a: &[u8], b: &[u8]) -> std::cmp::Ordering;
+}
+
+struct ByteOrder;
+
+impl KeyComparator for ByteOrder {
+    fn compare(&self, a: &[u8], b: &[u8]) -> std::cmp::Ordering {
+        a.cmp(b)
+    }
+}
+
+// Runs of digits compare by their numeric value, so `v1.2` sorts before `v1.10`.
+struct NaturalOrder;
+
+impl KeyComparator for NaturalOrder {
+    fn compare(&self, a: &[u8], b: &[u8]) -> std::cmp::Ordering {
+        let (mut a, mut b) = (a, b);
+        loop {
+            match (a.first(), b.first()) {
+                (None, None) => return std::cmp::Ordering::Equal,
+                (None, Some(_)) => return std::cmp::Ordering::Less,
+                (Some(_), None) => return std::cmp::Ordering::Greater,
+                (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                    let a_digits = a.iter().take_while(|c| c.is_ascii_digit()).count();
+                    let b_digits = b.iter().take_while(|c| c.is_ascii_digit()).count();
+                    let a_number = trim_leading_zeros(&a[..a_digits]);
+                    let b_number = trim_leading_zeros(&b[..b_digits]);
+                    let ordering = a_number
+                        .len()
+                        .cmp(&b_number.len())
+                        .then_with(|| a_number.cmp(b_number))
+                        // equal numbers, the one with fewer leading zeros first
+                        .then_with(|| a_digits.cmp(&b_digits));
+                    if ordering.is_ne() {
+                        return ordering;
+                    }
+                    a = &a[a_digits..];
+                    b = &b[b_digits..];
+                }
+                (Some(x), Some(y)) => {
+                    if x != y {
+                        return x.cmp(y);
+                    }
+                    a = &a[1..];
+                    b = &b[1..];
+                }
+            }
+        }
+    }
+}
+
+fn trim_leading_zeros(digits: &[u8]) -> &[u8] {
+    let zeros = digits.iter().take_while(|digit| **digit == b'0').count();
+    &digits[zeros..]
+}
+
+pub fn key_comparator(name: &str) -> Option<Arc<dyn KeyComparator>> {
+    match name {
+        "bytes" => Some(Arc::new(ByteOrder)),
+        "natural" => Some(Arc::new(NaturalOrder)),
+        _ => None,
+    }
+}
+
+// When writes are synced to disk. Until then an acknowledged write can be lost
+// to a power failure, though not to a crash of the process.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyncPolicy {
+    Never,
+    EveryWrite,
+    // once per that many writes, and whenever the write segment is retired
+    EveryN(usize),
+}
+
+// `never`, `every-write` or `every-<n>`
+pub fn sync_policy(name: &str) -> Option<SyncPolicy> {
+    match name {
+        "never" => Some(SyncPolicy::Never),
+        "every-write" => Some(SyncPolicy::EveryWrite),
+        _ => match name.strip_prefix("every-")?.parse::<usize>() {
+            Ok(0) | Err(_) => None,
+            Ok(writes) => Some(SyncPolicy::EveryN(writes)),
+        },
+    }
+}
+
+// Which retired segments a compaction merges.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompactionStrategy {
+    // all of them, into segments of the threshold size
+    Full,
+    // the newest run of TIER_MIN_SEGMENTS or more adjacent segments of the
+    // same size tier, into one segment of a larger tier
+    SizeTiered,
+}
+
+// `full` or `size-tiered`
+pub fn compaction_strategy(name: &str) -> Option<CompactionStrategy> {
+    match name {
+        "full" => Some(CompactionStrategy::Full),
+        "size-tiered" => Some(CompactionStrategy::SizeTiered),
+        _ => None,
+    }
+}
+
+pub fn segment_namer(name: &str) -> Option<Box<dyn SegmentNamer>> {
+    match name {
+        "numeric" => Some(Box::new(NumericNamer)),
+        "timestamp" => Some(Box::new(TimestampNamer)),
+        _ => None,
+    }
+}
+
+// The steps of retiring the write segment. The process may die after any of
+// them and an open recovers, the crash test stopping after each in turn.
+#[cfg_attr(not(feature = "dev"), allow(dead_code))]
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+enum RetireStep {
+    // the manifest lists the segment under its new name
+    Listed,
+    // the write segment was renamed to it
+    Renamed,
+    // and compressed, if compression is on
+    Compressed,
+    // its hint and Bloom filter were saved
+    Indexed,
+}
+
+// A write as `Environment::replicate` sends it: its number and its record as a
+// segment stores it, expiry and type included, for `apply_replicated`.
+pub type ReplicatedWrite = (u64, Vec<u8>);
+
+// A key of a batch and its new value, None for a delete.
+type BatchRecord = (Vec<u8>, Option<String>);
+
+// What the manifest of a store records, see `Environment::read_manifest`.
+struct Manifest {
+    // the last segment number handed out
+    last_segment: u64,
+    // the retired segments oldest first, None if the manifest predates the
+    // list, in which case the directory is searched for them
+    segments: Option<Vec<String>>,
+    codec: RecordCodec,
+}
+
+pub struct Environment {
+    data_path: String,
+    file_prefix: String,
+    // where the segments and the files next to them are kept, see `with_storage`
+    storage: Arc<dyn Storage>,
+    segments: Vec<Segment>,
+    write_segment: Segment,
+    // number of segments a read may scan before it is reported as degraded
+    pub max_read_fanout: Option<usize>,
+    // bytes a segment grows to before writes move on to a new one
+    pub segment_threshold: u64,
+    // records a segment takes before writes move on to a new one, whatever
+    // their size, for values too uneven for the byte threshold alone
+    pub max_records_per_segment: Option<u64>,
+    // retired segments past which writes start a background compaction
+    pub max_segments: Option<usize>,
+    // percentage of live records below which the write segment is retired
+    // early, so that compaction drops the records a hot key keeps shadowing
+    pub min_write_utilization: Option<u64>,
+    pub compaction_strategy: CompactionStrategy,
+    // longest key and largest value in bytes a write accepts
+    pub max_key_len: Option<usize>,
+    pub max_value_len: Option<usize>,
+    metrics: Metrics,
+    // sequence number of the latest record written
+    last_sequence: u64,
+    pub sync_policy: SyncPolicy,
+    // recently read values, dropped for every key that is written
+    value_cache: Mutex<ValueCache>,
+    // receivers of the changes to a key, see `watch`
+    watchers: Mutex<HashMap<Vec<u8>, Vec<mpsc::Sender<ChangeEvent>>>>,
+    // writes to the write segment since it was last synced
+    unsynced_writes: usize,
+    // exact number of live keys, maintained on writes when count tracking is enabled
+    live_count: Option<u64>,
+    // block size of the SSTables compaction writes, plain segments when None
+    pub sstable_block_size: Option<u64>,
+    // total on-disk budget; least recently used keys are evicted to stay under it
+    pub max_db_size: Option<u64>,
+    // bytes the segment files may take on disk before a retirement compacts them
+    // all; unlike `max_db_size` no key is ever evicted to stay under it
+    pub max_disk_bytes: Option<u64>,
+    // retired segments go to a `YYYY-MM-DD` subdirectory of the day they were retired
+    pub partition_by_date: bool,
+    access_clock: u64,
+    last_access: HashMap<Vec<u8>, u64>,
+    // receivers of every write, see `replicate`
+    replicas: Vec<mpsc::Sender<ReplicatedWrite>>,
+    // number of the latest write, writes are numbered from 1 as they are made
+    write_position: u64,
+    latencies: HashMap<String, LatencyHistogram>,
+    // commands take and print keys in hex, see --binary-keys
+    pub binary_keys: bool,
+    // records are written with a checksum, set by --checksums
+    checksums: bool,
+    checkpoint_sequence: u64,
+    // number of the newest segment ever named, see `next_file_name`
+    last_segment: u64,
+    namer: Box<dyn SegmentNamer>,
+    // shrinking updates of a key in the write segment overwrite its record
+    pub in_place_updates: bool,
+    // index entries kept per retired segment, set by TRIMINDEX
+    index_cap: Option<usize>,
+    // retired and compacted segments are gzip compressed, see `SegmentFile`
+    pub compress: bool,
+    // segment indexes keep their keys sorted, see `SegmentIndex`
+    ordered_index: bool,
+    // retired segments are read through memory maps, see `map_segments`
+    mmap: bool,
+    // writes queued since MULTI, applied together by EXEC
+    transaction: Option<Vec<BatchRecord>>,
+    // unix time in milliseconds that record expiry and date partitions go by
+    clock: fn() -> u64,
+    // (expires at, key) of every record written with an expiry, oldest first,
+    // maintained on writes once `track_expiries` built it
+    expiry_index: Option<BTreeSet<(u64, Vec<u8>)>>,
+    pins: Arc<Mutex<SegmentPins>>,
+    pub comparator: Arc<dyn KeyComparator>,
+    // when the current write segment was started, for retiring it by age
+    write_segment_started: std::time::Instant,
+    // writes that would leave less free disk space than this are rejected
+    pub min_free_bytes: Option<u64>,
+    // bytes available to unprivileged writers on the file system of a path
+    free_space: fn(&str) -> Result<u64, std::io::Error>,
+    // commands rejected in every mode, set by --disable-commands
+    pub disabled_commands: HashSet<String>,
+    // the latest writes as (unix time in ms, key, value), oldest first
+    recent: VecDeque<(u64, Vec<u8>, Option<Vec<u8>>)>,
+    // open SCAN cursors by id
+    cursors: HashMap<u64, std::iter::Peekable<SnapshotIter>>,
+    next_cursor: u64,
+    // how records are laid out, read from the manifest, see `set_record_codec`
+    codec: RecordCodec,
+    // a background compaction is running, see `start_compaction`
+    compacting: bool,
+    // started by `COMPACT --background`, for the caller to run
+    compaction_job: Option<CompactionJob>,
+    // opened by `open_read_only`, every write fails with ReadOnly
+    read_only: bool,
+}
+
+impl Environment {
+    pub fn with_namer(
+        data_path: &String,
+        prefix: &String,
+        namer: Box<dyn SegmentNamer>,
+    ) -> Result<Self, KvError> {
+        Environment::with_progress(data_path, prefix, namer, &mut |_| {})
+    }
+
+    // `with_namer` calling `progress` as each retired segment is loaded, from
+    // whichever thread loaded it but never from two at once.
+    pub fn with_progress(
+        data_path: &String,
+        prefix: &String,
+        namer: Box<dyn SegmentNamer>,
+        progress: &mut (dyn FnMut(OpenProgress) + Send),
+    ) -> Result<Self, KvError> {
+        Environment::open_in(data_path, prefix, namer, Arc::new(FileStorage), progress)
+    }
+
+    // `with_namer` keeping the files in `storage` rather than the file system,
+    // `data_path` then only naming them there.
+    pub fn with_storage(
+        data_path: &String,
+        prefix: &String,
+        namer: Box<dyn SegmentNamer>,
+        storage: Arc<dyn Storage>,
+    ) -> Result<Self, KvError> {
+        Environment::open_in(data_path, prefix, namer, storage, &mut |_| {})
+    }
+
+    fn open_in(
+        data_path: &String,
+        prefix: &String,
+        namer: Box<dyn SegmentNamer>,
+        storage: Arc<dyn Storage>,
+        progress: &mut (dyn FnMut(OpenProgress) + Send),
+    ) -> Result<Self, KvError> {
+        // fails if the path exists but is not a directory
+        storage.create_dir(data_path)?;
+        let mut env = Environment::assemble(data_path, prefix, namer, storage, false, progress)?;
+        // lists the segments of a directory written before the manifest did
+        env.write_manifest()?;
+        env.map_segments();
+        Ok(env)
+    }
+
+    // Opens the segments of `prefix` for reading only. The write segment is
+    // loaded if it exists but never created, and nothing is written to disk:
+    // missing indexes are rebuilt in memory and writes fail with ReadOnly.
+    pub fn open_read_only(
+        data_path: &String,
+        prefix: &String,
+        namer: Box<dyn SegmentNamer>,
+    ) -> Result<Self, KvError> {
+        Environment::open_read_only_in(data_path, prefix, namer, Arc::new(FileStorage))
+    }
+
+    // `open_read_only` with the files kept in `storage`, see `with_storage`.
+    pub fn open_read_only_in(
+        data_path: &String,
+        prefix: &String,
+        namer: Box<dyn SegmentNamer>,
+        storage: Arc<dyn Storage>,
+    ) -> Result<Self, KvError> {
+        let mut env = Environment::assemble(data_path, prefix, namer, storage, true, &mut |_| {})?;
+        env.map_segments();
+        Ok(env)
+    }
+
+    // The environment both opens start from: the segments the manifest lists,
+    // the write segment and every setting at its default. Only a writable open
+    // recovers a cut short retirement and creates the write segment.
+    fn assemble(
+        data_path: &String,
+        prefix: &String,
+        namer: Box<dyn SegmentNamer>,
+        storage: Arc<dyn Storage>,
+        read_only: bool,
+        progress: &mut (dyn FnMut(OpenProgress) + Send),
+    ) -> Result<Self, KvError> {
+        let manifest = Environment::read_manifest(&*storage, data_path, prefix);
+        let (last_segment, codec) = (manifest.last_segment, manifest.codec);
+        let segments = Environment::load_segments(
+            &storage,
+            data_path,
+            prefix,
+            namer.as_ref(),
+            manifest,
+            !read_only,
+            progress,
+        )?;
+        // after the segments are loaded, as a recovery may rename the write segment
+        let write_segment = match read_only {
+            false => Environment::new_write_segment(&storage, data_path, prefix, codec)?,
+            true => {
+                let write_segment_path = Path::new(data_path)
+                    .join(format!("{}.{}", prefix, CURRENT_SEGMENT_SUFFIX))
+                    .display()
+                    .to_string();
+                match storage.exists(&write_segment_path) {
+                    true => Segment::new(&storage, write_segment_path, codec)?,
+                    false => Segment::empty(&storage, write_segment_path),
+                }
+            }
+        };
+        let mut env = Environment {
+            data_path: data_path.clone(),
+            file_prefix: prefix.clone(),
+            segments,
+            write_segment,
+            checkpoint_sequence: Environment::read_checkpoint_sequence(
+                &*storage, data_path, prefix,
+            ),
+            last_segment,
+            max_read_fanout: None,
+            metrics: Metrics::default(),
+            live_count: None,
+            sstable_block_size: None,
+            last_sequence: 0,
+            sync_policy: SyncPolicy::Never,
+            value_cache: Mutex::new(ValueCache::default()),
+            watchers: Mutex::new(HashMap::new()),
+            unsynced_writes: 0,
+            segment_threshold: SEGMENT_THRESHOLD,
+            max_records_per_segment: None,
+            max_segments: None,
+            min_write_utilization: None,
+            compaction_strategy: CompactionStrategy::Full,
+            max_key_len: None,
+            max_value_len: None,
+            max_db_size: None,
+            max_disk_bytes: None,
+            partition_by_date: false,
+            access_clock: 0,
+            last_access: HashMap::new(),
+            replicas: Vec::new(),
+            write_position: 0,
+            latencies: HashMap::new(),
+            binary_keys: false,
+            checksums: false,
+            in_place_updates: false,
+            compress: false,
+            index_cap: None,
+            ordered_index: false,
+            mmap: true,
+            transaction: None,
+            clock: unix_millis,
+            expiry_index: None,
+            pins: Arc::new(Mutex::new(SegmentPins::new(storage.clone()))),
+            comparator: Arc::new(ByteOrder),
+            write_segment_started: std::time::Instant::now(),
+            min_free_bytes: None,
+            free_space: available_space,
+            disabled_commands: HashSet::new(),
+            recent: VecDeque::with_capacity(RECENT_BUFFER_SIZE),
+            cursors: HashMap::new(),
+            next_cursor: 0,
+            codec,
+            compacting: false,
+            compaction_job: None,
+            read_only,
+            namer,
+            storage,
+        };
+        env.last_segment = env.last_segment.max(env.newest_segment_number());
+        env.last_sequence = env.highest_sequence();
+        Ok(env)
+    }
+
+    // Opens the retired segments the manifest lists, or those found in the
+    // directory when it lists none. With `recover`, a retirement cut short
+    // between listing the segment and renaming the write segment is finished.
+    fn load_segments(
+        storage: &Arc<dyn Storage>,
+        data_path: &String,
+        prefix: &str,
+        namer: &dyn SegmentNamer,
+        manifest: Manifest,
+        recover: bool,
+        progress: &mut (dyn FnMut(OpenProgress) + Send),
+    ) -> Result<Vec<Segment>, KvError> {
+        let paths =
+            Environment::segment_paths(&**storage, data_path, prefix, namer, &manifest, recover)?;
+        open_retired_segments(storage, paths, manifest.codec, progress)
+    }
+
+    // The files of the retired segments `load_segments` opens, oldest first.
+    fn segment_paths(
+        storage: &dyn Storage,
+        data_path: &String,
+        prefix: &str,
+        namer: &dyn SegmentNamer,
+        manifest: &Manifest,
+        recover: bool,
+    ) -> Result<Vec<String>, std::io::Error> {
+        let listed = match &manifest.segments {
+            Some(listed) => listed,
+            None => return Environment::scan_segments(storage, data_path, prefix, namer),
+        };
+        let write_segment_path = Path::new(data_path)
+            .join(format!("{}.{}", prefix, CURRENT_SEGMENT_SUFFIX))
+            .display()
+            .to_string();
+        let mut paths = Vec::with_capacity(listed.len());
+        for (position, file_name) in listed.iter().enumerate() {
+            let file_path = Path::new(data_path).join(file_name).display().to_string();
+            // listed before it was compressed
+            let compressed_path = format!("{}.{}", file_path, COMPRESSED_SUFFIX);
+            let is_newest = position + 1 == listed.len();
+            if storage.exists(&compressed_path) {
+                // the compressed file only takes its name once complete, the
+                // uncompressed one may not have been removed yet
+                if recover && storage.exists(&file_path) {
+                    storage.remove(&file_path)?;
+                    remove_index_files(storage, &file_path)?;
+                }
+                paths.push(compressed_path);
+            } else if storage.exists(&file_path) {
+                paths.push(file_path);
+            } else if is_newest && storage.exists(&write_segment_path) {
+                if recover {
+                    storage.rename(&write_segment_path, &file_path)?;
+                    eprintln!("Finished retiring the write segment to [{}]", file_path);
+                    paths.push(file_path);
+                }
+            } else {
+                eprintln!(
+                    "Segment [{}] listed in the manifest is missing, skipped",
+                    file_path
+                );
+            }
+        }
+        Ok(paths)
+    }
+
+    // The files of the retired segments found in the directory, oldest first.
+    fn scan_segments(
+        storage: &dyn Storage,
+        data_path: &str,
+        prefix: &str,
+        namer: &dyn SegmentNamer,
+    ) -> Result<Vec<String>, std::io::Error> {
+        let file_name = |path: &String| {
+            let file_name = Path::new(path).file_name().unwrap_or_default();
+            file_name.to_string_lossy().into_owned()
+        };
+        let mut paths = Vec::new();
+        for path in storage.list(data_path)? {
+            if storage.is_dir(&path) {
+                // date partitions only ever hold retired segments
+                paths.extend(
+                    storage
+                        .list(&path)?
+                        .into_iter()
+                        .filter(|p| !file_name(p).ends_with(CURRENT_SEGMENT_SUFFIX)),
+                );
+            } else {
+                paths.push(path);
+            }
+        }
+
+        // the write segment is opened separately, after a torn tail is cut off
+        let mut paths: Vec<String> = paths
+            .into_iter()
+            .filter(|p| {
+                let file_name = file_name(p);
+                !file_name.ends_with(CURRENT_SEGMENT_SUFFIX)
+                    && is_segment_file(&file_name, prefix, namer)
+            })
+            .collect();
+        // read_dir order is unspecified, reads and compaction rely on oldest first
+        paths.sort_by_cached_key(|path| {
+            let sequence = namer.sequence(prefix, uncompressed_name(&file_name(path)));
+            (sequence, path.clone())
+        });
+        Ok(paths)
+    }
+
+    // Reopens the retired segments the manifest lists, or those found in the
+    // directory when there is none, and indexes them again.
+    pub fn reload(&mut self) -> Result<(), std::io::Error> {
+        self.check_writable()?;
+        // the files may have been repaired underneath
+        self.value_cache.get_mut().unwrap().clear();
+        let manifest =
+            Environment::read_manifest(&*self.storage, &self.data_path, &self.file_prefix);
+        self.segments = Environment::load_segments(
+            &self.storage,
+            &self.data_path,
+            &self.file_prefix,
+            self.namer.as_ref(),
+            Manifest {
+                codec: self.codec,
+                ..manifest
+            },
+            false,
+            &mut |_| {},
+        )?;
+        self.last_segment = self.last_segment.max(self.newest_segment_number());
+        self.write_manifest()?;
+        self.write_segment = Environment::new_write_segment(
+            &self.storage,
+            &self.data_path,
+            &self.file_prefix,
+            self.codec,
+        )?;
+        self.write_segment.checksums = self.checksums;
+        self.last_sequence = self.last_sequence.max(self.highest_sequence());
+        self.map_segments();
+        self.order_indexes();
+        self.trim_indexes();
+        if self.live_count.is_some() {
+            self.track_live_count()?;
+        }
+        if self.expiry_index.is_some() {
+            self.track_expiries()?;
+        }
+        Ok(())
+    }
+
+    // segments whose in-memory index no longer matches what is on disk
+    pub fn stale_segments(&self) -> Result<Vec<String>, std::io::Error> {
+        let mut result = Vec::new();
+        for segment in self
+            .segments
+            .iter()
+            .chain(std::iter::once(&self.write_segment))
+        {
+            let on_disk = build_index(&*self.storage, &segment.file_path, segment.codec)?;
+            // evicted entries are missing on purpose, only retained ones are checked
+            let stale = (segment.trimmed_from.is_none() && on_disk.len() != segment.index.len())
+                || segment
+                    .index
+                    .iter()
+                    .any(|(key, offset)| on_disk.get(key) != Some(offset));
+            // an SSTable keeps no index of its keys that could go stale
+            if segment.blocks.is_none() && stale {
+                result.push(segment.file_path.clone());
+            }
+        }
+        Ok(result)
+    }
+
+    fn checkpoint_path(data_path: &String, prefix: &str) -> String {
+        Path::new(data_path)
+            .join(format!("{}.{}", prefix, CHECKPOINT_SUFFIX))
+            .display()
+            .to_string()
+    }
+
+    fn manifest_path(data_path: &String, prefix: &str) -> String {
+        Path::new(data_path)
+            .join(format!("{}.{}", prefix, MANIFEST_SUFFIX))
+            .display()
+            .to_string()
+    }
+
+    // What the manifest records, 0 for the last segment number without one.
+    // A manifest that predates the codec line is of a text store.
+    fn read_manifest(storage: &dyn Storage, data_path: &String, prefix: &str) -> Manifest {
+        let contents = storage
+            .read(&Environment::manifest_path(data_path, prefix))
+            .ok()
+            .and_then(|contents| String::from_utf8(contents).ok())
+            .unwrap_or_default();
+        let mut lines = contents.lines();
+        let last_segment = lines
+            .next()
+            .and_then(|line| line.parse::<u64>().ok())
+            .unwrap_or(0);
+        let count = lines
+            .next()
+            .and_then(|line| line.strip_prefix("segments "))
+            .and_then(|count| count.parse::<usize>().ok());
+        let segments = count.map(|count| lines.by_ref().take(count).map(str::to_string).collect());
+        let codec = lines
+            .next()
+            .and_then(|line| line.strip_prefix("format "))
+            .and_then(record_codec)
+            .unwrap_or_default();
+        Manifest {
+            last_segment,
+            segments,
+            codec,
+        }
+    }
+
+    fn write_manifest(&self) -> Result<(), std::io::Error> {
+        self.write_manifest_with(None)
+    }
+
+    // Records the manifest, listing `pending` after the retired segments when a
+    // segment is about to take that name.
+    fn write_manifest_with(&self, pending: Option<&str>) -> Result<(), std::io::Error> {
+        let listed = self
+            .segments
+            .iter()
+            .map(|s| s.file_path.as_str())
+            .chain(pending)
+            .map(|file_path| {
+                // relative, so that the data directory can be moved
+                let relative = Path::new(file_path)
+                    .strip_prefix(&self.data_path)
+                    .unwrap_or(Path::new(file_path));
+                relative.display().to_string()
+            })
+            .collect();
+        let manifest = Manifest {
+            last_segment: self.last_segment,
+            segments: Some(listed),
+            codec: self.codec,
+        };
+        Environment::save_manifest(
+            &*self.storage,
+            &self.data_path,
+            &self.file_prefix,
+            &manifest,
+        )
+    }
+
+    // Writes `manifest` through a `.tmp` file. Its segments have to be listed.
+    fn save_manifest(
+        storage: &dyn Storage,
+        data_path: &String,
+        prefix: &str,
+        manifest: &Manifest,
+    ) -> Result<(), std::io::Error> {
+        let manifest_path = Environment::manifest_path(data_path, prefix);
+        let listed = manifest.segments.as_deref().unwrap_or_default();
+        let mut contents = format!("{}\nsegments {}\n", manifest.last_segment, listed.len());
+        for file_name in listed {
+            contents.push_str(&format!("{}\n", file_name));
+        }
+        contents.push_str(&format!("format {}\n", manifest.codec.name()));
+        let tmp_path = format!("{}.tmp", manifest_path);
+        storage.write(&tmp_path, contents.as_bytes())?;
+        storage.rename(&tmp_path, &manifest_path)
+    }
+
+    fn read_checkpoint_sequence(storage: &dyn Storage, data_path: &String, prefix: &str) -> u64 {
+        storage
+            .read(&Environment::checkpoint_path(data_path, prefix))
+            .ok()
+            .and_then(|contents| String::from_utf8(contents).ok())
+            .and_then(|contents| contents.lines().next()?.parse::<u64>().ok())
+            .unwrap_or(0)
+    }
+
+    // Syncs every segment to disk (compacting first if asked) and records a
+    // checkpoint marker: the next sequence number followed by the segment files
+    // that make up the checkpoint.
+    pub fn checkpoint(&mut self, compact: bool) -> Result<u64, std::io::Error> {
+        self.check_writable()?;
+        if compact {
+            self.compact_segments()?;
+        }
+        let mut contents = format!("{}\n", self.checkpoint_sequence + 1);
+        for segment in self
+            .segments
+            .iter()
+            .chain(std::iter::once(&self.write_segment))
+        {
+            self.storage.open(&segment.file_path)?.sync()?;
+            contents.push_str(&format!("{}\n", segment.file_path));
+        }
+        let checkpoint_path = Environment::checkpoint_path(&self.data_path, &self.file_prefix);
+        let tmp_path = format!("{}.tmp", checkpoint_path);
+        self.storage.write(&tmp_path, contents.as_bytes())?;
+        self.storage.rename(&tmp_path, &checkpoint_path)?;
+        self.checkpoint_sequence += 1;
+        Ok(self.checkpoint_sequence)
+    }
+
+    // Size of the segment files on disk, compressed ones as they are stored.
+    pub fn disk_bytes(&self) -> Result<u64, std::io::Error> {
+        let mut disk_bytes = 0;
+        for segment in self
+            .segments
+            .iter()
+            .chain(std::iter::once(&self.write_segment))
+        {
+            disk_bytes += self.storage.size(&segment.file_path)?;
+        }
+        Ok(disk_bytes)
+    }
+
+    pub fn disk_size(&self) -> u64 {
+        self.segments.iter().map(|s| s.size).sum::<u64>() + self.write_segment.size
+    }
+
+    // records an access for eviction, only tracked when a size budget is set
+    pub fn touch(&mut self, key: &[u8]) {
+        if self.max_db_size.is_some() {
+            self.access_clock += 1;
+            self.last_access.insert(key.to_vec(), self.access_clock);
+        }
+    }
+
+    // Numbers a write and sends it to the receivers of `replicate`, forgetting
+    // those that are gone.
+    fn send_to_replicas(&mut self, record: &Record) {
+        self.write_position += 1;
+        if !self.replicas.is_empty() {
+            // the follower numbers its writes itself
+            let mut record = record.clone();
+            record.header.fields.remove(&FIELD_SEQUENCE);
+            record.header.fields.remove(&FIELD_BATCH);
+            let write = (self.write_position, encode_record(&record));
+            self.replicas
+                .retain(|sender| sender.send(write.clone()).is_ok());
+        }
+    }
+
+    // The number `replicate` sent the latest write with, 0 before the first one.
+    pub fn write_position(&self) -> u64 {
+        self.write_position
+    }
+
+    // Counts a SET served outside `handle_command`, such as a binary framed one.
+    pub fn count_set(&self) {
+        self.metrics.sets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Receives every later write with its number once it has been appended, for
+    // a follower to apply them in the same order.
+    pub fn replicate(&mut self) -> mpsc::Receiver<ReplicatedWrite> {
+        let (sender, receiver) = mpsc::channel();
+        self.replicas.push(sender);
+        receiver
+    }
+
+    // Lays out the records of a store that holds none yet with `codec`, which
+    // the manifest keeps for every later open. A store with records stays with
+    // the codec they were written with.
+    pub fn set_record_codec(&mut self, codec: RecordCodec) -> Result<(), KvError> {
+        self.check_writable()?;
+        if codec == self.codec {
+            return Ok(());
+        }
+        if !self.segments.is_empty() || self.write_segment.size > 0 {
+            return Err(KvError::CodecInUse { codec: self.codec });
+        }
+        self.codec = codec;
+        self.write_segment.codec = codec;
+        self.write_manifest()?;
+        Ok(())
+    }
+
+    // Keeps up to `capacity` recently read values in memory, 0 turns it off.
+    pub fn set_value_cache_capacity(&mut self, capacity: usize) {
+        let cache = self.value_cache.get_mut().unwrap();
+        cache.capacity = capacity;
+        cache.clear();
+    }
+
+    pub fn track_live_count(&mut self) -> Result<(), std::io::Error> {
+        self.live_count = Some(live_keys(self)?.len() as u64);
+        Ok(())
+    }
+
+    // Builds the expiry index from the live records, a full scan done once so
+    // that sweeps only ever look at the keys that are due.
+    pub fn track_expiries(&mut self) -> Result<(), std::io::Error> {
+        let mut expiry_index = BTreeSet::new();
+        for key in live_keys(self)? {
+            if let Some(record) = live_record(self, &key)?
+                && let Some(expires_at) = record.header.fields.get(&FIELD_EXPIRY)
+            {
+                expiry_index.insert((*expires_at, key));
+            }
+        }
+        self.expiry_index = Some(expiry_index);
+        Ok(())
+    }
+
+    fn index_expiry(&mut self, record: &Record) {
+        if let Some(expiry_index) = self.expiry_index.as_mut()
+            && let Some(expires_at) = record.header.fields.get(&FIELD_EXPIRY)
+        {
+            expiry_index.insert((*expires_at, record.key.clone()));
+        }
+    }
+
+    // Tombstones the keys whose newest record has expired, taking only the
+    // due entries off the expiry index. An entry left behind by a key written
+    // again since is dropped without a write.
+    pub fn sweep_expired(&mut self) -> Result<SweepSummary, std::io::Error> {
+        self.check_writable()?;
+        if self.expiry_index.is_none() {
+            self.track_expiries()?;
+        }
+        let now = (self.clock)();
+        let mut summary = SweepSummary::default();
+        let mut tombstones = Vec::new();
+        while let Some(expiry_index) = self.expiry_index.as_mut()
+            && expiry_index
+                .first()
+                .is_some_and(|(expires_at, _)| *expires_at <= now)
+        {
+            let (expires_at, key) = expiry_index.pop_first().unwrap();
+            summary.examined += 1;
+            let newest = newest_record(self, &key, &mut OpenSegments::default())?;
+            if let Some((record, _)) = newest
+                && !record.is_tombstone()
+                && record.header.fields.get(&FIELD_EXPIRY) == Some(&expires_at)
+            {
+                tombstones.push(Record::tombstone(&key));
+            }
+        }
+        if !tombstones.is_empty() {
+            set_records(self, &tombstones)?;
+        }
+        summary.removed = tombstones.len();
+        Ok(summary)
+    }
+
+    fn report_read_fanout(&self, key: &[u8], max_read_fanout: usize) {
+        eprintln!(
+            "Warning: read of key [{}] scanned more than {} segments",
+            String::from_utf8_lossy(key),
+            max_read_fanout
+        );
+        self.metrics
+            .read_fanout_exceeded
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    // None for a segment the manifest lists under a name the namer does not
+    // number, such as one written with another naming scheme.
+    fn segment_sequence(&self, segment: &Segment) -> Option<u64> {
+        let file_name = Path::new(&segment.file_path).file_name()?;
+        self.namer.sequence(
+            &self.file_prefix,
+            uncompressed_name(&file_name.to_string_lossy()),
+        )
+    }
+
+    // a fresh database retires its first segment as number 1
+    fn newest_segment_number(&self) -> u64 {
+        self.segments
+            .iter()
+            .filter_map(|s| self.segment_sequence(s))
+            .max()
+            .unwrap_or(0)
+    }
+
+    // Names a new retired segment. The number is taken from the manifest rather
+    // than from the segments around, which a compaction may just have removed,
+    // and is recorded there before the name is used, so that no number is ever
+    // handed out twice.
+    pub fn next_file_name(&mut self) -> Result<String, std::io::Error> {
+        let file_path = self.file_name_after(self.last_segment)?;
+        let file_name = Path::new(&file_path).file_name().unwrap().to_string_lossy();
+        self.last_segment = self
+            .namer
+            .sequence(&self.file_prefix, &file_name)
+            .unwrap_or(self.last_segment + 1);
+        self.write_manifest()?;
+        Ok(file_path)
+    }
+
+    fn file_name_after(&self, file_number: u64) -> Result<String, std::io::Error> {
+        let mut directory = Path::new(&self.data_path).to_path_buf();
+        if self.partition_by_date {
+            directory.push(date_of((self.clock)()));
+            self.storage.create_dir(&directory.display().to_string())?;
+        }
+        let path_to_file = directory.join(self.namer.next_name(&self.file_prefix, file_number));
+        Ok(path_to_file.display().to_string())
+    }
+
+    fn numbered_segments(&self) -> BTreeMap<u64, Vec<String>> {
+        let mut by_number: BTreeMap<u64, Vec<String>> = BTreeMap::new();
+        for segment in self.segments.iter() {
+            if let Some(number) = self.segment_sequence(segment) {
+                by_number
+                    .entry(number)
+                    .or_default()
+                    .push(segment.file_path.clone());
+            }
+        }
+        by_number
+    }
+
+    // retired segments whose names the namer does not number
+    pub fn foreign_segments(&self) -> Vec<String> {
+        self.segments
+            .iter()
+            .filter(|segment| self.segment_sequence(segment).is_none())
+            .map(|segment| segment.file_path.clone())
+            .collect()
+    }
+
+    // sequence numbers shared by more than one retired segment
+    pub fn duplicate_numbers(&self) -> Vec<(u64, Vec<String>)> {
+        self.numbered_segments()
+            .into_iter()
+            .filter(|(_, file_paths)| file_paths.len() > 1)
+            .collect()
+    }
+
+    // ranges of sequence numbers missing between the lowest and the highest one
+    pub fn number_gaps(&self) -> Vec<(u64, u64)> {
+        let mut gaps = Vec::new();
+        let mut previous: Option<u64> = None;
+        for number in self.numbered_segments().into_keys() {
+            if let Some(previous) = previous
+                && number > previous + 1
+            {
+                gaps.push((previous + 1, number - 1));
+            }
+            previous = Some(number);
+        }
+        gaps
+    }
+
+    // Renames the retired segments to `1..n` in the order they are read, oldest
+    // first, each staying in its directory. Returns the number of renamed files.
+    pub fn renumber_segments(&mut self) -> Result<usize, std::io::Error> {
+        self.check_not_compacting()?;
+        let mut renames = Vec::new();
+        for (position, segment) in self.segments.iter().enumerate() {
+            let path = Path::new(&segment.file_path);
+            let file_name = path.file_name().unwrap().to_string_lossy();
+            // renumbering always produces numeric names, whatever the naming scheme
+            let mut target = NumericNamer.next_name(&self.file_prefix, position as u64);
+            if uncompressed_name(&file_name) != file_name {
+                target = format!("{}.{}", target, COMPRESSED_SUFFIX);
+            }
+            let target = path.with_file_name(target).display().to_string();
+            if target != segment.file_path {
+                renames.push((position, target));
+            }
+        }
+        // two passes, so a target name can never clash with a segment not yet moved
+        for (position, _) in renames.iter() {
+            let file_path = &self.segments[*position].file_path;
+            rename_with_index_files(
+                &*self.storage,
+                file_path,
+                &format!("{}.renumber", file_path),
+            )?;
+        }
+        for (position, target) in renames.iter() {
+            let file_path = &self.segments[*position].file_path;
+            rename_with_index_files(&*self.storage, &format!("{}.renumber", file_path), target)?;
+            self.segments[*position].file_path = target.clone();
+        }
+        // the manifest has to list the new names before they are read back
+        self.write_manifest()?;
+        self.reload()?;
+        Ok(renames.len())
+    }
+
+    fn new_write_segment(
+        storage: &Arc<dyn Storage>,
+        data_path: &String,
+        file_prefix: &String,
+        codec: RecordCodec,
+    ) -> Result<Segment, KvError> {
+        let file_path = Path::new(data_path)
+            .join(format!("{}.{}", file_prefix, CURRENT_SEGMENT_SUFFIX))
+            .display()
+            .to_string();
+        if storage.exists(&file_path)
+            && let Some(offset) = truncate_torn_tail(&**storage, &file_path, codec)?
+        {
+            eprintln!(
+                "Truncated a torn record at the end of [{}] offset {}",
+                file_path, offset
+            );
+        }
+        if storage.exists(&file_path)
+            && let Some(offset) = truncate_incomplete_batch(&**storage, &file_path, codec)?
+        {
+            eprintln!(
+                "Dropped an incomplete batch at the end of [{}] offset {}",
+                file_path, offset
+            );
+        }
+        Segment::new(storage, file_path, codec)
+    }
+
+    // Records written from now on carry a checksum that reads verify. Records
+    // without one, such as those written before, are read as they are.
+    pub fn enable_checksums(&mut self) {
+        self.checksums = true;
+        self.write_segment.checksums = true;
+    }
+
+    pub fn retire_write_segment(&mut self) -> Result<(), KvError> {
+        self.retire_write_segment_until(None)
+    }
+
+    // Retires the write segment, stopping right after `last_step` as if the
+    // process died there. The segment is listed before the write segment is
+    // renamed to it, so there is never a retired segment the manifest misses.
+    fn retire_write_segment_until(&mut self, last_step: Option<RetireStep>) -> Result<(), KvError> {
+        self.check_writable()?;
+        let stop_after = |step: RetireStep| last_step == Some(step);
+        // writes left unsynced by EveryN would never be synced once retired
+        if self.sync_policy != SyncPolicy::Never && self.unsynced_writes > 0 {
+            self.sync_write_segment()?;
+        }
+        // we have only one write thread, so this is fine
+        let next_file_name = self.next_file_name()?;
+        self.write_segment.seal();
+        self.write_manifest_with(Some(&next_file_name))?;
+        if stop_after(RetireStep::Listed) {
+            return Ok(());
+        }
+        self.storage
+            .rename(&self.write_segment.file_path, &next_file_name)?;
+        if stop_after(RetireStep::Renamed) {
+            return Ok(());
+        }
+        let mut segment = Segment::new(&self.storage, next_file_name, self.codec)?;
+        if self.compress {
+            segment.compress()?;
+        }
+        if stop_after(RetireStep::Compressed) {
+            return Ok(());
+        }
+        segment.persist_index()?;
+        if stop_after(RetireStep::Indexed) {
+            return Ok(());
+        }
+        self.segments.push(segment);
+        self.write_manifest()?;
+        self.write_segment = Environment::new_write_segment(
+            &self.storage,
+            &self.data_path,
+            &self.file_prefix,
+            self.codec,
+        )?;
+        self.write_segment.checksums = self.checksums;
+        self.map_segments();
+        self.order_indexes();
+        self.trim_indexes();
+        Ok(())
+    }
+
+    // Retires the write segment once it is older than `max_age`, even below the
+    // size threshold, so segments line up with time windows. Empty ones are kept.
+    pub fn retire_if_older_than(&mut self, max_age: std::time::Duration) -> Result<bool, KvError> {
+        if self.write_segment.size == 0 || self.write_segment_started.elapsed() < max_age {
+            return Ok(false);
+        }
+        self.retire_write_segment()?;
+        Ok(true)
+    }
+
+    // Sizes and false-positive rates of the Bloom filters of the retired
+    // segments, each probed with `samples` keys the segment does not hold.
+    // Filters not built yet are built first and kept.
+    pub fn filter_stats(&mut self, samples: usize) -> Result<Vec<FilterStats>, std::io::Error> {
+        let mut result = Vec::new();
+        for segment in self.segments.iter_mut() {
+            let keys = segment.keys()?;
+            let filter = segment
+                .filter
+                .get_or_insert_with(|| BloomFilter::with_keys(keys.iter()));
+            let mut probed = 0;
+            let mut passed = 0;
+            // a probe that happens to be a key of the segment is skipped
+            for probe in (0..).map(|i| format!("\0filterstats-{}", i).into_bytes()) {
+                if probed == samples {
+                    break;
+                }
+                if keys.contains(&probe) {
+                    continue;
+                }
+                probed += 1;
+                passed += filter.contains(&probe) as usize;
+            }
+            result.push(FilterStats {
+                file_path: segment.file_path.clone(),
+                bits: filter.bits(),
+                hashes: filter.hashes,
+                elements: keys.len(),
+                estimated_fpr: filter.estimated_fpr(keys.len()),
+                measured_fpr: passed as f64 / probed.max(1) as f64,
+            });
+        }
+        Ok(result)
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        self.segments
+            .iter()
+            .chain(std::iter::once(&self.write_segment))
+            .map(|segment| segment.memory_usage())
+            .sum()
+    }
+
+    // Frees what can be rebuilt from disk, for when the host runs low on memory.
+    pub fn release_memory(&mut self) {
+        for segment in self
+            .segments
+            .iter_mut()
+            .chain(std::iter::once(&mut self.write_segment))
+        {
+            segment.release_memory();
+        }
+        self.last_access.shrink_to_fit();
+        let cache = self.value_cache.get_mut().unwrap();
+        cache.clear();
+        cache.entries.shrink_to_fit();
+    }
+
+    // Live records as of now, newest segment first, each key once. Segments are
+    // read one at a time as the iterator is consumed.
+    pub fn iter_live(&self) -> Result<SnapshotIter, std::io::Error> {
+        Ok(SnapshotIter::new(self.snapshot()?))
+    }
+
+    // every live key once, as of the call
+    pub fn keys(&self) -> Result<Keys, std::io::Error> {
+        Ok(Keys {
+            records: self.iter_live()?,
+        })
+    }
+
+    pub fn snapshot(&self) -> Result<Snapshot, std::io::Error> {
+        let now = (self.clock)();
+        let mut write_records = HashMap::new();
+        for record in self.write_segment.records()? {
+            let record = record?;
+            write_records.insert(record.key.clone(), visible_value(record, now));
+        }
+        let segment_paths: Vec<String> =
+            self.segments.iter().map(|s| s.file_path.clone()).collect();
+        let mut pins = self.pins.lock().unwrap();
+        for file_path in segment_paths.iter() {
+            pins.pin(file_path);
+        }
+        Ok(Snapshot {
+            storage: self.storage.clone(),
+            pins: self.pins.clone(),
+            comparator: self.comparator.clone(),
+            segment_paths,
+            write_records,
+            now,
+            codec: self.codec,
+        })
+    }
+
+    fn check_writable(&self) -> Result<(), KvError> {
+        match self.read_only {
+            true => Err(KvError::ReadOnly),
+            false => Ok(()),
+        }
+    }
+
+    // Refuses a key or value over the limits before anything is written.
+    // Deletes are not checked, a key written before a limit was set can still go.
+    fn check_limits(&self, record: &Record) -> Result<(), KvError> {
+        let Some(value) = record.written_value() else {
+            return Ok(());
+        };
+        if let Some(limit) = self.max_key_len.filter(|limit| record.key.len() > *limit) {
+            return Err(KvError::KeyTooLong { limit });
+        }
+        if let Some(limit) = self.max_value_len.filter(|limit| value.len() > *limit) {
+            return Err(KvError::ValueTooLarge { limit });
+        }
+        Ok(())
+    }
+
+    // Fails with StorageFull if writing `bytes` more would cross `min_free_bytes`,
+    // so a write is refused up front instead of being torn by a full disk.
+    pub fn check_free_space(&self, bytes: u64) -> Result<(), std::io::Error> {
+        let min_free_bytes = match self.min_free_bytes {
+            Some(min_free_bytes) => min_free_bytes,
+            None => return Ok(()),
+        };
+        let available = (self.free_space)(&self.data_path)?;
+        if available.saturating_sub(bytes) < min_free_bytes {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::StorageFull,
+                format!(
+                    "disk full guard: [{}] bytes free, writing [{}] would go below [{}]",
+                    available, bytes, min_free_bytes
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    // Syncs the write segment and saves its index as a hint, so the next open
+    // does not scan it. Any later write leaves the hint stale, and it is ignored.
+    pub fn close(&mut self) -> Result<(), KvError> {
+        if self.read_only {
+            return Ok(());
+        }
+        if self.unsynced_writes > 0 {
+            self.sync_write_segment()?;
+        }
+        // a trimmed index no longer holds every key
+        if self.write_segment.size > 0 && self.write_segment.trimmed_from.is_none() {
+            let segment = &self.write_segment;
+            write_hint(
+                &*self.storage,
+                &segment.file_path,
+                &segment.index,
+                segment.record_count,
+                segment.max_sequence,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn sync_write_segment(&mut self) -> Result<(), std::io::Error> {
+        self.storage.open(&self.write_segment.file_path)?.sync()?;
+        self.unsynced_writes = 0;
+        Ok(())
+    }
+
+    // Counts a write and syncs the write segment if the policy asks for it. A
+    // failed sync fails the write, so it is never acknowledged.
+    fn sync_after_write(&mut self) -> Result<(), std::io::Error> {
+        self.unsynced_writes += 1;
+        let due = match self.sync_policy {
+            SyncPolicy::Never => false,
+            SyncPolicy::EveryWrite => true,
+            SyncPolicy::EveryN(writes) => self.unsynced_writes >= writes,
+        };
+        match due {
+            true => self.sync_write_segment(),
+            false => Ok(()),
+        }
+    }
+
+    // The highest sequence number of the records on disk. Numbering carries on
+    // from it after a reopen, whatever the clock did in between.
+    fn highest_sequence(&self) -> u64 {
+        self.segments
+            .iter()
+            .chain(std::iter::once(&self.write_segment))
+            .map(|segment| segment.max_sequence)
+            .max()
+            .unwrap_or(0)
+    }
+
+    // Tags a record with the next sequence number: the clock at microsecond
+    // resolution, bumped past the previous number so no two records share one.
+    fn stamp(&mut self, mut record: Record) -> Record {
+        let sequence = (self.clock)()
+            .saturating_mul(1000)
+            .max(self.last_sequence + 1);
+        self.last_sequence = sequence;
+        record.header.fields.insert(FIELD_SEQUENCE, sequence);
+        record
+    }
+
+    fn record_mutation(&mut self, key: &[u8], value: Option<&[u8]>) {
+        if self.recent.len() == RECENT_BUFFER_SIZE {
+            self.recent.pop_front();
+        }
+        self.recent
+            .push_back((unix_millis(), key.to_vec(), value.map(<[u8]>::to_vec)));
+        self.notify_watchers(key, value);
+    }
+
+    // Receives an event for every later write of `key`, sent once the write
+    // has been appended and synced as the policy asks. Records that expire
+    // send nothing until a sweep tombstones them.
+    pub fn watch(&self, key: &[u8]) -> mpsc::Receiver<ChangeEvent> {
+        let (sender, receiver) = mpsc::channel();
+        let mut watchers = self.watchers.lock().unwrap();
+        watchers.entry(key.to_vec()).or_default().push(sender);
+        receiver
+    }
+
+    // Sends the change to the watchers of `key`, forgetting those whose
+    // receiver is gone.
+    fn notify_watchers(&mut self, key: &[u8], value: Option<&[u8]>) {
+        let watchers = self.watchers.get_mut().unwrap();
+        let Some(senders) = watchers.get_mut(key) else {
+            return;
+        };
+        let event = match value {
+            Some(value) => ChangeEvent::Set(value.to_vec()),
+            None => ChangeEvent::Deleted,
+        };
+        senders.retain(|sender| sender.send(event.clone()).is_ok());
+        if senders.is_empty() {
+            watchers.remove(key);
+        }
+    }
+
+    // Rebuilds the index of one segment from its file.
+    pub fn reindex_segment(&mut self, file_path: &String) -> Result<(), std::io::Error> {
+        self.value_cache.get_mut().unwrap().clear();
+        let started = std::time::Instant::now();
+        let file = open_segment(&*self.storage, file_path)?;
+        let size = file.len()?;
+        let inflated = match &file {
+            SegmentFile::Inflated(text) => Some(text.get_ref().clone()),
+            SegmentFile::Plain(_) | SegmentFile::Mapped(_) => None,
+        };
+        let (index, record_count, max_sequence) = index_records(file, file_path, 0, self.codec)?;
+        let build_time = started.elapsed();
+        // a hint that led here would send the next open to the same offset
+        let persist = !self.read_only && *file_path != self.write_segment.file_path;
+        let segment = self
+            .segments
+            .iter_mut()
+            .chain(std::iter::once(&mut self.write_segment))
+            .find(|segment| segment.file_path == *file_path);
+        if let Some(segment) = segment {
+            *segment = Segment {
+                record_count,
+                max_sequence,
+                index: SegmentIndex::Hashed(index),
+                size,
+                build_time,
+                inflated,
+                codec: self.codec,
+                ..Segment::empty(&self.storage, file_path.clone())
+            };
+            if persist {
+                segment.persist_index()?;
+            }
+        }
+        self.map_segments();
+        self.order_indexes();
+        self.trim_indexes();
+        Ok(())
+    }
+
+    // Rewrites the hint of the retired segment named `name`, or of every one,
+    // from a scan of its records and checks that it reads back the same. A
+    // segment whose index came from a bad hint is reindexed as well. Returns
+    // the paths of the segments whose hint was rewritten.
+    pub fn rehint(&mut self, name: Option<&str>) -> Result<Vec<String>, std::io::Error> {
+        self.check_writable()?;
+        let file_paths: Vec<String> = self
+            .segments
+            .iter()
+            // an SSTable is looked up through its block index and needs no hint
+            .filter(|segment| segment.blocks.is_none())
+            .map(|segment| segment.file_path.clone())
+            .filter(|file_path| {
+                name.is_none_or(|name| Path::new(file_path).file_name().unwrap() == name)
+            })
+            .collect();
+        for file_path in file_paths.iter() {
+            remove_index_files(&*self.storage, file_path)?;
+            let file = open_segment(&*self.storage, file_path)?;
+            let (index, record_count, max_sequence) =
+                index_records(file, file_path, 0, self.codec)?;
+            write_hint(
+                &*self.storage,
+                file_path,
+                &SegmentIndex::Hashed(index.clone()),
+                record_count,
+                max_sequence,
+            )?;
+            write_filter(
+                &*self.storage,
+                file_path,
+                &BloomFilter::with_keys(index.keys()),
+            )?;
+            let hint = read_hint(&*self.storage, file_path);
+            let rewritten = (&index, record_count, max_sequence);
+            if hint
+                .as_ref()
+                .map(|(hinted, count, sequence)| (hinted, *count, *sequence))
+                != Some(rewritten)
+            {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "the rewritten hint of [{}] does not match the segment",
+                        file_path
+                    ),
+                ));
+            }
+            let segment = self
+                .segments
+                .iter()
+                .find(|segment| segment.file_path == *file_path)
+                .unwrap();
+            // evicted entries are missing on purpose, only retained ones are checked
+            let stale = (segment.trimmed_from.is_none() && segment.index.len() != index.len())
+                || segment
+                    .index
+                    .iter()
+                    .any(|(key, offset)| index.get(key) != Some(offset));
+            if stale {
+                self.reindex_segment(file_path)?;
+            }
+        }
+        Ok(file_paths)
+    }
+
+    // Backs the index of every segment by a sorted map, or a hash map again.
+    pub fn set_ordered_index(&mut self, ordered: bool) {
+        self.ordered_index = ordered;
+        self.order_indexes();
+    }
+
+    // Reads retired segments through the file instead of memory maps, for
+    // directories whose files may be truncated underneath, which a mapped
+    // segment does not survive.
+    pub fn set_mmap(&mut self, enabled: bool) {
+        self.mmap = enabled;
+        self.map_segments();
+    }
+
+    // Maps the retired segments opened since the option was set, or drops the
+    // maps. A segment that cannot be mapped is read through its file.
+    fn map_segments(&mut self) {
+        for segment in self.segments.iter_mut() {
+            if segment.map(self.mmap).is_err() {
+                segment.mapped = None;
+            }
+        }
+    }
+
+    // Gives the segments opened since the option was set the index it asks for.
+    fn order_indexes(&mut self) {
+        for segment in self
+            .segments
+            .iter_mut()
+            .chain(std::iter::once(&mut self.write_segment))
+        {
+            segment.index.set_ordered(self.ordered_index);
+        }
+    }
+
+    // Applies the index cap to every retired segment, returns the number of evicted entries.
+    pub fn trim_indexes(&mut self) -> usize {
+        match self.index_cap {
+            Some(cap) => self
+                .segments
+                .iter_mut()
+                .map(|segment| segment.trim_index(cap))
+                .sum(),
+            None => 0,
+        }
+    }
+
+    // Replaces the retired segments in `range` with one SSTable holding
+    // `records`, which its blocks keep searchable however large it grows.
+    fn replace_with_sstable(
+        &mut self,
+        range: std::ops::Range<usize>,
+        records: Vec<Record>,
+        block_size: u64,
+    ) -> Result<(), std::io::Error> {
+        let file_path = self.next_file_name()?;
+        let mut sstable = Segment::new(&self.storage, file_path, self.codec)?;
+        sstable.save_sstable(records, block_size)?;
+        if self.compress {
+            sstable.compress()?;
+        }
+        sstable.persist_index()?;
+        self.metrics
+            .bytes_written
+            .fetch_add(sstable.size, Ordering::Relaxed);
+        // the inputs go only once the manifest no longer lists them
+        let filenames: Vec<String> = self
+            .segments
+            .splice(range, [sstable])
+            .map(|s| s.file_path.clone())
+            .collect();
+        self.write_manifest()?;
+        let mut pins = self.pins.lock().unwrap();
+        for file_path in filenames {
+            pins.remove(&file_path)?;
+        }
+        drop(pins);
+        if self.live_count.is_some() {
+            self.track_live_count()?;
+        }
+        Ok(())
+    }
+
+    fn check_not_compacting(&self) -> Result<(), std::io::Error> {
+        if self.compacting {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::ResourceBusy,
+                "a background compaction is running",
+            ));
+        }
+        Ok(())
+    }
+
+    // Size tier of a segment: 0 up to TIER_GROWTH times the threshold, and each
+    // tier after that TIER_GROWTH times larger than the one before.
+    fn size_tier(&self, size: u64) -> u32 {
+        let mut tier = 0;
+        let mut bound = self.segment_threshold.max(1).saturating_mul(TIER_GROWTH);
+        while size > bound {
+            tier += 1;
+            bound = bound.saturating_mul(TIER_GROWTH);
+        }
+        tier
+    }
+
+    // Positions of the retired segments the strategy merges next. Reads go by
+    // the order of the segments and the merged ones are replaced where they
+    // were, so these are always adjacent. Empty if there is nothing to merge.
+    fn compaction_range(&self) -> std::ops::Range<usize> {
+        match self.compaction_strategy {
+            CompactionStrategy::Full => 0..self.segments.len(),
+            CompactionStrategy::SizeTiered => {
+                let tiers: Vec<u32> = self
+                    .segments
+                    .iter()
+                    .map(|s| self.size_tier(s.size))
+                    .collect();
+                let mut end = tiers.len();
+                while end > 0 {
+                    let start = (0..end)
+                        .rev()
+                        .take_while(|i| tiers[*i] == tiers[end - 1])
+                        .last()
+                        .unwrap();
+                    if end - start >= TIER_MIN_SEGMENTS {
+                        return start..end;
+                    }
+                    end = start;
+                }
+                0..0
+            }
+        }
+    }
+
+    // Merges the retired segments in `range` into the records a compaction of
+    // them writes, grouped by the segment each goes to, along with what that
+    // amounts to. Tombstones are only dropped when no older segment is left.
+    fn plan_compaction(
+        &self,
+        range: std::ops::Range<usize>,
+    ) -> Result<(Vec<Vec<Record>>, CompactionSummary), std::io::Error> {
+        let now = (self.clock)();
+        let mut summary = CompactionSummary {
+            segments_merged: range.len(),
+            ..CompactionSummary::default()
+        };
+        let split_at = match self.compaction_strategy {
+            CompactionStrategy::SizeTiered if range.is_empty() => return Ok((Vec::new(), summary)),
+            // an SSTable takes every record, its blocks keep it searchable
+            _ if self.sstable_block_size.is_some() => u64::MAX,
+            CompactionStrategy::Full => self.segment_threshold,
+            CompactionStrategy::SizeTiered => u64::MAX,
+        };
+        let covers_all_segments = range.start == 0;
+        let mut merged_bytes = 0;
+        let mut total_data: HashMap<Vec<u8>, Record> = HashMap::new();
+        for segment in self.segments[range].iter() {
+            merged_bytes += segment.size;
+            for record in segment.records()? {
+                let record = record?;
+                if record.is_tombstone() {
+                    summary.tombstones_purged += 1;
+                }
+                keep_newer(&mut total_data, record);
+            }
+        }
+        let mut encoder = Segment::empty(&self.storage, String::new());
+        encoder.checksums = self.checksums;
+        encoder.codec = self.codec;
+        let mut groups: Vec<Vec<Record>> = vec![Vec::new()];
+        let mut group_size = 0;
+        let mut written_bytes = 0;
+        let records = live_records(
+            total_data,
+            now,
+            covers_all_segments,
+            self.comparator.as_ref(),
+        );
+        for record in records {
+            // moves on before the record would take the segment over the threshold,
+            // so only a record larger than that on its own ends up over it
+            let length = encoder.saved_line(&record).len() as u64;
+            if group_size > 0 && group_size + length > split_at {
+                groups.push(Vec::new());
+                group_size = 0;
+            }
+            if record.is_tombstone() {
+                summary.tombstones_purged -= 1;
+            }
+            group_size += length;
+            written_bytes += length;
+            groups.last_mut().unwrap().push(record);
+        }
+        // an SSTable ends in its block index
+        if let Some(block_size) = self.sstable_block_size {
+            for group in groups.iter_mut() {
+                group.sort_by(|a, b| a.key.cmp(&b.key));
+                let (blocks, data_end) = encoder.sstable_blocks(group, block_size);
+                written_bytes += BlockIndex::new(blocks, data_end).encode().len() as u64;
+            }
+        }
+        summary.segments_written = groups.len();
+        summary.bytes_reclaimed = merged_bytes.saturating_sub(written_bytes);
+        Ok((groups, summary))
+    }
+
+    // What `compact_segments` would do now, without writing anything.
+    pub fn compact_dry_run(&self) -> Result<CompactionSummary, std::io::Error> {
+        Ok(self.plan_compaction(self.compaction_range())?.1)
+    }
+
+    pub fn compact_segments(&mut self) -> Result<CompactionSummary, std::io::Error> {
+        let range = self.compaction_range();
+        self.compact_range(range, None)
+    }
+
+    // Merges every retired segment, rewriting each record in `format` along the
+    // way, so a store moves to another format in the pass that drops its dead
+    // records. New records are still written as the store was opened to.
+    pub fn compact_to(
+        &mut self,
+        format: RecordFormat,
+    ) -> Result<CompactionSummary, std::io::Error> {
+        self.compact_range(0..self.segments.len(), Some(format))
+    }
+
+    // Merges the retired segments in `range`, whatever the compaction strategy.
+    // Records keep their checksums and get one if checksums are on, unless
+    // `format` says otherwise.
+    fn compact_range(
+        &mut self,
+        range: std::ops::Range<usize>,
+        format: Option<RecordFormat>,
+    ) -> Result<CompactionSummary, std::io::Error> {
+        self.check_writable()?;
+        // blocks the environment throughout, `start_compaction` does not
+        self.check_not_compacting()?;
+        let (mut groups, summary) = self.plan_compaction(range.clone())?;
+        if groups.is_empty() {
+            return Ok(summary);
+        }
+        // --checksums carries over to the compacted records unless asked otherwise
+        let format = format.or(self.checksums.then_some(RecordFormat::Checksummed));
+        for record in groups.iter_mut().flatten() {
+            match format {
+                // the value is filled in by encode_record
+                Some(RecordFormat::Checksummed) => {
+                    record.header.fields.insert(FIELD_CHECKSUM, 0);
+                }
+                Some(RecordFormat::Plain) => {
+                    record.header.fields.remove(&FIELD_CHECKSUM);
+                }
+                None => (),
+            }
+        }
+        self.metrics.compactions.fetch_add(1, Ordering::Relaxed);
+        if let Some(block_size) = self.sstable_block_size {
+            let records = groups.into_iter().flatten().collect();
+            self.replace_with_sstable(range, records, block_size)?;
+            return Ok(summary);
+        }
+        let mut new_segments: Vec<Segment> = Vec::new();
+        for records in groups {
+            let file_path = self.next_file_name()?;
+            let mut segment = Segment::new(&self.storage, file_path, self.codec)?;
+            for record in records.iter() {
+                segment.save_record(record)?;
+            }
+            new_segments.push(segment);
+        }
+        for segment in new_segments.iter_mut() {
+            segment.seal();
+            if self.compress {
+                segment.compress()?;
+            }
+            segment.persist_index()?;
+        }
+        let compacted_bytes: u64 = new_segments.iter().map(|s| s.size).sum();
+        self.metrics
+            .bytes_written
+            .fetch_add(compacted_bytes, Ordering::Relaxed);
+        // the inputs go only once the manifest no longer lists them
+        let filenames: Vec<String> = self
+            .segments
+            .splice(range, new_segments)
+            .map(|s| s.file_path.clone())
+            .collect();
+        self.write_manifest()?;
+        let mut pins = self.pins.lock().unwrap();
+        for file_path in filenames {
+            pins.remove(&file_path)?;
+        }
+        drop(pins);
+        self.map_segments();
+        self.order_indexes();
+        self.trim_indexes();
+        if self.live_count.is_some() {
+            self.track_live_count()?;
+        }
+        Ok(summary)
+    }
+
+    // Removes every key. The write segment is retired first so that all records
+    // are in listed segments, then writing a manifest that lists none drops them
+    // at once: after a crash either every key is there or none is. Segment
+    // numbers carry on, a number is never handed out twice.
+    pub fn clear(&mut self) -> Result<(), std::io::Error> {
+        self.check_writable()?;
+        self.check_not_compacting()?;
+        // releases the snapshots of open cursors, so their segments can go
+        self.cursors.clear();
+        if self.write_segment.size > 0 {
+            self.retire_write_segment()?;
+        }
+        let file_paths: Vec<String> = self.segments.drain(..).map(|s| s.file_path).collect();
+        self.write_manifest()?;
+        let mut pins = self.pins.lock().unwrap();
+        for file_path in file_paths.iter() {
+            pins.remove(file_path)?;
+        }
+        drop(pins);
+        for file_path in file_paths.iter() {
+            // a date partition left empty, one that still holds files stays
+            let directory = Path::new(file_path).parent().unwrap();
+            if directory != Path::new(&self.data_path) {
+                let _ = self.storage.remove(&directory.display().to_string());
+            }
+        }
+        self.value_cache.get_mut().unwrap().clear();
+        self.last_access.clear();
+        self.recent.clear();
+        if self.live_count.is_some() {
+            self.live_count = Some(0);
+        }
+        let watched: Vec<Vec<u8>> = self.watchers.get_mut().unwrap().keys().cloned().collect();
+        for key in watched {
+            self.notify_watchers(&key, None);
+        }
+        Ok(())
+    }
+
+    // Pins the retired segments for a compaction to run without the environment.
+    // None if there is nothing to compact.
+    pub fn start_compaction(&mut self) -> Result<Option<CompactionJob>, std::io::Error> {
+        self.check_writable()?;
+        self.check_not_compacting()?;
+        let range = self.compaction_range();
+        if range.is_empty() {
+            return Ok(None);
+        }
+        // numbered now, so that the output sorts after its inputs and before
+        // any segment retired while the job runs
+        let output_name = self.next_file_name()?;
+        let covers_all_segments = range.start == 0;
+        let inputs: Vec<String> = self.segments[range]
+            .iter()
+            .map(|s| s.file_path.clone())
+            .collect();
+        let mut pins = self.pins.lock().unwrap();
+        for file_path in inputs.iter() {
+            pins.pin(file_path);
+        }
+        drop(pins);
+        self.compacting = true;
+        Ok(Some(CompactionJob {
+            inputs,
+            output_name,
+            checksums: self.checksums,
+            codec: self.codec,
+            now: (self.clock)(),
+            covers_all_segments,
+            compress: self.compress,
+            storage: self.storage.clone(),
+            pins: self.pins.clone(),
+            comparator: self.comparator.clone(),
+            output: None,
+        }))
+    }
+
+    // The write segment has grown past the threshold, holds
+    // `max_records_per_segment` records, or enough of them for its utilization
+    // to have fallen under `min_write_utilization`.
+    fn write_segment_full(&self) -> bool {
+        let record_count = self.write_segment.record_count;
+        let underused = self.min_write_utilization.is_some_and(|min| {
+            record_count >= UTILIZATION_MIN_RECORDS && self.write_segment.utilization() < min
+        });
+        let counted_out = self
+            .max_records_per_segment
+            .is_some_and(|max_records| record_count >= max_records.max(1));
+        self.write_segment.size > self.segment_threshold || counted_out || underused
+    }
+
+    // Compacts every retired segment once the segment files take more than
+    // `max_disk_bytes`, and fails if they still do. A background compaction
+    // already running is left to finish, the next retirement checks again.
+    fn enforce_disk_limit(&mut self) -> Result<(), KvError> {
+        let limit = match self.max_disk_bytes {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+        if self.compacting || self.disk_bytes()? <= limit {
+            return Ok(());
+        }
+        self.compact_range(0..self.segments.len(), None)?;
+        let disk_bytes = self.disk_bytes()?;
+        if disk_bytes > limit {
+            return Err(KvError::DiskLimitExceeded { disk_bytes, limit });
+        }
+        Ok(())
+    }
+
+    // Starts a compaction once there are more than `max_segments` retired
+    // segments, leaving the job for the caller like `COMPACT --background`.
+    fn schedule_compaction(&mut self) -> Result<(), std::io::Error> {
+        let too_many = self
+            .max_segments
+            .is_some_and(|max_segments| self.segments.len() > max_segments);
+        if too_many && !self.compacting {
+            self.compaction_job = self.start_compaction()?;
+        }
+        Ok(())
+    }
+
+    pub fn take_compaction_job(&mut self) -> Option<CompactionJob> {
+        self.compaction_job.take()
+    }
+
+    // Swaps the output of a job in for its inputs, `result` being what its `run`
+    // returned. Segments retired while it ran are newer than all of its records
+    // and stay in place after it, as do any older or newer segments it left out.
+    pub fn finish_compaction(
+        &mut self,
+        mut job: CompactionJob,
+        result: Result<(), KvError>,
+    ) -> Result<(), KvError> {
+        self.compacting = false;
+        let mut pins = self.pins.lock().unwrap();
+        let swapped = result.and_then(|_| {
+            let segment = job.output.take().unwrap();
+            self.storage
+                .rename(&segment.file_path, &job.output_path())?;
+            Ok(segment)
+        });
+        for file_path in job.inputs.iter() {
+            pins.unpin(file_path)?;
+        }
+        let mut segment = match swapped {
+            Ok(segment) => segment,
+            Err(e) => {
+                job.remove_tmp_files();
+                return Err(e);
+            }
+        };
+        drop(pins);
+        segment.file_path = job.output_path();
+        segment.persist_index()?;
+        self.metrics.compactions.fetch_add(1, Ordering::Relaxed);
+        self.metrics
+            .bytes_written
+            .fetch_add(segment.size, Ordering::Relaxed);
+        // in place of its inputs, which are adjacent
+        let position = self
+            .segments
+            .iter()
+            .position(|s| job.inputs.contains(&s.file_path))
+            .unwrap_or(0);
+        self.segments.retain(|s| !job.inputs.contains(&s.file_path));
+        self.segments.insert(position, segment);
+        self.write_manifest()?;
+        // a snapshot reading an input keeps it under another name
+        let mut pins = self.pins.lock().unwrap();
+        for file_path in job.inputs.iter() {
+            pins.remove(file_path)?;
+        }
+        drop(pins);
+        self.map_segments();
+        self.order_indexes();
+        self.trim_indexes();
+        Ok(())
+    }
+}
+
+// A compaction of the retired segments that existed when it was started. Only
+// starting and finishing it need the environment, so writes and reads go on
+// while `run` rewrites the segments.
+pub struct CompactionJob {
+    inputs: Vec<String>,
+    // name of the output, without the suffix of compression
+    output_name: String,
+    checksums: bool,
+    codec: RecordCodec,
+    // records expired by then are dropped along with tombstones
+    now: u64,
+    // whether no retired segment is older than the inputs, so tombstones and
+    // expired records can go
+    covers_all_segments: bool,
+    compress: bool,
+    storage: Arc<dyn Storage>,
+    pins: Arc<Mutex<SegmentPins>>,
+    // the order the output is written in
+    comparator: Arc<dyn KeyComparator>,
+    // the merged segment, under its temporary name until the job is finished
+    output: Option<Segment>,
+}
+
+impl CompactionJob {
+    fn tmp_path(&self) -> String {
+        format!("{}.compacting.tmp", self.output_name)
+    }
+
+    // the output of a failed job, compressed or not
+    fn remove_tmp_files(&self) {
+        let tmp_path = self.tmp_path();
+        let compressed_path = format!("{}.{}", tmp_path, COMPRESSED_SUFFIX);
+        let _ = self.storage.remove(&format!("{}.tmp", compressed_path));
+        let _ = self.storage.remove(&compressed_path);
+        let _ = self.storage.remove(&tmp_path);
+    }
+
+    // the name the output is given, compressed or not as the output is
+    fn output_path(&self) -> String {
+        match self.compress {
+            true => format!("{}.{}", self.output_name, COMPRESSED_SUFFIX),
+            false => self.output_name.clone(),
+        }
+    }
+
+    // Merges the inputs into a temporary segment, dropping overwritten records,
+    // expired records and, when nothing older than the inputs exists, tombstones.
+    pub fn run(&mut self) -> Result<(), KvError> {
+        let mut total_data: HashMap<Vec<u8>, Record> = HashMap::new();
+        for file_path in self.inputs.iter() {
+            // the inputs are pinned, so they are readable until the job is finished
+            let file_path = self.pins.lock().unwrap().resolve(file_path);
+            let file = open_segment(&*self.storage, &file_path)?;
+            for record in SegmentRecords::new(file, self.codec) {
+                keep_newer(&mut total_data, record?);
+            }
+        }
+        let tmp_path = self.tmp_path();
+        self.storage.write(&tmp_path, b"")?;
+        let mut segment = Segment::new(&self.storage, tmp_path, self.codec)?;
+        segment.checksums = self.checksums;
+        let records = live_records(
+            total_data,
+            self.now,
+            self.covers_all_segments,
+            self.comparator.as_ref(),
+        );
+        for record in records {
+            segment.save_record(&record)?;
+        }
+        segment.seal();
+        if self.compress {
+            segment.compress()?;
+        }
+        self.output = Some(segment);
+        Ok(())
+    }
+}
+
+// Keeps the newer of two records of a key by their sequence numbers, so the
+// order segments are read in does not matter. Records from before sequence
+// numbers count as 0, between those the one read last wins.
+fn keep_newer(total_data: &mut HashMap<Vec<u8>, Record>, record: Record) {
+    let sequence = |record: &Record| record.header.fields.get(&FIELD_SEQUENCE).copied();
+    if let Some(kept) = total_data.get(&record.key)
+        && sequence(kept) > sequence(&record)
+    {
+        return;
+    }
+    total_data.insert(record.key.clone(), record);
+}
+
+// The merged records compaction writes out, in key order so that the output
+// does not depend on the order of the map. Tombstones and expired records are
+// only dropped when the merge covers every retired segment, otherwise an older
+// segment left out of it could bring the key back.
+fn live_records(
+    total_data: HashMap<Vec<u8>, Record>,
+    now: u64,
+    covers_all_segments: bool,
+    comparator: &dyn KeyComparator,
+) -> Vec<Record> {
+    let mut records: Vec<Record> = total_data
+        .into_values()
+        .filter(|record| {
+            !covers_all_segments || !record.is_tombstone() && !is_expired(&record.header, now)
+        })
+        .collect();
+    records.sort_by(|a, b| comparator.compare(&a.key, &b.key));
+    records
+}
+
+// Readers of the segment files a batch of lookups went through, so that each
+// file is opened once. Segments in memory need none.
+#[derive(Default)]
+struct OpenSegments(HashMap<String, BufReader<SegmentFile>>);
+
+// A segment opened for reading. Segments are compressed as a whole, so a
+// compressed one is inflated into memory and read from there, at the offsets
+// its records have in the text.
+enum SegmentFile {
+    Plain(Box<dyn StorageFile>),
+    Inflated(std::io::Cursor<Arc<[u8]>>),
+    Mapped(std::io::Cursor<mmap::Mmap>),
+}
+
+impl SegmentFile {
+    fn len(&self) -> Result<u64, std::io::Error> {
+        match self {
+            SegmentFile::Plain(file) => file.size(),
+            SegmentFile::Inflated(text) => Ok(text.get_ref().len() as u64),
+            SegmentFile::Mapped(text) => Ok(text.get_ref().len() as u64),
+        }
+    }
+}
+
+impl Read for SegmentFile {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            SegmentFile::Plain(file) => file.read(buf),
+            SegmentFile::Inflated(text) => text.read(buf),
+            SegmentFile::Mapped(text) => text.read(buf),
+        }
+    }
+}
+
+impl Seek for SegmentFile {
+    fn seek(&mut self, position: SeekFrom) -> std::io::Result<u64> {
+        match self {
+            SegmentFile::Plain(file) => file.seek(position),
+            SegmentFile::Inflated(text) => text.seek(position),
+            SegmentFile::Mapped(text) => text.seek(position),
+        }
+    }
+}
+
+// Opens a segment whether it is compressed or not, which is told by its first
+// bytes rather than its name: a held segment may have been renamed.
+fn open_segment(storage: &dyn Storage, file_path: &str) -> Result<SegmentFile, std::io::Error> {
+    let mut file = storage.open(file_path)?;
+    let mut magic = Vec::new();
+    (&mut file).take(2).read_to_end(&mut magic)?;
+    if !gzip::is_compressed(&magic) {
+        file.seek(SeekFrom::Start(0))?;
+        return Ok(SegmentFile::Plain(file));
+    }
+    file.read_to_end(&mut magic)?;
+    let text = gzip::decompress(&magic)?;
+    Ok(SegmentFile::Inflated(std::io::Cursor::new(text.into())))
+}
+
+// The name a segment namer gave a retired segment, without the suffix of compression.
+fn uncompressed_name(file_name: &str) -> &str {
+    file_name
+        .strip_suffix(COMPRESSED_SUFFIX)
+        .and_then(|name| name.strip_suffix('.'))
+        .unwrap_or(file_name)
+}
+
+fn build_index(
+    storage: &dyn Storage,
+    file_path: &str,
+    codec: RecordCodec,
+) -> Result<HashMap<Vec<u8>, u64>, KvError> {
+    build_index_from(storage, file_path, 0, codec)
+}
+
+// Cuts off the bytes after the last newline of a segment, what an interrupted
+// append leaves behind since every record ends with one. Binary frames may
+// hold newlines, so a binary segment is cut after its last complete frame.
+// Returns the offset the file was truncated to, None if it ended with a
+// complete record.
+fn truncate_torn_tail(
+    storage: &dyn Storage,
+    file_path: &str,
+    codec: RecordCodec,
+) -> Result<Option<u64>, std::io::Error> {
+    let mut file = storage.open_writable(file_path)?;
+    let len = file.size()?;
+    let mut good = 0;
+    if codec == RecordCodec::Binary {
+        let mut reader = BufReader::new(&mut file);
+        while let Some(frame) = read_frame(&mut reader, codec)?
+            && frame.complete
+        {
+            good += frame.len;
+        }
+    } else {
+        let mut buffer = vec![0u8; 4096];
+        let mut end = len;
+        while end > 0 {
+            let start = end.saturating_sub(buffer.len() as u64);
+            let chunk = &mut buffer[..(end - start) as usize];
+            file.seek(SeekFrom::Start(start))?;
+            file.read_exact(chunk)?;
+            if let Some(position) = chunk.iter().rposition(|byte| *byte == b'\n') {
+                good = start + position as u64 + 1;
+                break;
+            }
+            end = start;
+        }
+    }
+    if good == len {
+        return Ok(None);
+    }
+    file.set_len(good)?;
+    Ok(Some(good))
+}
+
+// Cuts off a batch whose last record never made it to disk, so that none of
+// its records become visible. Returns the offset the batch started at, None if
+// the segment does not end inside a batch.
+fn truncate_incomplete_batch(
+    storage: &dyn Storage,
+    file_path: &str,
+    codec: RecordCodec,
+) -> Result<Option<u64>, std::io::Error> {
+    let file = storage.open(file_path)?;
+    let mut batch_start = None;
+    let mut offset = 0;
+    for line in segment_frames(BufReader::new(file), codec) {
+        let (real_line, line_len) = line?;
+        if !is_padding(&real_line) {
+            let following = codec
+                .decode(&real_line)
+                .and_then(|record| record.header.fields.get(&FIELD_BATCH).copied())
+                .unwrap_or(0);
+            batch_start = match following {
+                0 => None,
+                _ => batch_start.or(Some(offset)),
+            };
+        }
+        offset += line_len;
+    }
+    let batch_start = match batch_start {
+        Some(batch_start) => batch_start,
+        None => return Ok(None),
+    };
+    storage.open_writable(file_path)?.set_len(batch_start)?;
+    Ok(Some(batch_start))
+}
+
+// Opens retired segments on up to one thread per core, each building the index
+// of its own file, and returns them in the order of `paths`. `progress` is told
+// about every segment opened, in the order they finish.
+fn open_retired_segments(
+    storage: &Arc<dyn Storage>,
+    paths: Vec<String>,
+    codec: RecordCodec,
+    progress: &mut (dyn FnMut(OpenProgress) + Send),
+) -> Result<Vec<Segment>, KvError> {
+    let sizes: Vec<u64> = paths
+        .iter()
+        .map(|file_path| storage.size(file_path).unwrap_or(0))
+        .collect();
+    let state = OpenProgress {
+        segments_total: paths.len(),
+        bytes_total: sizes.iter().sum(),
+        ..OpenProgress::default()
+    };
+    let reported = Mutex::new((state, progress));
+    let report = |position: usize| {
+        let mut reported = reported.lock().unwrap();
+        let (state, progress) = &mut *reported;
+        state.segments_loaded += 1;
+        state.bytes_loaded += sizes[position];
+        progress(*state);
+    };
+    let threads = std::thread::available_parallelism()
+        .map_or(1, |threads| threads.get())
+        .min(paths.len());
+    if threads <= 1 {
+        return paths
+            .into_iter()
+            .enumerate()
+            .map(|(position, file_path)| {
+                let segment = Segment::open_retired(storage, file_path, codec)?;
+                report(position);
+                Ok(segment)
+            })
+            .collect();
+    }
+    let next = AtomicUsize::new(0);
+    // once a segment fails to open the open fails, so no more are started
+    let failed = AtomicBool::new(false);
+    let opened: Vec<Mutex<Option<Result<Segment, KvError>>>> =
+        paths.iter().map(|_| Mutex::new(None)).collect();
+    std::thread::scope(|scope| {
+        for _ in 0..threads {
+            scope.spawn(|| {
+                while !failed.load(Ordering::Relaxed) {
+                    let position = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(file_path) = paths.get(position) else {
+                        break;
+                    };
+                    let segment = Segment::open_retired(storage, file_path.clone(), codec);
+                    match segment {
+                        Ok(_) => report(position),
+                        Err(_) => failed.store(true, Ordering::Relaxed),
+                    }
+                    *opened[position].lock().unwrap() = Some(segment);
+                }
+            });
+        }
+    });
+    // positions are taken in order, so every one before the first failure
+    // was opened and collecting stops at that failure
+    opened
+        .into_iter()
+        .map_while(|segment| segment.into_inner().unwrap())
+        .collect()
+}
+
+// The index of the records of a segment, their count and highest sequence number.
+type IndexedRecords = (HashMap<Vec<u8>, u64>, u64, u64);
+
+fn hint_path(file_path: &str) -> String {
+    format!("{}.{}", file_path, HINT_SUFFIX)
+}
+
+// Saves the index of a segment next to it, after a marked line with the size
+// of the segment it covers, the number of records in it and their highest
+// sequence number. Written through a `.tmp` file, so a crash never leaves a
+// partial hint behind.
+fn write_hint(
+    storage: &dyn Storage,
+    file_path: &str,
+    index: &SegmentIndex,
+    record_count: u64,
+    max_sequence: u64,
+) -> Result<(), std::io::Error> {
+    let hint_path = hint_path(file_path);
+    let tmp_path = format!("{}.tmp", hint_path);
+    let size = storage.size(file_path)?;
+    let mut contents = format!(
+        "{}{};{};{}\n",
+        HEADER_MARKER as char, size, record_count, max_sequence
+    )
+    .into_bytes();
+    for (key, offset) in index.iter() {
+        contents.extend_from_slice(format!("{},", offset).as_bytes());
+        contents.extend_from_slice(&escape_field(key));
+        contents.push(b'\n');
+    }
+    storage.write(&tmp_path, &contents)?;
+    storage.rename(&tmp_path, &hint_path)
+}
+
+// The saved index of a segment, its record count and highest sequence number,
+// None if there is no hint, it is older than the segment, it covers another
+// size or it does not parse; the caller then scans the segment instead. The
+// size catches appends to the write segment that a coarse modification time
+// misses, hints written before it had one go by time. Hints written before
+// they carried the sequence number are not used, only a scan finds it.
+fn read_hint(storage: &dyn Storage, file_path: &str) -> Option<IndexedRecords> {
+    let hint_path = hint_path(file_path);
+    let hint_modified = storage.modified(&hint_path).ok()?;
+    if hint_modified < storage.modified(file_path).ok()? {
+        return None;
+    }
+    let segment_size = storage.size(file_path).ok()?;
+    let mut index = HashMap::new();
+    let mut header = None;
+    for line in byte_lines(BufReader::new(storage.open(&hint_path).ok()?)) {
+        let line = line.ok()?;
+        if let Some(fields) = line.strip_prefix(&[HEADER_MARKER]) {
+            let fields = std::str::from_utf8(fields).ok()?.split(';');
+            let fields = fields
+                .map(|field| field.parse::<u64>().ok())
+                .collect::<Option<Vec<u64>>>()?;
+            let [size, record_count, max_sequence] = fields[..] else {
+                return None;
+            };
+            if size != segment_size {
+                return None;
+            }
+            header = Some((record_count, max_sequence));
+            continue;
+        }
+        let (offset, key) = split_line(&line)?;
+        let offset = std::str::from_utf8(offset).ok()?.parse::<u64>().ok()?;
+        index.insert(unescape_field(key)?, offset);
+    }
+    let (record_count, max_sequence) = header?;
+    Some((index, record_count, max_sequence))
+}
+
+fn filter_path(file_path: &str) -> String {
+    format!("{}.{}", file_path, FILTER_SUFFIX)
+}
+
+// Saves the Bloom filter of a retired segment next to it as the number of
+// hashes followed by the words of the bit array, all little-endian.
+fn write_filter(
+    storage: &dyn Storage,
+    file_path: &str,
+    filter: &BloomFilter,
+) -> Result<(), std::io::Error> {
+    let filter_path = filter_path(file_path);
+    let tmp_path = format!("{}.tmp", filter_path);
+    let mut contents = filter.hashes.to_le_bytes().to_vec();
+    for word in filter.words.iter() {
+        contents.extend_from_slice(&word.to_le_bytes());
+    }
+    storage.write(&tmp_path, &contents)?;
+    storage.rename(&tmp_path, &filter_path)
+}
+
+// The saved filter of a segment, None if it is missing, older than the
+// segment or malformed; the caller then builds it from the index.
+fn read_filter(storage: &dyn Storage, file_path: &str) -> Option<BloomFilter> {
+    let filter_path = filter_path(file_path);
+    let filter_modified = storage.modified(&filter_path).ok()?;
+    if filter_modified < storage.modified(file_path).ok()? {
+        return None;
+    }
+    let contents = storage.read(&filter_path).ok()?;
+    let (hashes, words) = contents.split_first_chunk::<4>()?;
+    if words.is_empty() || words.len() % 8 != 0 {
+        return None;
+    }
+    Some(BloomFilter {
+        hashes: u32::from_le_bytes(*hashes),
+        words: words
+            .chunks_exact(8)
+            .map(|word| u64::from_le_bytes(word.try_into().unwrap()))
+            .collect(),
+    })
+}
+
+// Removes the hint and the filter saved next to a segment, if there are any.
+fn remove_index_files(storage: &dyn Storage, file_path: &str) -> Result<(), std::io::Error> {
+    for path in [hint_path(file_path), filter_path(file_path)] {
+        match storage.remove(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+fn rename_with_index_files(
+    storage: &dyn Storage,
+    from: &str,
+    to: &str,
+) -> Result<(), std::io::Error> {
+    storage.rename(from, to)?;
+    for (from, to) in [
+        (hint_path(from), hint_path(to)),
+        (filter_path(from), filter_path(to)),
+    ] {
+        if storage.exists(&from) {
+            storage.rename(&from, &to)?;
+        }
+    }
+    Ok(())
+}
+
+// Indexes the records starting at `start`, which has to be a record boundary.
+fn build_index_from(
+    storage: &dyn Storage,
+    file_path: &str,
+    start: u64,
+    codec: RecordCodec,
+) -> Result<HashMap<Vec<u8>, u64>, KvError> {
+    let file = open_segment(storage, file_path)?;
+    Ok(index_records(file, file_path, start, codec)?.0)
+}
+
+// The index of the records from `start` on, with how many records there are,
+// overwritten ones included, and their highest sequence number.
+fn index_records(
+    mut file: SegmentFile,
+    file_path: &str,
+    start: u64,
+    codec: RecordCodec,
+) -> Result<IndexedRecords, KvError> {
+    let mut result = HashMap::new();
+    let mut record_count = 0;
+    let mut max_sequence = 0;
+    file.seek(SeekFrom::Start(start))?;
+    let buf_reader = BufReader::new(file);
+
+    let mut current_position: u64 = start;
+    for line in segment_frames(buf_reader, codec) {
+        let (real_line, line_len) = line?;
+        if is_padding(&real_line) {
+            current_position += line_len;
+            continue;
+        }
+        if real_line.starts_with(BLOCK_LINE_MARKER) {
+            // the block lines end an SSTable
+            break;
+        }
+        let record = match codec.decode(&real_line) {
+            Some(record) if !checksum_matches(&record) => {
+                return Err(KvError::ChecksumMismatch {
+                    file_path: file_path.to_string(),
+                    offset: current_position,
+                });
+            }
+            Some(record) => record,
+            None => {
+                return Err(KvError::Corrupt {
+                    file_path: file_path.to_string(),
+                    offset: current_position,
+                    line: String::from_utf8_lossy(&real_line).into_owned(),
+                });
+            }
+        };
+        if let Some(sequence) = record.header.fields.get(&FIELD_SEQUENCE) {
+            max_sequence = max_sequence.max(*sequence);
+        }
+        result.insert(record.key, current_position);
+        record_count += 1;
+        current_position += line_len;
+    }
+    Ok((result, record_count, max_sequence))
+}
+
+// The records of a segment as `read_frame` reads them, with the padding and
+// block lines between them, each with the number of bytes it takes in the file.
+fn segment_frames<R: BufRead>(
+    mut reader: R,
+    codec: RecordCodec,
+) -> impl Iterator<Item = Result<(Vec<u8>, u64), std::io::Error>> {
+    std::iter::from_fn(move || match read_frame(&mut reader, codec) {
+        Ok(frame) => frame.map(|frame| Ok((frame.bytes, frame.len))),
+        Err(e) => Some(Err(e)),
+    })
+}
+
+// A record as it is stored, or a line holding none, see `read_frame`.
+struct Frame {
+    // without the newline of a line
+    bytes: Vec<u8>,
+    // bytes taken in the file, the newline included
+    len: u64,
+    // false for what an interrupted append left at the end of the file
+    complete: bool,
+}
+
+// Reads the next record of a segment, None at its end. Text records, padding
+// and block lines are read as lines, without their newline. A line ending in
+// `\r\n`, which only a segment written by hand or before `\r` was escaped can
+// hold, has both dropped, so counting one byte for the newline would put every
+// later offset one short. A binary frame is read by its lengths, whatever
+// bytes it holds.
+fn read_frame(reader: &mut impl BufRead, codec: RecordCodec) -> std::io::Result<Option<Frame>> {
+    let mut bytes = Vec::new();
+    let is_frame =
+        codec == RecordCodec::Binary && reader.fill_buf()?.first() == Some(&FRAME_MARKER);
+    if !is_frame {
+        let read = reader.read_until(b'\n', &mut bytes)? as u64;
+        if read == 0 {
+            return Ok(None);
+        }
+        let complete = bytes.last() == Some(&b'\n');
+        trim_newline(&mut bytes);
+        return Ok(Some(Frame {
+            bytes,
+            len: read,
+            complete,
+        }));
+    }
+    // true if all `len` bytes were there to read
+    let mut read = |len: u64, bytes: &mut Vec<u8>| -> std::io::Result<bool> {
+        Ok(reader.by_ref().take(len).read_to_end(bytes)? as u64 == len)
+    };
+    let mut complete = read(1, &mut bytes)?;
+    for _ in 0..3 {
+        let start = bytes.len();
+        if !complete || !read(4, &mut bytes)? {
+            complete = false;
+            break;
+        }
+        let len = u32::from_le_bytes(bytes[start..].try_into().unwrap());
+        complete = read(len as u64, &mut bytes)?;
+    }
+    complete = complete && read(4, &mut bytes)?;
+    Ok(Some(Frame {
+        len: bytes.len() as u64,
+        bytes,
+        complete,
+    }))
+}
+
+// drops the `\n` or `\r\n` a line read with its newline ends with
+fn trim_newline(line: &mut Vec<u8>) {
+    if line.last() == Some(&b'\n') {
+        line.pop();
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+    }
+}
+
+// Free space via statvfs(3), the only platform this is wired up for so far.
+#[cfg(all(target_os = "linux", target_pointer_width = "64"))]
+fn available_space(path: &str) -> Result<u64, std::io::Error> {
+    // struct statvfs of 64-bit Linux
+    #[repr(C)]
+    #[derive(Default)]
+    struct StatVfs {
+        f_bsize: u64,
+        f_frsize: u64,
+        f_blocks: u64,
+        f_bfree: u64,
+        f_bavail: u64,
+        f_files: u64,
+        f_ffree: u64,
+        f_favail: u64,
+        f_fsid: u64,
+        f_flag: u64,
+        f_namemax: u64,
+        f_spare: [i32; 6],
+    }
+    unsafe extern "C" {
+        fn statvfs(path: *const std::ffi::c_char, buf: *mut StatVfs) -> std::ffi::c_int;
+    }
+    let path = std::ffi::CString::new(path)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    let mut stats = StatVfs::default();
+    // SAFETY: `path` is NUL terminated and `stats` matches the C layout
+    if unsafe { statvfs(path.as_ptr(), &mut stats) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(stats.f_bavail * stats.f_frsize)
+}
+
+// without a way to ask, the guard never triggers
+#[cfg(not(all(target_os = "linux", target_pointer_width = "64")))]
+fn available_space(_path: &str) -> Result<u64, std::io::Error> {
+    Ok(u64::MAX)
+}
+
+fn is_expired(header: &RecordHeader, now: u64) -> bool {
+    header
+        .fields
+        .get(&FIELD_EXPIRY)
+        .is_some_and(|expires_at| *expires_at <= now)
+}
+
+// The value of a record as reads see it, None for a tombstone or once the
+// record expired.
+fn visible_value(record: Record, now: u64) -> Option<Vec<u8>> {
+    match record.is_tombstone() || is_expired(&record.header, now) {
+        true => None,
+        false => Some(record.value),
+    }
+}
+
+// A value for the string API, which has no string for other bytes.
+fn utf8_value(key: &[u8], value: Vec<u8>) -> Result<String, KvError> {
+    String::from_utf8(value).map_err(|_| KvError::NotUtf8 {
+        key: String::from_utf8_lossy(key).into_owned(),
+    })
+}
+
+// Filler left behind by an in-place update that shrank a record, it holds no data.
+fn is_padding(line: &[u8]) -> bool {
+    line.iter().all(|b| *b == b' ')
+}
+
+fn unix_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+// UTC date of a unix time in milliseconds as `YYYY-MM-DD`.
+fn date_of(unix_millis: u64) -> String {
+    let seconds = unix_millis / 1000;
+    // days to civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let z = (seconds / 86400) as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+fn is_segment_file(file_name: &str, prefix: &str, namer: &dyn SegmentNamer) -> bool {
+    file_name == format!("{}.{}", prefix, CURRENT_SEGMENT_SUFFIX)
+        || namer
+            .sequence(prefix, uncompressed_name(file_name))
+            .is_some()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DoctorIssue {
+    LeftoverTmpFile(String),
+    EmptySegment(String),
+    TornTail { file_path: String, offset: u64 },
+    CorruptRecord { file_path: String, offset: u64 },
+    StaleIndex(String),
+}
+
+impl fmt::Display for DoctorIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DoctorIssue::LeftoverTmpFile(file_path) => {
+                write!(f, "leftover temporary file [{}]", file_path)
+            }
+            DoctorIssue::EmptySegment(file_path) => {
+                write!(f, "empty retired segment [{}]", file_path)
+            }
+            DoctorIssue::TornTail { file_path, offset } => {
+                write!(
+                    f,
+                    "torn record at the tail of [{}] offset {}",
+                    file_path, offset
+                )
+            }
+            DoctorIssue::CorruptRecord { file_path, offset } => {
+                write!(f, "corrupt record in [{}] offset {}", file_path, offset)
+            }
+            DoctorIssue::StaleIndex(file_path) => {
+                write!(f, "index of [{}] does not match its file", file_path)
+            }
+        }
+    }
+}
+
+// Checks the segment files of a data directory without opening an environment
+// over it, so that it also works on directories that would fail to open. The
+// retired segments are those an open would load, followed by the write
+// segment. With `fix`, leftover temporary files and empty retired segments
+// are removed and torn tails are truncated, the manifest and the hints then
+// following the repaired files; corrupt records in the middle of a segment are
+// only reported.
+pub fn doctor(
+    storage: &dyn Storage,
+    data_path: &String,
+    prefix: &str,
+    namer: &dyn SegmentNamer,
+    fix: bool,
+) -> Result<Vec<DoctorIssue>, std::io::Error> {
+    let mut issues = Vec::new();
+    let mut manifest = Environment::read_manifest(storage, data_path, prefix);
+    let codec = manifest.codec;
+    let write_segment_path = Path::new(data_path)
+        .join(format!("{}.{}", prefix, CURRENT_SEGMENT_SUFFIX))
+        .display()
+        .to_string();
+    let mut file_paths =
+        Environment::segment_paths(storage, data_path, prefix, namer, &manifest, false)?;
+    if storage.exists(&write_segment_path) {
+        file_paths.push(write_segment_path.clone());
+    }
+    let mut removed = Vec::new();
+    for file_path in file_paths {
+        let is_write_segment = file_path == write_segment_path;
+        // a compressed segment that does not inflate has no record to trust
+        let file = match open_segment(storage, &file_path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+                issues.push(DoctorIssue::CorruptRecord {
+                    file_path,
+                    offset: 0,
+                });
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        let is_compressed = matches!(file, SegmentFile::Inflated(_));
+        let file_len = file.len()?;
+        if file_len == 0 && !is_write_segment {
+            if fix {
+                storage.remove(&file_path)?;
+                remove_index_files(storage, &file_path)?;
+                removed.push(file_path.clone());
+            }
+            issues.push(DoctorIssue::EmptySegment(file_path));
+            continue;
+        }
+        let mut buf_reader = BufReader::new(file);
+        let mut offset: u64 = 0;
+        while let Some(frame) = read_frame(&mut buf_reader, codec)? {
+            if !frame.complete {
+                issues.push(DoctorIssue::TornTail {
+                    file_path: file_path.clone(),
+                    offset,
+                });
+                // compressed segments are written whole, their tails are
+                // only reported
+                if fix && !is_compressed {
+                    storage.open_writable(&file_path)?.set_len(offset)?;
+                    rebuild_index_files(storage, &file_path, codec, !is_write_segment)?;
+                }
+                break;
+            }
+            let line = &frame.bytes;
+            let is_record = line.starts_with(BLOCK_LINE_MARKER)
+                || is_padding(line)
+                || codec.decode(line).is_some();
+            if !is_record {
+                issues.push(DoctorIssue::CorruptRecord {
+                    file_path: file_path.clone(),
+                    offset,
+                });
+            }
+            offset += frame.len;
+        }
+    }
+    // the manifest would send the next open to the removed segments
+    if let Some(listed) = manifest.segments.as_mut()
+        && !removed.is_empty()
+    {
+        listed.retain(|file_name| {
+            let file_path = Path::new(data_path).join(file_name).display().to_string();
+            !removed.contains(&file_path)
+        });
+        Environment::save_manifest(storage, data_path, prefix, &manifest)?;
+    }
+    // date partitions hold the files of retired segments as well
+    let mut dirs = vec![data_path.clone()];
+    dirs.extend(
+        storage
+            .list(data_path)?
+            .into_iter()
+            .filter(|path| storage.is_dir(path)),
+    );
+    for dir in dirs {
+        let mut file_paths = storage.list(&dir)?;
+        file_paths.sort();
+        for file_path in file_paths {
+            let file_name = Path::new(&file_path).file_name().unwrap_or_default();
+            let file_name = file_name.to_string_lossy();
+            // not the files of other prefixes that merely start the same
+            let is_ours = file_name
+                .strip_prefix(prefix)
+                .is_some_and(|rest| rest.starts_with('.'));
+            let is_leftover = file_name.ends_with(".tmp")
+                || file_name.ends_with(&format!(".{}", OBSOLETE_SUFFIX));
+            if is_ours && is_leftover && !storage.is_dir(&file_path) {
+                if fix {
+                    storage.remove(&file_path)?;
+                }
+                issues.push(DoctorIssue::LeftoverTmpFile(file_path));
+            }
+        }
+    }
+    Ok(issues)
+}
+
+// Replaces the hint of a segment whose file was repaired, and the filter of
+// a retired one, with those of its records. A segment that does not index
+// cleanly is left without them, to be scanned on open.
+fn rebuild_index_files(
+    storage: &dyn Storage,
+    file_path: &str,
+    codec: RecordCodec,
+    retired: bool,
+) -> Result<(), std::io::Error> {
+    remove_index_files(storage, file_path)?;
+    let indexed = open_segment(storage, file_path)
+        .map_err(KvError::from)
+        .and_then(|file| index_records(file, file_path, 0, codec));
+    let Ok((index, record_count, max_sequence)) = indexed else {
+        return Ok(());
+    };
+    let index = SegmentIndex::Hashed(index);
+    write_hint(storage, file_path, &index, record_count, max_sequence)?;
+    if retired {
+        write_filter(storage, file_path, &BloomFilter::with_keys(index.keys()))?;
+    }
+    Ok(())
+}
+
+// What VERIFY found in one segment. Offsets are those of the segment text.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SegmentVerification {
+    pub file_path: String,
+    // records that decode and match their checksum, if they carry one
+    pub ok_records: u64,
+    // offsets of the lines that do not decode or fail their checksum
+    pub corrupt_records: Vec<u64>,
+    // offset of a last line cut off before its newline
+    pub torn_tail: Option<u64>,
+    // index entries whose offset does not hold the newest record of their key
+    pub index_mismatches: Vec<(Vec<u8>, u64)>,
+}
+
+impl SegmentVerification {
+    pub fn is_ok(&self) -> bool {
+        self.corrupt_records.is_empty()
+            && self.torn_tail.is_none()
+            && self.index_mismatches.is_empty()
+    }
+}
+
+// Every segment of the environment, oldest first and the write segment last.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VerifyReport {
+    pub segments: Vec<SegmentVerification>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.segments.iter().all(SegmentVerification::is_ok)
+    }
+}
+
+// Reads every segment of an open environment and checks its records against
+// their checksums and its index against its records. Unlike `doctor` it goes
+// by the indexes in memory, and it never changes anything; `DOCTOR --fix`
+// repairs what can be.
+pub fn verify(env: &Environment) -> Result<VerifyReport, std::io::Error> {
+    let mut report = VerifyReport::default();
+    for segment in env
+        .segments
+        .iter()
+        .chain(std::iter::once(&env.write_segment))
+    {
+        let mut checked = SegmentVerification {
+            file_path: segment.file_path.clone(),
+            ..SegmentVerification::default()
+        };
+        // the offset of the newest record of each key, which the index should hold
+        let mut newest: HashMap<Vec<u8>, u64> = HashMap::new();
+        let mut buf_reader = BufReader::new(open_segment(&*env.storage, &segment.file_path)?);
+        let mut offset: u64 = 0;
+        while let Some(frame) = read_frame(&mut buf_reader, segment.codec)? {
+            if !frame.complete {
+                checked.torn_tail = Some(offset);
+                break;
+            }
+            // neither is padding left by an in-place update nor a block line
+            if !frame.bytes.starts_with(BLOCK_LINE_MARKER) && !is_padding(&frame.bytes) {
+                match segment.codec.decode(&frame.bytes) {
+                    Some(record) if checksum_matches(&record) => {
+                        checked.ok_records += 1;
+                        newest.insert(record.key, offset);
+                    }
+                    _ => checked.corrupt_records.push(offset),
+                }
+            }
+            offset += frame.len;
+        }
+        for (key, offset) in segment.index.iter() {
+            if newest.get(key) != Some(offset) {
+                checked.index_mismatches.push((key.clone(), *offset));
+            }
+        }
+        checked.index_mismatches.sort();
+        report.segments.push(checked);
+    }
+    Ok(report)
+}
+
+pub fn print_verify_report(out: &mut dyn Write, report: &VerifyReport) -> std::io::Result<()> {
+    for segment in report.segments.iter() {
+        writeln!(
+            out,
+            "[{}] records ok: [{}] corrupt: [{}]",
+            segment.file_path,
+            segment.ok_records,
+            segment.corrupt_records.len()
+        )?;
+        for offset in segment.corrupt_records.iter() {
+            writeln!(out, "  corrupt record at offset {}", offset)?;
+        }
+        if let Some(offset) = segment.torn_tail {
+            writeln!(out, "  torn record at the tail, offset {}", offset)?;
+        }
+        for (key, offset) in segment.index_mismatches.iter() {
+            writeln!(
+                out,
+                "  index entry [{}] at offset {} is not the newest record of the key",
+                String::from_utf8_lossy(key),
+                offset
+            )?;
+        }
+    }
+    match report.is_ok() {
+        true => writeln!(out, "No issues found"),
+        false => {
+            let failed = report.segments.iter().filter(|s| !s.is_ok()).count();
+            writeln!(out, "Found issues in [{}] segment(s)", failed)
+        }
+    }
+}
+
+// a segment file with its keys, each marked live or shadowed
+type SegmentKeys = (String, Vec<(Vec<u8>, bool)>);
+
+// Keys indexed by every segment, oldest first and the write segment last.
+// A key is live in the newest segment that indexes it and shadowed in the others.
+fn segment_layout(env: &Environment) -> Result<Vec<SegmentKeys>, std::io::Error> {
+    let segments: Vec<&Segment> = env
+        .segments
+        .iter()
+        .chain(std::iter::once(&env.write_segment))
+        .collect();
+    let mut seen: HashSet<Vec<u8>> = HashSet::new();
+    let mut result = Vec::new();
+    for segment in segments.iter().rev() {
+        let mut keys: Vec<(Vec<u8>, bool)> = segment
+            .keys()?
+            .into_iter()
+            .map(|key| {
+                let is_live = seen.insert(key.clone());
+                (key, is_live)
+            })
+            .collect();
+        keys.sort_by(|(a, _), (b, _)| env.comparator.compare(a, b));
+        result.push((segment.file_path.clone(), keys));
+    }
+    result.reverse();
+    Ok(result)
+}
+
+// Number of records in a segment that are shadowed by a later record for the
+// same key within that same segment.
+fn count_duplicates(segment: &Segment) -> Result<(u64, u64), std::io::Error> {
+    let mut seen: HashSet<Vec<u8>> = HashSet::new();
+    let mut records = 0;
+    for entry in segment.iter()? {
+        let (key, _, _) = entry?;
+        seen.insert(key);
+        records += 1;
+    }
+    Ok((records, records - seen.len() as u64))
+}
+
+// FNV-1a continuing from `hash`. Unlike the std hasher it is stable across
+// builds, so two instances can compare the hashes they compute.
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(FNV_PRIME)
+    })
+}
+
+// Set of the keys of a segment that may answer yes for a key it does not hold,
+// but never no for one it does.
+#[derive(Debug, Clone, PartialEq)]
+struct BloomFilter {
+    words: Vec<u64>,
+    hashes: u32,
+}
+
+impl BloomFilter {
+    // sized for the number of keys at BLOOM_BITS_PER_KEY
+    fn with_keys<'a>(keys: impl ExactSizeIterator<Item = &'a Vec<u8>>) -> Self {
+        let bits = (keys.len() * BLOOM_BITS_PER_KEY).max(64);
+        let mut filter = BloomFilter {
+            words: vec![0; bits.div_ceil(64)],
+            hashes: BLOOM_HASHES,
+        };
+        for key in keys {
+            filter.insert(key);
+        }
+        filter
+    }
+
+    // Bits of a key by double hashing, with FNV so they are stable across builds.
+    fn positions(&self, key: &[u8]) -> impl Iterator<Item = usize> + use<> {
+        let bits = self.words.len() as u64 * 64;
+        let h1 = fnv1a(FNV_OFFSET, key);
+        let h2 = fnv1a(h1, key) | 1;
+        (0..self.hashes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % bits) as usize)
+    }
+
+    fn insert(&mut self, key: &[u8]) {
+        for position in self.positions(key) {
+            self.words[position / 64] |= 1 << (position % 64);
+        }
+    }
+
+    fn contains(&self, key: &[u8]) -> bool {
+        self.positions(key)
+            .all(|position| self.words[position / 64] & (1 << (position % 64)) != 0)
+    }
+
+    fn bits(&self) -> usize {
+        self.words.len() * 64
+    }
+
+    // (1 - e^(-kn/m))^k, the usual estimate for `elements` keys in m bits with k hashes
+    fn estimated_fpr(&self, elements: usize) -> f64 {
+        let hashes = self.hashes as f64;
+        let unset = (-hashes * elements as f64 / self.bits() as f64).exp();
+        (1.0 - unset).powf(hashes)
+    }
+}
+
+// The Bloom filter of a retired segment as FILTERSTATS reports it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilterStats {
+    file_path: String,
+    bits: usize,
+    hashes: u32,
+    // keys the filter was built for
+    elements: usize,
+    estimated_fpr: f64,
+    // share of the probed keys, none of them in the segment, that it let through
+    measured_fpr: f64,
+}
+
+// Leaf holding `key`: the top `depth` bits of its first eight bytes, so every
+// leaf covers a contiguous range of the sorted keyspace.
+fn merkle_leaf(key: &[u8], depth: u32) -> usize {
+    if depth == 0 {
+        return 0;
+    }
+    let mut prefix = [0u8; 8];
+    for (slot, byte) in prefix.iter_mut().zip(key) {
+        *slot = *byte;
+    }
+    (u64::from_be_bytes(prefix) >> (64 - depth)) as usize
+}
+
+// Hashes of a binary Merkle tree over the live records, the root level first.
+// Each leaf hashes its records in key order, each inner node its two children.
+fn merkle_tree(env: &Environment, depth: u32) -> Result<Vec<Vec<u64>>, std::io::Error> {
+    let mut keys: Vec<Vec<u8>> = live_keys(env)?.into_iter().collect();
+    // always byte order: peers have to agree on it whatever their comparator
+    keys.sort();
+    let mut level = vec![FNV_OFFSET; 1 << depth];
+    for key in keys {
+        if let Some(value) = lookup_bytes(env, &key)? {
+            let leaf = &mut level[merkle_leaf(&key, depth)];
+            let mut line = encode_record(&Record::new(&key, &value));
+            line.push(b'\n');
+            *leaf = fnv1a(*leaf, &line);
+        }
+    }
+    let mut levels = vec![level];
+    while levels[0].len() > 1 {
+        let parents = levels[0]
+            .chunks(2)
+            .map(|children| {
+                let hash = fnv1a(FNV_OFFSET, &children[0].to_be_bytes());
+                fnv1a(hash, &children[1].to_be_bytes())
+            })
+            .collect();
+        levels.insert(0, parents);
+    }
+    Ok(levels)
+}
+
+// Writes every live record to `out` in key order, one `key,value` line each.
+// Records are sorted in runs of EXPORT_RUN_SIZE spilled to temporary files,
+// which are then merged, so only one run is ever held in memory.
+fn sorted_export(env: &Environment, out: &mut dyn Write) -> Result<u64, std::io::Error> {
+    let mut run_paths = Vec::new();
+    let result =
+        write_sorted_runs(env, &mut run_paths).and_then(|_| merge_runs(env, &run_paths, out));
+    for run_path in run_paths.iter() {
+        env.storage.remove(run_path)?;
+    }
+    result
+}
+
+// Writes the live records as one JSON object with a member per line, the values
+// being strings. Returns the number of records written. A key or value that
+// is not UTF-8 has no JSON string to go in, it fails the export.
+fn export_json(env: &Environment, out: &mut dyn Write) -> Result<u64, std::io::Error> {
+    let mut exported = 0;
+    write!(out, "{{")?;
+    for record in env.iter_live()? {
+        let (key, value) = record?;
+        let key = String::from_utf8(key).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "key [{}] is not UTF-8",
+                    String::from_utf8_lossy(e.as_bytes())
+                ),
+            )
+        })?;
+        let value = utf8_value(key.as_bytes(), value)?;
+        let separator = if exported == 0 { "" } else { "," };
+        write!(
+            out,
+            "{}\n  {}: {}",
+            separator,
+            json_string(&key),
+            json_string(&value)
+        )?;
+        exported += 1;
+    }
+    let last_line = if exported == 0 { "" } else { "\n" };
+    writeln!(out, "{}}}", last_line)?;
+    Ok(exported)
+}
+
+fn json_string(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c < ' ' => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// Reads a JSON object whose values are all strings, the members in the order
+// they appear. Any other JSON is rejected with the byte offset it fails at.
+struct JsonObjectParser<'a> {
+    text: &'a str,
+    chars: std::iter::Peekable<std::str::CharIndices<'a>>,
+}
+
+impl<'a> JsonObjectParser<'a> {
+    fn parse(text: &'a str) -> Result<Vec<(String, String)>, String> {
+        let mut parser = JsonObjectParser {
+            text,
+            chars: text.char_indices().peekable(),
+        };
+        let mut members = Vec::new();
+        parser.expect('{')?;
+        if parser.peek() == Some('}') {
+            parser.chars.next();
+        } else {
+            loop {
+                let key = parser.string()?;
+                parser.expect(':')?;
+                let value = parser.string()?;
+                members.push((key, value));
+                match parser.next_token()? {
+                    ',' => continue,
+                    '}' => break,
+                    _ => return Err(parser.error("',' or '}'")),
+                }
+            }
+        }
+        match parser.peek() {
+            None => Ok(members),
+            Some(_) => Err(parser.error("the end of input")),
+        }
+    }
+
+    // the next character that is not whitespace, without consuming it
+    fn peek(&mut self) -> Option<char> {
+        while self
+            .chars
+            .next_if(|(_, c)| matches!(c, ' ' | '\t' | '\n' | '\r'))
+            .is_some()
+        {}
+        self.chars.peek().map(|(_, c)| *c)
+    }
+
+    fn next_token(&mut self) -> Result<char, String> {
+        self.peek();
+        self.chars
+            .next()
+            .map(|(_, c)| c)
+            .ok_or_else(|| self.error("more input"))
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        match self.peek() {
+            Some(c) if c == expected => {
+                self.chars.next();
+                Ok(())
+            }
+            _ => Err(self.error(&format!("'{}'", expected))),
+        }
+    }
+
+    fn error(&mut self, expected: &str) -> String {
+        let offset = self
+            .chars
+            .peek()
+            .map_or(self.text.len(), |(offset, _)| *offset);
+        format!("expected {} at byte {}", expected, offset)
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut result = String::new();
+        loop {
+            match self.chars.next() {
+                Some((_, '"')) => return Ok(result),
+                Some((_, '\\')) => {
+                    let escaped = match self.chars.next() {
+                        Some((_, '"')) => '"',
+                        Some((_, '\\')) => '\\',
+                        Some((_, '/')) => '/',
+                        Some((_, 'b')) => '\u{8}',
+                        Some((_, 'f')) => '\u{c}',
+                        Some((_, 'n')) => '\n',
+                        Some((_, 'r')) => '\r',
+                        Some((_, 't')) => '\t',
+                        Some((_, 'u')) => self.unicode_escape()?,
+                        _ => return Err(self.error("a valid escape")),
+                    };
+                    result.push(escaped);
+                }
+                Some((_, c)) if c < ' ' => return Err(self.error("an escaped control character")),
+                Some((_, c)) => result.push(c),
+                None => return Err(self.error("'\"'")),
+            }
+        }
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let mut code = 0;
+        for _ in 0..4 {
+            let digit = match self.chars.peek().and_then(|(_, c)| c.to_digit(16)) {
+                Some(digit) => digit,
+                None => return Err(self.error("a hex digit")),
+            };
+            self.chars.next();
+            code = code * 16 + digit;
+        }
+        Ok(code)
+    }
+
+    // the character of a `\u` escape, characters outside of the basic plane
+    // being written as two of them
+    fn unicode_escape(&mut self) -> Result<char, String> {
+        let code = self.hex4()?;
+        let code = match code {
+            0xd800..=0xdbff => {
+                if self.chars.next().map(|(_, c)| c) != Some('\\')
+                    || self.chars.next().map(|(_, c)| c) != Some('u')
+                {
+                    return Err(self.error("a low surrogate"));
+                }
+                match self.hex4()? {
+                    low @ 0xdc00..=0xdfff => 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00),
+                    _ => return Err(self.error("a low surrogate")),
+                }
+            }
+            0xdc00..=0xdfff => return Err(self.error("a high surrogate first")),
+            code => code,
+        };
+        Ok(char::from_u32(code).unwrap())
+    }
+}
+
+// Applies the records of a JSON export with ordinary writes. Keys already
+// holding the same value are skipped, so importing a file again writes
+// nothing. The whole file is parsed before the first write. Returns the number
+// of records written and the number skipped.
+fn import_json(env: &mut Environment, input: &mut dyn Read) -> Result<(u64, u64), std::io::Error> {
+    let mut text = String::new();
+    input.read_to_string(&mut text)?;
+    let invalid = |message: String| std::io::Error::new(std::io::ErrorKind::InvalidData, message);
+    let records = JsonObjectParser::parse(&text).map_err(invalid)?;
+    let (mut written, mut unchanged) = (0, 0);
+    for (key, value) in records {
+        if lookup(env, key.as_bytes())?.is_some_and(|current| current == value) {
+            unchanged += 1;
+            continue;
+        }
+        set_data(env, key.as_bytes(), &value)?;
+        written += 1;
+    }
+    Ok((written, unchanged))
+}
+
+fn write_sorted_runs(env: &Environment, run_paths: &mut Vec<String>) -> Result<(), std::io::Error> {
+    let mut records = env.iter_live()?.peekable();
+    while records.peek().is_some() {
+        let mut run = records
+            .by_ref()
+            .take(EXPORT_RUN_SIZE)
+            .collect::<Result<Vec<(Vec<u8>, Vec<u8>)>, std::io::Error>>()?;
+        run.sort_by(|(a, _), (b, _)| env.comparator.compare(a, b));
+        let run_path = Path::new(&env.data_path)
+            .join(format!(
+                "{}.export.{}.tmp",
+                env.file_prefix,
+                run_paths.len()
+            ))
+            .display()
+            .to_string();
+        let mut contents = Vec::new();
+        for (key, value) in run {
+            contents.extend_from_slice(&encode_record(&Record::new(&key, &value)));
+            contents.push(b'\n');
+        }
+        env.storage.write(&run_path, &contents)?;
+        run_paths.push(run_path);
+    }
+    Ok(())
+}
+
+fn merge_runs(
+    env: &Environment,
+    run_paths: &[String],
+    out: &mut dyn Write,
+) -> Result<u64, std::io::Error> {
+    let mut runs = Vec::new();
+    let mut heads = Vec::new();
+    for run_path in run_paths {
+        // runs are written as text whatever the codec of the store
+        let file = open_segment(&*env.storage, run_path)?;
+        let mut run = SegmentRecords::new(file, RecordCodec::Text);
+        heads.push(run.next().transpose()?);
+        runs.push(run);
+    }
+    let mut exported = 0;
+    loop {
+        // runs are few, a linear pick of the smallest head is enough
+        let mut smallest: Option<usize> = None;
+        for (position, head) in heads.iter().enumerate() {
+            let key = match head {
+                Some(record) => &record.key,
+                None => continue,
+            };
+            let is_smaller = smallest.is_none_or(|smallest| {
+                let smallest_key = &heads[smallest].as_ref().unwrap().key;
+                env.comparator.compare(key, smallest_key).is_lt()
+            });
+            if is_smaller {
+                smallest = Some(position);
+            }
+        }
+        let position = match smallest {
+            Some(position) => position,
+            None => return Ok(exported),
+        };
+        let record = std::mem::replace(&mut heads[position], runs[position].next().transpose()?);
+        out.write_all(&encode_record(&record.unwrap()))?;
+        out.write_all(b"\n")?;
+        exported += 1;
+    }
+}
+
+// Live keys whose current value contains `pattern`, sorted, at most `limit` of them.
+fn grep_values(
+    env: &Environment,
+    pattern: &str,
+    limit: Option<usize>,
+) -> Result<Vec<Vec<u8>>, std::io::Error> {
+    let mut keys: Vec<Vec<u8>> = live_keys(env)?.into_iter().collect();
+    keys.sort_by(|a, b| env.comparator.compare(a, b));
+    let mut result = Vec::new();
+    for key in keys {
+        if limit.is_some_and(|limit| result.len() >= limit) {
+            break;
+        }
+        if lookup(env, &key)?.is_some_and(|value| value.contains(pattern)) {
+            result.push(key);
+        }
+    }
+    Ok(result)
+}
+
+// Bytes the live records would take if each key was written exactly once.
+fn live_bytes(env: &Environment) -> Result<u64, std::io::Error> {
+    let mut result = 0;
+    for key in live_keys(env)? {
+        if let Some(value) = lookup(env, &key)? {
+            result += key.len() as u64 + value.len() as u64 + 2;
+        }
+    }
+    Ok(result)
+}
+
+// Live logical bytes and total on-disk bytes (shadowed and deleted records
+// included) of the keys starting with `prefix`.
+fn prefix_usage(env: &Environment, prefix: &[u8]) -> Result<(u64, u64), std::io::Error> {
+    let mut on_disk = 0;
+    for segment in env
+        .segments
+        .iter()
+        .chain(std::iter::once(&env.write_segment))
+    {
+        // an SSTable keeps no index of its keys to rule it out with
+        if segment.blocks.is_none()
+            && segment.trimmed_from.is_none()
+            && !segment.index.keys().any(|key| key.starts_with(prefix))
+        {
+            continue;
+        }
+        for record in segment.records()? {
+            let record = record?;
+            if record.key.starts_with(prefix) {
+                on_disk += encode_record(&record).len() as u64 + 1;
+            }
+        }
+    }
+    let mut logical = 0;
+    for key in live_keys(env)? {
+        if !key.starts_with(prefix) {
+            continue;
+        }
+        if let Some(value) = lookup(env, &key)? {
+            logical += key.len() as u64 + value.len() as u64 + 2;
+        }
+    }
+    Ok((logical, on_disk))
+}
+
+// A write to a watched key, see `Environment::watch`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChangeEvent {
+    Set(Vec<u8>),
+    Deleted,
+}
+
+// How far an open is into loading the retired segments, see
+// `KvStore::open_with_progress`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct OpenProgress {
+    pub segments_loaded: usize,
+    pub segments_total: usize,
+    // size of the segment files, compressed ones as they are stored
+    pub bytes_loaded: u64,
+    pub bytes_total: u64,
+}
+
+// What a compaction of the retired segments did, or would do for a dry run.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CompactionSummary {
+    pub segments_merged: usize,
+    pub segments_written: usize,
+    // uncompressed bytes of the merged segments less those of the written ones
+    pub bytes_reclaimed: u64,
+    // deletion records dropped, shadowed ones included
+    pub tombstones_purged: u64,
+}
+
+// A value with what its record says about it, see `KvStore::get_with_meta`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValueWithMeta {
+    pub value: String,
+    // 0 unless the value was written with a tag
+    pub type_tag: u8,
+    // unix time in milliseconds
+    pub expires_at: Option<u64>,
+}
+
+// What the store holds, as reported by STATS.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Stats {
+    pub retired_segments: usize,
+    // size of the segment files, the write segment included
+    pub disk_bytes: u64,
+    pub live_keys: u64,
+    pub write_segment_bytes: u64,
+    // deletion records in all segments, shadowed ones included
+    pub tombstones: u64,
+    // uncompressed bytes of shadowed, deleted and expired records
+    pub reclaimable_bytes: u64,
+    // `max_disk_bytes`, that `disk_bytes` is kept under
+    pub disk_limit: Option<u64>,
+}
+
+// Merges the records of all segments, the newest of each key counting as live
+// unless it is a tombstone or has expired.
+pub fn stats(env: &Environment) -> Result<Stats, std::io::Error> {
+    let mut stats = Stats {
+        retired_segments: env.segments.len(),
+        write_segment_bytes: env.write_segment.size,
+        disk_limit: env.max_disk_bytes,
+        ..Stats::default()
+    };
+    let mut text_bytes = 0;
+    let mut newest: HashMap<Vec<u8>, Record> = HashMap::new();
+    for segment in env
+        .segments
+        .iter()
+        .chain(std::iter::once(&env.write_segment))
+    {
+        stats.disk_bytes += env.storage.size(&segment.file_path)?;
+        text_bytes += segment.size;
+        for record in segment.records()? {
+            let record = record?;
+            if record.is_tombstone() {
+                stats.tombstones += 1;
+            }
+            keep_newer(&mut newest, record);
+        }
+    }
+    let now = (env.clock)();
+    let mut live_bytes = 0;
+    for record in newest.values() {
+        if record.is_tombstone() || is_expired(&record.header, now) {
+            continue;
+        }
+        stats.live_keys += 1;
+        live_bytes += encode_record(record).len() as u64 + 1;
+    }
+    stats.reclaimable_bytes = text_bytes.saturating_sub(live_bytes);
+    Ok(stats)
+}
+
+pub fn print_doctor_report(
+    out: &mut dyn Write,
+    issues: &[DoctorIssue],
+    fix: bool,
+) -> std::io::Result<()> {
+    if issues.is_empty() {
+        writeln!(out, "No issues found")?;
+        return Ok(());
+    }
+    writeln!(out, "Found {} issue(s):", issues.len())?;
+    for issue in issues {
+        let repaired = match issue {
+            DoctorIssue::CorruptRecord { .. } => false,
+            _ => fix,
+        };
+        if repaired {
+            writeln!(out, "  {} (fixed)", issue)?;
+        } else {
+            writeln!(out, "  {}", issue)?;
+        }
+    }
+    Ok(())
+}
+
+// None if no segment holds a record of `key`.
+fn get_data(env: &Environment, key: &[u8]) -> Result<Option<String>, KvError> {
+    get_bytes(env, key)?
+        .map(|value| utf8_value(key, value))
+        .transpose()
+}
+
+// `get_data` for values that need not be UTF-8.
+fn get_bytes(env: &Environment, key: &[u8]) -> Result<Option<Vec<u8>>, KvError> {
+    get_bytes_in(env, key, &mut OpenSegments::default())
+}
+
+// `get_bytes` reading unmapped segments through the readers kept in `open`.
+fn get_bytes_in(
+    env: &Environment,
+    key: &[u8],
+    open: &mut OpenSegments,
+) -> Result<Option<Vec<u8>>, KvError> {
+    let now = (env.clock)();
+    if let Some(value) = env.value_cache.lock().unwrap().get(key, now) {
+        return Ok(Some(value));
+    }
+    let (record, file_path) = match newest_record(env, key, open)? {
+        Some(found) => found,
+        None => return Ok(None),
+    };
+    let expires_at = record.header.fields.get(&FIELD_EXPIRY).copied();
+    // an expired record reads like a tombstone, it hides older records of the key
+    let Some(value) = visible_value(record, now) else {
+        return Err(KvError::KeyDeleted {
+            file_path: file_path.clone(),
+        });
+    };
+    env.value_cache
+        .lock()
+        .unwrap()
+        .insert(key, &value, expires_at);
+    Ok(Some(value))
+}
+
+// The newest record of `key` and the segment it is stored in, the write
+// segment first and then the retired ones from the newest. Tombstones and
+// expired records are returned as they are. The search stops at the first
+// record, tombstone or value, so a delete hides every older value and a set
+// after it hides the delete.
+fn newest_record<'a>(
+    env: &'a Environment,
+    key: &[u8],
+    open: &mut OpenSegments,
+) -> Result<Option<(Record, &'a String)>, KvError> {
+    // segments read so far, the write segment included
+    let mut read = 1;
+    let mut found = env
+        .write_segment
+        .get_record(key, open)?
+        .map(|record| (record, &env.write_segment.file_path));
+    for segment in env.segments.iter().rev() {
+        if found.is_some() {
+            break;
+        }
+        if !segment.may_contain(key) {
+            env.metrics.filter_negatives.fetch_add(1, Ordering::Relaxed);
+            continue;
+        }
+        read += 1;
+        found = segment
+            .get_record(key, open)?
+            .map(|record| (record, &segment.file_path));
+    }
+    // segments the Bloom filter ruled out were never opened, they do not count
+    if let Some(max_read_fanout) = env.max_read_fanout
+        && read > max_read_fanout
+    {
+        env.report_read_fanout(key, max_read_fanout);
+    }
+    Ok(found)
+}
+
+// The newest record of `key` unless it is a tombstone or has expired.
+fn live_record(env: &Environment, key: &[u8]) -> Result<Option<Record>, KvError> {
+    let now = (env.clock)();
+    match newest_record(env, key, &mut OpenSegments::default())? {
+        Some((record, _)) if !record.is_tombstone() && !is_expired(&record.header, now) => {
+            Ok(Some(record))
+        }
+        _ => Ok(None),
+    }
+}
+
+// The value of `key` along with its type tag and expiry, None if it is not set.
+fn get_with_meta(env: &Environment, key: &[u8]) -> Result<Option<ValueWithMeta>, KvError> {
+    let Some(record) = live_record(env, key)? else {
+        return Ok(None);
+    };
+    Ok(Some(ValueWithMeta {
+        type_tag: record_type(&record),
+        expires_at: record.header.fields.get(&FIELD_EXPIRY).copied(),
+        value: utf8_value(key, record.value)?,
+    }))
+}
+
+fn record_type(record: &Record) -> u8 {
+    let tag = record.header.fields.get(&FIELD_TYPE).copied().unwrap_or(0);
+    tag as u8
+}
+
+// Values of `keys` in their order, deleted and expired keys reading as None.
+// Each segment file is opened at most once for the whole batch.
+fn multi_get(env: &Environment, keys: &[&[u8]]) -> Vec<Result<Option<String>, KvError>> {
+    let mut open = OpenSegments::default();
+    keys.iter()
+        .map(|key| match get_bytes_in(env, key, &mut open) {
+            Err(KvError::KeyDeleted { .. }) => Ok(None),
+            Ok(value) => value.map(|value| utf8_value(key, value)).transpose(),
+            Err(e) => Err(e),
+        })
+        .collect()
+}
+
+// Whether `key` has a live value, decided by its newest record like `get_data`
+// but without reading the value.
+fn contains_key(env: &Environment, key: &[u8]) -> Result<bool, KvError> {
+    let now = (env.clock)();
+    for segment in std::iter::once(&env.write_segment).chain(env.segments.iter().rev()) {
+        if !segment.may_contain(key) {
+            env.metrics.filter_negatives.fetch_add(1, Ordering::Relaxed);
+            continue;
+        }
+        if let Some(live) = segment.contains(key, now)? {
+            return Ok(live);
+        }
+    }
+    Ok(false)
+}
+
+pub fn set_data(env: &mut Environment, key: &[u8], value: &str) -> Result<(), std::io::Error> {
+    set_bytes(env, key, value.as_bytes())
+}
+
+// `set_data` for values that need not be UTF-8.
+pub fn set_bytes(env: &mut Environment, key: &[u8], value: &[u8]) -> Result<(), std::io::Error> {
+    set_record(env, &Record::new(key, value))
+}
+
+// Writes a record `Environment::replicate` sent, as the leader wrote it.
+pub fn apply_replicated(env: &mut Environment, record: &[u8]) -> Result<(), std::io::Error> {
+    let record = decode_record(record).ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, "not a replicated record")
+    })?;
+    set_record(env, &record)
+}
+
+// Deletes `key` by writing a tombstone over it.
+fn delete_data(env: &mut Environment, key: &[u8]) -> Result<(), std::io::Error> {
+    set_record(env, &Record::tombstone(key))
+}
+
+fn set_record(env: &mut Environment, record: &Record) -> Result<(), std::io::Error> {
+    env.check_writable()?;
+    env.check_limits(record)?;
+    let record = &env.stamp(record.clone());
+    let (key, value) = (record.key.as_slice(), record.written_value());
+    env.value_cache.get_mut().unwrap().invalidate(key);
+    // deletes always go through, they are how space gets reclaimed
+    if value.is_some() {
+        let line = env.write_segment.saved_line(record);
+        env.check_free_space(line.len() as u64)?;
+    }
+    let was_present = match env.live_count {
+        Some(_) => lookup_bytes(env, key)?.is_some(),
+        None => false,
+    };
+    if env.write_segment_full() {
+        env.retire_write_segment()?;
+        env.enforce_disk_limit()?;
+        env.schedule_compaction()?;
+    }
+    if env.write_segment.size == 0 {
+        // the age of a write segment counts from its first record
+        env.write_segment_started = std::time::Instant::now();
+    }
+    let size_before = env.write_segment.size;
+    let rewritten = match env.in_place_updates {
+        true => env.write_segment.overwrite_in_place(record)?,
+        false => None,
+    };
+    let bytes_written = match rewritten {
+        Some(bytes_written) => bytes_written,
+        None => {
+            env.write_segment.save_record(record)?;
+            env.write_segment.size - size_before
+        }
+    };
+    env.send_to_replicas(record);
+    env.index_expiry(record);
+    env.sync_after_write()?;
+    env.metrics
+        .bytes_written
+        .fetch_add(bytes_written, Ordering::Relaxed);
+    env.record_mutation(key, value);
+    if let Some(count) = env.live_count {
+        let is_present = value.is_some();
+        env.live_count = Some(count + is_present as u64 - was_present as u64);
+    }
+    env.touch(key);
+    evict_to_budget(env)
+}
+
+// Sets `key` to a value that a sweep tombstones once `ttl` has passed.
+fn set_with_expiry(
+    env: &mut Environment,
+    key: &[u8],
+    value: &str,
+    ttl: std::time::Duration,
+) -> Result<(), std::io::Error> {
+    set_record(env, &expiring_record(env, key, value.as_bytes(), ttl))
+}
+
+fn expiring_record(
+    env: &Environment,
+    key: &[u8],
+    value: &[u8],
+    ttl: std::time::Duration,
+) -> Record {
+    let mut record = Record::new(key, value);
+    let expires_at = (env.clock)().saturating_add(ttl.as_millis() as u64);
+    record.header.fields.insert(FIELD_EXPIRY, expires_at);
+    record
+}
+
+// Tag 0 is the same as no tag, a plain SET.
+fn set_with_type(
+    env: &mut Environment,
+    key: &[u8],
+    value: &[u8],
+    tag: u8,
+) -> Result<(), std::io::Error> {
+    let mut record = Record::new(key, value);
+    if tag != 0 {
+        record.header.fields.insert(FIELD_TYPE, tag as u64);
+    }
+    set_record(env, &record)
+}
+
+// Rewrites the current value of `key` with a new expiry, keeping its type tag,
+// false if the key is absent. Expired records are dropped by compaction, until
+// then they read as tombstones.
+fn expire_data(
+    env: &mut Environment,
+    key: &[u8],
+    ttl: std::time::Duration,
+) -> Result<bool, KvError> {
+    let current = match live_record(env, key)? {
+        Some(current) => current,
+        None => return Ok(false),
+    };
+    let mut record = expiring_record(env, key, &current.value, ttl);
+    if let Some(tag) = current.header.fields.get(&FIELD_TYPE) {
+        record.header.fields.insert(FIELD_TYPE, *tag);
+    }
+    set_record(env, &record)?;
+    Ok(true)
+}
+
+// Tombstones the least recently used keys once the segments take more than
+// `max_db_size`, until the live data fits into it, then retires the write
+// segment and compacts everything so the space is actually reclaimed. A
+// background compaction already running is left to finish, the next write
+// over the budget compacts.
+fn evict_to_budget(env: &mut Environment) -> Result<(), std::io::Error> {
+    let max_db_size = match env.max_db_size {
+        Some(max_db_size) if env.disk_size() > max_db_size => max_db_size,
+        _ => return Ok(()),
+    };
+    let mut candidates = Vec::new();
+    let mut total_bytes = 0;
+    for key in live_keys(env)? {
+        if let Some((mut record, _)) = newest_record(env, &key, &mut OpenSegments::default())? {
+            // as compaction writes it back, sequence number included
+            record.header.fields.remove(&FIELD_BATCH);
+            let record_bytes = encode_record(&record).len() as u64 + 1;
+            total_bytes += record_bytes;
+            let last_access = env.last_access.get(&key).copied().unwrap_or(0);
+            candidates.push((last_access, key, record_bytes));
+        }
+    }
+    candidates.sort();
+    let mut tombstones = Vec::new();
+    for (_, key, record_bytes) in candidates {
+        if total_bytes <= max_db_size {
+            break;
+        }
+        total_bytes -= record_bytes;
+        env.last_access.remove(&key);
+        tombstones.push(Record::tombstone(&key));
+    }
+    if !tombstones.is_empty() {
+        append_records(env, &tombstones)?;
+        env.metrics
+            .keys_evicted
+            .fetch_add(tombstones.len() as u64, Ordering::Relaxed);
+    }
+    if env.compacting {
+        return Ok(());
+    }
+    env.retire_write_segment()?;
+    env.compact_segments()?;
+    Ok(())
+}
+
+// None for a delete of the key.
+fn set_batch(
+    env: &mut Environment,
+    records: &[(Vec<u8>, Option<impl AsRef<[u8]>>)],
+) -> Result<(), std::io::Error> {
+    let records: Vec<Record> = records
+        .iter()
+        .map(|(key, value)| match value {
+            Some(value) => Record::new(key, value.as_ref()),
+            None => Record::tombstone(key),
+        })
+        .collect();
+    set_records(env, &records)
+}
+
+// `set_batch` for records that may carry header fields, such as an expiry.
+fn set_records(env: &mut Environment, records: &[Record]) -> Result<(), std::io::Error> {
+    append_records(env, records)?;
+    for record in records {
+        env.touch(&record.key);
+    }
+    evict_to_budget(env)
+}
+
+// `set_records` short of the eviction, which writes its tombstones with it.
+fn append_records(env: &mut Environment, records: &[Record]) -> Result<(), std::io::Error> {
+    env.check_writable()?;
+    for record in records {
+        env.check_limits(record)?;
+    }
+    let stamped: Vec<Record> = records
+        .iter()
+        .map(|record| env.stamp(record.clone()))
+        .collect();
+    let batch_bytes: usize = stamped
+        .iter()
+        .map(|record| encode_record(record).len() + 1)
+        .sum();
+    env.check_free_space(batch_bytes as u64)?;
+    let keys: HashSet<&Vec<u8>> = records.iter().map(|record| &record.key).collect();
+    for key in keys.iter() {
+        env.value_cache.get_mut().unwrap().invalidate(key);
+    }
+    let mut was_present = 0;
+    if env.live_count.is_some() {
+        for key in keys.iter() {
+            was_present += lookup_bytes(env, key)?.is_some() as u64;
+        }
+    }
+    if env.write_segment_full() {
+        env.retire_write_segment()?;
+        env.enforce_disk_limit()?;
+        env.schedule_compaction()?;
+    }
+    if env.write_segment.size == 0 {
+        // the age of a write segment counts from its first record
+        env.write_segment_started = std::time::Instant::now();
+    }
+    let size_before = env.write_segment.size;
+    env.write_segment.save_batch(&stamped)?;
+    for record in records {
+        env.send_to_replicas(record);
+    }
+    env.sync_after_write()?;
+    env.metrics
+        .bytes_written
+        .fetch_add(env.write_segment.size - size_before, Ordering::Relaxed);
+    for record in records {
+        env.record_mutation(&record.key, record.written_value());
+        env.index_expiry(record);
+    }
+    if let Some(count) = env.live_count {
+        let mut is_present = 0;
+        for key in keys.iter() {
+            is_present += lookup_bytes(env, key)?.is_some() as u64;
+        }
+        env.live_count = Some(count + is_present - was_present);
+    }
+    Ok(())
+}
+
+// Applies queued SET and DELETE records with a single write, so either all of
+// them become visible or none does.
+fn apply_batch(env: &mut Environment, records: &[BatchRecord]) -> Result<(), std::io::Error> {
+    for (_, value) in records.iter() {
+        let counter = match value {
+            Some(_) => &env.metrics.sets,
+            None => &env.metrics.deletes,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+    if records.is_empty() {
+        return Ok(());
+    }
+    set_batch(env, records)
+}
+
+// Applies the records like `set_batch` and syncs them to disk before returning,
+// with a single fsync for the whole batch.
+fn write_batch(env: &mut Environment, records: &[BatchRecord]) -> Result<(), std::io::Error> {
+    if records.is_empty() {
+        return Ok(());
+    }
+    set_batch(env, records)?;
+    // the policy may have synced it already
+    match env.unsynced_writes {
+        0 => Ok(()),
+        _ => env.sync_write_segment(),
+    }
+}
+
+// Reads `SET <key> <value>` and `DELETE <key>` lines up to an `END` line or the
+// end of input. Any invalid line rejects the whole block.
+fn parse_load_block(
+    env: &Environment,
+    lines: &mut dyn Iterator<Item = std::io::Result<String>>,
+) -> Result<Vec<BatchRecord>, String> {
+    let mut records = Vec::new();
+    let mut error = None;
+    for line in lines {
+        let line = line.map_err(|e| e.to_string())?;
+        if line == "END" {
+            break;
+        }
+        let parts: Vec<&str> = line.splitn(3, ' ').collect();
+        if env.binary_keys
+            && let Some(key) = parts.get(1)
+            && decode_hex(key).is_none()
+        {
+            error.get_or_insert(format!("Key [{}] is not hex", key));
+            continue;
+        }
+        match parts.as_slice() {
+            ["SET", key, value] => records.push((command_key(env, key), Some(value.to_string()))),
+            ["DELETE", key] => records.push((command_key(env, key), None)),
+            // keep reading to the terminator, the rest of the block is not a command
+            _ => {
+                error.get_or_insert(format!("Invalid line [{}]", line));
+            }
+        }
+    }
+    match error {
+        Some(error) => Err(error),
+        None => Ok(records),
+    }
+}
+
+pub fn atomic_load(
+    env: &mut Environment,
+    lines: &mut dyn Iterator<Item = std::io::Result<String>>,
+    out: &mut dyn Write,
+) -> std::io::Result<()> {
+    let records = match parse_load_block(env, lines) {
+        Ok(records) => records,
+        Err(e) => {
+            writeln!(out, "Nothing loaded. Error: [{}]", e)?;
+            return Ok(());
+        }
+    };
+    // checked after reading the block, so its lines are never run as commands
+    if env.disabled_commands.contains("ATOMICLOAD") {
+        writeln!(out, "Command [ATOMICLOAD] is disabled")?;
+        return Ok(());
+    }
+    if env.transaction.is_some() {
+        writeln!(out, "Command [ATOMICLOAD] is not allowed inside MULTI")?;
+        return Ok(());
+    }
+    match apply_batch(env, &records) {
+        Ok(_) => writeln!(out, "Loaded [{}] records", records.len())?,
+        Err(e) => writeln!(out, "Nothing loaded. Error: [{}]", e)?,
+    }
+    Ok(())
+}
+
+pub fn live_keys(env: &Environment) -> Result<HashSet<Vec<u8>>, std::io::Error> {
+    env.iter_live()?
+        .map(|record| record.map(|(key, _)| key))
+        .collect()
+}
+
+// Live keys from `start` up to but excluding `end` with their values, sorted.
+// SSTable segments read only the blocks the range overlaps.
+fn range_data(
+    env: &Environment,
+    start: &[u8],
+    end: &[u8],
+) -> Result<Vec<(Vec<u8>, String)>, std::io::Error> {
+    let mut keys: Vec<Vec<u8>> = Vec::new();
+    for segment in env
+        .segments
+        .iter()
+        .chain(std::iter::once(&env.write_segment))
+    {
+        keys.extend(segment.keys_in(start, end)?);
+    }
+    keys.sort();
+    keys.dedup();
+    let mut result = Vec::new();
+    for key in keys {
+        if let Some(value) = lookup(env, &key)? {
+            result.push((key, value));
+        }
+    }
+    Ok(result)
+}
+
+// What `get_state` found for a key.
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeyState {
+    Present(String),
+    // no segment holds a record of the key
+    Absent,
+    // the newest record of the key is a tombstone or has expired, until a
+    // compaction of every segment drops it and the key reads as absent
+    Deleted,
+}
+
+// `get_data` telling a deleted key from one that was never set. Errors are only
+// those of reading the segments, such as IO errors and truncated segments.
+fn get_state(env: &Environment, key: &[u8]) -> Result<KeyState, KvError> {
+    match get_data(env, key) {
+        Ok(Some(value)) => Ok(KeyState::Present(value)),
+        Ok(None) => Ok(KeyState::Absent),
+        Err(KvError::KeyDeleted { .. }) => Ok(KeyState::Deleted),
+        Err(e) => Err(e),
+    }
+}
+
+pub fn lookup(env: &Environment, key: &[u8]) -> Result<Option<String>, std::io::Error> {
+    match get_state(env, key)? {
+        KeyState::Present(value) => Ok(Some(value)),
+        KeyState::Absent | KeyState::Deleted => Ok(None),
+    }
+}
+
+// `lookup` for values that need not be UTF-8.
+pub fn lookup_bytes(env: &Environment, key: &[u8]) -> Result<Option<Vec<u8>>, std::io::Error> {
+    match get_bytes(env, key) {
+        Ok(value) => Ok(value),
+        Err(KvError::KeyDeleted { .. }) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Missing (or deleted) keys count as nil: swapping a present key with a missing
+// one moves the value over and deletes the present key. Swapping two missing
+// keys writes nothing.
+fn swap_data(env: &mut Environment, key1: &[u8], key2: &[u8]) -> Result<(), std::io::Error> {
+    let value1 = lookup_bytes(env, key1)?;
+    let value2 = lookup_bytes(env, key2)?;
+    if value1.is_none() && value2.is_none() {
+        return Ok(());
+    }
+    let records = [(key1.to_vec(), value2), (key2.to_vec(), value1)];
+    set_batch(env, &records)
+}
+
+// Moves the value of `old` to `new` with a single batch, so that after a crash
+// either the new key has it and the old one is deleted, or nothing changed. An
+// expiry and type tag go along with the value. False if `old` has no live value.
+fn rename_data(env: &mut Environment, old: &[u8], new: &[u8]) -> Result<bool, KvError> {
+    let record = match live_record(env, old)? {
+        Some(record) => record,
+        None => return Ok(false),
+    };
+    if old == new {
+        return Ok(true);
+    }
+    let mut moved = Record::new(new, &record.value);
+    for field in [FIELD_EXPIRY, FIELD_TYPE] {
+        if let Some(value) = record.header.fields.get(&field) {
+            moved.header.fields.insert(field, *value);
+        }
+    }
+    set_records(env, &[moved, Record::tombstone(old)])?;
+    Ok(true)
+}
+
+// Adds `by` to the integer value of `key` and returns the result. A missing
+// or deleted key counts as 0, a value outside of the i64 range is an error
+// and leaves the key untouched.
+fn incr_data(env: &mut Environment, key: &[u8], by: i64) -> Result<i64, KvError> {
+    let current = match get_data(env, key) {
+        Ok(Some(value)) => value,
+        Ok(None) | Err(KvError::KeyDeleted { .. }) => String::from("0"),
+        Err(e) => return Err(e),
+    };
+    let current = current.parse::<i64>().map_err(|_| KvError::NotAnInteger {
+        key: String::from_utf8_lossy(key).into_owned(),
+        value: current.clone(),
+    })?;
+    let updated = current
+        .checked_add(by)
+        .ok_or_else(|| KvError::IntegerOverflow {
+            key: String::from_utf8_lossy(key).into_owned(),
+        })?;
+    set_data(env, key, &updated.to_string())?;
+    Ok(updated)
+}
+
+// Appends `suffix` to the value of `key`, empty while absent, and returns the
+// new length in bytes. The value is rewritten as a whole, escaped like any other.
+fn append_data(env: &mut Environment, key: &[u8], suffix: &str) -> Result<usize, KvError> {
+    let mut value = match get_data(env, key) {
+        Ok(Some(value)) => value,
+        Ok(None) | Err(KvError::KeyDeleted { .. }) => String::new(),
+        Err(e) => return Err(e),
+    };
+    value.push_str(suffix);
+    set_data(env, key, &value)?;
+    Ok(value.len())
+}
+
+// Sets `key` to `new` only if its current value is `expected`, None expecting
+// the key to be absent or deleted. Returns whether the value was written.
+fn compare_and_swap(
+    env: &mut Environment,
+    key: &[u8],
+    expected: Option<&str>,
+    new: &str,
+) -> Result<bool, KvError> {
+    // a tombstone reads as absent, not as the value it shadows. Compared as
+    // bytes, so a value that is not UTF-8 is simply not `expected`.
+    let current = match get_bytes(env, key) {
+        Err(KvError::KeyDeleted { .. }) => None,
+        result => result?,
+    };
+    if current.as_deref() != expected.map(str::as_bytes) {
+        return Ok(false);
+    }
+    set_data(env, key, new)?;
+    Ok(true)
+}
+
+// Writes `value`, or a tombstone for None, over `key` and returns the value it
+// replaced, None if the key was absent. Unlike `set_data` this reads the key first.
+fn get_and_set(
+    env: &mut Environment,
+    key: &[u8],
+    value: Option<&str>,
+) -> Result<Option<String>, KvError> {
+    // a tombstone reads as absent, not as the value it shadows
+    let previous = match get_data(env, key) {
+        Err(KvError::KeyDeleted { .. }) => None,
+        result => result?,
+    };
+    match value {
+        Some(value) => set_data(env, key, value)?,
+        None => delete_data(env, key)?,
+    }
+    Ok(previous)
+}
+
+// Splits a line of the line protocol into arguments the way `resp` splits an
+// inline command: words apart by spaces or tabs, a word in double quotes
+// holding spaces and `\"` or `\\` escapes, `""` being the empty argument.
+pub fn split_command(line: &str) -> std::io::Result<Vec<String>> {
+    let args = resp::split_inline(line.as_bytes())?;
+    // splitting valid UTF-8 at ASCII bytes keeps it valid
+    Ok(args
+        .into_iter()
+        .map(|arg| String::from_utf8(arg).unwrap())
+        .collect())
+}
+
+// Quotes `arg` so that `split_command` reads it back as one argument.
+pub fn quote_arg(arg: &str) -> String {
+    format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+// The usage of `command_args[0]` if it is given too few or too many arguments.
+fn arity_error(command_args: &[String]) -> Option<&'static str> {
+    let (_, min, max, usage) = COMMAND_ARITY
+        .iter()
+        .find(|(name, ..)| *name == command_args[0])?;
+    (command_args.len() < *min || command_args.len() > *max).then_some(*usage)
+}
+
+// What a command did, for the caller to present. The CLI and the servers
+// format it for the line protocol.
+#[derive(Debug)]
+pub enum CommandResult {
+    // the value GET found, None if the key has no record
+    Value(Option<String>),
+    Written,
+    Deleted,
+    Compacted(CompactionSummary),
+    Error(KvError),
+    // a read ran past the end of a truncated segment, which was reindexed so
+    // that the next read sees whatever the file still holds
+    Reindexed(KvError, std::io::Result<()>),
+    // the lines any other command answers with
+    Output(Vec<u8>),
+}
+
+impl CommandResult {
+    fn line(text: impl std::fmt::Display) -> CommandResult {
+        CommandResult::Output(format!("{}\n", text).into_bytes())
+    }
+}
+
+// Runs one command. Nothing is written out, the caller presents the result.
+pub fn handle_command(
+    env: &mut Environment,
+    command_args: &[String],
+) -> std::io::Result<CommandResult> {
+    if command_args.is_empty() {
+        return Ok(CommandResult::line("No command given"));
+    }
+    if env.disabled_commands.contains(&command_args[0]) {
+        return Ok(CommandResult::line(format!(
+            "Command [{}] is disabled",
+            command_args[0]
+        )));
+    }
+    if let Some(usage) = arity_error(command_args) {
+        return Ok(CommandResult::line(format!("Usage: {}", usage)));
+    }
+    if let Some(arg) = invalid_key_arg(env, command_args) {
+        return Ok(CommandResult::line(format!(
+            "Key [{}] is not hex, --binary-keys takes keys in hex",
+            arg
+        )));
+    }
+    let started = std::time::Instant::now();
+    let result = dispatch_command(env, command_args);
+    let command = command_args[0].as_str();
+    if LATENCY_TRACKED_COMMANDS.contains(&command) {
+        env.latencies
+            .entry(command.to_string())
+            .or_default()
+            .record(started.elapsed());
+    }
+    result
+}
+
+// GET for callers sharing the environment with other readers. Unlike through
+// `handle_command` the access is not recorded for eviction and a truncated
+// segment is not reindexed, so it is no substitute while a size budget is set.
+pub fn handle_shared_get(
+    env: &Environment,
+    key: &String,
+    out: &mut dyn Write,
+) -> std::io::Result<()> {
+    if env.disabled_commands.contains("GET") {
+        writeln!(out, "Command [GET] is disabled")?;
+        return Ok(());
+    }
+    if env.binary_keys && decode_hex(key).is_none() {
+        writeln!(
+            out,
+            "Key [{}] is not hex, --binary-keys takes keys in hex",
+            key
+        )?;
+        return Ok(());
+    }
+    env.metrics.gets.fetch_add(1, Ordering::Relaxed);
+    match get_data(env, &command_key(env, key)) {
+        Ok(Some(value)) => writeln!(out, "Found value: [{}]", value),
+        Ok(None) => writeln!(out, "Value not found"),
+        Err(KvError::KeyDeleted { .. }) => writeln!(out, "Value not found (actually deleted)"),
+        Err(e) => writeln!(out, "Could not read key [{}]. Error: [{}]", key, e),
+    }
+}
+
+// Replays a trace file where each line is `<delay in ms> <command>`, the delay
+// being the time since the previous command. Returns the number of commands
+// applied, the total wall time and the slowest command.
+fn replay_trace(
+    env: &mut Environment,
+    trace_path: &String,
+    honor_timing: bool,
+) -> std::io::Result<(u64, std::time::Duration, std::time::Duration)> {
+    let file = OpenOptions::new().read(true).open(trace_path)?;
+    let started = std::time::Instant::now();
+    let mut applied = 0;
+    let mut slowest = std::time::Duration::ZERO;
+    for line in BufReader::new(file).lines() {
+        let real_line = line?;
+        if real_line.trim().is_empty() {
+            continue;
+        }
+        let (delay, command) = real_line.split_once(' ').ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Invalid trace line [{}]", real_line),
+            )
+        })?;
+        let delay = delay.parse::<u64>().map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Invalid delay in trace line [{}]", real_line),
+            )
+        })?;
+        if honor_timing {
+            std::thread::sleep(std::time::Duration::from_millis(delay));
+        }
+        let command_args = split_command(command)?;
+        let command_started = std::time::Instant::now();
+        handle_command(env, &command_args)?;
+        slowest = slowest.max(command_started.elapsed());
+        applied += 1;
+    }
+    Ok((applied, started.elapsed(), slowest))
+}
+
+// SET, GET, DELETE and a foreground COMPACT have results of their own, every
+// other command answers with the lines `dispatch_output` writes.
+fn dispatch_command(
+    env: &mut Environment,
+    command_args: &[String],
+) -> std::io::Result<CommandResult> {
+    let command = command_args[0].as_str();
+    // Inside MULTI writes are queued and GET sees the state from before the
+    // transaction, anything else has to wait for EXEC or DISCARD.
+    if env.transaction.is_some() {
+        if (command == "SET" && command_args.len() == 3) || command == "DELETE" {
+            let value = match command {
+                "SET" => Some(command_args[2].clone()),
+                _ => None,
+            };
+            let key = command_key(env, &command_args[1]);
+            if let Some(queued) = env.transaction.as_mut() {
+                queued.push((key, value));
+            }
+            return Ok(CommandResult::line("QUEUED"));
+        } else if command != "GET" && command != "EXEC" && command != "DISCARD" {
+            return Ok(CommandResult::line(format!(
+                "Command [{}] is not allowed inside MULTI",
+                command
+            )));
+        }
+    }
+    let result = match command {
+        // with --type it is left to `dispatch_output`
+        "SET" if command_args.len() == 3 => {
+            let value = command_args[2].as_str();
+            env.metrics.sets.fetch_add(1, Ordering::Relaxed);
+            let key = command_key(env, &command_args[1]);
+            set_data(env, &key, value)
+                .map(|_| CommandResult::Written)
+                .map_err(KvError::from)
+        }
+        "GET" => {
+            let key = command_key(env, &command_args[1]);
+            env.touch(&key);
+            env.metrics.gets.fetch_add(1, Ordering::Relaxed);
+            get_data(env, &key).map(CommandResult::Value)
+        }
+        "DELETE" => {
+            env.metrics.deletes.fetch_add(1, Ordering::Relaxed);
+            let key = command_key(env, &command_args[1]);
+            delete_data(env, &key)
+                .map(|_| CommandResult::Deleted)
+                .map_err(KvError::from)
+        }
+        "COMPACT"
+            if command_args.get(1).is_none_or(|arg| {
+                !["--background", "--dry-run", "--format"].contains(&arg.as_str())
+            }) =>
+        {
+            env.compact_segments()
+                .map(CommandResult::Compacted)
+                .map_err(KvError::from)
+        }
+        _ => {
+            let mut out = Vec::new();
+            dispatch_output(env, command_args, &mut out)?;
+            return Ok(CommandResult::Output(out));
+        }
+    };
+    Ok(match result {
+        Ok(result) => result,
+        // the next read sees whatever the truncated file still holds
+        Err(e) => match &e {
+            KvError::OffsetBeyondEof { file_path, .. } => {
+                let reindexed = env.reindex_segment(file_path);
+                CommandResult::Reindexed(e, reindexed)
+            }
+            _ => CommandResult::Error(e),
+        },
+    })
+}
+
+fn dispatch_output(
+    env: &mut Environment,
+    command_args: &[String],
+    out: &mut dyn Write,
+) -> std::io::Result<()> {
+    let command = &command_args[0];
+    if command == "SETEX" {
+        let key = command_key(env, &command_args[1]);
+        let (seconds, value) = match command_args[2].parse::<u64>() {
+            Ok(seconds) => (seconds, command_args[3].as_str()),
+            Err(_) => {
+                writeln!(out, "Usage: SETEX <key> <seconds> <value>")?;
+                return Ok(());
+            }
+        };
+        env.metrics.sets.fetch_add(1, Ordering::Relaxed);
+        let ttl = std::time::Duration::from_secs(seconds);
+        match set_with_expiry(env, &key, value, ttl) {
+            Ok(_) => {
+                writeln!(
+                    out,
+                    "Written key: [{}] value: [{}] expires in: [{}] s",
+                    command_args[1], value, seconds
+                )?;
+            }
+            Err(e) => {
+                writeln!(out, "Could not write key-value pair. Error: [{}]", e)?;
+            }
+        }
+    } else if command == "EXPIRE" {
+        let key = &command_args[1];
+        let seconds = match command_args.get(2).map(|seconds| seconds.parse::<u64>()) {
+            Some(Ok(seconds)) => seconds,
+            _ => {
+                writeln!(out, "Usage: EXPIRE <key> <seconds>")?;
+                return Ok(());
+            }
+        };
+        let ttl = std::time::Duration::from_secs(seconds);
+        match expire_data(env, &command_key(env, key), ttl) {
+            Ok(updated) => {
+                writeln!(out, "{}", updated as u8)?;
+            }
+            Err(e) => {
+                writeln!(out, "Could not update key [{}]. Error: [{}]", key, e)?;
+            }
+        }
+    } else if command == "SWEEP" {
+        match env.sweep_expired() {
+            Ok(summary) => writeln!(
+                out,
+                "Removed [{}] expired keys, examined [{}] index entries",
+                summary.removed, summary.examined
+            )?,
+            Err(e) => writeln!(out, "Could not sweep expired keys. Error: [{}]", e)?,
+        }
+    } else if command == "RANGE" {
+        let start = command_key(env, &command_args[1]);
+        let end = command_key(env, &command_args[2]);
+        match range_data(env, &start, &end) {
+            Ok(records) => {
+                for (key, value) in records.iter() {
+                    writeln!(out, "{} {}", display_key(env, key), value)?;
+                }
+                writeln!(out, "Matched keys: [{}]", records.len())?;
+            }
+            Err(e) => {
+                writeln!(out, "Could not read range. Error: [{}]", e)?;
+            }
+        }
+    } else if command == "COMPACT" && command_args.get(1).is_some_and(|arg| arg == "--format") {
+        let format = match command_args.get(2).map(String::as_str) {
+            Some("plain") => RecordFormat::Plain,
+            Some("checksummed") => RecordFormat::Checksummed,
+            _ => {
+                writeln!(out, "Usage: COMPACT --format plain|checksummed")?;
+                return Ok(());
+            }
+        };
+        match env.compact_to(format) {
+            Ok(summary) => writeln!(
+                out,
+                "Rewrote [{}] segments into [{}] as [{}]",
+                summary.segments_merged, summary.segments_written, command_args[2]
+            )?,
+            Err(e) => writeln!(out, "Failed to compact segments: [{}]", e)?,
+        }
+    } else if command == "COMPACT" && command_args.get(1).is_some_and(|arg| arg == "--dry-run") {
+        match env.compact_dry_run() {
+            Ok(summary) => writeln!(
+                out,
+                "Would merge [{}] segments into [{}], reclaiming [{}] bytes and purging [{}] tombstones",
+                summary.segments_merged,
+                summary.segments_written,
+                summary.bytes_reclaimed,
+                summary.tombstones_purged
+            )?,
+            Err(e) => writeln!(out, "Failed to plan the compaction: [{}]", e)?,
+        }
+    } else if command == "COMPACT" && command_args.get(1).is_some_and(|arg| arg == "--background") {
+        // the caller runs the job, see `take_compaction_job`
+        match env.start_compaction() {
+            Ok(Some(job)) => {
+                env.compaction_job = Some(job);
+                writeln!(out, "Compaction started")?;
+            }
+            Ok(None) => {
+                writeln!(out, "No segments to compact")?;
+            }
+            Err(e) => {
+                writeln!(out, "Failed to compact segments: [{}]", e)?;
+            }
+        }
+    } else if command == "SET" {
+        let key = &command_args[1];
+        let value = command_args[2].as_str();
+        let tag = match (command_args.get(3), command_args.get(4)) {
+            (Some(flag), Some(tag)) if flag == "--type" => tag.parse::<u8>().ok(),
+            _ => None,
+        };
+        let tag = match tag {
+            Some(tag) => tag,
+            None => {
+                writeln!(out, "Usage: SET <key> <value> [--type <tag>]")?;
+                return Ok(());
+            }
+        };
+        env.metrics.sets.fetch_add(1, Ordering::Relaxed);
+        let key_bytes = command_key(env, key);
+        match set_with_type(env, &key_bytes, value.as_bytes(), tag) {
+            Ok(_) => {
+                writeln!(
+                    out,
+                    "Written key: [{}] value: [{}] type: [{}]",
+                    key, value, tag
+                )?;
+            }
+            Err(e) => {
+                writeln!(out, "Could not write key-value pair. Error: [{}]", e)?;
+            }
+        }
+    } else if command == "TYPE" {
+        let key = &command_args[1];
+        // the tag is read without the value, which need not be UTF-8
+        match live_record(env, &command_key(env, key)) {
+            Ok(Some(record)) => writeln!(out, "{}", record_type(&record))?,
+            Ok(None) => writeln!(out, "Key [{}] not found", key)?,
+            Err(e) => writeln!(out, "Could not read key [{}]. Error: [{}]", key, e)?,
+        }
+    } else if command == "MSET" {
+        let args = &command_args[1..];
+        if !args.len().is_multiple_of(2) {
+            writeln!(out, "Usage: MSET <key> <value> [<key> <value> ...]")?;
+            return Ok(());
+        }
+        let records: Vec<BatchRecord> = args
+            .chunks(2)
+            .map(|pair| (command_key(env, &pair[0]), Some(pair[1].clone())))
+            .collect();
+        env.metrics
+            .sets
+            .fetch_add(records.len() as u64, Ordering::Relaxed);
+        match write_batch(env, &records) {
+            Ok(_) => {
+                writeln!(out, "Written [{}] keys", records.len())?;
+            }
+            Err(e) => {
+                writeln!(out, "Could not write keys. Error: [{}]", e)?;
+            }
+        }
+    } else if command == "INCR" || command == "DECR" {
+        let key = &command_args[1];
+        let by = match command == "INCR" {
+            true => 1,
+            false => -1,
+        };
+        env.metrics.sets.fetch_add(1, Ordering::Relaxed);
+        match incr_data(env, &command_key(env, key), by) {
+            Ok(value) => {
+                writeln!(out, "{}", value)?;
+            }
+            Err(e) => {
+                writeln!(out, "Could not update key [{}]. Error: [{}]", key, e)?;
+            }
+        }
+    } else if command == "APPEND" {
+        let key = &command_args[1];
+        let suffix = command_args[2].as_str();
+        env.metrics.sets.fetch_add(1, Ordering::Relaxed);
+        match append_data(env, &command_key(env, key), suffix) {
+            Ok(length) => {
+                writeln!(out, "{}", length)?;
+            }
+            Err(e) => {
+                writeln!(out, "Could not update key [{}]. Error: [{}]", key, e)?;
+            }
+        }
+    } else if command == "CAS" {
+        let args: Vec<&str> = command_args[1..].iter().map(String::as_str).collect();
+        let (key, expected, new) = match args.as_slice() {
+            [key, "--absent", new] => (key.to_string(), None, *new),
+            [key, expected, new] => (key.to_string(), Some(*expected), *new),
+            _ => {
+                writeln!(
+                    out,
+                    "Usage: CAS <key> <expected value>|--absent <new value>"
+                )?;
+                return Ok(());
+            }
+        };
+        env.metrics.sets.fetch_add(1, Ordering::Relaxed);
+        match compare_and_swap(env, &command_key(env, &key), expected, new) {
+            Ok(swapped) => {
+                writeln!(out, "{}", swapped as u8)?;
+            }
+            Err(e) => {
+                writeln!(out, "Could not update key [{}]. Error: [{}]", key, e)?;
+            }
+        }
+    } else if command == "SETNX" {
+        // CAS --absent under its own name
+        let key = &command_args[1];
+        let value = command_args[2].as_str();
+        env.metrics.sets.fetch_add(1, Ordering::Relaxed);
+        let key_bytes = command_key(env, key);
+        match compare_and_swap(env, &key_bytes, None, value) {
+            Ok(written) => {
+                writeln!(out, "{}", written as u8)?;
+            }
+            Err(e) => {
+                writeln!(out, "Could not update key [{}]. Error: [{}]", key, e)?;
+            }
+        }
+    } else if command == "SWAP" {
+        let key1 = command_key(env, &command_args[1]);
+        let key2 = command_key(env, &command_args[2]);
+        match swap_data(env, &key1, &key2) {
+            Ok(_) => {
+                writeln!(
+                    out,
+                    "Swapped keys: [{}] [{}]",
+                    command_args[1], command_args[2]
+                )?;
+            }
+            Err(e) => {
+                writeln!(out, "Could not swap keys. Error: [{}]", e)?;
+            }
+        }
+    } else if command == "RENAME" {
+        let old = &command_args[1];
+        let new = &command_args[2];
+        let (old_bytes, new_bytes) = (command_key(env, old), command_key(env, new));
+        match rename_data(env, &old_bytes, &new_bytes) {
+            Ok(true) => {
+                writeln!(out, "Renamed key: [{}] to: [{}]", old, new)?;
+            }
+            Ok(false) => {
+                writeln!(out, "Key [{}] not found", old)?;
+            }
+            Err(e) => {
+                writeln!(out, "Could not rename key. Error: [{}]", e)?;
+            }
+        }
+    } else if command == "DBSIZE" {
+        let size = match env.live_count {
+            Some(count) => Ok(count),
+            None => live_keys(env).map(|keys| keys.len() as u64),
+        };
+        match size {
+            Ok(size) => {
+                writeln!(out, "Live keys: [{}]", size)?;
+            }
+            Err(e) => {
+                writeln!(out, "Could not count keys. Error: [{}]", e)?;
+            }
+        }
+    } else if command == "DOCTOR" {
+        let fix = command_args.get(1).is_some_and(|arg| arg == "--fix");
+        // a torn tail cut off under a map would fault the next read of it
+        let mmap = env.mmap;
+        if fix {
+            env.set_mmap(false);
+        }
+        let checked = doctor(
+            &*env.storage,
+            &env.data_path,
+            &env.file_prefix,
+            env.namer.as_ref(),
+            fix,
+        );
+        env.set_mmap(mmap);
+        let mut issues = match checked {
+            Ok(issues) => issues,
+            Err(e) => {
+                writeln!(out, "Could not check data directory. Error: [{}]", e)?;
+                return Ok(());
+            }
+        };
+        match env.stale_segments() {
+            Ok(stale) => issues.extend(stale.into_iter().map(DoctorIssue::StaleIndex)),
+            Err(e) => {
+                writeln!(out, "Could not check segment indexes. Error: [{}]", e)?;
+            }
+        }
+        if fix && !issues.is_empty() {
+            // rebuilds every index from the repaired files
+            if let Err(e) = env.reload() {
+                writeln!(out, "Could not reload segments. Error: [{}]", e)?;
+            }
+        }
+        print_doctor_report(out, &issues, fix)?;
+    } else if command == "VERIFY" {
+        match verify(env) {
+            Ok(report) => print_verify_report(out, &report)?,
+            Err(e) => writeln!(out, "Could not verify segments. Error: [{}]", e)?,
+        }
+    } else if command == "LAYOUT" {
+        let with_status = command_args.get(1).is_some_and(|arg| arg == "--status");
+        let layout = match segment_layout(env) {
+            Ok(layout) => layout,
+            Err(e) => {
+                writeln!(out, "Could not read segment keys. Error: [{}]", e)?;
+                return Ok(());
+            }
+        };
+        for (file_path, keys) in layout {
+            writeln!(out, "[{}]", file_path)?;
+            for (key, is_live) in keys {
+                let key = display_key(env, &key);
+                if !with_status {
+                    writeln!(out, "  {}", key)?;
+                } else if is_live {
+                    writeln!(out, "  {} (live)", key)?;
+                } else {
+                    writeln!(out, "  {} (shadowed)", key)?;
+                }
+            }
+        }
+    } else if command == "FILTERSTATS" {
+        let samples = match command_args.get(1).map(|samples| samples.parse::<usize>()) {
+            None => FILTER_SAMPLE_COUNT,
+            Some(Ok(samples)) if samples > 0 => samples,
+            Some(_) => {
+                writeln!(out, "Usage: FILTERSTATS [samples]")?;
+                return Ok(());
+            }
+        };
+        match env.filter_stats(samples) {
+            Ok(stats) => {
+                for stats in stats.iter() {
+                    writeln!(
+                        out,
+                        "[{}] bits: [{}] hashes: [{}] elements: [{}] estimated FPR: [{:.4}] measured FPR: [{:.4}]",
+                        stats.file_path,
+                        stats.bits,
+                        stats.hashes,
+                        stats.elements,
+                        stats.estimated_fpr,
+                        stats.measured_fpr
+                    )?;
+                }
+                writeln!(out, "Filters: [{}]", stats.len())?;
+            }
+            Err(e) => writeln!(out, "Could not read the filters. Error: [{}]", e)?,
+        }
+    } else if command == "REHINT" {
+        match env.rehint(command_args.get(1).map(String::as_str)) {
+            Ok(file_paths) if file_paths.is_empty() => match command_args.get(1) {
+                Some(name) => writeln!(out, "Segment [{}] not found", name)?,
+                None => writeln!(out, "No retired segments to rehint")?,
+            },
+            Ok(file_paths) => {
+                for file_path in file_paths.iter() {
+                    writeln!(out, "Rewrote the hint of [{}]", file_path)?;
+                }
+            }
+            Err(e) => writeln!(out, "Could not rewrite hints. Error: [{}]", e)?,
+        }
+    } else if command == "DUPES" {
+        let name = &command_args[1];
+        let segment = env
+            .segments
+            .iter()
+            .chain(std::iter::once(&env.write_segment))
+            .find(|s| {
+                Path::new(&s.file_path)
+                    .file_name()
+                    .is_some_and(|f| f == name.as_str())
+            });
+        match segment.map(count_duplicates) {
+            Some(Ok((records, duplicates))) => {
+                writeln!(
+                    out,
+                    "Segment [{}]: {} records, {} shadowed within the segment",
+                    name, records, duplicates
+                )?;
+            }
+            Some(Err(e)) => {
+                writeln!(out, "Could not read segment [{}]. Error: [{}]", name, e)?;
+            }
+            None => {
+                writeln!(out, "Segment [{}] not found", name)?;
+            }
+        }
+    } else if command == "WRITEAMP" {
+        let bytes_written = env.metrics.bytes_written.load(Ordering::Relaxed);
+        match live_bytes(env) {
+            Ok(live_bytes) => {
+                let ratio = if live_bytes == 0 {
+                    0.0
+                } else {
+                    bytes_written as f64 / live_bytes as f64
+                };
+                writeln!(
+                    out,
+                    "Bytes written: [{}] live bytes: [{}] write amplification: [{:.2}]",
+                    bytes_written, live_bytes, ratio
+                )?;
+            }
+            Err(e) => {
+                writeln!(out, "Could not compute live bytes. Error: [{}]", e)?;
+            }
+        }
+    } else if cfg!(feature = "dev") && command == "CRASHTEST" {
+        #[cfg(feature = "dev")]
+        match crash_test(&env.file_prefix, 8).and_then(|mut problems| {
+            problems.extend(retire_crash_test(&env.file_prefix, 8)?);
+            Ok(problems)
+        }) {
+            Ok(problems) if problems.is_empty() => {
+                writeln!(out, "Recovery consistent")?;
+            }
+            Ok(problems) => {
+                writeln!(out, "Recovery inconsistent:")?;
+                for problem in problems {
+                    writeln!(out, "  {}", problem)?;
+                }
+            }
+            Err(e) => {
+                writeln!(out, "Crash test failed. Error: [{}]", e)?;
+            }
+        }
+    } else if cfg!(feature = "dev") && command == "STRESS" {
+        #[cfg(feature = "dev")]
+        {
+            let readers = match command_args.get(1).map(|readers| readers.parse::<usize>()) {
+                None => STRESS_DEFAULT_READERS,
+                Some(Ok(readers)) if readers > 0 => readers,
+                Some(_) => {
+                    writeln!(out, "Readers have to be a positive number")?;
+                    return Ok(());
+                }
+            };
+            match stress_test(&env.file_prefix, readers) {
+                Ok(report) if report.problems.is_empty() => {
+                    writeln!(
+                        out,
+                        "No problems: [{}] reads by [{}] readers, [{}] writes, up to [{}] readers at once",
+                        report.reads, readers, report.writes, report.most_concurrent
+                    )?;
+                }
+                Ok(report) => {
+                    writeln!(out, "Problems under load:")?;
+                    for problem in report.problems {
+                        writeln!(out, "  {}", problem)?;
+                    }
+                }
+                Err(e) => {
+                    writeln!(out, "Stress test failed. Error: [{}]", e)?;
+                }
+            }
+        }
+    } else if command == "MERKLE" {
+        let depth = match command_args.get(1).map(|depth| depth.parse::<u32>()) {
+            None => MERKLE_DEFAULT_DEPTH,
+            Some(Ok(depth)) if depth <= MERKLE_MAX_DEPTH => depth,
+            Some(_) => {
+                writeln!(out, "Depth has to be a number up to [{}]", MERKLE_MAX_DEPTH)?;
+                return Ok(());
+            }
+        };
+        match merkle_tree(env, depth) {
+            Ok(levels) => {
+                for (number, level) in levels.iter().enumerate() {
+                    let hashes: Vec<String> =
+                        level.iter().map(|hash| format!("{:016x}", hash)).collect();
+                    writeln!(out, "Level [{}]: {}", number, hashes.join(" "))?;
+                }
+            }
+            Err(e) => {
+                writeln!(out, "Could not compute tree. Error: [{}]", e)?;
+            }
+        }
+    } else if command == "MGET" {
+        let keys = &command_args[1..];
+        let key_bytes: Vec<Vec<u8>> = keys.iter().map(|key| command_key(env, key)).collect();
+        for key in key_bytes.iter() {
+            env.touch(key);
+        }
+        env.metrics
+            .gets
+            .fetch_add(keys.len() as u64, Ordering::Relaxed);
+        let key_slices: Vec<&[u8]> = key_bytes.iter().map(Vec::as_slice).collect();
+        for (key, value) in keys.iter().zip(multi_get(env, &key_slices)) {
+            match value {
+                Ok(Some(value)) => writeln!(out, "Key [{}] value: [{}]", key, value)?,
+                Ok(None) => writeln!(out, "Key [{}] not found", key)?,
+                Err(e) => writeln!(out, "Could not read key [{}]. Error: [{}]", key, e)?,
+            }
+        }
+    } else if command == "EXISTS" {
+        let key = &command_args[1];
+        match contains_key(env, &command_key(env, key)) {
+            Ok(exists) => writeln!(out, "{}", exists as u8)?,
+            Err(e) => writeln!(out, "Could not read key [{}]. Error: [{}]", key, e)?,
+        }
+    } else if command == "KEYS" {
+        let keys = match env.keys() {
+            Ok(keys) => keys,
+            Err(e) => {
+                writeln!(out, "Could not take a snapshot. Error: [{}]", e)?;
+                return Ok(());
+            }
+        };
+        let mut count = 0;
+        for key in keys {
+            match key {
+                Ok(key) => writeln!(out, "{}", display_key(env, &key))?,
+                Err(e) => {
+                    writeln!(out, "Could not read snapshot. Error: [{}]", e)?;
+                    return Ok(());
+                }
+            }
+            count += 1;
+        }
+        writeln!(out, "Keys: [{}]", count)?;
+    } else if command == "GREP" {
+        let pattern = &command_args[1];
+        let options: Vec<&str> = command_args[2..].iter().map(String::as_str).collect();
+        let limit = match options.as_slice() {
+            [] => None,
+            ["--limit", limit] => match limit.parse::<usize>() {
+                Ok(limit) => Some(limit),
+                Err(_) => {
+                    writeln!(out, "Invalid limit [{}]", limit)?;
+                    return Ok(());
+                }
+            },
+            _ => {
+                writeln!(out, "Usage: GREP <substring> [--limit N]")?;
+                return Ok(());
+            }
+        };
+        match grep_values(env, pattern, limit) {
+            Ok(keys) => {
+                for key in keys.iter() {
+                    writeln!(out, "{}", display_key(env, key))?;
+                }
+                writeln!(out, "Matched keys: [{}]", keys.len())?;
+            }
+            Err(e) => {
+                writeln!(out, "Could not search values. Error: [{}]", e)?;
+            }
+        }
+    } else if command == "USAGE" {
+        let prefix = command_args.get(1).map(String::as_str).unwrap_or("");
+        match prefix_usage(env, &command_key(env, prefix)) {
+            Ok((logical, on_disk)) => {
+                writeln!(
+                    out,
+                    "Prefix [{}] logical bytes: [{}] on-disk bytes: [{}] reclaimable: [{}]",
+                    prefix,
+                    logical,
+                    on_disk,
+                    on_disk.saturating_sub(logical)
+                )?;
+            }
+            Err(e) => {
+                writeln!(out, "Could not compute usage. Error: [{}]", e)?;
+            }
+        }
+    } else if command == "LATENCY" {
+        if command_args.get(1).is_some_and(|arg| arg == "RESET") {
+            env.latencies.clear();
+            writeln!(out, "Latency histograms reset")?;
+            return Ok(());
+        }
+        for command in LATENCY_TRACKED_COMMANDS {
+            match env.latencies.get(command) {
+                Some(histogram) => {
+                    writeln!(
+                        out,
+                        "{}: count {} p50 <= {}us p95 <= {}us p99 <= {}us",
+                        command,
+                        histogram.count,
+                        histogram.percentile(50),
+                        histogram.percentile(95),
+                        histogram.percentile(99)
+                    )?;
+                }
+                None => {
+                    writeln!(out, "{}: count 0", command)?;
+                }
+            }
+        }
+    } else if command == "GETSET" {
+        let key = &command_args[1];
+        let value = command_args[2].as_str();
+        env.metrics.sets.fetch_add(1, Ordering::Relaxed);
+        match get_and_set(env, &command_key(env, key), Some(value)) {
+            Ok(Some(previous)) => {
+                writeln!(
+                    out,
+                    "Written key: [{}] value: [{}] replaced: [{}]",
+                    key, value, previous
+                )?;
+            }
+            Ok(None) => {
+                writeln!(out, "Written key: [{}] value: [{}]", key, value)?;
+            }
+            Err(e) => {
+                writeln!(out, "Could not write key-value pair. Error: [{}]", e)?;
+            }
+        }
+    } else if command == "GETDEL" {
+        let key = &command_args[1];
+        env.metrics.deletes.fetch_add(1, Ordering::Relaxed);
+        match get_and_set(env, &command_key(env, key), None) {
+            Ok(Some(previous)) => {
+                writeln!(out, "Deleted key: [{}] value: [{}]", key, previous)?;
+            }
+            Ok(None) => {
+                writeln!(out, "Deleted key: [{}]", key)?;
+            }
+            Err(e) => {
+                writeln!(out, "Could not write key-value pair. Error: [{}]", e)?;
+            }
+        }
+    } else if command == "FLUSHALL" {
+        match env.clear() {
+            Ok(_) => {
+                writeln!(out, "Database cleared")?;
+            }
+            Err(e) => {
+                writeln!(out, "Could not clear the database. Error: [{}]", e)?;
+            }
+        }
+    } else if command == "CHECKPOINT" {
+        let compact = command_args.get(1).is_some_and(|arg| arg == "--compact");
+        match env.checkpoint(compact) {
+            Ok(sequence) => {
+                writeln!(out, "Checkpoint: [{}]", sequence)?;
+            }
+            Err(e) => {
+                writeln!(out, "Failed to checkpoint: [{}]", e)?;
+            }
+        }
+    } else if command == "REPLAY" {
+        let trace_path = &command_args[1];
+        let honor_timing = command_args
+            .get(2)
+            .is_none_or(|arg| arg != "--as-fast-as-possible");
+        match replay_trace(env, trace_path, honor_timing) {
+            Ok((applied, elapsed, slowest)) => {
+                let seconds = elapsed.as_secs_f64();
+                let throughput = if seconds > 0.0 {
+                    applied as f64 / seconds
+                } else {
+                    0.0
+                };
+                writeln!(
+                    out,
+                    "Replayed {} commands in {:.3}s ({:.1} ops/s, slowest {}us)",
+                    applied,
+                    seconds,
+                    throughput,
+                    slowest.as_micros()
+                )?;
+            }
+            Err(e) => {
+                writeln!(out, "Failed to replay trace [{}]: [{}]", trace_path, e)?;
+            }
+        }
+    } else if command == "STATS" {
+        match stats(env) {
+            Ok(stats) => {
+                for (name, value) in [
+                    ("retired segments", stats.retired_segments as u64),
+                    ("disk bytes", stats.disk_bytes),
+                    ("live keys", stats.live_keys),
+                    ("write segment bytes", stats.write_segment_bytes),
+                    ("tombstones", stats.tombstones),
+                    ("reclaimable bytes", stats.reclaimable_bytes),
+                ] {
+                    writeln!(out, "{:<21}{}", format!("{}:", name), value)?;
+                }
+                if let Some(disk_limit) = stats.disk_limit {
+                    let used = stats.disk_bytes * 100 / disk_limit.max(1);
+                    writeln!(out, "{:<21}{} ({}% used)", "disk limit:", disk_limit, used)?;
+                }
+            }
+            Err(e) => {
+                writeln!(out, "Could not compute stats. Error: [{}]", e)?;
+            }
+        }
+    } else if command == "METRICS" {
+        let reset = command_args.get(1).is_some_and(|arg| arg == "RESET");
+        for (name, value) in env.metrics.snapshot(reset) {
+            writeln!(out, "{}: {}", name, value)?;
+        }
+    } else if command == "MULTI" {
+        env.transaction = Some(Vec::new());
+        writeln!(out, "OK")?;
+    } else if command == "EXEC" {
+        let queued = match env.transaction.take() {
+            Some(queued) => queued,
+            None => {
+                writeln!(out, "EXEC without MULTI")?;
+                return Ok(());
+            }
+        };
+        match apply_batch(env, &queued) {
+            Ok(_) => {
+                writeln!(out, "Transaction applied: [{}] commands", queued.len())?;
+            }
+            Err(e) => {
+                writeln!(out, "Could not apply transaction. Error: [{}]", e)?;
+            }
+        }
+    } else if command == "ATOMICLOAD" {
+        // without a file the block is read from stdin, see `main`
+        let file_path = match command_args.get(1) {
+            Some(file_path) => file_path,
+            None => {
+                writeln!(out, "ATOMICLOAD needs a file here")?;
+                return Ok(());
+            }
+        };
+        match File::open(file_path) {
+            Ok(file) => atomic_load(env, &mut BufReader::new(file).lines(), out)?,
+            Err(e) => writeln!(out, "Could not open [{}]. Error: [{}]", file_path, e)?,
+        }
+    } else if command == "DISCARD" {
+        match env.transaction.take() {
+            Some(queued) => writeln!(out, "Discarded [{}] commands", queued.len())?,
+            None => writeln!(out, "DISCARD without MULTI")?,
+        }
+    } else if command == "NUMBERS" {
+        let duplicates = env.duplicate_numbers();
+        let gaps = env.number_gaps();
+        let foreign = env.foreign_segments();
+        for file_path in foreign.iter() {
+            writeln!(out, "Segment [{}] has no number", file_path)?;
+        }
+        for (number, file_paths) in duplicates.iter() {
+            writeln!(
+                out,
+                "Number [{}] is used by [{}]",
+                number,
+                file_paths.join(", ")
+            )?;
+        }
+        for (from, to) in gaps.iter() {
+            writeln!(out, "Numbers [{}-{}] are missing", from, to)?;
+        }
+        if duplicates.is_empty() && gaps.is_empty() && foreign.is_empty() {
+            writeln!(out, "Segment numbers are contiguous")?;
+        }
+        if command_args.get(1).is_some_and(|arg| arg == "--renumber") {
+            match env.renumber_segments() {
+                Ok(renamed) => writeln!(out, "Renumbered [{}] segments", renamed)?,
+                Err(e) => writeln!(out, "Could not renumber segments. Error: [{}]", e)?,
+            }
+        }
+    } else if command == "LOWMEM" {
+        let before = env.memory_usage();
+        env.release_memory();
+        writeln!(
+            out,
+            "Index memory: [{}] bytes, was [{}]",
+            env.memory_usage(),
+            before
+        )?;
+    } else if command == "SORTEDEXPORT" {
+        let result = match command_args.get(1) {
+            Some(file_path) => File::create(file_path).and_then(|file| {
+                let mut file = std::io::BufWriter::new(file);
+                let exported = sorted_export(env, &mut file)?;
+                file.flush()?;
+                Ok(exported)
+            }),
+            None => sorted_export(env, out),
+        };
+        match (result, command_args.get(1)) {
+            (Ok(exported), Some(file_path)) => {
+                writeln!(out, "Exported [{}] records to [{}]", exported, file_path)?;
+            }
+            (Ok(_), None) => (),
+            (Err(e), _) => {
+                writeln!(out, "Could not export. Error: [{}]", e)?;
+            }
+        }
+    } else if command == "EXPORT" {
+        let file_path = match command_args.get(1) {
+            Some(file_path) => file_path,
+            None => {
+                writeln!(out, "EXPORT requires a file path")?;
+                return Ok(());
+            }
+        };
+        let result = File::create(file_path).and_then(|file| {
+            let mut file = std::io::BufWriter::new(file);
+            let exported = export_json(env, &mut file)?;
+            file.into_inner()?.sync_all()?;
+            Ok(exported)
+        });
+        match result {
+            Ok(exported) => {
+                writeln!(out, "Exported [{}] records to [{}]", exported, file_path)?;
+            }
+            Err(e) => {
+                writeln!(out, "Could not export. Error: [{}]", e)?;
+            }
+        }
+    } else if command == "IMPORT" {
+        let file_path = match command_args.get(1) {
+            Some(file_path) => file_path,
+            None => {
+                writeln!(out, "IMPORT requires a file path")?;
+                return Ok(());
+            }
+        };
+        match File::open(file_path).and_then(|mut file| import_json(env, &mut file)) {
+            Ok((written, unchanged)) => {
+                writeln!(
+                    out,
+                    "Imported [{}] records from [{}], [{}] already up to date",
+                    written, file_path, unchanged
+                )?;
+            }
+            Err(e) => {
+                writeln!(out, "Could not import. Error: [{}]", e)?;
+            }
+        }
+    } else if command == "RECENT" {
+        let count = match command_args.get(1).map(|count| count.parse::<usize>()) {
+            None => RECENT_BUFFER_SIZE,
+            Some(Ok(count)) => count,
+            Some(Err(_)) => {
+                writeln!(out, "Invalid count [{}]", command_args[1])?;
+                return Ok(());
+            }
+        };
+        for (timestamp, key, value) in env.recent.iter().rev().take(count) {
+            let key = display_key(env, key);
+            match value {
+                Some(value) => {
+                    let value = String::from_utf8_lossy(value);
+                    writeln!(out, "[{}] SET key: [{}] value: [{}]", timestamp, key, value)?;
+                }
+                None => writeln!(out, "[{}] DELETE key: [{}]", timestamp, key)?,
+            }
+        }
+    } else if command == "OPENSTATS" {
+        let mut total_keys = 0;
+        let mut total_time = std::time::Duration::ZERO;
+        for segment in env
+            .segments
+            .iter()
+            .chain(std::iter::once(&env.write_segment))
+        {
+            let source = match segment.from_hint {
+                true => "hint",
+                false => "full scan",
+            };
+            writeln!(
+                out,
+                "[{}] {}: [{}] us, keys: [{}]",
+                segment.file_path,
+                source,
+                segment.build_time.as_micros(),
+                segment.index.len()
+            )?;
+            total_keys += segment.index.len();
+            total_time += segment.build_time;
+        }
+        writeln!(
+            out,
+            "Total: [{}] us, keys: [{}]",
+            total_time.as_micros(),
+            total_keys
+        )?;
+    } else if command == "SCAN" && command_args.get(1).is_some_and(|arg| arg == "--prefix") {
+        let prefix = match command_args.get(2) {
+            Some(prefix) => command_key(env, prefix),
+            None => {
+                writeln!(out, "Usage: SCAN --prefix <prefix>")?;
+                return Ok(());
+            }
+        };
+        let records = match env.iter_live() {
+            Ok(records) => PrefixScan { records, prefix },
+            Err(e) => {
+                writeln!(out, "Could not take a snapshot. Error: [{}]", e)?;
+                return Ok(());
+            }
+        };
+        let mut count = 0;
+        for record in records {
+            match record {
+                Ok((key, value)) => writeln!(out, "{} {}", display_key(env, &key), value)?,
+                Err(e) => {
+                    writeln!(out, "Could not read snapshot. Error: [{}]", e)?;
+                    return Ok(());
+                }
+            }
+            count += 1;
+        }
+        writeln!(out, "Matched keys: [{}]", count)?;
+    } else if command == "SCAN" {
+        // `SCAN` opens a cursor over a snapshot, `SCAN <cursor> [count]` pages through it
+        let cursor = match command_args.get(1) {
+            None => {
+                match env.iter_live() {
+                    Ok(records) => {
+                        let cursor = env.next_cursor;
+                        env.next_cursor += 1;
+                        env.cursors.insert(cursor, records.peekable());
+                        writeln!(out, "Cursor [{}]", cursor)?;
+                    }
+                    Err(e) => writeln!(out, "Could not take a snapshot. Error: [{}]", e)?,
+                }
+                return Ok(());
+            }
+            Some(cursor) => cursor,
+        };
+        let count = match command_args.get(2).map(|count| count.parse::<usize>()) {
+            None => SCAN_DEFAULT_COUNT,
+            Some(Ok(count)) => count,
+            Some(Err(_)) => {
+                writeln!(out, "Invalid count [{}]", command_args[2])?;
+                return Ok(());
+            }
+        };
+        let binary_keys = env.binary_keys;
+        let (cursor, records) = match cursor
+            .parse::<u64>()
+            .ok()
+            .and_then(|id| env.cursors.get_mut(&id).map(|records| (id, records)))
+        {
+            Some(found) => found,
+            None => {
+                writeln!(out, "No cursor [{}]", cursor)?;
+                return Ok(());
+            }
+        };
+        let mut failed = false;
+        for record in records.by_ref().take(count) {
+            match record {
+                Ok((key, value)) => {
+                    let key = match binary_keys {
+                        true => encode_hex(&key),
+                        false => String::from_utf8_lossy(&key).into_owned(),
+                    };
+                    writeln!(out, "{} {}", key, String::from_utf8_lossy(&value))?
+                }
+                Err(e) => {
+                    writeln!(out, "Could not read snapshot. Error: [{}]", e)?;
+                    failed = true;
+                    break;
+                }
+            }
+        }
+        if failed || records.peek().is_none() {
+            // dropping the cursor releases the segments it pinned
+            env.cursors.remove(&cursor);
+            writeln!(out, "Cursor [{}] closed", cursor)?;
+        }
+    } else if command == "TRIMINDEX" {
+        let cap = match command_args.get(1).map(|cap| cap.parse::<usize>()) {
+            Some(Ok(cap)) => cap,
+            _ => {
+                writeln!(out, "TRIMINDEX requires the number of entries to keep")?;
+                return Ok(());
+            }
+        };
+        env.index_cap = Some(cap);
+        let evicted = env.trim_indexes();
+        writeln!(
+            out,
+            "Index capped at [{}] entries per segment, evicted [{}]",
+            cap, evicted
+        )?;
+    }
+    Ok(())
+}
+
+// The bytes of a key argument, hex digits with `binary_keys`. `handle_command`
+// has rejected arguments that are not hex by then, see `invalid_key_arg`.
+pub fn command_key(env: &Environment, arg: &str) -> Vec<u8> {
+    match env.binary_keys {
+        true => decode_hex(arg).unwrap_or_else(|| arg.as_bytes().to_vec()),
+        false => arg.as_bytes().to_vec(),
+    }
+}
+
+// A stored key as commands print it: in hex with `binary_keys`, otherwise as
+// text, bytes that are not UTF-8 shown as replacement characters.
+fn display_key(env: &Environment, key: &[u8]) -> String {
+    match env.binary_keys {
+        true => encode_hex(key),
+        false => String::from_utf8_lossy(key).into_owned(),
+    }
+}
+
+pub fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// None unless `text` is an even number of hex digits, of either case.
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    // `from_str_radix` would take a leading `+` as well
+    if !text.len().is_multiple_of(2) || !text.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+// The arguments of `command_args` that name keys.
+fn key_args(command_args: &[String]) -> Vec<&String> {
+    let args = &command_args[1..];
+    match command_args[0].as_str() {
+        "SET" | "SETEX" | "EXPIRE" | "GET" | "DELETE" | "GETSET" | "GETDEL" | "EXISTS" | "INCR"
+        | "DECR" | "APPEND" | "CAS" | "SETNX" | "TYPE" => args.iter().take(1).collect(),
+        "SWAP" | "RANGE" | "RENAME" => args.iter().take(2).collect(),
+        "SCAN" if args.first().is_some_and(|arg| arg == "--prefix") => {
+            args.get(1).into_iter().collect()
+        }
+        "MGET" => args.iter().collect(),
+        "MSET" => args.iter().step_by(2).collect(),
+        "USAGE" => args.first().into_iter().collect(),
+        _ => Vec::new(),
+    }
+}
+
+// The first key argument that is not hex although `binary_keys` asks for it.
+fn invalid_key_arg<'a>(env: &Environment, command_args: &'a [String]) -> Option<&'a String> {
+    if !env.binary_keys {
+        return None;
+    }
+    key_args(command_args)
+        .into_iter()
+        .find(|arg| decode_hex(arg).is_none())
+}
+
+// Writes records into a scratch environment, cuts a batch short after its
+// first record and tears the last record as if the process died mid-write,
+// then reopens the environment and checks that every acknowledged record reads
+// back intact while neither the torn record nor any of the batch is visible.
+#[cfg(feature = "dev")]
+fn crash_test(prefix: &String, records: usize) -> std::io::Result<Vec<String>> {
+    let scratch = scratch::ScratchDir::new();
+    let scratch_path = &scratch.0;
+    let mut env = Environment::with_namer(scratch_path, prefix, Box::new(NumericNamer))?;
+    let mut written = Vec::new();
+    for i in 0..records {
+        let key = format!("crash-{}", i);
+        let value = format!("value-{}", i);
+        set_data(&mut env, key.as_bytes(), &value)?;
+        written.push((key, value));
+    }
+    let batch: Vec<Record> = (0..3)
+        .map(|i| {
+            Record::new(
+                format!("crash-batch-{}", i).as_bytes(),
+                format!("value-{}", i).as_bytes(),
+            )
+        })
+        .collect();
+    env.write_segment.save_batch(&batch)?;
+    // only the first record of the batch made it to disk
+    let second_offset = *env.write_segment.index.get(&batch[1].key).unwrap();
+    OpenOptions::new()
+        .write(true)
+        .open(&env.write_segment.file_path)?
+        .set_len(second_offset)?;
+    let torn_record = "crash-torn,value-torn\n";
+    let mut file = OpenOptions::new()
+        .append(true)
+        .open(&env.write_segment.file_path)?;
+    // cut inside the value, after the key and delimiter made it to disk
+    file.write_all(&torn_record.as_bytes()[..torn_record.len() - 4])?;
+    file.sync_all()?;
+    drop(env);
+
+    let mut problems = Vec::new();
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(|_| {}));
+    let reopened = std::panic::catch_unwind(|| {
+        Environment::with_namer(scratch_path, prefix, Box::new(NumericNamer))
+    });
+    std::panic::set_hook(default_hook);
+    match reopened {
+        Ok(Ok(env)) => {
+            for (key, value) in written {
+                match lookup(&env, key.as_bytes()) {
+                    Ok(Some(found)) if found == value => (),
+                    Ok(found) => problems.push(format!(
+                        "key [{}] expected [{}] found [{:?}]",
+                        key, value, found
+                    )),
+                    Err(e) => problems.push(format!("key [{}] failed to read: [{}]", key, e)),
+                }
+            }
+            if let Ok(Some(found)) = lookup(&env, b"crash-torn") {
+                problems.push(format!("torn record is visible with value [{}]", found));
+            }
+            for record in batch.iter() {
+                let key = &record.key;
+                if let Ok(Some(found)) = lookup(&env, key) {
+                    problems.push(format!(
+                        "key [{}] of an incomplete batch is visible with value [{}]",
+                        String::from_utf8_lossy(key),
+                        found
+                    ));
+                }
+            }
+        }
+        Ok(Err(e)) => problems.push(format!("environment failed to reopen: [{}]", e)),
+        Err(_) => problems.push(String::from("environment panicked while reopening")),
+    }
+    Ok(problems)
+}
+
+// Retires a compressed write segment and stops after each step in turn as if
+// the process died there, then reopens the environment and checks that every
+// record reads back and that the segment is neither lost nor loaded twice.
+#[cfg(feature = "dev")]
+fn retire_crash_test(prefix: &String, records: usize) -> std::io::Result<Vec<String>> {
+    let mut problems = Vec::new();
+    for step in [
+        RetireStep::Listed,
+        RetireStep::Renamed,
+        RetireStep::Compressed,
+        RetireStep::Indexed,
+    ] {
+        let scratch = scratch::ScratchDir::new();
+        let scratch_path = &scratch.0;
+        let mut env = Environment::with_namer(scratch_path, prefix, Box::new(NumericNamer))?;
+        env.compress = true;
+        let mut written = Vec::new();
+        for i in 0..records {
+            let key = format!("crash-{}", i);
+            let value = format!("value-{}", i);
+            set_data(&mut env, key.as_bytes(), &value)?;
+            written.push((key, value));
+        }
+        // writing may have retired segments already
+        let expected_segments = env.segments.len() + 1;
+        env.retire_write_segment_until(Some(step))?;
+        drop(env);
+
+        match Environment::with_namer(scratch_path, prefix, Box::new(NumericNamer)) {
+            Ok(env) => {
+                if env.segments.len() != expected_segments {
+                    problems.push(format!(
+                        "after {:?}: {} retired segments instead of {}",
+                        step,
+                        env.segments.len(),
+                        expected_segments
+                    ));
+                }
+                for (key, value) in written {
+                    match lookup(&env, key.as_bytes()) {
+                        Ok(Some(found)) if found == value => (),
+                        Ok(found) => problems.push(format!(
+                            "after {:?}: key [{}] expected [{}] found [{:?}]",
+                            step, key, value, found
+                        )),
+                        Err(e) => problems.push(format!(
+                            "after {:?}: key [{}] failed to read: [{}]",
+                            step, key, e
+                        )),
+                    }
+                }
+            }
+            Err(e) => problems.push(format!(
+                "after {:?}: environment failed to reopen: [{}]",
+                step, e
+            )),
+        }
+    }
+    Ok(problems)
+}
+
+// Sets and removals applied together by `KvStore::write`, in the order they
+// were added. A key added twice ends up with its last value.
+#[derive(Debug, Default)]
+pub struct WriteBatch {
+    records: Vec<BatchRecord>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        WriteBatch::default()
+    }
+
+    pub fn set(&mut self, key: impl AsRef<[u8]>, value: &str) -> &mut Self {
+        self.records
+            .push((key.as_ref().to_vec(), Some(value.to_string())));
+        self
+    }
+
+    pub fn remove(&mut self, key: impl AsRef<[u8]>) -> &mut Self {
+        self.records.push((key.as_ref().to_vec(), None));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+}
+
+// readers STRESS starts without an argument, and the keys and rounds of writes
+// they read through
+#[cfg(feature = "dev")]
+const STRESS_DEFAULT_READERS: usize = 8;
+#[cfg(feature = "dev")]
+const STRESS_KEYS: usize = 64;
+#[cfg(feature = "dev")]
+const STRESS_ROUNDS: u64 = 50;
+
+#[cfg(feature = "dev")]
+struct StressReport {
+    reads: u64,
+    writes: u64,
+    // readers seen holding the shared lock at the same time
+    most_concurrent: usize,
+    problems: Vec<String>,
+}
+
+// Shares a scratch environment the way the server does, with readers GETting
+// every key under the read lock while a writer keeps counting the values up,
+// rolling segments and compacting them. A reader must never see a value go
+// back or vanish, and no thread may panic.
+#[cfg(feature = "dev")]
+fn stress_test(prefix: &String, readers: usize) -> std::io::Result<StressReport> {
+    use std::sync::RwLock;
+    use std::sync::atomic::{AtomicBool, AtomicUsize};
+
+    let scratch = scratch::ScratchDir::new();
+    let mut env = Environment::with_namer(&scratch.0, prefix, Box::new(NumericNamer))?;
+    env.segment_threshold = 1024;
+    env.max_segments = Some(4);
+    let keys: Vec<String> = (0..STRESS_KEYS).map(|i| format!("stress-{}", i)).collect();
+    for key in keys.iter() {
+        set_data(&mut env, key.as_bytes(), "0")?;
+    }
+    let env = RwLock::new(env);
+    let done = AtomicBool::new(false);
+    let (in_flight, most_concurrent) = (AtomicUsize::new(0), AtomicUsize::new(0));
+    let (reads, writes) = (AtomicU64::new(0), AtomicU64::new(0));
+    let problems = Mutex::new(Vec::new());
+    let report = |problem: String| problems.lock().unwrap().push(problem);
+
+    std::thread::scope(|scope| {
+        let writer = scope.spawn(|| {
+            for round in 1..=STRESS_ROUNDS {
+                for key in keys.iter() {
+                    let mut locked = env.write().unwrap();
+                    if let Err(e) = set_data(&mut locked, key.as_bytes(), &round.to_string()) {
+                        report(format!("write of [{}] failed: [{}]", key, e));
+                    }
+                    writes.fetch_add(1, Ordering::Relaxed);
+                    let job = locked.take_compaction_job();
+                    drop(locked);
+                    // compacts without the lock, as a background compaction does
+                    if let Some(mut job) = job {
+                        let result = job.run();
+                        if let Err(e) = env.write().unwrap().finish_compaction(job, result) {
+                            report(format!("compaction failed: [{}]", e));
+                        }
+                    }
+                }
+            }
+            done.store(true, Ordering::Relaxed);
+        });
+        let handles: Vec<_> = (0..readers)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut seen = vec![0u64; keys.len()];
+                    while !done.load(Ordering::Relaxed) {
+                        let locked = env.read().unwrap();
+                        let now_in_flight = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                        most_concurrent.fetch_max(now_in_flight, Ordering::SeqCst);
+                        for (key, seen) in keys.iter().zip(seen.iter_mut()) {
+                            let found = lookup(&locked, key.as_bytes());
+                            match found
+                                .as_ref()
+                                .map(|value| value.as_ref().map(|v| v.parse()))
+                            {
+                                Ok(Some(Ok(value))) if value >= *seen => *seen = value,
+                                _ => report(format!(
+                                    "key [{}] read [{:?}] after [{}]",
+                                    key, found, seen
+                                )),
+                            }
+                            reads.fetch_add(1, Ordering::Relaxed);
+                        }
+                        in_flight.fetch_sub(1, Ordering::SeqCst);
+                    }
+                })
+            })
+            .collect();
+        if writer.join().is_err() {
+            report(String::from("the writer panicked"));
+            done.store(true, Ordering::Relaxed);
+        }
+        for handle in handles {
+            if handle.join().is_err() {
+                report(String::from("a reader panicked"));
+            }
+        }
+    });
+    Ok(StressReport {
+        reads: reads.into_inner(),
+        writes: writes.into_inner(),
+        most_concurrent: most_concurrent.into_inner(),
+        problems: problems.into_inner().unwrap(),
+    })
+}
+
+// An embeddable handle on a database directory, for use without the CLI.
+// It opens the default `db` prefix with numeric segment names. Reads take
+// `&self`, so a store shared as `Arc<RwLock<KvStore>>` serves them side by side.
+pub struct KvStore {
+    env: Environment,
+    closed: bool,
+}
+
+// A store dropped without `close` is closed here, where a failure can only be
+// reported.
+impl Drop for KvStore {
+    fn drop(&mut self) {
+        if !self.closed
+            && let Err(e) = self.env.close()
+        {
+            eprintln!("Could not close the store. Error: [{}]", e);
+        }
+    }
+}
+
+impl KvStore {
+    pub fn open(path: impl AsRef<Path>) -> Result<KvStore, KvError> {
+        KvStore::open_with_progress(path, |_| {})
+    }
+
+    // `open` calling `progress` each time the index of a retired segment is
+    // built or read from its hint, the last call having every segment loaded.
+    // Nothing is called for a store without retired segments.
+    pub fn open_with_progress(
+        path: impl AsRef<Path>,
+        mut progress: impl FnMut(OpenProgress) + Send,
+    ) -> Result<KvStore, KvError> {
+        let data_path = path.as_ref().display().to_string();
+        let env = Environment::with_progress(
+            &data_path,
+            &String::from("db"),
+            Box::new(NumericNamer),
+            &mut progress,
+        )?;
+        Ok(KvStore { env, closed: false })
+    }
+
+    // `open` with the files kept in `storage`, such as a `MemoryStorage` for a
+    // store that never touches the disk.
+    pub fn open_in(path: impl AsRef<Path>, storage: Arc<dyn Storage>) -> Result<KvStore, KvError> {
+        KvStore::open_in_with_progress(path, storage, |_| {})
+    }
+
+    // `open_in` calling `progress` like `open_with_progress`
+    pub fn open_in_with_progress(
+        path: impl AsRef<Path>,
+        storage: Arc<dyn Storage>,
+        mut progress: impl FnMut(OpenProgress) + Send,
+    ) -> Result<KvStore, KvError> {
+        let data_path = path.as_ref().display().to_string();
+        let env = Environment::open_in(
+            &data_path,
+            &String::from("db"),
+            Box::new(NumericNamer),
+            storage,
+            &mut progress,
+        )?;
+        Ok(KvStore { env, closed: false })
+    }
+
+    // Opens a directory another process may be writing to without touching it:
+    // no write segment is created and writes fail with ReadOnly. Reads go by
+    // the indexes built at the open.
+    pub fn open_read_only(path: impl AsRef<Path>) -> Result<KvStore, KvError> {
+        KvStore::open_read_only_in(path, Arc::new(FileStorage))
+    }
+
+    // `open_read_only` with the files kept in `storage`, see `open_in`.
+    pub fn open_read_only_in(
+        path: impl AsRef<Path>,
+        storage: Arc<dyn Storage>,
+    ) -> Result<KvStore, KvError> {
+        let data_path = path.as_ref().display().to_string();
+        let env = Environment::open_read_only_in(
+            &data_path,
+            &String::from("db"),
+            Box::new(NumericNamer),
+            storage,
+        )?;
+        Ok(KvStore { env, closed: false })
+    }
+
+    // Syncs the write segment and saves its index, see `Environment::close`.
+    pub fn close(mut self) -> Result<(), KvError> {
+        self.closed = true;
+        self.env.close()
+    }
+
+    // None if the key was never set or has been removed. A value that is not
+    // UTF-8 fails with NotUtf8, `get_bytes` reads it.
+    pub fn get(&self, key: impl AsRef<[u8]>) -> Result<Option<String>, KvError> {
+        let key = key.as_ref();
+        self.get_bytes(key)?
+            .map(|value| utf8_value(key, value))
+            .transpose()
+    }
+
+    // `get` for values that need not be UTF-8
+    pub fn get_bytes(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>, KvError> {
+        match get_bytes(&self.env, key.as_ref()) {
+            Err(KvError::KeyDeleted { .. }) => Ok(None),
+            result => result,
+        }
+    }
+
+    // `get` along with the type tag and expiry of the value
+    pub fn get_with_meta(&self, key: impl AsRef<[u8]>) -> Result<Option<ValueWithMeta>, KvError> {
+        get_with_meta(&self.env, key.as_ref())
+    }
+
+    // values of `keys` in the same order, None for a key that is not set
+    pub fn multi_get(&self, keys: &[impl AsRef<[u8]>]) -> Vec<Result<Option<String>, KvError>> {
+        let keys: Vec<&[u8]> = keys.iter().map(AsRef::as_ref).collect();
+        multi_get(&self.env, &keys)
+    }
+
+    // Like `get`, but tells a removed key from one that was never set.
+    pub fn get_state(&self, key: impl AsRef<[u8]>) -> Result<KeyState, KvError> {
+        get_state(&self.env, key.as_ref())
+    }
+
+    pub fn set(&mut self, key: impl AsRef<[u8]>, value: &str) -> Result<(), KvError> {
+        self.set_bytes(key, value.as_bytes())
+    }
+
+    // `set` for values that need not be UTF-8
+    pub fn set_bytes(&mut self, key: impl AsRef<[u8]>, value: &[u8]) -> Result<(), KvError> {
+        Ok(set_bytes(&mut self.env, key.as_ref(), value)?)
+    }
+
+    // `set` with an application defined type tag, which `get_with_meta` returns
+    pub fn set_with_type(
+        &mut self,
+        key: impl AsRef<[u8]>,
+        value: &str,
+        tag: u8,
+    ) -> Result<(), KvError> {
+        Ok(set_with_type(
+            &mut self.env,
+            key.as_ref(),
+            value.as_bytes(),
+            tag,
+        )?)
+    }
+
+    pub fn remove(&mut self, key: impl AsRef<[u8]>) -> Result<(), KvError> {
+        Ok(delete_data(&mut self.env, key.as_ref())?)
+    }
+
+    // `set` returning the value it replaced, None if the key was absent. It reads
+    // the key before writing, so `set` stays the cheaper of the two.
+    pub fn get_and_set(
+        &mut self,
+        key: impl AsRef<[u8]>,
+        value: &str,
+    ) -> Result<Option<String>, KvError> {
+        get_and_set(&mut self.env, key.as_ref(), Some(value))
+    }
+
+    // `remove` returning the value removed, None if the key was absent.
+    pub fn get_and_remove(&mut self, key: impl AsRef<[u8]>) -> Result<Option<String>, KvError> {
+        get_and_set(&mut self.env, key.as_ref(), None)
+    }
+
+    // Moves the value of `old` to `new` in one atomic write, replacing any value
+    // of `new`. False if `old` is not set.
+    pub fn rename(
+        &mut self,
+        old: impl AsRef<[u8]>,
+        new: impl AsRef<[u8]>,
+    ) -> Result<bool, KvError> {
+        rename_data(&mut self.env, old.as_ref(), new.as_ref())
+    }
+
+    // Appends every record of the batch with one write and one fsync. After a
+    // crash either all of them are visible or none is.
+    pub fn write(&mut self, batch: WriteBatch) -> Result<(), KvError> {
+        Ok(write_batch(&mut self.env, &batch.records)?)
+    }
+
+    // Adds `by` to the value of `key`, which counts as 0 while absent, and
+    // returns the new value.
+    pub fn incr(&mut self, key: impl AsRef<[u8]>, by: i64) -> Result<i64, KvError> {
+        incr_data(&mut self.env, key.as_ref(), by)
+    }
+
+    // Appends `suffix` to the value of `key`, treating an absent key as empty,
+    // and returns the new length of the value in bytes.
+    pub fn append(&mut self, key: impl AsRef<[u8]>, suffix: &str) -> Result<usize, KvError> {
+        append_data(&mut self.env, key.as_ref(), suffix)
+    }
+
+    // Writes `new` only if the current value of `key` is `expected`, None
+    // meaning absent. Returns whether the value was written.
+    pub fn compare_and_swap(
+        &mut self,
+        key: impl AsRef<[u8]>,
+        expected: Option<&str>,
+        new: &str,
+    ) -> Result<bool, KvError> {
+        compare_and_swap(&mut self.env, key.as_ref(), expected, new)
+    }
+
+    // Writes `value` only if `key` is absent, deleted or expired. Returns
+    // whether it was written.
+    pub fn set_if_absent(&mut self, key: impl AsRef<[u8]>, value: &str) -> Result<bool, KvError> {
+        compare_and_swap(&mut self.env, key.as_ref(), None, value)
+    }
+
+    // Checks every segment against its checksums and index, see `verify`.
+    pub fn verify(&self) -> Result<VerifyReport, KvError> {
+        Ok(verify(&self.env)?)
+    }
+
+    // every live key once, as of the call
+    pub fn keys(&self) -> Result<Keys, KvError> {
+        Ok(self.env.keys()?)
+    }
+
+    pub fn contains_key(&self, key: impl AsRef<[u8]>) -> Result<bool, KvError> {
+        contains_key(&self.env, key.as_ref())
+    }
+
+    pub fn scan_prefix(&self, prefix: impl AsRef<[u8]>) -> Result<PrefixScan, KvError> {
+        let records = self.env.iter_live()?;
+        Ok(PrefixScan {
+            records,
+            prefix: prefix.as_ref().to_vec(),
+        })
+    }
+
+    // live records with keys from `start` up to but excluding `end`, sorted
+    pub fn range(
+        &self,
+        start: impl AsRef<[u8]>,
+        end: impl AsRef<[u8]>,
+    ) -> Result<RangeScan<'_>, KvError> {
+        RangeScan::new(&self.env, start.as_ref(), end.as_ref())
+    }
+
+    // Keeps the keys of every index sorted, which makes `range` cheaper at the
+    // cost of slower lookups and more memory per key.
+    pub fn set_ordered_index(&mut self, ordered: bool) {
+        self.env.set_ordered_index(ordered);
+    }
+
+    // Writes every live record as one JSON object, returns the number written.
+    pub fn export_json(&self, mut writer: impl Write) -> Result<u64, KvError> {
+        Ok(export_json(&self.env, &mut writer)?)
+    }
+
+    // Applies an object written by `export_json`, returns the number of records
+    // that were written, leaving out those already holding the same value.
+    pub fn import_json(&mut self, mut reader: impl Read) -> Result<u64, KvError> {
+        let (written, _) = import_json(&mut self.env, &mut reader)?;
+        Ok(written)
+    }
+
+    pub fn set_sync_policy(&mut self, policy: SyncPolicy) {
+        self.env.sync_policy = policy;
+    }
+
+    pub fn set_compaction_strategy(&mut self, strategy: CompactionStrategy) {
+        self.env.compaction_strategy = strategy;
+    }
+
+    // the write segment is rolled after `limit` records as well as by size
+    pub fn set_max_records_per_segment(&mut self, limit: Option<u64>) {
+        self.env.max_records_per_segment = limit;
+    }
+
+    // writes fail with `DiskLimitExceeded` once even a full compaction leaves
+    // the segment files over `limit` bytes
+    pub fn set_max_disk_bytes(&mut self, limit: Option<u64>) {
+        self.env.max_disk_bytes = limit;
+    }
+
+    // retired segments are memory mapped unless this is turned off
+    pub fn set_mmap(&mut self, enabled: bool) {
+        self.env.set_mmap(enabled);
+    }
+
+    pub fn set_cache_capacity(&mut self, capacity: usize) {
+        self.env.set_value_cache_capacity(capacity);
+    }
+
+    // only while the store is empty, see `Environment::set_record_codec`
+    pub fn set_record_codec(&mut self, codec: RecordCodec) -> Result<(), KvError> {
+        self.env.set_record_codec(codec)
+    }
+
+    // longest key and largest value in bytes `set` accepts, None for no limit
+    pub fn set_max_key_len(&mut self, limit: Option<usize>) {
+        self.env.max_key_len = limit;
+    }
+
+    pub fn set_max_value_len(&mut self, limit: Option<usize>) {
+        self.env.max_value_len = limit;
+    }
+
+    // Removes every key, see `Environment::clear`.
+    pub fn clear(&mut self) -> Result<(), KvError> {
+        Ok(self.env.clear()?)
+    }
+
+    pub fn compact(&mut self) -> Result<CompactionSummary, KvError> {
+        Ok(self.env.compact_segments()?)
+    }
+
+    // changes to `key` from now on, see `Environment::watch`
+    pub fn watch(&self, key: impl AsRef<[u8]>) -> mpsc::Receiver<ChangeEvent> {
+        self.env.watch(key.as_ref())
+    }
+
+    // what `compact` would do now, nothing is written
+    pub fn compact_dry_run(&self) -> Result<CompactionSummary, KvError> {
+        Ok(self.env.compact_dry_run()?)
+    }
+
+    pub fn stats(&self) -> Result<Stats, KvError> {
+        Ok(stats(&self.env)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scratch::ScratchDir;
+    use std::fs::{read_dir, remove_file};
+
+    fn open(dir: &ScratchDir) -> Environment {
+        Environment::with_namer(&dir.0, &String::from("db"), Box::new(NumericNamer)).unwrap()
+    }
+
+    fn get(env: &Environment, key: &str) -> Option<String> {
+        lookup(env, key.as_bytes()).unwrap()
+    }
+
+    fn execute(env: &mut Environment, line: &str) -> CommandResult {
+        handle_command(env, &split_command(line).unwrap()).unwrap()
+    }
+
+    // Runs one command line and returns the lines it answered with, the
+    // result of SET, GET, DELETE and COMPACT as debugged.
+    fn run(env: &mut Environment, line: &str) -> String {
+        match execute(env, line) {
+            CommandResult::Output(lines) => String::from_utf8(lines).unwrap(),
+            result => format!("{:?}\n", result),
+        }
+    }
+
+    #[test]
+    fn read_fanout_beyond_the_threshold_is_counted() {
+        let dir = ScratchDir::new();
+        for i in 0..3 {
+            let segment = format!("{}/db.{:05}", dir.0, i + 1);
+            std::fs::write(segment, format!("key-{},value\n", i)).unwrap();
+        }
+        let mut env = open(&dir);
+        env.segments.sort_by(|a, b| a.file_path.cmp(&b.file_path));
+        let exceeded = |env: &Environment| env.metrics.read_fanout_exceeded.load(Ordering::Relaxed);
+
+        // the write segment and the newest retired one are read
+        env.max_read_fanout = Some(2);
+        assert_eq!(get_data(&env, b"key-2").unwrap().as_deref(), Some("value"));
+        assert_eq!(exceeded(&env), 0);
+        // the Bloom filters rule out the two newer segments, three are not read
+        assert_eq!(get_data(&env, b"key-0").unwrap().as_deref(), Some("value"));
+        assert_eq!(exceeded(&env), 0);
+
+        env.max_read_fanout = Some(0);
+        get_data(&env, b"key-2").unwrap();
+        assert_eq!(exceeded(&env), 1);
+    }
+
+    #[test]
+    fn swap_exchanges_two_present_keys() {
+        let dir = ScratchDir::new();
+        let mut env = open(&dir);
+        set_data(&mut env, b"a", "1").unwrap();
+        set_data(&mut env, b"b", "2").unwrap();
+        swap_data(&mut env, b"a", b"b").unwrap();
+        assert_eq!(lookup(&env, b"a").unwrap().as_deref(), Some("2"));
+        assert_eq!(lookup(&env, b"b").unwrap().as_deref(), Some("1"));
+    }
+
+    #[test]
+    fn swap_with_a_missing_key_deletes_the_present_one() {
+        let dir = ScratchDir::new();
+        let mut env = open(&dir);
+        set_data(&mut env, b"a", "1").unwrap();
+        swap_data(&mut env, b"a", b"missing").unwrap();
+        assert_eq!(lookup(&env, b"a").unwrap(), None);
+        assert_eq!(lookup(&env, b"missing").unwrap().as_deref(), Some("1"));
+    }
+
+    #[test]
+    fn tracked_live_count_matches_a_full_count() {
+        let dir = ScratchDir::new();
+        // a first retired segment, the write segment numbers its successors from it
+        std::fs::write(format!("{}/db.00001", dir.0), "key-0,old\n").unwrap();
+        let mut env = open(&dir);
+        env.track_live_count().unwrap();
+        let key = |i: usize| format!("key-{}", i % 17);
+        for i in 0..60 {
+            match i % 5 {
+                3 => delete_data(&mut env, key(i).as_bytes()).unwrap(),
+                4 => swap_data(&mut env, key(i).as_bytes(), key(i + 3).as_bytes()).unwrap(),
+                _ => set_data(&mut env, key(i).as_bytes(), &i.to_string()).unwrap(),
+            };
+        }
+        assert!(env.segments.len() > 1);
+        env.compact_segments().unwrap();
+        delete_data(&mut env, key(0).as_bytes()).unwrap();
+        let counted = live_keys(&env).unwrap().len() as u64;
+        assert_eq!(env.live_count, Some(counted));
+    }
+
+    #[test]
+    fn doctor_reports_and_fixes_seeded_defects() {
+        let dir = ScratchDir::new();
+        let path = |name: &str| Path::new(&dir.0).join(name).display().to_string();
+        std::fs::write(path("db.00001"), "key-0,value\n").unwrap();
+        std::fs::write(path("db.00002"), "key-1,value\n").unwrap();
+        let append = |name: &str, bytes: &str| {
+            let mut file = OpenOptions::new().append(true).open(path(name)).unwrap();
+            file.write_all(bytes.as_bytes()).unwrap();
+        };
+        let record_len = std::fs::metadata(path("db.00001")).unwrap().len();
+        std::fs::write(path("db.00002.tmp"), "key-9,left over\n").unwrap();
+        std::fs::write(path("db.00009"), "").unwrap();
+        append("db.00001", "key-torn,no newline");
+        append("db.00002", "not a record\n");
+        let db00002_len = std::fs::metadata(path("db.00002")).unwrap().len();
+
+        let issues = doctor(&FileStorage, &dir.0, "db", &NumericNamer, false).unwrap();
+        assert_eq!(
+            issues,
+            vec![
+                DoctorIssue::TornTail {
+                    file_path: path("db.00001"),
+                    offset: record_len
+                },
+                DoctorIssue::CorruptRecord {
+                    file_path: path("db.00002"),
+                    offset: record_len
+                },
+                DoctorIssue::EmptySegment(path("db.00009")),
+                DoctorIssue::LeftoverTmpFile(path("db.00002.tmp")),
+            ]
+        );
+        assert_eq!(
+            doctor(&FileStorage, &dir.0, "db", &NumericNamer, true).unwrap(),
+            issues
+        );
+
+        // only the corrupt record in the middle of a segment is left alone
+        assert_eq!(
+            doctor(&FileStorage, &dir.0, "db", &NumericNamer, false).unwrap(),
+            vec![issues[1].clone()]
+        );
+        assert!(!Path::new(&path("db.00002.tmp")).exists());
+        assert!(!Path::new(&path("db.00009")).exists());
+        assert_eq!(
+            std::fs::metadata(path("db.00001")).unwrap().len(),
+            record_len
+        );
+        assert_eq!(
+            std::fs::metadata(path("db.00002")).unwrap().len(),
+            db00002_len
+        );
+    }
+
+    #[test]
+    fn doctor_repairs_the_listed_segments_of_date_partitions() {
+        let dir = ScratchDir::new();
+        let mut env = open(&dir);
+        env.partition_by_date = true;
+        env.clock = || 20_000 * 86_400_000;
+        for key in ["a", "b"] {
+            set_data(&mut env, key.as_bytes(), "1").unwrap();
+            env.retire_write_segment().unwrap();
+        }
+        set_data(&mut env, b"c", "1").unwrap();
+        drop(env);
+        let path = |name: &str| Path::new(&dir.0).join(name).display().to_string();
+        let torn = path("2024-10-04/db.00001");
+        let intact_len = std::fs::metadata(&torn).unwrap().len();
+        let mut file = OpenOptions::new().append(true).open(&torn).unwrap();
+        file.write_all(b"key-torn,no newline").unwrap();
+        std::fs::write(path("2024-10-04/db.00002"), "").unwrap();
+        std::fs::write(path("2024-10-04/db.00002.hint.tmp"), "").unwrap();
+
+        let issues = doctor(&FileStorage, &dir.0, "db", &NumericNamer, false).unwrap();
+        assert_eq!(
+            issues,
+            vec![
+                DoctorIssue::TornTail {
+                    file_path: torn.clone(),
+                    offset: intact_len
+                },
+                DoctorIssue::EmptySegment(path("2024-10-04/db.00002")),
+                DoctorIssue::LeftoverTmpFile(path("2024-10-04/db.00002.hint.tmp")),
+            ]
+        );
+        assert_eq!(
+            doctor(&FileStorage, &dir.0, "db", &NumericNamer, true).unwrap(),
+            issues
+        );
+        assert!(
+            doctor(&FileStorage, &dir.0, "db", &NumericNamer, false)
+                .unwrap()
+                .is_empty()
+        );
+
+        // the manifest no longer lists the removed segment, and the repaired
+        // one is indexed from its new hint
+        let manifest = Environment::read_manifest(&FileStorage, &dir.0, "db");
+        assert_eq!(
+            manifest.segments,
+            Some(vec![String::from("2024-10-04/db.00001")])
+        );
+        let env = open(&dir);
+        assert_eq!(env.segments.len(), 1);
+        assert!(env.segments[0].from_hint);
+        assert_eq!(get(&env, "a").as_deref(), Some("1"));
+        assert_eq!(get(&env, "c").as_deref(), Some("1"));
+    }
+
+    #[test]
+    fn doctor_fix_reindexes_the_repaired_segments() {
+        let dir = ScratchDir::new();
+        let file_path = Path::new(&dir.0).join("db.00001").display().to_string();
+        std::fs::write(&file_path, "key,value\n").unwrap();
+        let mut env = open(&dir);
+        let mut file = OpenOptions::new().append(true).open(&file_path).unwrap();
+        file.write_all(b"key-torn,no newline").unwrap();
+        assert_eq!(env.stale_segments().unwrap(), vec![file_path]);
+
+        doctor(&FileStorage, &dir.0, "db", &NumericNamer, true).unwrap();
+        env.reload().unwrap();
+        assert!(env.stale_segments().unwrap().is_empty());
+        assert_eq!(lookup(&env, b"key").unwrap().as_deref(), Some("value"));
+    }
+
+    #[test]
+    fn layout_shows_an_overwritten_key_shadowed_and_live() {
+        let dir = ScratchDir::new();
+        let path = |name: &str| Path::new(&dir.0).join(name).display().to_string();
+        std::fs::write(path("db.00001"), "key,old\n").unwrap();
+        let mut env = open(&dir);
+        set_data(&mut env, b"key", "new").unwrap();
+        assert_eq!(
+            run(&mut env, "LAYOUT --status"),
+            format!(
+                "[{}]\n  key (shadowed)\n[{}]\n  key (live)\n",
+                path("db.00001"),
+                path("db.current")
+            )
+        );
+    }
+
+    #[test]
+    fn dupes_counts_records_shadowed_within_a_segment() {
+        let dir = ScratchDir::new();
+        let mut env = open(&dir);
+        for line in [
+            "SET a 1", "SET a 2", "SET a 3", "SET b 1", "SET b 2", "SET c 1",
+        ] {
+            run(&mut env, line);
+        }
+        assert_eq!(count_duplicates(&env.write_segment).unwrap(), (6, 3));
+        assert_eq!(
+            run(&mut env, "DUPES db.current"),
+            "Segment [db.current]: 6 records, 3 shadowed within the segment\n"
+        );
+    }
+
+    #[test]
+    fn range_scan_over_an_sstable_reads_only_the_overlapping_blocks() {
+        let dir = ScratchDir::new();
+        let records: String = (0..20).map(|i| format!("k{:02},v{}\n", i, i)).collect();
+        std::fs::write(Path::new(&dir.0).join("db.00001"), records).unwrap();
+        let mut env = open(&dir);
+        env.sstable_block_size = Some(32);
+        env.compact_segments().unwrap();
+        assert_eq!(env.segments.len(), 1);
+        let blocks = env.segments[0].blocks.as_ref().unwrap();
+        assert!(blocks.blocks.len() > 3);
+        assert!(env.segments[0].index.len() == 0);
+
+        let overlapping = blocks.blocks_in(b"k08", b"k11");
+        assert_eq!(
+            run(&mut env, "RANGE k08 k11"),
+            "k08 v8\nk09 v9\nk10 v10\nMatched keys: [3]\n"
+        );
+        let blocks = env.segments[0].blocks.as_ref().unwrap();
+        for (block, reads) in blocks.reads.iter().enumerate() {
+            assert_eq!(
+                reads.load(Ordering::Relaxed) > 0,
+                overlapping.contains(&block),
+                "block {}",
+                block
+            );
+        }
+
+        // a reopened SSTable answers reads from its block index
+        drop(env);
+        let mut env = open(&dir);
+        assert!(env.segments[0].blocks.is_some());
+        assert!(matches!(
+            execute(&mut env, "GET k19"),
+            CommandResult::Value(Some(value)) if value == "v19"
+        ));
+        assert!(matches!(
+            execute(&mut env, "GET k5"),
+            CommandResult::Value(None)
+        ));
+        assert_eq!(live_keys(&env).unwrap().len(), 20);
+        assert!(env.stale_segments().unwrap().is_empty());
+    }
+
+    fn bracketed_numbers(line: &str) -> Vec<f64> {
+        line.split('[')
+            .skip(1)
+            .filter_map(|part| part.split(']').next()?.parse().ok())
+            .collect()
+    }
+
+    #[test]
+    fn compaction_adds_to_bytes_written_and_the_ratio() {
+        let dir = ScratchDir::new();
+        std::fs::write(Path::new(&dir.0).join("db.00001"), "").unwrap();
+        let mut env = open(&dir);
+        for round in 0..3 {
+            for i in 0..4 {
+                run(&mut env, &format!("SET key-{} value-{}", i, round));
+            }
+            env.retire_write_segment().unwrap();
+        }
+        let before = bracketed_numbers(&run(&mut env, "WRITEAMP"));
+        env.compact_segments().unwrap();
+        let after = bracketed_numbers(&run(&mut env, "WRITEAMP"));
+        let (written, live, ratio) = (after[0], after[1], after[2]);
+        assert!(written > before[0], "{:?} {:?}", before, after);
+        assert_eq!(live, before[1]);
+        assert!(ratio > before[2]);
+        assert!((ratio - written / live).abs() < 0.01);
+    }
+
+    #[cfg(feature = "dev")]
+    #[test]
+    fn crashtest_reports_a_consistent_recovery() {
+        let dir = ScratchDir::new();
+        let mut env = open(&dir);
+        assert_eq!(run(&mut env, "CRASHTEST"), "Recovery consistent\n");
+    }
+
+    #[test]
+    fn exceeding_the_size_budget_evicts_the_coldest_keys() {
+        let dir = ScratchDir::new();
+        std::fs::write(Path::new(&dir.0).join("db.00001"), "").unwrap();
+        let mut env = open(&dir);
+        env.max_db_size = Some(250);
+        let value = "v".repeat(20);
+        for i in 0..4 {
+            run(&mut env, &format!("SET cold-{} {}", i, value));
+        }
+        for i in 0..2 {
+            run(&mut env, &format!("SET hot-{} {}", i, value));
+        }
+        for _ in 0..3 {
+            run(&mut env, "GET hot-0");
+            run(&mut env, "GET hot-1");
+        }
+        for i in 0..3 {
+            run(&mut env, &format!("SET new-{} {}", i, value));
+        }
+        assert!(env.metrics.keys_evicted.load(Ordering::Relaxed) > 0);
+        assert!(env.disk_size() <= 250);
+        assert_eq!(get(&env, "hot-0"), Some(value.clone()));
+        assert_eq!(get(&env, "hot-1"), Some(value.clone()));
+        assert_eq!(get(&env, "new-2"), Some(value.clone()));
+        assert_eq!(get(&env, "cold-0"), None);
+    }
+
+    #[test]
+    fn batches_over_the_size_budget_evict_and_keep_the_count() {
+        let dir = ScratchDir::new();
+        std::fs::write(Path::new(&dir.0).join("db.00001"), "").unwrap();
+        let mut env = open(&dir);
+        env.max_db_size = Some(250);
+        env.track_live_count().unwrap();
+        let value = "v".repeat(20);
+        for round in 0..3 {
+            let pairs: Vec<String> = (0..3)
+                .map(|i| format!("key-{}-{} {}", round, i, value))
+                .collect();
+            run(&mut env, &format!("MSET {}", pairs.join(" ")));
+        }
+        assert!(env.metrics.keys_evicted.load(Ordering::Relaxed) > 0);
+        assert!(env.disk_size() <= 250);
+        assert_eq!(get(&env, "key-2-2"), Some(value.clone()));
+        assert_eq!(get(&env, "key-0-0"), None);
+        let live = live_keys(&env).unwrap().len();
+        assert_eq!(run(&mut env, "DBSIZE"), format!("Live keys: [{}]\n", live));
+    }
+
+    #[test]
+    fn the_size_budget_waits_for_a_background_compaction() {
+        let dir = ScratchDir::new();
+        std::fs::write(Path::new(&dir.0).join("db.00001"), "").unwrap();
+        let mut env = open(&dir);
+        let value = "v".repeat(20);
+        for i in 0..6 {
+            set_data(&mut env, format!("old-{}", i).as_bytes(), &value).unwrap();
+        }
+        env.retire_write_segment().unwrap();
+        let mut job = env.start_compaction().unwrap().unwrap();
+        env.max_db_size = Some(250);
+        assert_eq!(run(&mut env, &format!("SET new {}", value)), "Written\n");
+        assert!(env.metrics.keys_evicted.load(Ordering::Relaxed) > 0);
+        assert_eq!(get(&env, "new"), Some(value.clone()));
+
+        let result = job.run();
+        env.finish_compaction(job, result).unwrap();
+        run(&mut env, &format!("SET newer {}", value));
+        assert!(env.disk_size() <= 250);
+        assert_eq!(get(&env, "new"), Some(value.clone()));
+        assert_eq!(get(&env, "newer"), Some(value));
+    }
+
+    #[test]
+    fn retired_segments_go_to_the_partition_of_their_date() {
+        static NOW: AtomicU64 = AtomicU64::new(0);
+        const DAY: u64 = 86_400_000;
+        let dir = ScratchDir::new();
+        let mut env = open(&dir);
+        env.partition_by_date = true;
+        env.clock = || NOW.load(Ordering::Relaxed);
+        for (day, key) in [(20_000, "first"), (20_001, "second")] {
+            NOW.store(day * DAY, Ordering::Relaxed);
+            set_data(&mut env, key.as_bytes(), "value").unwrap();
+            env.retire_write_segment().unwrap();
+        }
+        let in_partition =
+            |date: &str, name: &str| Path::new(&dir.0).join(date).join(name).exists();
+        assert!(in_partition("2024-10-04", "db.00001"));
+        assert!(in_partition("2024-10-05", "db.00002"));
+        drop(env);
+
+        // a directory in the way of the partition fails the retirement
+        let mut env = open(&dir);
+        env.partition_by_date = true;
+        env.clock = || NOW.load(Ordering::Relaxed);
+        NOW.store(20_002 * DAY, Ordering::Relaxed);
+        std::fs::write(Path::new(&dir.0).join("2024-10-06"), "").unwrap();
+        assert!(env.retire_write_segment().is_err());
+        std::fs::remove_file(Path::new(&dir.0).join("2024-10-06")).unwrap();
+        drop(env);
+
+        let env = open(&dir);
+        assert_eq!(get(&env, "first").as_deref(), Some("value"));
+        assert_eq!(get(&env, "second").as_deref(), Some("value"));
+    }
+
+    #[test]
+    fn overwrites_grow_on_disk_usage_but_not_logical_usage() {
+        let dir = ScratchDir::new();
+        let mut env = open(&dir);
+        run(&mut env, "SET tenant-a:1 value");
+        run(&mut env, "SET tenant-b:1 value");
+        let (logical, on_disk) = prefix_usage(&env, b"tenant-a:").unwrap();
+        for _ in 0..3 {
+            run(&mut env, "SET tenant-a:1 value");
+        }
+        let (logical_after, on_disk_after) = prefix_usage(&env, b"tenant-a:").unwrap();
+        assert_eq!(logical_after, logical);
+        assert_eq!(on_disk_after, on_disk * 4);
+        assert_eq!(
+            prefix_usage(&env, b"tenant-b:").unwrap(),
+            (logical, on_disk)
+        );
+    }
+
+    // Writes through to a file until `remaining` bytes are used up, then fails.
+    #[derive(Debug)]
+    struct FailingAppender {
+        file: File,
+        remaining: usize,
+    }
+
+    impl Write for FailingAppender {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if self.remaining == 0 {
+                return Err(std::io::Error::other("disk gone"));
+            }
+            let written = self.file.write(&buf[..buf.len().min(self.remaining)])?;
+            self.remaining -= written;
+            Ok(written)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.file.flush()
+        }
+    }
+
+    impl Appender for FailingAppender {
+        fn set_len(&self, len: u64) -> std::io::Result<()> {
+            self.file.set_len(len)
+        }
+    }
+
+    #[test]
+    fn a_write_failing_partway_is_rolled_back() {
+        let dir = ScratchDir::new();
+        let mut env = open(&dir);
+        run(&mut env, "SET a 1");
+        let file_path = env.write_segment.file_path.clone();
+        let file_len = std::fs::metadata(&file_path).unwrap().len();
+        let file = OpenOptions::new().append(true).open(&file_path).unwrap();
+        env.write_segment.appender = Some(Box::new(FailingAppender { file, remaining: 2 }));
+
+        assert!(set_data(&mut env, b"b", "2").is_err());
+        assert_eq!(std::fs::metadata(&file_path).unwrap().len(), file_len);
+        assert_eq!(env.write_segment.size, file_len);
+        assert_eq!(env.write_segment.index.get(b"b".as_slice()), None);
+
+        env.write_segment.appender = None;
+        run(&mut env, "SET c 3");
+        drop(env);
+        let env = open(&dir);
+        assert_eq!(get(&env, "a").as_deref(), Some("1"));
+        assert_eq!(get(&env, "b"), None);
+        assert_eq!(get(&env, "c").as_deref(), Some("3"));
+    }
+
+    #[test]
+    fn latency_reports_the_commands_run() {
+        let dir = ScratchDir::new();
+        let mut env = open(&dir);
+        run(&mut env, "SET a 1");
+        run(&mut env, "SET b 2");
+        run(&mut env, "GET a");
+        let report = run(&mut env, "LATENCY");
+        let lines: Vec<&str> = report.lines().collect();
+        assert!(lines[0].starts_with("GET: count 1 p50 <= "), "{}", report);
+        assert!(lines[1].starts_with("SET: count 2 p50 <= "), "{}", report);
+        assert_eq!(&lines[2..], ["DELETE: count 0", "COMPACT: count 0"]);
+
+        run(&mut env, "LATENCY RESET");
+        assert_eq!(
+            run(&mut env, "LATENCY"),
+            "GET: count 0\nSET: count 0\nDELETE: count 0\nCOMPACT: count 0\n"
+        );
+    }
+
+    #[test]
+    fn binary_keys_are_taken_and_printed_in_hex() {
+        let dir = ScratchDir::new();
+        let mut env = open(&dir);
+        env.binary_keys = true;
+        assert!(matches!(
+            execute(&mut env, "SET ff00 value"),
+            CommandResult::Written
+        ));
+        assert_eq!(lookup(&env, &[0xff, 0]).unwrap().as_deref(), Some("value"));
+        assert!(matches!(
+            execute(&mut env, "GET FF00"),
+            CommandResult::Value(Some(value)) if value == "value"
+        ));
+        assert_eq!(
+            run(&mut env, "RANGE 00 ffff"),
+            "ff00 value\nMatched keys: [1]\n"
+        );
+        assert_eq!(
+            run(&mut env, "GET key"),
+            "Key [key] is not hex, --binary-keys takes keys in hex\n"
+        );
+        assert_eq!(
+            run(&mut env, "SWAP ff00 +f"),
+            "Key [+f] is not hex, --binary-keys takes keys in hex\n"
+        );
+        assert_eq!(
+            run(&mut env, "MSET 01 one k2 two"),
+            "Key [k2] is not hex, --binary-keys takes keys in hex\n"
+        );
+        assert_eq!(
+            run(&mut env, "MGET ff00 k2"),
+            "Key [k2] is not hex, --binary-keys takes keys in hex\n"
+        );
+        assert_eq!(
+            run(&mut env, "RENAME ff00 new"),
+            "Key [new] is not hex, --binary-keys takes keys in hex\n"
+        );
+        assert_eq!(
+            run(&mut env, "TYPE key"),
+            "Key [key] is not hex, --binary-keys takes keys in hex\n"
+        );
+    }
+
+    #[test]
+    fn keys_that_are_not_utf8_survive_a_reopen_and_compaction() {
+        let dir = ScratchDir::new();
+        std::fs::write(format!("{}/db.00001", dir.0), "").unwrap();
+        let key: &[u8] = &[0xff, 0xfe, 0];
+        let mut env = open(&dir);
+        set_data(&mut env, key, "value").unwrap();
+        drop(env);
+        let mut env = open(&dir);
+        assert_eq!(lookup(&env, key).unwrap().as_deref(), Some("value"));
+        env.retire_write_segment().unwrap();
+        env.compact_segments().unwrap();
+        assert_eq!(lookup(&env, key).unwrap().as_deref(), Some("value"));
+    }
+
+    #[test]
+    fn checkpoints_count_up_across_reopens() {
+        let dir = ScratchDir::new();
+        std::fs::write(format!("{}/db.00001", dir.0), "").unwrap();
+        let mut env = open(&dir);
+        set_data(&mut env, b"a", "1").unwrap();
+        assert_eq!(run(&mut env, "CHECKPOINT"), "Checkpoint: [1]\n");
+        set_data(&mut env, b"b", "2").unwrap();
+        env.retire_write_segment().unwrap();
+        assert_eq!(run(&mut env, "CHECKPOINT --compact"), "Checkpoint: [2]\n");
+        drop(env);
+
+        let mut env = open(&dir);
+        assert_eq!(env.checkpoint_sequence, 2);
+        let marker = std::fs::read_to_string(Environment::checkpoint_path(&dir.0, "db")).unwrap();
+        assert_eq!(marker.lines().next(), Some("2"));
+        assert_eq!(run(&mut env, "CHECKPOINT"), "Checkpoint: [3]\n");
+    }
+
+    #[test]
+    fn a_shrinking_update_is_written_in_place() {
+        let dir = ScratchDir::new();
+        let mut env = open(&dir);
+        env.in_place_updates = true;
+        set_data(&mut env, b"key", "a longer value").unwrap();
+        let size = env.write_segment.size;
+        set_data(&mut env, b"key", "short").unwrap();
+        assert_eq!(env.write_segment.size, size);
+        assert_eq!(count_duplicates(&env.write_segment).unwrap(), (1, 0));
+        assert_eq!(get(&env, "key").as_deref(), Some("short"));
+        drop(env);
+        assert_eq!(get(&open(&dir), "key").as_deref(), Some("short"));
+    }
+
+    // A file in memory whose next write fails after `fail_after` bytes.
+    struct FailingCursor {
+        cursor: std::io::Cursor<Vec<u8>>,
+        fail_after: Option<usize>,
+    }
+
+    impl Write for FailingCursor {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            match self.fail_after.take() {
+                Some(0) => Err(std::io::Error::other("disk gone")),
+                Some(bytes) => {
+                    self.fail_after = Some(0);
+                    self.cursor.write(&buf[..bytes.min(buf.len())])
+                }
+                None => self.cursor.write(buf),
+            }
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for FailingCursor {
+        fn seek(&mut self, position: SeekFrom) -> std::io::Result<u64> {
+            self.cursor.seek(position)
+        }
+    }
+
+    #[test]
+    fn an_overwrite_failing_partway_restores_the_old_record() {
+        let contents = b"a,1\nkey,old value\nb,2\n".to_vec();
+        let mut file = FailingCursor {
+            cursor: std::io::Cursor::new(contents.clone()),
+            fail_after: Some(3),
+        };
+        assert!(
+            overwrite_or_restore(&mut file, 4, b"key,old value\n", b"key,new\n     \n").is_err()
+        );
+        assert_eq!(file.cursor.into_inner(), contents);
+    }
+
+    #[test]
+    fn replay_applies_every_traced_command() {
+        let dir = ScratchDir::new();
+        let mut env = open(&dir);
+        let trace_path = Path::new(&dir.0).join("trace").display().to_string();
+        let trace = "0 SET a 1\n5 SET b 2\n\n0 DELETE a\n1 SET c \"two words\"\n";
+        std::fs::write(&trace_path, trace).unwrap();
+        let (applied, elapsed, _) = replay_trace(&mut env, &trace_path, true).unwrap();
+        assert_eq!(applied, 4);
+        assert!(elapsed >= std::time::Duration::from_millis(6));
+        assert_eq!(get(&env, "a"), None);
+        assert_eq!(get(&env, "b").as_deref(), Some("2"));
+        assert_eq!(get(&env, "c").as_deref(), Some("two words"));
+    }
+
+    #[test]
+    fn compaction_drops_tombstones_but_keeps_values() {
+        let dir = ScratchDir::new();
+        std::fs::write(format!("{}/db.00001", dir.0), "").unwrap();
+        let mut env = open(&dir);
+        set_data(&mut env, b"deleted", "value").unwrap();
+        delete_data(&mut env, b"deleted").unwrap();
+        set_data(&mut env, b"kept", " ").unwrap();
+        set_data(&mut env, b"empty", "").unwrap();
+        env.retire_write_segment().unwrap();
+        env.compact_segments().unwrap();
+        let mut keys: Vec<Vec<u8>> = env.segments[0].keys().unwrap().into_iter().collect();
+        keys.sort();
+        assert_eq!(keys, [b"empty".to_vec(), b"kept".to_vec()]);
+        assert_eq!(get(&env, "kept").as_deref(), Some(" "));
+        assert_eq!(get(&env, "empty").as_deref(), Some(""));
+        assert_eq!(get(&env, "deleted"), None);
+    }
+
+    #[test]
+    fn a_record_with_several_fields_round_trips() {
+        let mut record = Record::new(b"\x01key", b"value, with comma");
+        record.header.flags = 0x80;
+        for (field, value) in [('e', 1_700_000_000_000), ('s', 42), ('t', 7)] {
+            record.header.fields.insert(field, value);
+        }
+        let line = encode_record(&record);
+        assert!(!line.contains(&b'\n'));
+        let decoded = decode_record(&line).unwrap();
+        // the comma has the value stored escaped
+        assert_eq!(decoded.header.flags, record.header.flags | FLAG_ESCAPED);
+        assert_eq!((&decoded.key, &decoded.value), (&record.key, &record.value));
+        assert_eq!(decoded.header.fields, record.header.fields);
+        assert_eq!(encode_record(&decoded), line);
+        // a key starting like a header gets an empty one to tell them apart
+        let plain = Record::new(b"\x01key", b"value");
+        assert_eq!(decode_record(&encode_record(&plain)), Some(plain));
+        assert_eq!(encode_record(&Record::new(b"key", b"value")), b"key,value");
+    }
+
+    #[test]
+    fn metrics_reset_returns_the_counts_and_zeroes_them() {
+        let dir = ScratchDir::new();
+        let mut env = open(&dir);
+        run(&mut env, "SET a 1");
+        run(&mut env, "GET a");
+        run(&mut env, "DELETE a");
+        let reset = run(&mut env, "METRICS RESET");
+        for counter in ["gets: 1", "sets: 1", "deletes: 1"] {
+            assert!(reset.lines().any(|line| line == counter), "{}", reset);
+        }
+        assert!(!reset.contains("bytes_written: 0"), "{}", reset);
+        let after = run(&mut env, "METRICS");
+        assert!(after.lines().all(|line| line.ends_with(": 0")), "{}", after);
+    }
+
+    // `db.<n>t`, numbered by a clock that moves 1000 ticks per segment.
+    struct TickNamer;
+
+    impl SegmentNamer for TickNamer {
+        fn sequence(&self, prefix: &str, file_name: &str) -> Option<u64> {
+            let suffix = file_name.strip_prefix(prefix)?.strip_prefix('.')?;
+            suffix.strip_suffix('t')?.parse().ok()
+        }
+
+        fn next_name(&self, prefix: &str, last: u64) -> String {
+            format!("{}.{}t", prefix, (last / 1000 + 1) * 1000)
+        }
+    }
+
+    #[test]
+    fn a_custom_namer_keeps_retirement_and_open_order() {
+        let dir = ScratchDir::new();
+        std::fs::write(format!("{}/db.0t", dir.0), "").unwrap();
+        let open_ticked =
+            || Environment::with_namer(&dir.0, &String::from("db"), Box::new(TickNamer)).unwrap();
+        let mut env = open_ticked();
+        for round in 0..3 {
+            set_data(&mut env, b"key", &format!("value-{}", round)).unwrap();
+            set_data(&mut env, format!("key-{}", round).as_bytes(), "value").unwrap();
+            env.retire_write_segment().unwrap();
+        }
+        for name in ["db.1000t", "db.2000t", "db.3000t"] {
+            assert!(Path::new(&dir.0).join(name).exists(), "{}", name);
+        }
+        drop(env);
+        let env = open_ticked();
+        assert_eq!(get(&env, "key").as_deref(), Some("value-2"));
+        assert_eq!(get(&env, "key-0").as_deref(), Some("value"));
+        assert_eq!(env.segments.len(), 4);
+    }
+
+    #[test]
+    fn segments_the_namer_does_not_number_are_reported() {
+        let dir = ScratchDir::new();
+        let mut env = open(&dir);
+        set_data(&mut env, b"key", "numeric").unwrap();
+        env.retire_write_segment().unwrap();
+        drop(env);
+
+        let mut env =
+            Environment::with_namer(&dir.0, &String::from("db"), Box::new(TickNamer)).unwrap();
+        let foreign = Path::new(&dir.0).join("db.00001").display().to_string();
+        assert_eq!(env.foreign_segments(), vec![foreign.clone()]);
+        assert_eq!(
+            run(&mut env, "NUMBERS"),
+            format!("Segment [{}] has no number\n", foreign)
+        );
+        assert_eq!(get(&env, "key").as_deref(), Some("numeric"));
+        set_data(&mut env, b"key", "ticked").unwrap();
+        env.retire_write_segment().unwrap();
+        assert!(Path::new(&dir.0).join("db.1000t").exists());
+        assert_eq!(get(&env, "key").as_deref(), Some("ticked"));
+    }
+
+    #[test]
+    fn a_key_trimmed_from_the_index_is_found_by_a_scan_and_reindexed() {
+        let dir = ScratchDir::new();
+        std::fs::write(format!("{}/db.00001", dir.0), "").unwrap();
+        let mut env = open(&dir);
+        for key in ["cold", "warm", "hot"] {
+            set_data(&mut env, key.as_bytes(), &format!("{}-value", key)).unwrap();
+        }
+        env.retire_write_segment().unwrap();
+        // reads are only tracked once an index is capped
+        run(&mut env, "TRIMINDEX 3");
+        run(&mut env, "GET warm");
+        run(&mut env, "GET hot");
+        assert_eq!(
+            run(&mut env, "TRIMINDEX 2"),
+            "Index capped at [2] entries per segment, evicted [1]\n"
+        );
+        assert_eq!(env.segments[1].index.get(b"cold".as_slice()), None);
+
+        assert_eq!(get(&env, "cold").as_deref(), Some("cold-value"));
+        assert!(
+            env.segments[1]
+                .recovered
+                .lock()
+                .unwrap()
+                .contains_key(b"cold".as_slice())
+        );
+        // the next trim folds it back in, evicting a key read less recently
+        run(&mut env, "GET cold");
+        env.trim_indexes();
+        assert!(env.segments[1].index.get(b"cold".as_slice()).is_some());
+        assert_eq!(env.segments[1].index.get(b"warm".as_slice()), None);
+        assert_eq!(get(&env, "warm").as_deref(), Some("warm-value"));
+    }
+
+    #[test]
+    fn writes_queued_in_multi_are_invisible_until_exec() {
+        let dir = ScratchDir::new();
+        let mut env = open(&dir);
+        assert_eq!(run(&mut env, "MULTI"), "OK\n");
+        assert_eq!(run(&mut env, "SET a 1"), "QUEUED\n");
+        assert_eq!(run(&mut env, "SET b 2"), "QUEUED\n");
+        assert_eq!(get(&env, "a"), None);
+        assert_eq!(get(&env, "b"), None);
+        assert_eq!(run(&mut env, "EXEC"), "Transaction applied: [2] commands\n");
+        assert_eq!(get(&env, "a").as_deref(), Some("1"));
+        assert_eq!(get(&env, "b").as_deref(), Some("2"));
+
+        run(&mut env, "MULTI");
+        run(&mut env, "SET a 3");
+        assert_eq!(run(&mut env, "DISCARD"), "Discarded [1] commands\n");
+        assert_eq!(get(&env, "a").as_deref(), Some("1"));
+    }
+
+    #[test]
+    fn duplicate_segment_numbers_are_reported_and_renumbered() {
+        let dir = ScratchDir::new();
+        std::fs::write(format!("{}/db.00001", dir.0), "a,1\n").unwrap();
+        // a second segment numbered 1, in a date partition
+        let partition = Path::new(&dir.0).join("2026-01-01");
+        std::fs::create_dir(&partition).unwrap();
+        std::fs::write(partition.join("db.00001"), "c,3\n").unwrap();
+        std::fs::write(format!("{}/db.00003", dir.0), "b,2\n").unwrap();
+
+        let mut env = open(&dir);
+        assert_eq!(env.duplicate_numbers().len(), 1);
+        let report = run(&mut env, "NUMBERS --renumber");
+        assert!(report.starts_with("Number [1] is used by ["));
+        assert!(report.contains("Numbers [2-2] are missing\n"));
+        assert!(report.ends_with("Renumbered [1] segments\n"));
+
+        assert!(env.duplicate_numbers().is_empty());
+        assert!(env.number_gaps().is_empty());
+        for (key, value) in [("a", "1"), ("b", "2"), ("c", "3")] {
+            assert_eq!(get(&env, key).as_deref(), Some(value));
+        }
+        drop(env);
+        let env = open(&dir);
+        assert_eq!(get(&env, "b").as_deref(), Some("2"));
+        assert_eq!(get(&env, "c").as_deref(), Some("3"));
+    }
+
+    #[test]
+    fn lowmem_releases_recovered_entries_and_cached_values() {
+        let dir = ScratchDir::new();
+        std::fs::write(format!("{}/db.00001", dir.0), "").unwrap();
+        let mut env = open(&dir);
+        env.set_value_cache_capacity(100);
+        for i in 0..50 {
+            set_data(&mut env, format!("key-{}", i).as_bytes(), "value").unwrap();
+        }
+        env.retire_write_segment().unwrap();
+        run(&mut env, "TRIMINDEX 2");
+        // the evicted keys are found by scans and kept as recovered entries
+        for i in 0..50 {
+            assert_eq!(get(&env, &format!("key-{}", i)).as_deref(), Some("value"));
+        }
+        assert!(!env.value_cache.lock().unwrap().entries.is_empty());
+
+        let numbers = bracketed_numbers(&run(&mut env, "LOWMEM"));
+        assert!(numbers[0] < numbers[1], "{:?}", numbers);
+        assert_eq!(env.memory_usage() as f64, numbers[0]);
+        assert!(
+            env.segments
+                .iter()
+                .all(|segment| segment.recovered.lock().unwrap().is_empty())
+        );
+        assert!(env.value_cache.lock().unwrap().entries.is_empty());
+        assert_eq!(get(&env, "key-0").as_deref(), Some("value"));
+    }
+
+    #[test]
+    fn merkle_trees_differ_only_along_the_path_to_a_changed_key() {
+        let (left_dir, right_dir) = (ScratchDir::new(), ScratchDir::new());
+        let (mut left, mut right) = (open(&left_dir), open(&right_dir));
+        for key in [
+            "apple", "banana", "kiwi", "mango", "pear", "quince", "~tilde",
+        ] {
+            set_data(&mut left, key.as_bytes(), "same").unwrap();
+            set_data(&mut right, key.as_bytes(), "same").unwrap();
+        }
+        set_data(&mut right, b"mango", "changed").unwrap();
+
+        let depth = 3;
+        let (left, right) = (
+            merkle_tree(&left, depth).unwrap(),
+            merkle_tree(&right, depth).unwrap(),
+        );
+        let leaf = merkle_leaf(b"mango", depth);
+        for (number, (left, right)) in left.iter().zip(right.iter()).enumerate() {
+            let differing: Vec<usize> = (0..left.len()).filter(|i| left[*i] != right[*i]).collect();
+            assert_eq!(differing, vec![leaf >> (depth as usize - number)]);
+        }
+    }
+
+    #[test]
+    fn a_segment_truncated_after_indexing_gives_a_typed_error() {
+        let dir = ScratchDir::new();
+        std::fs::write(format!("{}/db.00001", dir.0), "").unwrap();
+        let mut env = open(&dir);
+        // a mapped segment keeps the length it had when it was mapped
+        env.set_mmap(false);
+        set_data(&mut env, b"kept", "1").unwrap();
+        set_data(&mut env, b"cut", "2").unwrap();
+        env.retire_write_segment().unwrap();
+        let segment = env.segments.len() - 1;
+        let file_path = env.segments[segment].file_path.clone();
+        let offset = *env.segments[segment].index.get(b"cut".as_slice()).unwrap();
+        File::options()
+            .write(true)
+            .open(&file_path)
+            .unwrap()
+            .set_len(offset)
+            .unwrap();
+
+        match get_data(&env, b"cut") {
+            Err(KvError::OffsetBeyondEof {
+                file_path: path,
+                offset: at,
+                file_len,
+            }) => {
+                assert_eq!((path, at, file_len), (file_path.clone(), offset, offset));
+            }
+            other => panic!("expected OffsetBeyondEof, got {:?}", other),
+        }
+        // GET reindexes the segment, after which the key is simply missing
+        assert!(matches!(
+            execute(&mut env, "GET cut"),
+            CommandResult::Reindexed(KvError::OffsetBeyondEof { .. }, Ok(_))
+        ));
+        assert_eq!(get(&env, "cut"), None);
+        assert_eq!(get(&env, "kept").as_deref(), Some("1"));
+    }
+
+    #[test]
+    fn grep_returns_live_keys_whose_current_value_matches() {
+        let dir = ScratchDir::new();
+        std::fs::write(format!("{}/db.00001", dir.0), "").unwrap();
+        let mut env = open(&dir);
+        set_data(&mut env, b"a", "needle").unwrap();
+        set_data(&mut env, b"b", "haystack").unwrap();
+        set_data(&mut env, b"c", "needle").unwrap();
+        set_data(&mut env, b"d", "a needle in it").unwrap();
+        env.retire_write_segment().unwrap();
+        // overwritten and deleted values no longer count
+        set_data(&mut env, b"c", "nothing").unwrap();
+        set_data(&mut env, b"b", "needles").unwrap();
+        run(&mut env, "DELETE d");
+
+        assert_eq!(run(&mut env, "GREP needle"), "a\nb\nMatched keys: [2]\n");
+        assert_eq!(
+            run(&mut env, "GREP needle --limit 1"),
+            "a\nMatched keys: [1]\n"
+        );
+    }
+
+    #[test]
+    fn a_sweep_only_examines_the_keys_that_are_due() {
+        static NOW: AtomicU64 = AtomicU64::new(1_000_000);
+        let dir = ScratchDir::new();
+        std::fs::write(format!("{}/db.00001", dir.0), "").unwrap();
+        let mut env = open(&dir);
+        env.clock = || NOW.load(Ordering::Relaxed);
+        for i in 0..1000 {
+            set_data(&mut env, format!("key-{}", i).as_bytes(), "value").unwrap();
+        }
+        env.track_expiries().unwrap();
+        let second = std::time::Duration::from_secs(1);
+        for key in ["soon-0", "soon-1", "soon-2"] {
+            set_with_expiry(&mut env, key.as_bytes(), "value", second).unwrap();
+        }
+        for key in ["later-0", "later-1"] {
+            set_with_expiry(&mut env, key.as_bytes(), "value", 3600 * second).unwrap();
+        }
+        // written again without an expiry, its index entry is stale
+        set_data(&mut env, b"soon-2", "kept").unwrap();
+
+        NOW.fetch_add(2000, Ordering::Relaxed);
+        assert_eq!(
+            env.sweep_expired().unwrap(),
+            SweepSummary {
+                examined: 3,
+                removed: 2
+            }
+        );
+        assert_eq!(get(&env, "soon-0"), None);
+        assert_eq!(get(&env, "soon-2").as_deref(), Some("kept"));
+        assert_eq!(get(&env, "later-0").as_deref(), Some("value"));
+        assert_eq!(env.sweep_expired().unwrap().examined, 0);
+
+        // rebuilt from the records on reopening
+        drop(env);
+        let mut env = open(&dir);
+        env.clock = || NOW.load(Ordering::Relaxed);
+        env.track_expiries().unwrap();
+        assert_eq!(env.expiry_index.as_ref().unwrap().len(), 2);
+        NOW.fetch_add(3600 * 1000, Ordering::Relaxed);
+        assert_eq!(
+            run(&mut env, "SWEEP"),
+            "Removed [2] expired keys, examined [2] index entries\n"
+        );
+    }
+
+    #[test]
+    fn a_concurrent_reader_sees_all_of_an_atomic_load_or_none() {
+        let dir = ScratchDir::new();
+        let env = Arc::new(std::sync::RwLock::new(open(&dir)));
+        let keys: Vec<String> = (0..50).map(|i| format!("key-{}", i)).collect();
+        let block: Vec<std::io::Result<String>> = keys
+            .iter()
+            .map(|key| Ok(format!("SET {} value", key)))
+            .chain([Ok(String::from("END"))])
+            .collect();
+
+        let writer = {
+            let env = env.clone();
+            std::thread::spawn(move || {
+                let mut out = Vec::new();
+                atomic_load(&mut env.write().unwrap(), &mut block.into_iter(), &mut out).unwrap();
+                String::from_utf8(out).unwrap()
+            })
+        };
+        loop {
+            let finished = writer.is_finished();
+            let env = env.read().unwrap();
+            let visible = keys
+                .iter()
+                .filter(|key| lookup(&env, key.as_bytes()).unwrap().is_some())
+                .count();
+            assert!(
+                visible == 0 || visible == keys.len(),
+                "saw [{}] keys",
+                visible
+            );
+            if finished {
+                assert_eq!(visible, keys.len());
+                break;
+            }
+        }
+        assert_eq!(writer.join().unwrap(), "Loaded [50] records\n");
+
+        // an invalid line rejects the whole block
+        let mut env = env.write().unwrap();
+        let mut block = ["SET fresh value", "BOGUS", "END"]
+            .map(|line| Ok(line.to_string()))
+            .into_iter();
+        let mut out = Vec::new();
+        atomic_load(&mut env, &mut block, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "Nothing loaded. Error: [Invalid line [BOGUS]]\n"
+        );
+        assert_eq!(get(&env, "fresh"), None);
+    }
+
+    #[test]
+    fn a_scan_started_before_compaction_reads_its_snapshot() {
+        let dir = ScratchDir::new();
+        std::fs::write(format!("{}/db.00001", dir.0), "").unwrap();
+        let mut env = open(&dir);
+        for i in 0..4 {
+            set_data(&mut env, format!("key-{}", i).as_bytes(), "old").unwrap();
+            env.retire_write_segment().unwrap();
+        }
+        let mut iter = SnapshotIter::new(env.snapshot().unwrap());
+        let first = iter.next().unwrap().unwrap();
+
+        set_data(&mut env, b"key-0", "new").unwrap();
+        run(&mut env, "DELETE key-1");
+        env.compact_segments().unwrap();
+        let obsolete = |dir: &ScratchDir| {
+            read_dir(&dir.0)
+                .unwrap()
+                .filter(|entry| {
+                    let name = entry.as_ref().unwrap().file_name();
+                    name.to_string_lossy().ends_with(OBSOLETE_SUFFIX)
+                })
+                .count()
+        };
+        assert!(obsolete(&dir) > 0);
+
+        let mut records: Vec<(Vec<u8>, Vec<u8>)> = std::iter::once(Ok(first))
+            .chain(iter)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        records.sort();
+        let expected: Vec<(Vec<u8>, Vec<u8>)> = (0..4)
+            .map(|i| (format!("key-{}", i).into_bytes(), b"old".to_vec()))
+            .collect();
+        assert_eq!(records, expected);
+        // the last reader of the compacted segments removed them
+        assert_eq!(obsolete(&dir), 0);
+        assert_eq!(get(&env, "key-0").as_deref(), Some("new"));
+        assert_eq!(get(&env, "key-1"), None);
+    }
+
+    #[test]
+    fn openstats_reports_the_index_of_every_segment() {
+        let dir = ScratchDir::new();
+        std::fs::write(format!("{}/db.00001", dir.0), "a,1\nb,2\na,3\n").unwrap();
+        let mut env = open(&dir);
+        set_data(&mut env, b"c", "4").unwrap();
+        let report = run(&mut env, "OPENSTATS");
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines.len(), 3, "{}", report);
+        assert!(lines[0].starts_with(&format!("[{}] full scan: [", env.segments[0].file_path)));
+        assert!(lines[0].ends_with("keys: [2]"));
+        assert!(lines[1].ends_with("keys: [1]"));
+        assert!(lines[2].starts_with("Total: ["));
+        assert!(lines[2].ends_with("keys: [3]"));
+    }
+
+    // Dotted version numbers, compared part by part as numbers.
+    struct VersionOrder;
+
+    impl KeyComparator for VersionOrder {
+        fn compare(&self, a: &[u8], b: &[u8]) -> std::cmp::Ordering {
+            let parts = |key: &[u8]| -> Vec<u64> {
+                String::from_utf8_lossy(key)
+                    .split('.')
+                    .map(|part| part.parse().unwrap_or(0))
+                    .collect()
+            };
+            parts(a).cmp(&parts(b))
+        }
+    }
+
+    #[test]
+    fn a_custom_comparator_orders_sorted_output() {
+        let dir = ScratchDir::new();
+        std::fs::write(format!("{}/db.00001", dir.0), "").unwrap();
+        let mut env = open(&dir);
+        env.comparator = Arc::new(VersionOrder);
+        for key in ["1.10", "2.0", "1.2"] {
+            set_data(&mut env, key.as_bytes(), "value").unwrap();
+        }
+        set_data(&mut env, b"1.9", "other").unwrap();
+        assert_eq!(
+            run(&mut env, "GREP value"),
+            "1.2\n1.10\n2.0\nMatched keys: [3]\n"
+        );
+
+        // a compacted segment is laid out in comparator order too
+        env.retire_write_segment().unwrap();
+        env.compact_segments().unwrap();
+        let keys: Vec<Vec<u8>> = BufReader::new(File::open(&env.segments[0].file_path).unwrap())
+            .lines()
+            .map(|line| decode_record(line.unwrap().as_bytes()).unwrap().key)
+            .collect();
+        assert_eq!(keys, [&b"1.2"[..], b"1.9", b"1.10", b"2.0"]);
+        assert!(env.comparator.compare(b"1.2", b"1.10").is_lt());
+        assert!(ByteOrder.compare(b"1.2", b"1.10").is_gt());
+        assert!(NaturalOrder.compare(b"v1.2", b"v1.10").is_lt());
+    }
+
+    #[test]
+    fn rehint_replaces_a_corrupt_hint() {
+        let dir = ScratchDir::new();
+        std::fs::write(format!("{}/db.00001", dir.0), "").unwrap();
+        let mut env = open(&dir);
+        set_data(&mut env, b"a", "1").unwrap();
+        set_data(&mut env, b"b", "2").unwrap();
+        env.retire_write_segment().unwrap();
+        let retired = env.segments[1].file_path.clone();
+        assert_eq!(
+            run(&mut env, "REHINT db.00002"),
+            format!("Rewrote the hint of [{}]\n", retired)
+        );
+        drop(env);
+        // still covers the size of the segment, so it is believed on open
+        let size = std::fs::metadata(&retired).unwrap().len();
+        std::fs::write(
+            hint_path(&retired),
+            format!("{}{};1;0\n0,bogus\n", HEADER_MARKER as char, size),
+        )
+        .unwrap();
+
+        let mut env = open(&dir);
+        let report = run(&mut env, "OPENSTATS");
+        assert!(
+            report.contains(&format!("[{}] hint: [", retired)),
+            "{}",
+            report
+        );
+        assert_eq!(get(&env, "a"), None);
+        assert_eq!(
+            run(&mut env, "REHINT db.00002"),
+            format!("Rewrote the hint of [{}]\n", retired)
+        );
+        assert_eq!(get(&env, "a").as_deref(), Some("1"));
+        assert_eq!(
+            run(&mut env, "REHINT db.00042"),
+            "Segment [db.00042] not found\n"
+        );
+        drop(env);
+
+        let mut env = open(&dir);
+        let report = run(&mut env, "OPENSTATS");
+        let line = report.lines().find(|line| line.contains(&retired)).unwrap();
+        assert!(line.starts_with(&format!("[{}] hint: [", retired)));
+        assert!(line.ends_with("keys: [2]"));
+        assert_eq!(get(&env, "b").as_deref(), Some("2"));
+    }
+
+    #[test]
+    fn get_with_include_tombstone_tells_deleted_from_never_set() {
+        let dir = ScratchDir::new();
+        std::fs::write(format!("{}/db.00001", dir.0), "").unwrap();
+        let mut env = open(&dir);
+        set_data(&mut env, b"gone", "1").unwrap();
+        env.retire_write_segment().unwrap();
+        run(&mut env, "DELETE gone");
+
+        match execute(&mut env, "GET gone --include-tombstone") {
+            CommandResult::Error(KvError::KeyDeleted { file_path }) => {
+                assert_eq!(file_path, env.write_segment.file_path)
+            }
+            other => panic!("expected KeyDeleted, got {:?}", other),
+        }
+        assert!(matches!(
+            execute(&mut env, "GET never --include-tombstone"),
+            CommandResult::Value(None)
+        ));
+    }
+
+    #[test]
+    fn sorted_export_streams_exactly_the_live_records_in_order() {
+        let dir = ScratchDir::new();
+        std::fs::write(format!("{}/db.00001", dir.0), "").unwrap();
+        let mut env = open(&dir);
+        // more records than one run holds, written out of order
+        let count = EXPORT_RUN_SIZE * 2 + 100;
+        for i in 0..count {
+            let key = format!("key-{}", (i * 7919) % count);
+            set_data(&mut env, key.as_bytes(), &format!("old-{}", i)).unwrap();
+        }
+        set_data(&mut env, b"key-5", "new").unwrap();
+        run(&mut env, "DELETE key-6");
+
+        let mut out = Vec::new();
+        assert_eq!(sorted_export(&env, &mut out).unwrap(), count as u64 - 1);
+        let records: Vec<(String, String)> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|line| {
+                let (key, value) = line.split_once(',').unwrap();
+                (key.to_string(), value.to_string())
+            })
+            .collect();
+        assert!(records.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        assert_eq!(records.len(), count - 1);
+        assert!(records.contains(&(String::from("key-5"), String::from("new"))));
+        assert!(!records.iter().any(|(key, _)| key == "key-6"));
+        // the runs spilled along the way are gone
+        let leftovers = read_dir(&dir.0)
+            .unwrap()
+            .filter(|entry| {
+                let name = entry.as_ref().unwrap().file_name();
+                name.to_string_lossy().contains(".export.")
+            })
+            .count();
+        assert_eq!(leftovers, 0);
+    }
+
+    #[test]
+    fn writes_are_refused_below_the_free_space_guard() {
+        static FREE: AtomicU64 = AtomicU64::new(10_000);
+        let dir = ScratchDir::new();
+        let mut env = open(&dir);
+        env.free_space = |_| Ok(FREE.load(Ordering::Relaxed));
+        env.min_free_bytes = Some(1000);
+        assert!(matches!(
+            execute(&mut env, "SET a 1"),
+            CommandResult::Written
+        ));
+
+        FREE.store(1002, Ordering::Relaxed);
+        let size = env.write_segment.size;
+        match execute(&mut env, "SET b 2") {
+            CommandResult::Error(e) => assert!(
+                e.to_string().contains("disk full guard: [1002] bytes free"),
+                "{}",
+                e
+            ),
+            other => panic!("expected an error, got {:?}", other),
+        }
+        assert_eq!(env.write_segment.size, size);
+        assert_eq!(get(&env, "b"), None);
+        // room for `b,2` alone, not for its sequence number as well
+        FREE.store(1004, Ordering::Relaxed);
+        assert!(matches!(
+            execute(&mut env, "SET b 2"),
+            CommandResult::Error(_)
+        ));
+        assert_eq!(env.write_segment.size, size);
+        // deletes free space, so they go through
+        assert!(matches!(
+            execute(&mut env, "DELETE a"),
+            CommandResult::Deleted
+        ));
+
+        FREE.store(10_000, Ordering::Relaxed);
+        assert!(matches!(
+            execute(&mut env, "SET b 2"),
+            CommandResult::Written
+        ));
+        assert_eq!(get(&env, "b").as_deref(), Some("2"));
+    }
+
+    #[test]
+    fn compacting_to_the_checksummed_format_rewrites_legacy_records() {
+        let dir = ScratchDir::new();
+        // segments from before records had headers
+        std::fs::write(format!("{}/db.00001", dir.0), "a,old\nb,2\n").unwrap();
+        std::fs::write(format!("{}/db.00002", dir.0), "a,new\nb,\n").unwrap();
+        let mut env = open(&dir);
+        env.segments.sort_by(|a, b| a.file_path.cmp(&b.file_path));
+        let lines = |file_path: &str| -> Vec<Vec<u8>> {
+            byte_lines(BufReader::new(File::open(file_path).unwrap()))
+                .map(Result::unwrap)
+                .collect()
+        };
+        assert_eq!(
+            lines(&env.segments[0].file_path),
+            [b"a,old".to_vec(), b"b,2".to_vec()]
+        );
+
+        assert_eq!(
+            run(&mut env, "COMPACT --format checksummed"),
+            "Rewrote [2] segments into [1] as [checksummed]\n"
+        );
+        let records: Vec<Record> = lines(&env.segments[0].file_path)
+            .iter()
+            .map(|line| decode_record(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 1);
+        assert!(records[0].header.fields.contains_key(&FIELD_CHECKSUM));
+        assert!(checksum_matches(&records[0]));
+        assert!(verify(&env).unwrap().is_ok());
+        drop(env);
+
+        let mut env = open(&dir);
+        assert_eq!(get(&env, "a").as_deref(), Some("new"));
+        assert_eq!(get(&env, "b"), None);
+        // and back, the checksums are dropped
+        run(&mut env, "COMPACT --format plain");
+        let records = lines(&env.segments[0].file_path);
+        assert_eq!(records, [b"a,new".to_vec()]);
+        assert_eq!(
+            run(&mut env, "COMPACT --format gzip"),
+            "Usage: COMPACT --format plain|checksummed\n"
+        );
+    }
+
+    #[test]
+    fn get_state_tells_present_absent_deleted_and_errors_apart() {
+        let dir = ScratchDir::new();
+        std::fs::write(format!("{}/db.00001", dir.0), "").unwrap();
+        let mut env = open(&dir);
+        set_data(&mut env, b"kept", "1").unwrap();
+        set_data(&mut env, b"gone", "2").unwrap();
+        set_data(&mut env, b"cut", "3").unwrap();
+        run(&mut env, "DELETE gone");
+        assert_eq!(
+            get_state(&env, b"kept").unwrap(),
+            KeyState::Present(String::from("1"))
+        );
+        assert_eq!(get_state(&env, b"never").unwrap(), KeyState::Absent);
+        assert_eq!(get_state(&env, b"gone").unwrap(), KeyState::Deleted);
+        // the deletion is also seen from a retired segment
+        env.retire_write_segment().unwrap();
+        assert_eq!(get_state(&env, b"gone").unwrap(), KeyState::Deleted);
+        assert_eq!(lookup(&env, b"gone").unwrap(), None);
+
+        env.set_mmap(false);
+        let segment = env.segments.last().unwrap();
+        let offset = *segment.index.get(b"cut".as_slice()).unwrap();
+        File::options()
+            .write(true)
+            .open(&segment.file_path)
+            .unwrap()
+            .set_len(offset)
+            .unwrap();
+        assert!(matches!(
+            get_state(&env, b"cut"),
+            Err(KvError::OffsetBeyondEof { .. })
+        ));
+    }
+
+    #[test]
+    fn filterstats_estimates_close_to_the_configured_rate() {
+        let dir = ScratchDir::new();
+        std::fs::write(format!("{}/db.00001", dir.0), "").unwrap();
+        let mut env = open(&dir);
+        // one batch lands in a single segment whatever its size
+        let records: Vec<(Vec<u8>, Option<&str>)> = (0..2000)
+            .map(|i| (format!("key-{}", i).into_bytes(), Some("value")))
+            .collect();
+        set_batch(&mut env, &records).unwrap();
+        env.retire_write_segment().unwrap();
+
+        let output = run(&mut env, "FILTERSTATS 20000");
+        // the seeded empty segment comes first
+        let numbers = bracketed_numbers(output.lines().nth(1).unwrap());
+        // bits (rounded up to whole words), hashes, elements, estimated and measured rate
+        assert_eq!(numbers[0..3], [20_032.0, 7.0, 2000.0]);
+        // ten bits per key and seven hashes make for a rate of about 0.82%
+        let (estimated, measured) = (numbers[3], numbers[4]);
+        assert!((0.007..0.010).contains(&estimated), "{}", output);
+        assert!((0.004..0.013).contains(&measured), "{}", output);
+        assert!(output.ends_with("Filters: [2]\n"));
+        assert_eq!(
+            run(&mut env, "FILTERSTATS none"),
+            "Usage: FILTERSTATS [samples]\n"
+        );
+    }
+
+    #[test]
+    fn recent_lists_the_latest_mutations_newest_first() {
+        let dir = ScratchDir::new();
+        std::fs::write(format!("{}/db.00001", dir.0), "").unwrap();
+        let mut env = open(&dir);
+        run(&mut env, "SET a 1");
+        run(&mut env, "SET b 2");
+        run(&mut env, "DELETE a");
+
+        let output = run(&mut env, "RECENT 2");
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with("] DELETE key: [a]"), "{}", output);
+        assert!(
+            lines[1].ends_with("] SET key: [b] value: [2]"),
+            "{}",
+            output
+        );
+        assert!(run(&mut env, "RECENT").ends_with("] SET key: [a] value: [1]\n"));
+
+        for i in 0..RECENT_BUFFER_SIZE + 10 {
+            set_data(&mut env, format!("key-{}", i).as_bytes(), "value").unwrap();
+        }
+        let output = run(&mut env, "RECENT 1000");
+        assert_eq!(output.lines().count(), RECENT_BUFFER_SIZE);
+        let newest = format!("SET key: [key-{}]", RECENT_BUFFER_SIZE + 9);
+        assert!(output.lines().next().unwrap().contains(&newest));
+        assert!(output.lines().last().unwrap().contains("SET key: [key-10]"));
+        assert_eq!(run(&mut env, "RECENT x"), "Invalid count [x]\n");
+    }
+
+    #[test]
+    fn empty_values_are_kept_apart_from_tombstones() {
+        let dir = ScratchDir::new();
+        std::fs::write(format!("{}/db.00001", dir.0), "").unwrap();
+        let mut env = open(&dir);
+        run(&mut env, "SET empty \"\"");
+        assert!(matches!(
+            execute(&mut env, "GET empty"),
+            CommandResult::Value(Some(value)) if value.is_empty()
+        ));
+
+        run(&mut env, "SET gone value");
+        run(&mut env, "DELETE gone");
+        assert!(matches!(
+            execute(&mut env, "GET gone"),
+            CommandResult::Error(KvError::KeyDeleted { .. })
+        ));
+
+        run(&mut env, "SET revived value");
+        run(&mut env, "DELETE revived");
+        run(&mut env, "SET revived \"\"");
+        assert!(matches!(
+            execute(&mut env, "GET revived"),
+            CommandResult::Value(Some(value)) if value.is_empty()
+        ));
+
+        // read back from disk, from a retired segment
+        env.retire_write_segment().unwrap();
+        drop(env);
+        let mut env = open(&dir);
+        assert!(matches!(
+            execute(&mut env, "GET empty"),
+            CommandResult::Value(Some(value)) if value.is_empty()
+        ));
+        assert!(matches!(
+            execute(&mut env, "GET gone"),
+            CommandResult::Error(KvError::KeyDeleted { .. })
+        ));
+        assert!(matches!(
+            execute(&mut env, "GET revived"),
+            CommandResult::Value(Some(value)) if value.is_empty()
+        ));
+    }
+
+    #[test]
+    fn segments_found_in_any_order_are_read_oldest_first() {
+        let dir = ScratchDir::new();
+        std::fs::write(format!("{}/db.00001", dir.0), "").unwrap();
+        let mut env = open(&dir);
+        for round in ["old", "new"] {
+            for i in 0..60 {
+                set_data(&mut env, format!("key-{}", i).as_bytes(), round).unwrap();
+            }
+        }
+        env.retire_write_segment().unwrap();
+        assert!(env.segments.len() > 3);
+
+        env.segments.reverse();
+        assert_eq!(get(&env, "key-0").as_deref(), Some("old"));
+        env.reload().unwrap();
+        let numbers: Vec<u64> = env
+            .segments
+            .iter()
+            .map(|segment| {
+                let file_name = Path::new(&segment.file_path).file_name().unwrap();
+                NumericNamer
+                    .sequence("db", &file_name.to_string_lossy())
+                    .unwrap()
+            })
+            .collect();
+        assert!(
+            numbers.windows(2).all(|pair| pair[0] < pair[1]),
+            "{:?}",
+            numbers
+        );
+        for i in 0..60 {
+            assert_eq!(get(&env, &format!("key-{}", i)).as_deref(), Some("new"));
+        }
+    }
+
+    #[test]
+    fn the_first_retired_segment_of_a_fresh_directory_is_numbered_one() {
+        let dir = ScratchDir::new();
+        let mut env = open(&dir);
+        assert!(env.segments.is_empty());
+        // far more than SEGMENT_THRESHOLD, stopping at the first retirement
+        for i in 0..SEGMENT_THRESHOLD {
+            set_data(&mut env, format!("key-{}", i).as_bytes(), "value").unwrap();
+            if !env.segments.is_empty() {
+                break;
+            }
+        }
+        let file_names: Vec<String> = env
+            .segments
+            .iter()
+            .map(|segment| segment.file_path.clone())
+            .collect();
+        assert_eq!(
+            file_names,
+            [Path::new(&dir.0).join("db.00001").display().to_string()]
+        );
+    }
+
+    #[test]
+    fn keys_and_values_with_separators_round_trip() {
+        let dir = ScratchDir::new();
+        let mut env = open(&dir);
+        let records = [
+            ("csv,row", "a,b,c"),
+            ("json", "{\"lines\": [\n1,\n2\r\n]}"),
+            ("backslashes", "\\leading and trailing\\"),
+            ("\\n,", "\\,\\\\"),
+            ("empty", ""),
+        ];
+        for (key, value) in records {
+            set_data(&mut env, key.as_bytes(), value).unwrap();
+        }
+        for (key, value) in records {
+            assert_eq!(get(&env, key).as_deref(), Some(value));
+        }
+
+        // from disk, indexed by a scan of the retired segment
+        env.retire_write_segment().unwrap();
+        drop(env);
+        let env = open(&dir);
+        for (key, value) in records {
+            assert_eq!(get(&env, key).as_deref(), Some(value));
+        }
+        let index =
+            build_index(&FileStorage, &env.segments[0].file_path, RecordCodec::Text).unwrap();
+        let mut keys: Vec<&[u8]> = index.keys().map(Vec::as_slice).collect();
+        keys.sort();
+        let mut expected: Vec<&[u8]> = records.iter().map(|(key, _)| key.as_bytes()).collect();
+        expected.sort();
+        assert_eq!(keys, expected);
+    }
+
+    #[test]
+    fn kv_store_round_trips_through_a_reopen_and_compaction() {
+        let dir = ScratchDir::new();
+        let path = Path::new(&dir.0).join("store");
+        let mut store = KvStore::open(&path).unwrap();
+        store.set("a", "1").unwrap();
+        store.set(b"b", "2").unwrap();
+        store.remove("b").unwrap();
+        assert_eq!(store.get("a").unwrap().as_deref(), Some("1"));
+        assert_eq!(store.get("b").unwrap(), None);
+        assert_eq!(store.get_state("b").unwrap(), KeyState::Deleted);
+        assert_eq!(store.get_state("c").unwrap(), KeyState::Absent);
+        drop(store);
+
+        let mut store = KvStore::open(&path).unwrap();
+        store.compact().unwrap();
+        assert_eq!(
+            store.get_state("a").unwrap(),
+            KeyState::Present("1".to_string())
+        );
+        assert_eq!(store.get("b").unwrap(), None);
+    }
+
+    #[test]
+    fn a_corrupt_segment_fails_to_open_with_its_location() {
+        let dir = ScratchDir::new();
+        let file_path = Path::new(&dir.0).join("db.00001").display().to_string();
+        std::fs::write(&file_path, "key,value\nno separator\n").unwrap();
+        let err = Environment::with_namer(&dir.0, &String::from("db"), Box::new(NumericNamer))
+            .err()
+            .unwrap();
+        match err {
+            KvError::Corrupt {
+                file_path: corrupt_path,
+                offset,
+                line,
+            } => {
+                assert_eq!((corrupt_path, offset), (file_path, 10));
+                assert_eq!(line, "no separator");
+            }
+            e => panic!("unexpected error [{}]", e),
+        }
+        assert!(KvStore::open(&dir.0).is_err());
+    }
+
+    #[test]
+    fn a_hint_loads_the_same_index_as_a_scan() {
+        let dir = ScratchDir::new();
+        let mut env = open(&dir);
+        for round in ["first", "second"] {
+            for i in 0..30 {
+                set_data(&mut env, format!("key,{}", i).as_bytes(), round).unwrap();
+            }
+        }
+        delete_data(&mut env, b"key,3").unwrap();
+        env.retire_write_segment().unwrap();
+        assert!(env.segments.len() > 1);
+
+        for segment in env.segments.iter() {
+            let (hint, ..) = read_hint(&FileStorage, &segment.file_path).unwrap();
+            assert_eq!(
+                hint,
+                build_index(&FileStorage, &segment.file_path, RecordCodec::Text).unwrap()
+            );
+        }
+
+        // a segment that changed after its hint was written is scanned again
+        let file_path = env.segments[0].file_path.clone();
+        drop(env);
+        OpenOptions::new()
+            .append(true)
+            .open(&file_path)
+            .unwrap()
+            .write_all(b"extra,1\n")
+            .unwrap();
+        assert!(read_hint(&FileStorage, &file_path).is_none());
+        let env = open(&dir);
+        assert_eq!(get(&env, "extra").as_deref(), Some("1"));
+    }
+
+    #[test]
+    fn openstats_reports_segments_loaded_from_their_hint() {
+        let dir = ScratchDir::new();
+        let mut env = open(&dir);
+        set_data(&mut env, b"a", "1").unwrap();
+        set_data(&mut env, b"b", "2").unwrap();
+        env.retire_write_segment().unwrap();
+        let retired = env.segments[0].file_path.clone();
+        assert!(Path::new(&hint_path(&retired)).exists());
+        drop(env);
+
+        let mut env = open(&dir);
+        let report = run(&mut env, "OPENSTATS");
+        let lines: Vec<&str> = report.lines().collect();
+        assert!(
+            lines[0].starts_with(&format!("[{}] hint: [", retired)),
+            "{}",
+            report
+        );
+        assert!(lines[0].ends_with("keys: [2]"));
+        assert!(lines[1].contains("] full scan: ["));
+        assert!(lines[2].ends_with("keys: [2]"));
+
+        // without its hint the segment is scanned
+        drop(env);
+        remove_file(hint_path(&retired)).unwrap();
+        let mut env = open(&dir);
+        assert!(run(&mut env, "OPENSTATS").starts_with(&format!("[{}] full scan: [", retired)));
+    }
+
+    #[test]
+    fn a_value_that_fails_its_checksum_is_reported() {
+        let dir = ScratchDir::new();
+        let mut env = open(&dir);
+        set_data(&mut env, b"plain", "old").unwrap();
+        env.enable_checksums();
+        set_data(&mut env, b"checked", "value").unwrap();
+        let file_path = env.write_segment.file_path.clone();
+        let contents = std::fs::read_to_string(&file_path).unwrap();
+        std::fs::write(&file_path, contents.replace("value", "valve")).unwrap();
+        assert_eq!(get(&env, "plain"), Some(String::from("old")));
+        match get_state(&env, b"checked") {
+            Err(KvError::ChecksumMismatch {
+                file_path: path, ..
+            }) => assert_eq!(path, file_path),
+            other => panic!("unexpected result [{:?}]", other.map(|_| ())),
+        }
+        drop(env);
+        match Environment::with_namer(&dir.0, &String::from("db"), Box::new(NumericNamer)) {
+            Err(KvError::ChecksumMismatch {
+                file_path: path, ..
+            }) => assert_eq!(path, file_path),
+            other => panic!("unexpected result [{:?}]", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn a_torn_last_write_is_cut_off_on_open() {
+        let dir = ScratchDir::new();
+        let mut env = open(&dir);
+        set_data(&mut env, b"a", "1").unwrap();
+        set_data(&mut env, b"b", "2").unwrap();
+        drop(env);
+        let write_segment_path = Path::new(&dir.0).join(format!("db.{}", CURRENT_SEGMENT_SUFFIX));
+        let intact = std::fs::read(&write_segment_path).unwrap();
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&write_segment_path)
+            .unwrap()
+            .write_all(b"half-writ")
+            .unwrap();
+
+        let mut env = open(&dir);
+        assert_eq!(std::fs::read(&write_segment_path).unwrap(), intact);
+        assert_eq!(get(&env, "a").as_deref(), Some("1"));
+        assert_eq!(get(&env, "b").as_deref(), Some("2"));
+        assert_eq!(get(&env, "half-writ"), None);
+        set_data(&mut env, b"c", "3").unwrap();
+        drop(env);
+        let env = open(&dir);
+        assert_eq!(get(&env, "c").as_deref(), Some("3"));
+    }
+
+    #[test]
+    fn writes_during_a_background_compaction_are_kept() {
+        let dir = ScratchDir::new();
+        let mut env = open(&dir);
+        for round in ["old", "compacted"] {
+            for i in 0..40 {
+                set_data(&mut env, format!("key-{}", i).as_bytes(), round).unwrap();
+            }
+        }
+        run(&mut env, "DELETE key-0");
+        env.retire_write_segment().unwrap();
+        let inputs = env.segments.len();
+
+        let mut job = env.start_compaction().unwrap().unwrap();
+        let env = Arc::new(Mutex::new(env));
+        let compaction = std::thread::spawn(move || {
+            let result = job.run();
+            (job, result)
+        });
+        // overwrites of compacted keys and new keys, enough to retire segments
+        for i in 0..20 {
+            let mut locked = env.lock().unwrap();
+            set_data(&mut locked, format!("key-{}", i * 2).as_bytes(), "during").unwrap();
+            set_data(&mut locked, format!("new-{}", i).as_bytes(), "during").unwrap();
+            drop(locked);
+            let shared = env.lock().unwrap();
+            assert_eq!(get(&shared, "key-1").as_deref(), Some("compacted"));
+            assert_eq!(
+                get(&shared, &format!("new-{}", i)).as_deref(),
+                Some("during")
+            );
+        }
+        let (job, result) = compaction.join().unwrap();
+        let mut env = Arc::into_inner(env).unwrap().into_inner().unwrap();
+        assert!(env.segments.len() > inputs);
+        env.finish_compaction(job, result).unwrap();
+
+        let check = |env: &Environment| {
+            for i in 0..40 {
+                let expected = match i % 2 == 0 {
+                    true => "during",
+                    false => "compacted",
+                };
+                assert_eq!(get(env, &format!("key-{}", i)).as_deref(), Some(expected));
+            }
+            for i in 0..20 {
+                assert_eq!(get(env, &format!("new-{}", i)).as_deref(), Some("during"));
+            }
+        };
+        check(&env);
+        drop(env);
+        check(&open(&dir));
+    }
+
+    #[test]
+    fn a_missing_data_directory_is_created_with_its_parents() {
+        let dir = ScratchDir::new();
+        let nested = Path::new(&dir.0).join("a/b/c").display().to_string();
+        let mut env =
+            Environment::with_namer(&nested, &String::from("db"), Box::new(NumericNamer)).unwrap();
+        assert!(Path::new(&nested).is_dir());
+        set_data(&mut env, b"a", "1").unwrap();
+        assert_eq!(get(&env, "a").as_deref(), Some("1"));
+
+        // a file in the way is reported rather than a panic
+        let file_path = Path::new(&dir.0).join("file").display().to_string();
+        std::fs::write(&file_path, "").unwrap();
+        for data_path in [file_path.clone(), format!("{}/data", file_path)] {
+            let opened =
+                Environment::with_namer(&data_path, &String::from("db"), Box::new(NumericNamer));
+            assert!(opened.is_err());
+        }
+    }
+
+    #[test]
+    fn scan_prefix_returns_the_newest_live_values_under_a_prefix() {
+        let dir = ScratchDir::new();
+        let mut store = KvStore::open(&dir.0).unwrap();
+        for (key, value) in [
+            ("user:1:email", "old@example.com"),
+            ("user:1:name", "one"),
+            ("user:12:name", "twelve"),
+            ("user:1", "bare"),
+            ("account:1", "other"),
+        ] {
+            store.set(key, value).unwrap();
+        }
+        store.env.retire_write_segment().unwrap();
+        store.set("user:1:email", "new@example.com").unwrap();
+        store.remove("user:1:name").unwrap();
+
+        let scan = |prefix: &str| {
+            let mut records: Vec<(Vec<u8>, String)> = store
+                .scan_prefix(prefix)
+                .unwrap()
+                .map(|record| record.unwrap())
+                .collect();
+            records.sort();
+            records
+        };
+        let owned = |records: &[(&str, &str)]| {
+            records
+                .iter()
+                .map(|(key, value)| (key.as_bytes().to_vec(), value.to_string()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            scan("user:1:"),
+            owned(&[("user:1:email", "new@example.com")])
+        );
+        assert_eq!(
+            scan("user:1"),
+            owned(&[
+                ("user:1", "bare"),
+                ("user:12:name", "twelve"),
+                ("user:1:email", "new@example.com"),
+            ])
+        );
+        assert_eq!(scan("nobody"), Vec::new());
+
+        let output = run(&mut store.env, "SCAN --prefix user:12");
+        assert_eq!(output, "user:12:name twelve\nMatched keys: [1]\n");
+    }
+
+    #[test]
+    fn contains_key_sees_tombstones_in_newer_segments() {
+        let dir = ScratchDir::new();
+        let mut store = KvStore::open(&dir.0).unwrap();
+        store.set("present", "1").unwrap();
+        store.set("deleted", "1").unwrap();
+        store.env.retire_write_segment().unwrap();
+        store.remove("deleted").unwrap();
+
+        assert!(store.contains_key("present").unwrap());
+        assert!(!store.contains_key("absent").unwrap());
+        assert!(!store.contains_key("deleted").unwrap());
+        assert_eq!(run(&mut store.env, "EXISTS present"), "1\n");
+        assert_eq!(run(&mut store.env, "EXISTS absent"), "0\n");
+        assert_eq!(run(&mut store.env, "EXISTS deleted"), "0\n");
+    }
+
+    #[test]
+    fn a_batch_cut_short_by_a_crash_leaves_none_of_its_records() {
+        let dir = ScratchDir::new();
+        let mut store = KvStore::open(&dir.0).unwrap();
+        store.env.segment_threshold = u64::MAX;
+        store.set("before", "1").unwrap();
+        let mut batch = WriteBatch::new();
+        batch.set("a", "1").set("b", "2").remove("before");
+        store.write(batch).unwrap();
+        assert_eq!(run(&mut store.env, "MSET c 3 d 4"), "Written [2] keys\n");
+        drop(store);
+
+        // the crash hit while the second batch was written: its last record is lost
+        let write_segment_path = Path::new(&dir.0).join(format!("db.{}", CURRENT_SEGMENT_SUFFIX));
+        let contents = std::fs::read(&write_segment_path).unwrap();
+        let last_line = contents[..contents.len() - 1]
+            .iter()
+            .rposition(|byte| *byte == b'\n')
+            .unwrap();
+        std::fs::write(&write_segment_path, &contents[..last_line + 1]).unwrap();
+
+        let store = KvStore::open(&dir.0).unwrap();
+        assert_eq!(store.get("before").unwrap(), None);
+        assert_eq!(store.get("a").unwrap().as_deref(), Some("1"));
+        assert_eq!(store.get("b").unwrap().as_deref(), Some("2"));
+        assert_eq!(store.get("c").unwrap(), None);
+        assert_eq!(store.get("d").unwrap(), None);
+        drop(store);
+
+        // the first batch losing its tombstone loses all of it
+        let records = String::from_utf8(contents).unwrap();
+        // `before`, then the first two records of the batch
+        let lines: Vec<&str> = records.lines().take(3).collect();
+        std::fs::write(&write_segment_path, format!("{}\n", lines.join("\n"))).unwrap();
+        let store = KvStore::open(&dir.0).unwrap();
+        assert_eq!(store.get("before").unwrap().as_deref(), Some("1"));
+        assert_eq!(store.get("a").unwrap(), None);
+        assert_eq!(store.get("b").unwrap(), None);
+    }
+
+    #[test]
+    fn incr_counts_from_absent_and_rejects_overflow_and_non_integers() {
+        let dir = ScratchDir::new();
+        let mut store = KvStore::open(&dir.0).unwrap();
+        assert_eq!(store.incr("hits", 5).unwrap(), 5);
+        assert_eq!(store.incr("hits", -7).unwrap(), -2);
+        assert_eq!(run(&mut store.env, "INCR hits"), "-1\n");
+        assert_eq!(run(&mut store.env, "DECR missing"), "-1\n");
+        store.remove("hits").unwrap();
+        assert_eq!(store.incr("hits", 1).unwrap(), 1);
+
+        store.set("big", &i64::MAX.to_string()).unwrap();
+        assert!(matches!(
+            store.incr("big", 1),
+            Err(KvError::IntegerOverflow { key }) if key == "big"
+        ));
+        assert_eq!(store.get("big").unwrap(), Some(i64::MAX.to_string()));
+        assert_eq!(store.incr("big", -1).unwrap(), i64::MAX - 1);
+
+        store.set("name", "twelve").unwrap();
+        assert!(matches!(
+            store.incr("name", 1),
+            Err(KvError::NotAnInteger { key, value }) if key == "name" && value == "twelve"
+        ));
+        assert_eq!(store.get("name").unwrap().as_deref(), Some("twelve"));
+        assert!(run(&mut store.env, "INCR name").starts_with("Could not update key [name]."));
+    }
+
+    #[test]
+    fn appends_build_a_value_across_segment_rolls() {
+        let dir = ScratchDir::new();
+        let mut store = KvStore::open(&dir.0).unwrap();
+        assert_eq!(store.append("log", "start,").unwrap(), 6);
+        let mut expected = String::from("start,");
+        for i in 0..20 {
+            let line = format!("line {},\\{}\n", i, i);
+            expected.push_str(&line);
+            assert_eq!(store.append("log", &line).unwrap(), expected.len());
+        }
+        assert!(!store.env.segments.is_empty());
+        assert_eq!(store.get("log").unwrap(), Some(expected.clone()));
+        assert_eq!(run(&mut store.env, "APPEND other abc"), "3\n");
+        assert_eq!(run(&mut store.env, "APPEND other \"\""), "3\n");
+        drop(store);
+
+        let store = KvStore::open(&dir.0).unwrap();
+        assert_eq!(store.get("log").unwrap(), Some(expected));
+        assert_eq!(store.get("other").unwrap().as_deref(), Some("abc"));
+    }
+
+    #[test]
+    fn compare_and_swap_writes_only_on_a_match() {
+        let dir = ScratchDir::new();
+        let mut store = KvStore::open(&dir.0).unwrap();
+        assert!(store.compare_and_swap("k", None, "first").unwrap());
+        assert!(!store.compare_and_swap("k", None, "second").unwrap());
+        assert!(
+            !store
+                .compare_and_swap("k", Some("wrong"), "second")
+                .unwrap()
+        );
+        assert_eq!(store.get("k").unwrap().as_deref(), Some("first"));
+        assert!(
+            store
+                .compare_and_swap("k", Some("first"), "second")
+                .unwrap()
+        );
+        assert_eq!(store.get("k").unwrap().as_deref(), Some("second"));
+
+        // a deleted key counts as absent, not as the value it shadows
+        store.env.retire_write_segment().unwrap();
+        store.remove("k").unwrap();
+        assert!(
+            !store
+                .compare_and_swap("k", Some("second"), "third")
+                .unwrap()
+        );
+        assert!(store.compare_and_swap("k", None, "third").unwrap());
+
+        assert_eq!(run(&mut store.env, "CAS k third fourth"), "1\n");
+        assert_eq!(run(&mut store.env, "CAS k third fifth"), "0\n");
+        assert_eq!(run(&mut store.env, "CAS other --absent 1"), "1\n");
+        assert_eq!(store.get("k").unwrap().as_deref(), Some("fourth"));
+    }
+
+    #[test]
+    fn expired_keys_read_as_absent_and_are_dropped_by_compaction() {
+        static NOW: AtomicU64 = AtomicU64::new(1_000_000);
+        let dir = ScratchDir::new();
+        let mut env = open(&dir);
+        env.clock = || NOW.load(Ordering::Relaxed);
+        run(&mut env, "SETEX cache 10 value");
+        run(&mut env, "SET session token");
+        run(&mut env, "SET kept forever");
+        assert_eq!(run(&mut env, "EXPIRE session 5"), "1\n");
+        assert_eq!(run(&mut env, "EXPIRE missing 5"), "0\n");
+
+        NOW.fetch_add(5_000, Ordering::Relaxed);
+        assert_eq!(get(&env, "session"), None);
+        assert_eq!(get(&env, "cache").as_deref(), Some("value"));
+        NOW.fetch_add(5_000, Ordering::Relaxed);
+        assert_eq!(get(&env, "cache"), None);
+        assert_eq!(run(&mut env, "EXPIRE cache 5"), "0\n");
+        assert_eq!(get(&env, "kept").as_deref(), Some("forever"));
+
+        env.retire_write_segment().unwrap();
+        run(&mut env, "COMPACT");
+        let mut keys = HashSet::new();
+        for segment in env.segments.iter() {
+            keys.extend(
+                build_index(&FileStorage, &segment.file_path, RecordCodec::Text)
+                    .unwrap()
+                    .into_keys(),
+            );
+        }
+        assert_eq!(keys, HashSet::from([b"kept".to_vec()]));
+    }
+
+    #[test]
+    fn compaction_keeps_the_highest_sequence_whatever_the_segment_order() {
+        let dir = ScratchDir::new();
+        let mut env = open(&dir);
+        for value in ["old", "new"] {
+            set_data(&mut env, b"a", value).unwrap();
+            set_data(&mut env, format!("only-{}", value).as_bytes(), value).unwrap();
+            env.retire_write_segment().unwrap();
+        }
+        drop(env);
+        // the newer records end up in the lower numbered segment
+        let first = Path::new(&dir.0).join("db.00001").display().to_string();
+        let second = Path::new(&dir.0).join("db.00002").display().to_string();
+        let (older, newer) = (
+            std::fs::read(&first).unwrap(),
+            std::fs::read(&second).unwrap(),
+        );
+        std::fs::write(&first, newer).unwrap();
+        std::fs::write(&second, older).unwrap();
+        for file_path in [&first, &second] {
+            std::fs::remove_file(hint_path(file_path)).unwrap();
+        }
+
+        let mut env = open(&dir);
+        env.compact_segments().unwrap();
+        assert_eq!(env.segments.len(), 1);
+        assert_eq!(get(&env, "a").as_deref(), Some("new"));
+        assert_eq!(get(&env, "only-old").as_deref(), Some("old"));
+        assert_eq!(get(&env, "only-new").as_deref(), Some("new"));
+    }
+
+    #[test]
+    fn sequence_numbers_carry_on_past_a_clock_set_back_across_a_reopen() {
+        for keep_hints in [true, false] {
+            let dir = ScratchDir::new();
+            let mut env = open(&dir);
+            env.clock = || 2_000_000;
+            set_data(&mut env, b"a", "old").unwrap();
+            env.retire_write_segment().unwrap();
+            set_data(&mut env, b"b", "old").unwrap();
+            env.close().unwrap();
+            drop(env);
+            if !keep_hints {
+                for name in ["db.00001", "db.current"] {
+                    let file_path = Path::new(&dir.0).join(name).display().to_string();
+                    std::fs::remove_file(hint_path(&file_path)).unwrap();
+                }
+            }
+
+            let mut env = open(&dir);
+            env.clock = || 1_000_000;
+            set_data(&mut env, b"a", "new").unwrap();
+            set_data(&mut env, b"b", "new").unwrap();
+            env.retire_write_segment().unwrap();
+            env.compact_segments().unwrap();
+            assert_eq!(get(&env, "a").as_deref(), Some("new"), "{}", keep_hints);
+            assert_eq!(get(&env, "b").as_deref(), Some("new"), "{}", keep_hints);
+        }
+    }
+
+    #[test]
+    fn every_write_syncs_each_write_before_it_returns() {
+        let dir = ScratchDir::new();
+        let mut store = KvStore::open(&dir.0).unwrap();
+        store.set_sync_policy(SyncPolicy::EveryWrite);
+        for i in 0..5 {
+            store.set(format!("key-{}", i), "value").unwrap();
+            assert_eq!(store.env.unsynced_writes, 0);
+        }
+        store.set_sync_policy(SyncPolicy::EveryN(3));
+        let unsynced: Vec<usize> = (0..4)
+            .map(|i| {
+                store.set(format!("batched-{}", i), "value").unwrap();
+                store.env.unsynced_writes
+            })
+            .collect();
+        assert_eq!(unsynced, [1, 2, 0, 1]);
+        // gone without being closed, as in a crash
+        std::mem::forget(store);
+
+        let store = KvStore::open(&dir.0).unwrap();
+        for i in 0..5 {
+            assert_eq!(
+                store.get(format!("key-{}", i)).unwrap().as_deref(),
+                Some("value")
+            );
+        }
+    }
+
+    #[test]
+    fn the_append_handle_keeps_offsets_in_step_with_the_file() {
+        let dir = ScratchDir::new();
+        for round in 0..2 {
+            // the second round appends through a handle opened on an existing file
+            let mut env = open(&dir);
+            for i in 0..60 {
+                let key = format!("key-{}", i % 9);
+                let value = format!("{}\n{}", round, "é".repeat(i % 7));
+                match i % 10 {
+                    9 => write_batch(&mut env, &[(key.clone().into_bytes(), Some(value.clone()))])
+                        .unwrap(),
+                    _ => set_data(&mut env, key.as_bytes(), &value).unwrap(),
+                }
+                let file_path = env.write_segment.file_path.clone();
+                let file_len = std::fs::metadata(&file_path).unwrap().len();
+                assert_eq!(env.write_segment.size, file_len);
+                assert_eq!(
+                    env.write_segment.index,
+                    SegmentIndex::Hashed(
+                        build_index(&FileStorage, &file_path, RecordCodec::Text).unwrap()
+                    )
+                );
+                assert_eq!(get(&env, &key), Some(value));
+            }
+            assert!(!env.segments.is_empty());
+        }
+    }
+
+    #[test]
+    fn bloom_filters_skip_segments_without_false_negatives() {
+        let dir = ScratchDir::new();
+        let mut env = open(&dir);
+        for i in 0..100 {
+            set_data(&mut env, format!("key-{}", i).as_bytes(), "value").unwrap();
+        }
+        env.retire_write_segment().unwrap();
+        drop(env);
+        // filters read back from disk
+        let env = open(&dir);
+        let segments = env.segments.len() as u64;
+        assert!(segments > 5);
+
+        for segment in env.segments.iter() {
+            assert!(segment.filter.is_some());
+            for key in build_index(&FileStorage, &segment.file_path, RecordCodec::Text)
+                .unwrap()
+                .keys()
+            {
+                assert!(segment.may_contain(key), "{:?}", key);
+            }
+        }
+        for i in 0..100 {
+            assert_eq!(get(&env, &format!("key-{}", i)).as_deref(), Some("value"));
+        }
+
+        let skipped = || env.metrics.filter_negatives.load(Ordering::Relaxed);
+        let before = skipped();
+        for i in 0..1000 {
+            assert_eq!(get(&env, &format!("absent-{}", i)), None);
+        }
+        // a full probe would look into every segment for each of them
+        let probed = segments * 1000 - (skipped() - before);
+        assert!(
+            probed < segments * 1000 / 20,
+            "{} of {}",
+            probed,
+            segments * 1000
+        );
+    }
+
+    #[test]
+    fn cached_values_are_invalidated_by_updates_and_deletes() {
+        let dir = ScratchDir::new();
+        let mut env = open(&dir);
+        env.set_value_cache_capacity(2);
+        for key in ["a", "b", "c"] {
+            set_data(&mut env, key.as_bytes(), "old").unwrap();
+        }
+        env.retire_write_segment().unwrap();
+        let cached = |env: &Environment| {
+            let mut keys: Vec<String> = env
+                .value_cache
+                .lock()
+                .unwrap()
+                .entries
+                .keys()
+                .map(|key| String::from_utf8(key.clone()).unwrap())
+                .collect();
+            keys.sort();
+            keys
+        };
+
+        // the least recently read key makes room
+        for key in ["a", "b", "a", "c"] {
+            assert_eq!(get(&env, key).as_deref(), Some("old"));
+        }
+        assert_eq!(cached(&env), ["a", "c"]);
+        // a hit is served without reading the segment, emptied meanwhile
+        let file_path = env.segments[0].file_path.clone();
+        let contents = std::fs::read(&file_path).unwrap();
+        std::fs::write(&file_path, "").unwrap();
+        assert_eq!(get(&env, "a").as_deref(), Some("old"));
+        std::fs::write(&file_path, contents).unwrap();
+
+        set_data(&mut env, b"a", "new").unwrap();
+        assert_eq!(cached(&env), ["c"]);
+        assert_eq!(get(&env, "a").as_deref(), Some("new"));
+        run(&mut env, "DELETE c");
+        assert_eq!(get(&env, "c"), None);
+        assert_eq!(get(&env, "a").as_deref(), Some("new"));
+    }
+
+    #[test]
+    fn exceeding_max_segments_schedules_a_compaction() {
+        let dir = ScratchDir::new();
+        let mut env = open(&dir);
+        env.max_segments = Some(3);
+        let mut most_segments = 0;
+        let mut compactions = 0;
+        for i in 0..200 {
+            set_data(
+                &mut env,
+                format!("key-{}", i % 20).as_bytes(),
+                &i.to_string(),
+            )
+            .unwrap();
+            most_segments = most_segments.max(env.segments.len());
+            // run as a server's background thread runs it
+            if let Some(mut job) = env.take_compaction_job() {
+                let result = job.run();
+                env.finish_compaction(job, result).unwrap();
+                compactions += 1;
+            }
+        }
+        assert!(compactions > 1);
+        assert!(most_segments <= 4, "{}", most_segments);
+        assert!(env.segments.len() <= 4);
+        for i in 180..200 {
+            assert_eq!(get(&env, &format!("key-{}", i % 20)), Some(i.to_string()));
+        }
+    }
+
+    #[test]
+    fn tombstones_are_kept_unless_the_merge_covers_every_segment() {
+        let mut total_data = HashMap::new();
+        for record in [
+            Record::tombstone(b"deleted"),
+            Record::new(b"kept", b"value"),
+        ] {
+            total_data.insert(record.key.clone(), record);
+        }
+        let keys = |covers_all_segments| {
+            live_records(total_data.clone(), 0, covers_all_segments, &ByteOrder)
+                .into_iter()
+                .map(|record| record.key)
+                .collect::<Vec<Vec<u8>>>()
+        };
+        // an older segment left out of the merge may still hold the key
+        assert_eq!(keys(false), [b"deleted".to_vec(), b"kept".to_vec()]);
+        assert_eq!(keys(true), [b"kept".to_vec()]);
+    }
+
+    #[test]
+    fn a_delete_survives_a_compaction_that_leaves_its_value_out() {
+        let dir = ScratchDir::new();
+        let mut env = open(&dir);
+        set_data(&mut env, b"k", "value").unwrap();
+        env.retire_write_segment().unwrap();
+        delete_data(&mut env, b"k").unwrap();
+        env.retire_write_segment().unwrap();
+        set_data(&mut env, b"other", "value").unwrap();
+        env.retire_write_segment().unwrap();
+        // the tier of the newer segments, the one holding the value left out
+        env.compact_range(1..env.segments.len(), None).unwrap();
+        assert_eq!(get(&env, "k"), None);
+        drop(env);
+
+        let env = open(&dir);
+        assert_eq!(get(&env, "k"), None);
+        assert_eq!(get(&env, "other"), Some(String::from("value")));
+    }
+
+    #[test]
+    fn range_includes_its_start_and_excludes_its_end() {
+        for ordered in [false, true] {
+            let dir = ScratchDir::new();
+            let mut store = KvStore::open(&dir.0).unwrap();
+            store.set_ordered_index(ordered);
+            for key in ["a", "b", "ba", "c", "d"] {
+                store.set(key, "old").unwrap();
+            }
+            store.env.retire_write_segment().unwrap();
+            store.set("b", "new").unwrap();
+            store.remove("c").unwrap();
+
+            let range = |start: &str, end: &str| -> Vec<(String, String)> {
+                store
+                    .range(start, end)
+                    .unwrap()
+                    .map(|record| {
+                        let (key, value) = record.unwrap();
+                        (String::from_utf8(key).unwrap(), value)
+                    })
+                    .collect()
+            };
+            let keys = |start: &str, end: &str| -> Vec<String> {
+                range(start, end).into_iter().map(|(key, _)| key).collect()
+            };
+            assert_eq!(keys("b", "d"), ["b", "ba"]);
+            assert_eq!(keys("a", "e"), ["a", "b", "ba", "d"]);
+            assert_eq!(range("b", "ba"), [(String::from("b"), String::from("new"))]);
+            assert!(keys("b", "b").is_empty());
+            assert!(keys("d", "a").is_empty());
+            assert!(keys("x", "z").is_empty());
+        }
+    }
+
+    #[test]
+    fn keys_yields_each_live_key_once() {
+        static NOW: AtomicU64 = AtomicU64::new(1_000_000);
+        let dir = ScratchDir::new();
+        let mut store = KvStore::open(&dir.0).unwrap();
+        store.env.clock = || NOW.load(Ordering::Relaxed);
+        for key in ["kept", "overwritten", "deleted"] {
+            store.set(key, "old").unwrap();
+        }
+        let ttl = std::time::Duration::from_secs(1);
+        set_with_expiry(&mut store.env, b"expired", "value", ttl).unwrap();
+        store.env.retire_write_segment().unwrap();
+        store.set("overwritten", "new").unwrap();
+        store.remove("deleted").unwrap();
+        set_with_expiry(&mut store.env, b"expiring", "value", ttl * 10).unwrap();
+        NOW.fetch_add(2_000, Ordering::Relaxed);
+
+        let mut keys: Vec<Vec<u8>> = store.keys().unwrap().map(|key| key.unwrap()).collect();
+        keys.sort();
+        assert_eq!(
+            keys,
+            [
+                b"expiring".to_vec(),
+                b"kept".to_vec(),
+                b"overwritten".to_vec()
+            ]
+        );
+    }
+
+    #[test]
+    fn an_export_imported_into_an_empty_store_matches_the_original() {
+        let dir = ScratchDir::new();
+        let mut store = KvStore::open(&dir.0).unwrap();
+        let records = [
+            ("plain", "value"),
+            ("quote\"d", "back\\slash"),
+            ("lines", "one\ntwo\r\n\ttabbed"),
+            ("control", "\u{1}\u{1f}"),
+            ("unicode", "été ✓"),
+            ("empty", ""),
+        ];
+        for (key, value) in records {
+            store.set(key, "old").unwrap();
+            store.set(key, value).unwrap();
+        }
+        store.set("deleted", "value").unwrap();
+        store.remove("deleted").unwrap();
+        let live = |store: &KvStore| -> Vec<(Vec<u8>, String)> {
+            let mut keys: Vec<Vec<u8>> = store.keys().unwrap().map(|key| key.unwrap()).collect();
+            keys.sort();
+            keys.into_iter()
+                .map(|key| {
+                    let value = store.get(&key).unwrap().unwrap();
+                    (key, value)
+                })
+                .collect()
+        };
+        let original = live(&store);
+
+        let mut exported = Vec::new();
+        assert_eq!(
+            store.export_json(&mut exported).unwrap(),
+            records.len() as u64
+        );
+        drop(store);
+        std::fs::remove_dir_all(&dir.0).unwrap();
+
+        let mut store = KvStore::open(&dir.0).unwrap();
+        assert_eq!(
+            store.import_json(exported.as_slice()).unwrap(),
+            records.len() as u64
+        );
+        assert_eq!(live(&store), original);
+        // importing again changes nothing
+        assert_eq!(store.import_json(exported.as_slice()).unwrap(), 0);
+        assert_eq!(live(&store), original);
+    }
+
+    #[test]
+    fn values_read_the_same_from_compressed_segments() {
+        let dir = ScratchDir::new();
+        let mut env = open(&dir);
+        let value = |i: usize| format!("repetitive,{}\n{}", i, "text ".repeat(i % 7));
+        for i in 0..20 {
+            set_data(&mut env, format!("plain-{}", i).as_bytes(), &value(i)).unwrap();
+        }
+        env.retire_write_segment().unwrap();
+        let plain_segments = env.segments.len();
+        env.compress = true;
+        for i in 0..20 {
+            set_data(&mut env, format!("compressed-{}", i).as_bytes(), &value(i)).unwrap();
+        }
+        run(&mut env, "DELETE plain-3");
+        env.retire_write_segment().unwrap();
+
+        let compressed: Vec<&Segment> = env
+            .segments
+            .iter()
+            .filter(|segment| {
+                segment
+                    .file_path
+                    .ends_with(&format!(".{}", COMPRESSED_SUFFIX))
+            })
+            .collect();
+        assert_eq!(compressed.len(), env.segments.len() - plain_segments);
+        assert!(!compressed.is_empty());
+        drop(env);
+
+        // both kinds side by side after reopening
+        let env = open(&dir);
+        for i in 0..20 {
+            assert_eq!(get(&env, &format!("compressed-{}", i)), Some(value(i)));
+            let expected = (i != 3).then(|| value(i));
+            assert_eq!(get(&env, &format!("plain-{}", i)), expected);
+        }
+    }
+
+    #[test]
+    fn stats_count_segments_and_bytes_after_known_writes() {
+        let dir = ScratchDir::new();
+        let mut store = KvStore::open(&dir.0).unwrap();
+        store.env.segment_threshold = u64::MAX;
+        for i in 0..10 {
+            store.set(format!("key-{}", i), "value").unwrap();
+        }
+        store.env.retire_write_segment().unwrap();
+        for i in 0..5 {
+            store.set(format!("key-{}", i), "newer").unwrap();
+        }
+        store.remove("key-8").unwrap();
+        store.remove("key-9").unwrap();
+        store.env.retire_write_segment().unwrap();
+        store.set("last", "value").unwrap();
+
+        let file_size = |file_path: &str| std::fs::metadata(file_path).unwrap().len();
+        let write_segment_bytes = file_size(&store.env.write_segment.file_path);
+        let retired_bytes: u64 = store
+            .env
+            .segments
+            .iter()
+            .map(|s| file_size(&s.file_path))
+            .sum();
+        let stats = store.stats().unwrap();
+        assert_eq!(stats.retired_segments, 2);
+        assert_eq!(stats.write_segment_bytes, write_segment_bytes);
+        assert_eq!(stats.disk_bytes, retired_bytes + write_segment_bytes);
+        assert_eq!(stats.live_keys, 9);
+        assert_eq!(stats.tombstones, 2);
+        assert!(stats.reclaimable_bytes > 0);
+
+        let output = run(&mut store.env, "STATS");
+        assert!(output.starts_with("retired segments:    2\n"), "{}", output);
+        assert!(output.contains("live keys:           9\n"));
+        store.compact().unwrap();
+        let stats = store.stats().unwrap();
+        assert_eq!((stats.tombstones, stats.reclaimable_bytes), (0, 0));
+        assert_eq!(stats.live_keys, 9);
+    }
+
+    #[test]
+    fn keys_and_values_at_their_limit_are_written_and_one_byte_over_refused() {
+        let dir = ScratchDir::new();
+        let mut store = KvStore::open(&dir.0).unwrap();
+        store.env.max_key_len = Some(8);
+        store.env.max_value_len = Some(16);
+        let size_before = store.env.write_segment.size;
+
+        store.set("k".repeat(8).as_str(), "v").unwrap();
+        store.set("key", "v".repeat(16).as_str()).unwrap();
+        let size_at_limit = store.env.write_segment.size;
+        assert!(size_at_limit > size_before);
+
+        let key_error = store.set("k".repeat(9).as_str(), "v").unwrap_err();
+        assert!(
+            matches!(key_error, KvError::KeyTooLong { limit: 8 }),
+            "{:?}",
+            key_error
+        );
+        let value_error = store.set("key", "v".repeat(17).as_str()).unwrap_err();
+        assert!(
+            matches!(value_error, KvError::ValueTooLarge { limit: 16 }),
+            "{:?}",
+            value_error
+        );
+        // nothing written by either
+        assert_eq!(store.env.write_segment.size, size_at_limit);
+        assert_eq!(store.get("key").unwrap(), Some("v".repeat(16)));
+
+        assert!(matches!(
+            execute(&mut store.env, &format!("SET key {}", "v".repeat(17))),
+            CommandResult::Error(KvError::ValueTooLarge { limit: 16 })
+        ));
+        assert_eq!(store.get("key").unwrap(), Some("v".repeat(16)));
+    }
+
+    #[test]
+    fn readers_share_the_store_while_a_writer_rolls_and_compacts() {
+        use std::sync::atomic::AtomicBool;
+        use std::sync::{Barrier, RwLock};
+        const READERS: usize = 4;
+        const KEYS: usize = 32;
+        const ROUNDS: u64 = 20;
+
+        let dir = ScratchDir::new();
+        let mut store = KvStore::open(&dir.0).unwrap();
+        store.env.max_segments = Some(4);
+        let keys: Vec<String> = (0..KEYS).map(|i| format!("key-{}", i)).collect();
+        for key in keys.iter() {
+            store.set(key, "0").unwrap();
+        }
+        let store = RwLock::new(store);
+        // opens only once every reader holds the read lock at the same time
+        let all_reading = Barrier::new(READERS);
+        let started = Barrier::new(READERS + 1);
+        let done = AtomicBool::new(false);
+
+        std::thread::scope(|scope| {
+            let readers: Vec<_> = (0..READERS)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let shared = store.read().unwrap();
+                        all_reading.wait();
+                        assert_eq!(shared.get("key-0").unwrap().as_deref(), Some("0"));
+                        drop(shared);
+                        started.wait();
+
+                        // values only ever count up, whatever is rolled or compacted meanwhile
+                        let mut seen = vec![0u64; KEYS];
+                        while !done.load(Ordering::Relaxed) {
+                            let shared = store.read().unwrap();
+                            for (key, seen) in keys.iter().zip(seen.iter_mut()) {
+                                let value: u64 = shared.get(key).unwrap().unwrap().parse().unwrap();
+                                assert!(value >= *seen, "{} went from {} to {}", key, seen, value);
+                                *seen = value;
+                            }
+                        }
+                    })
+                })
+                .collect();
+            started.wait();
+            let mut compactions = 0;
+            for round in 1..=ROUNDS {
+                for key in keys.iter() {
+                    let mut locked = store.write().unwrap();
+                    locked.set(key, &round.to_string()).unwrap();
+                    let job = locked.env.take_compaction_job();
+                    drop(locked);
+                    if let Some(mut job) = job {
+                        let result = job.run();
+                        store
+                            .write()
+                            .unwrap()
+                            .env
+                            .finish_compaction(job, result)
+                            .unwrap();
+                        compactions += 1;
+                    }
+                }
+            }
+            done.store(true, Ordering::Relaxed);
+            for reader in readers {
+                reader.join().unwrap();
+            }
+            assert!(compactions > 0);
+        });
+
+        let store = store.into_inner().unwrap();
+        for key in keys.iter() {
+            assert_eq!(store.get(key).unwrap(), Some(ROUNDS.to_string()));
+        }
+    }
+
+    #[test]
+    fn mapped_reads_match_buffered_reads() {
+        let dir = ScratchDir::new();
+        let mut env = open(&dir);
+        for i in 0..200 {
+            let value = format!("value,{}\n{}", i, "x".repeat(i % 13));
+            set_data(&mut env, format!("key-{}", i % 150).as_bytes(), &value).unwrap();
+        }
+        run(&mut env, "DELETE key-7");
+        set_data(&mut env, b"in-write-segment", "value").unwrap();
+
+        let keys: Vec<String> = (0..160)
+            .map(|i| format!("key-{}", (i * 37) % 160))
+            .chain([String::from("in-write-segment")])
+            .collect();
+        env.set_mmap(true);
+        assert!(env.segments.iter().all(|segment| segment.mapped.is_some()));
+        let mapped: Vec<Option<String>> = keys.iter().map(|key| get(&env, key)).collect();
+        env.set_mmap(false);
+        assert!(env.segments.iter().all(|segment| segment.mapped.is_none()));
+        let buffered: Vec<Option<String>> = keys.iter().map(|key| get(&env, key)).collect();
+        assert_eq!(mapped, buffered);
+        assert_eq!(mapped.iter().filter(|value| value.is_none()).count(), 11);
+    }
+
+    #[test]
+    fn multi_get_keeps_the_order_of_its_keys() {
+        let dir = ScratchDir::new();
+        let mut store = KvStore::open(&dir.0).unwrap();
+        store.set("old", "1").unwrap();
+        store.set("deleted", "2").unwrap();
+        store.env.retire_write_segment().unwrap();
+        store.set("new", "3").unwrap();
+        store.remove("deleted").unwrap();
+
+        let values: Vec<Option<String>> = store
+            .multi_get(&["new", "absent", "old", "deleted", "new"])
+            .into_iter()
+            .map(|value| value.unwrap())
+            .collect();
+        assert_eq!(
+            values,
+            [Some("3"), None, Some("1"), None, Some("3")].map(|value| value.map(String::from))
+        );
+        assert_eq!(
+            run(&mut store.env, "MGET old absent deleted"),
+            "Key [old] value: [1]\nKey [absent] not found\nKey [deleted] not found\n"
+        );
+    }
+
+    #[test]
+    fn segment_numbers_only_grow_across_compactions() {
+        let dir = ScratchDir::new();
+        std::fs::create_dir_all(&dir.0).unwrap();
+        // files that share the prefix without being segments
+        for stray in ["db.notes", "db.0x1f", "db.99999.bak"] {
+            std::fs::write(Path::new(&dir.0).join(stray), "a,b\n").unwrap();
+        }
+        let mut env = open(&dir);
+        let number = |file_path: &String| {
+            let file_name = Path::new(file_path).file_name().unwrap().to_string_lossy();
+            NumericNamer.sequence("db", &file_name).unwrap()
+        };
+        let mut highest = 0;
+        for round in 0..5 {
+            for i in 0..20 {
+                set_data(
+                    &mut env,
+                    format!("key-{}", i).as_bytes(),
+                    &round.to_string(),
+                )
+                .unwrap();
+            }
+            env.retire_write_segment().unwrap();
+            let numbers: Vec<u64> = env.segments.iter().map(|s| number(&s.file_path)).collect();
+            assert!(
+                numbers.windows(2).all(|pair| pair[0] < pair[1]),
+                "{:?}",
+                numbers
+            );
+            assert!(*numbers.last().unwrap() > highest);
+            env.compact_segments().unwrap();
+            let compacted = env
+                .segments
+                .iter()
+                .map(|s| number(&s.file_path))
+                .max()
+                .unwrap();
+            assert!(
+                compacted > *numbers.last().unwrap(),
+                "{} {:?}",
+                compacted,
+                numbers
+            );
+            highest = compacted;
+            if round == 2 {
+                drop(env);
+                env = open(&dir);
+            }
+        }
+        assert_eq!(get(&env, "key-0").as_deref(), Some("4"));
+    }
+
+    #[test]
+    fn segments_missing_from_the_manifest_are_ignored() {
+        let dir = ScratchDir::new();
+        let mut env = open(&dir);
+        set_data(&mut env, b"a", "listed").unwrap();
+        env.retire_write_segment().unwrap();
+        // named like segments, but never listed: a copy left behind and a
+        // compaction output cut short
+        std::fs::write(Path::new(&dir.0).join("db.00042"), "a,stray\nb,stray\n").unwrap();
+        std::fs::write(Path::new(&dir.0).join("db.00043.compacting.tmp"), "c,").unwrap();
+
+        env.reload().unwrap();
+        assert_eq!(env.segments.len(), 1);
+        assert_eq!(get(&env, "a").as_deref(), Some("listed"));
+        assert_eq!(get(&env, "b"), None);
+        drop(env);
+        let mut env = open(&dir);
+        assert_eq!(env.segments.len(), 1);
+        assert_eq!(get(&env, "a").as_deref(), Some("listed"));
+        assert_eq!(get(&env, "b"), None);
+        // numbering follows the manifest too, not the stray file
+        set_data(&mut env, b"c", "3").unwrap();
+        env.retire_write_segment().unwrap();
+        assert!(env.segments[1].file_path.ends_with("db.00002"));
+    }
+
+    #[test]
+    fn a_retirement_cut_short_at_any_step_loses_nothing() {
+        let steps = [
+            RetireStep::Listed,
+            RetireStep::Renamed,
+            RetireStep::Compressed,
+            RetireStep::Indexed,
+        ];
+        for (step, compress) in steps
+            .into_iter()
+            .flat_map(|step| [(step, false), (step, true)])
+        {
+            let dir = ScratchDir::new();
+            let mut env = open(&dir);
+            env.compress = compress;
+            env.segment_threshold = u64::MAX;
+            set_data(&mut env, b"retired", "1").unwrap();
+            env.retire_write_segment().unwrap();
+            set_data(&mut env, b"crashed", "2").unwrap();
+            env.retire_write_segment_until(Some(step)).unwrap();
+            drop(env);
+
+            let mut env = open(&dir);
+            assert_eq!(env.segments.len(), 2, "after {:?}", step);
+            assert_eq!(
+                get(&env, "retired").as_deref(),
+                Some("1"),
+                "after {:?}",
+                step
+            );
+            assert_eq!(
+                get(&env, "crashed").as_deref(),
+                Some("2"),
+                "after {:?}",
+                step
+            );
+            // the next retirement takes a fresh number
+            set_data(&mut env, b"after", "3").unwrap();
+            env.retire_write_segment().unwrap();
+            let file_name = Path::new(&env.segments[2].file_path).file_name().unwrap();
+            assert_eq!(uncompressed_name(&file_name.to_string_lossy()), "db.00003");
+            drop(env);
+            let env = open(&dir);
+            assert_eq!(env.segments.len(), 3, "after {:?}", step);
+            assert_eq!(get(&env, "after").as_deref(), Some("3"));
+        }
+    }
+
+    #[test]
+    fn handle_command_returns_what_it_did() {
+        let dir = ScratchDir::new();
+        let mut env = open(&dir);
+        let mut execute = |line: &str| execute(&mut env, line);
+        assert!(matches!(
+            execute("SET a \"one two\""),
+            CommandResult::Written
+        ));
+        assert!(matches!(
+            execute("GET a"),
+            CommandResult::Value(Some(value)) if value == "one two"
+        ));
+        assert!(matches!(execute("GET never"), CommandResult::Value(None)));
+        assert!(matches!(execute("DELETE a"), CommandResult::Deleted));
+        assert!(matches!(
+            execute("GET a"),
+            CommandResult::Error(KvError::KeyDeleted { .. })
+        ));
+        assert!(matches!(execute("COMPACT"), CommandResult::Compacted(_)));
+        // any other command answers with its lines
+        for line in ["COMPACT --background", "KEYS", "PING"] {
+            assert!(
+                matches!(execute(line), CommandResult::Output(_)),
+                "{}",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn quoted_arguments_and_argument_counts() {
+        let dir = ScratchDir::new();
+        let mut env = open(&dir);
+        assert_eq!(
+            run(&mut env, "GET"),
+            "Usage: GET <key> [--include-tombstone]\n"
+        );
+        assert_eq!(
+            run(&mut env, "SET a b c d e"),
+            "Usage: SET <key> <value> [--type <tag>]\n"
+        );
+        run(&mut env, "SET \"a key\" \"two words\"");
+        assert_eq!(get(&env, "a key").as_deref(), Some("two words"));
+        run(&mut env, "SET quote \"say \\\"hi\\\"\"");
+        assert_eq!(get(&env, "quote").as_deref(), Some("say \"hi\""));
+        run(&mut env, "SET empty \"\"");
+        assert_eq!(get(&env, "empty").as_deref(), Some(""));
+        // two quote characters, escaped, are a value like any other
+        run(&mut env, "SET quotes \"\\\"\\\"\"");
+        assert_eq!(get(&env, "quotes").as_deref(), Some("\"\""));
+        assert_eq!(
+            split_command("GET \t spaced   out").unwrap(),
+            vec!["GET", "spaced", "out"]
+        );
+        assert!(split_command("SET k \"unterminated").is_err());
+        assert!(split_command("SET k \"closed\"trailing").is_err());
+    }
+
+    #[test]
+    fn the_newest_record_of_a_key_wins_across_segments() {
+        let dir = ScratchDir::new();
+        let mut env = open(&dir);
+        set_data(&mut env, b"gone", "old").unwrap();
+        set_data(&mut env, b"back", "first").unwrap();
+        delete_data(&mut env, b"back").unwrap();
+        env.retire_write_segment().unwrap();
+        delete_data(&mut env, b"gone").unwrap();
+        set_data(&mut env, b"back", "second").unwrap();
+        for _ in 0..2 {
+            assert_eq!(get(&env, "gone"), None);
+            assert_eq!(get(&env, "back").as_deref(), Some("second"));
+            env.retire_write_segment().unwrap();
+        }
+        set_data(&mut env, b"gone", "again").unwrap();
+        assert_eq!(get(&env, "gone").as_deref(), Some("again"));
+        drop(env);
+        let env = open(&dir);
+        assert_eq!(get(&env, "gone").as_deref(), Some("again"));
+        assert_eq!(get(&env, "back").as_deref(), Some("second"));
+
+        // a delete is the tombstone flag, no value reads as one
+        let mut store = KvStore::open(&dir.0).unwrap();
+        assert_eq!(store.append("absent", "\n").unwrap(), 1);
+        assert!(store.compare_and_swap("gone", Some("again"), "\n").unwrap());
+        assert_eq!(store.append("gone", "\n").unwrap(), 2);
+        let mut batch = WriteBatch::new();
+        batch.set("back", "\n");
+        store.write(batch).unwrap();
+        store.compact().unwrap();
+        drop(store);
+        let store = KvStore::open(&dir.0).unwrap();
+        assert_eq!(store.get("absent").unwrap().as_deref(), Some("\n"));
+        assert_eq!(store.get("gone").unwrap().as_deref(), Some("\n\n"));
+        assert_eq!(store.get("back").unwrap().as_deref(), Some("\n"));
+    }
+
+    #[test]
+    fn compaction_output_is_sorted_deterministic_and_split_before_the_threshold() {
+        // each compacted segment as its size and records, sequences left out
+        // since they come from the clock
+        fn compacted(dir: &ScratchDir) -> Vec<(u64, Vec<(String, String)>)> {
+            let mut env = open(dir);
+            for i in 0..120 {
+                let value = "v".repeat(if i == 60 { 400 } else { i % 23 });
+                set_data(
+                    &mut env,
+                    format!("key-{:03}", (i * 37) % 120).as_bytes(),
+                    &value,
+                )
+                .unwrap();
+            }
+            env.retire_write_segment().unwrap();
+            env.compact_segments().unwrap();
+            env.segments
+                .iter()
+                .map(|segment| {
+                    let records = segment.records().unwrap();
+                    let records = records.map(|r| r.unwrap()).map(|r| {
+                        let key = String::from_utf8(r.key).unwrap();
+                        (key, String::from_utf8(r.value).unwrap())
+                    });
+                    (segment.size, records.collect())
+                })
+                .collect()
+        }
+        let first = ScratchDir::new();
+        let second = ScratchDir::new();
+        let segments = compacted(&first);
+        assert_eq!(segments, compacted(&second));
+        assert!(segments.len() > 1);
+        for (size, records) in &segments {
+            assert!(*size <= SEGMENT_THRESHOLD || records.len() == 1);
+        }
+        let keys: Vec<&String> = segments.iter().flat_map(|s| &s.1).map(|r| &r.0).collect();
+        let mut sorted = keys.clone();
+        sorted.sort();
+        assert_eq!(keys, sorted);
+        assert_eq!(keys.len(), 120);
+    }
+
+    #[test]
+    fn flushall_leaves_no_keys_and_a_clean_directory() {
+        let dir = ScratchDir::new();
+        let mut env = open(&dir);
+        for i in 0..40 {
+            set_data(&mut env, format!("key-{}", i).as_bytes(), "value").unwrap();
+        }
+        assert!(!env.segments.is_empty());
+        assert_eq!(run(&mut env, "FLUSHALL"), "Database cleared\n");
+        assert_eq!(get(&env, "key-0"), None);
+        assert!(live_keys(&env).unwrap().is_empty());
+        let mut listing: Vec<String> = std::fs::read_dir(&dir.0)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        listing.sort();
+        assert_eq!(listing, ["db.current", "db.manifest"]);
+        assert_eq!(
+            std::fs::metadata(format!("{}/db.current", dir.0))
+                .unwrap()
+                .len(),
+            0
+        );
+        drop(env);
+        let env = open(&dir);
+        assert_eq!(get(&env, "key-39"), None);
+    }
+
+    #[test]
+    fn get_and_set_returns_the_value_it_replaced() {
+        let dir = ScratchDir::new();
+        let mut store = KvStore::open(&dir.0).unwrap();
+        assert_eq!(store.get_and_set("k", "one").unwrap(), None);
+        assert_eq!(
+            store.get_and_set("k", "two").unwrap().as_deref(),
+            Some("one")
+        );
+        assert_eq!(store.get_and_remove("k").unwrap().as_deref(), Some("two"));
+        assert_eq!(store.get_and_remove("k").unwrap(), None);
+        assert_eq!(store.get_and_set("k", "three").unwrap(), None);
+        assert_eq!(
+            store.get_and_set("k", "\n").unwrap().as_deref(),
+            Some("three")
+        );
+        assert_eq!(store.get("k").unwrap().as_deref(), Some("\n"));
+
+        let env = &mut store.env;
+        assert_eq!(run(env, "GETSET n a"), "Written key: [n] value: [a]\n");
+        assert_eq!(
+            run(env, "GETSET n b"),
+            "Written key: [n] value: [b] replaced: [a]\n"
+        );
+        assert_eq!(run(env, "GETDEL n"), "Deleted key: [n] value: [b]\n");
+        assert_eq!(run(env, "GETDEL n"), "Deleted key: [n]\n");
+    }
+
+    #[test]
+    fn iterators_yield_the_merged_live_view() {
+        let dir = ScratchDir::new();
+        let mut env = open(&dir);
+        assert_eq!(env.iter_live().unwrap().count(), 0);
+        assert_eq!(env.write_segment.iter().unwrap().count(), 0);
+
+        set_data(&mut env, b"a", "old").unwrap();
+        set_data(&mut env, b"b", "gone soon").unwrap();
+        set_data(&mut env, b"c", "kept").unwrap();
+        env.retire_write_segment().unwrap();
+        set_data(&mut env, b"a", "new").unwrap();
+        delete_data(&mut env, b"b").unwrap();
+        set_data(&mut env, b"d", "fresh").unwrap();
+
+        let entries: Vec<(Vec<u8>, Vec<u8>, bool)> = env
+            .write_segment
+            .iter()
+            .unwrap()
+            .map(|entry| entry.unwrap())
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                (b"a".to_vec(), b"new".to_vec(), false),
+                (b"b".to_vec(), Vec::new(), true),
+                (b"d".to_vec(), b"fresh".to_vec(), false),
+            ]
+        );
+        let mut live: Vec<(Vec<u8>, Vec<u8>)> =
+            env.iter_live().unwrap().map(|r| r.unwrap()).collect();
+        live.sort();
+        assert_eq!(
+            live,
+            [
+                (b"a".to_vec(), b"new".to_vec()),
+                (b"c".to_vec(), b"kept".to_vec()),
+                (b"d".to_vec(), b"fresh".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn read_only_open_finds_segments_with_the_given_namer() {
+        let dir = ScratchDir::new();
+        let prefix = String::from("db");
+        let mut env = Environment::with_namer(&dir.0, &prefix, Box::new(TickNamer)).unwrap();
+        set_data(&mut env, b"k", "v").unwrap();
+        env.retire_write_segment().unwrap();
+        drop(env);
+        // without a manifest the segments are found by their names
+        std::fs::remove_file(Environment::manifest_path(&dir.0, "db")).unwrap();
+        let before = std::fs::read_dir(&dir.0).unwrap().count();
+
+        let env = Environment::open_read_only(&dir.0, &prefix, Box::new(TickNamer)).unwrap();
+        assert_eq!(get(&env, "k").as_deref(), Some("v"));
+        let env = Environment::open_read_only(&dir.0, &prefix, Box::new(NumericNamer)).unwrap();
+        assert_eq!(get(&env, "k"), None);
+
+        let mut store = KvStore::open_read_only(&dir.0).unwrap();
+        assert!(matches!(store.set("k", "w"), Err(KvError::ReadOnly)));
+        assert!(matches!(store.remove("k"), Err(KvError::ReadOnly)));
+        assert!(matches!(store.compact(), Err(KvError::ReadOnly)));
+        assert!(matches!(
+            store.set_bytes("k", b"\xff"),
+            Err(KvError::ReadOnly)
+        ));
+        assert!(matches!(
+            store.set_record_codec(RecordCodec::Binary),
+            Err(KvError::ReadOnly)
+        ));
+        assert_eq!(std::fs::read_dir(&dir.0).unwrap().count(), before);
+    }
+
+    #[test]
+    fn values_with_newlines_and_nul_bytes_survive_the_binary_codec() {
+        let dir = ScratchDir::new();
+        let mut store = KvStore::open(&dir.0).unwrap();
+        store.set_record_codec(RecordCodec::Binary).unwrap();
+        store.env.segment_threshold = u64::MAX;
+        store.env.in_place_updates = true;
+        store.set("lines", "one\ntwo\r\n").unwrap();
+        store.set("nul", "a\0b").unwrap();
+        store.set(b"key\n\0", "").unwrap();
+        // the bytes the text codec holds a tombstone as are a value here
+        store.set("newline", "\n").unwrap();
+        let mut batch = WriteBatch::new();
+        batch.set("batched", "x\ny").set("gone", "1").remove("gone");
+        batch.set("crlf", "\r\n");
+        store.write(batch).unwrap();
+        // appended, not rewritten in place
+        store.set("nul", "\0").unwrap();
+        let write_segment_path = Path::new(&dir.0).join(format!("db.{}", CURRENT_SEGMENT_SUFFIX));
+        let contents = std::fs::read(&write_segment_path).unwrap();
+        assert!(contents.windows(9).any(|bytes| bytes == b"one\ntwo\r\n"));
+        store.env.retire_write_segment().unwrap();
+        drop(store);
+
+        let check = |store: &KvStore| {
+            assert_eq!(store.get("lines").unwrap().as_deref(), Some("one\ntwo\r\n"));
+            assert_eq!(store.get("nul").unwrap().as_deref(), Some("\0"));
+            assert_eq!(store.get(b"key\n\0").unwrap().as_deref(), Some(""));
+            assert_eq!(store.get("batched").unwrap().as_deref(), Some("x\ny"));
+            assert_eq!(store.get("gone").unwrap(), None);
+            assert_eq!(store.get("newline").unwrap().as_deref(), Some("\n"));
+            assert_eq!(store.get("crlf").unwrap().as_deref(), Some("\r\n"));
+            assert!(store.contains_key("newline").unwrap());
+            assert!(store.contains_key(b"key\n\0").unwrap());
+            assert!(!store.contains_key("gone").unwrap());
+            assert!(verify(&store.env).unwrap().is_ok());
+        };
+        let mut store = KvStore::open(&dir.0).unwrap();
+        assert_eq!(store.env.codec, RecordCodec::Binary);
+        check(&store);
+        assert!(
+            doctor(&FileStorage, &dir.0, "db", &NumericNamer, false)
+                .unwrap()
+                .is_empty()
+        );
+        store.compact().unwrap();
+        check(&store);
+        store.env.sstable_block_size = Some(16);
+        store.set("more", "\n\n").unwrap();
+        store.env.retire_write_segment().unwrap();
+        store.compact().unwrap();
+        assert!(store.env.segments[0].blocks.is_some());
+        check(&store);
+        drop(store);
+        check(&KvStore::open(&dir.0).unwrap());
+    }
+
+    #[test]
+    fn a_torn_binary_frame_is_cut_off_on_open() {
+        let dir = ScratchDir::new();
+        let mut env = open(&dir);
+        env.set_record_codec(RecordCodec::Binary).unwrap();
+        set_data(&mut env, b"a", "1\n").unwrap();
+        drop(env);
+        let write_segment_path = Path::new(&dir.0).join(format!("db.{}", CURRENT_SEGMENT_SUFFIX));
+        let intact = std::fs::read(&write_segment_path).unwrap();
+        // all of a frame but its checksum, newline included
+        let frame = encode_frame(&Record::new(b"b", b"2\n"));
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&write_segment_path)
+            .unwrap()
+            .write_all(&frame[..frame.len() - 4])
+            .unwrap();
+        let issues = doctor(&FileStorage, &dir.0, "db", &NumericNamer, false).unwrap();
+        assert_eq!(
+            issues,
+            [DoctorIssue::TornTail {
+                file_path: write_segment_path.display().to_string(),
+                offset: intact.len() as u64,
+            }]
+        );
+
+        let env = open(&dir);
+        assert_eq!(std::fs::read(&write_segment_path).unwrap(), intact);
+        assert_eq!(get(&env, "a").as_deref(), Some("1\n"));
+        assert_eq!(get(&env, "b"), None);
+    }
+
+    #[test]
+    fn the_record_codec_is_kept_in_the_manifest() {
+        let dir = ScratchDir::new();
+        let mut env = open(&dir);
+        set_data(&mut env, b"a", "1").unwrap();
+        assert!(matches!(
+            env.set_record_codec(RecordCodec::Binary),
+            Err(KvError::CodecInUse {
+                codec: RecordCodec::Text
+            })
+        ));
+        env.retire_write_segment().unwrap();
+        drop(env);
+
+        // a manifest written before the codec was recorded is of a text store
+        let manifest_path = Environment::manifest_path(&dir.0, "db");
+        let manifest = std::fs::read_to_string(&manifest_path).unwrap();
+        let old_manifest = manifest.strip_suffix("format text\n").unwrap();
+        std::fs::write(&manifest_path, old_manifest).unwrap();
+        let env = open(&dir);
+        assert_eq!(env.codec, RecordCodec::Text);
+        assert_eq!(get(&env, "a").as_deref(), Some("1"));
+        drop(env);
+
+        let other = ScratchDir::new();
+        let mut env = open(&other);
+        env.set_record_codec(RecordCodec::Binary).unwrap();
+        drop(env);
+        let manifest = std::fs::read_to_string(Environment::manifest_path(&other.0, "db")).unwrap();
+        assert!(manifest.ends_with("format binary\n"), "{}", manifest);
+        assert_eq!(open(&other).codec, RecordCodec::Binary);
+    }
+
+    #[test]
+    fn every_byte_value_round_trips_through_the_bytes_api() {
+        let every_byte: Vec<u8> = (0..=255).collect();
+        for codec in [RecordCodec::Text, RecordCodec::Binary] {
+            let dir = ScratchDir::new();
+            let mut store = KvStore::open(&dir.0).unwrap();
+            store.set_record_codec(codec).unwrap();
+            store.set_bytes("bytes", &every_byte).unwrap();
+            store.set_bytes("old", b"\xff").unwrap();
+            store.set_bytes("old", b"\xfe").unwrap();
+            store.set("text", "plain").unwrap();
+            store.set_bytes("newline", b"\n").unwrap();
+            store.env.retire_write_segment().unwrap();
+            drop(store);
+
+            let check = |store: &KvStore| {
+                assert_eq!(store.get_bytes("bytes").unwrap(), Some(every_byte.clone()));
+                assert_eq!(store.get_bytes("old").unwrap(), Some(vec![0xfe]));
+                assert_eq!(store.get_bytes("newline").unwrap(), Some(b"\n".to_vec()));
+                assert_eq!(store.get_bytes("text").unwrap(), Some(b"plain".to_vec()));
+                assert_eq!(store.get("text").unwrap().as_deref(), Some("plain"));
+                assert_eq!(store.get_bytes("missing").unwrap(), None);
+                assert!(matches!(
+                    store.get("bytes"),
+                    Err(KvError::NotUtf8 { key }) if key == "bytes"
+                ));
+                assert!(verify(&store.env).unwrap().is_ok());
+            };
+            let mut store = KvStore::open(&dir.0).unwrap();
+            check(&store);
+            store.compact().unwrap();
+            check(&store);
+            drop(store);
+            check(&KvStore::open(&dir.0).unwrap());
+        }
+    }
+
+    #[test]
+    fn dropping_the_store_keeps_every_key_and_writes_a_hint() {
+        for codec in [RecordCodec::Text, RecordCodec::Binary] {
+            let dir = ScratchDir::new();
+            let mut store = KvStore::open(&dir.0).unwrap();
+            store.set_record_codec(codec).unwrap();
+            for i in 0..50 {
+                store
+                    .set(format!("key-{}", i), &format!("value-{}", i))
+                    .unwrap();
+            }
+            store.remove("key-7").unwrap();
+            let write_segment = store.env.write_segment.file_path.clone();
+            let index = store
+                .env
+                .write_segment
+                .index
+                .iter()
+                .map(|(k, o)| (k.clone(), *o));
+            let index: HashMap<Vec<u8>, u64> = index.collect();
+            let record_count = store.env.write_segment.record_count;
+            let max_sequence = store.env.write_segment.max_sequence;
+            drop(store);
+            assert_eq!(
+                read_hint(&FileStorage, &write_segment),
+                Some((index, record_count, max_sequence))
+            );
+
+            let store = KvStore::open(&dir.0).unwrap();
+            for i in 0..50 {
+                let expected = (i != 7).then(|| format!("value-{}", i));
+                assert_eq!(store.get(format!("key-{}", i)).unwrap(), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn overwriting_one_key_retires_an_underused_write_segment() {
+        let dir = ScratchDir::new();
+        let mut env = open(&dir);
+        env.segment_threshold = u64::MAX;
+        for i in 0..100 {
+            set_data(&mut env, b"hot", &i.to_string()).unwrap();
+        }
+        assert!(env.segments.is_empty());
+        // the hint keeps the overwritten records counted across a restart
+        env.close().unwrap();
+        drop(env);
+        let mut env = open(&dir);
+        assert!(env.write_segment.from_hint);
+        assert_eq!(env.write_segment.record_count, 100);
+
+        env.min_write_utilization = Some(50);
+        env.retire_write_segment().unwrap();
+        let retired = env.segments.len();
+        for i in 0..UTILIZATION_MIN_RECORDS {
+            set_data(&mut env, b"hot", &i.to_string()).unwrap();
+        }
+        assert_eq!(env.segments.len(), retired);
+        // the write that finds eight records for one key retires them first
+        set_data(&mut env, b"hot", "last").unwrap();
+        assert_eq!(env.segments.len(), retired + 1);
+        assert_eq!(env.write_segment.record_count, 1);
+        assert_eq!(get(&env, "hot").as_deref(), Some("last"));
+    }
+
+    #[test]
+    fn a_dry_run_predicts_the_bytes_a_compaction_frees() {
+        let layouts = [
+            (RecordCodec::Text, None),
+            (RecordCodec::Text, Some(64)),
+            (RecordCodec::Binary, None),
+            (RecordCodec::Binary, Some(64)),
+        ];
+        for (codec, sstable_block_size) in layouts {
+            let dir = ScratchDir::new();
+            let mut env = open(&dir);
+            env.set_record_codec(codec).unwrap();
+            env.sstable_block_size = sstable_block_size;
+            for round in 0..3 {
+                for i in 0..30 {
+                    set_data(
+                        &mut env,
+                        format!("key-{}", i).as_bytes(),
+                        &format!("value-{}", round),
+                    )
+                    .unwrap();
+                }
+            }
+            for i in 0..10 {
+                delete_data(&mut env, format!("key-{}", i).as_bytes()).unwrap();
+            }
+            env.retire_write_segment().unwrap();
+            let retired_bytes =
+                |env: &Environment| env.segments.iter().map(|s| s.size).sum::<u64>();
+            let before = retired_bytes(&env);
+            let segments = env.segments.len();
+
+            let listing = std::fs::read_dir(&dir.0).unwrap().count();
+            let planned = env.compact_dry_run().unwrap();
+            let line = run(&mut env, "COMPACT --dry-run");
+            assert_eq!(std::fs::read_dir(&dir.0).unwrap().count(), listing);
+            assert_eq!(retired_bytes(&env), before);
+            assert_eq!(
+                bracketed_numbers(&line),
+                [
+                    segments as f64,
+                    planned.segments_written as f64,
+                    planned.bytes_reclaimed as f64,
+                    planned.tombstones_purged as f64
+                ]
+            );
+            assert_eq!(planned.tombstones_purged, 10);
+
+            let done = env.compact_segments().unwrap();
+            assert_eq!(done.segments_merged, planned.segments_merged);
+            assert_eq!(done.segments_written, planned.segments_written);
+            assert_eq!(done.bytes_reclaimed, planned.bytes_reclaimed);
+            assert_eq!(done.tombstones_purged, planned.tombstones_purged);
+            assert_eq!(before - retired_bytes(&env), planned.bytes_reclaimed);
+        }
+    }
+
+    #[test]
+    fn every_indexed_offset_starts_a_record_of_its_key() {
+        for codec in [RecordCodec::Text, RecordCodec::Binary] {
+            let dir = ScratchDir::new();
+            let mut env = open(&dir);
+            env.set_record_codec(codec).unwrap();
+            env.segment_threshold = u64::MAX;
+            let mut newest = HashMap::new();
+            for i in 0..400 {
+                let key = match i % 3 {
+                    0 => format!("key-{}", i % 17),
+                    1 => format!("k,é\n{}", i % 11),
+                    _ => format!("long-key-{}-{}", i % 5, "x".repeat(i % 40)),
+                };
+                let value = match i % 4 {
+                    0 => "v".repeat(i % 90),
+                    1 => format!("line\none,two\\{}", i),
+                    2 => format!("ünïcode-{}", i),
+                    _ => String::new(),
+                };
+                if i == 200 {
+                    env.enable_checksums();
+                }
+                if i % 23 == 0 {
+                    delete_data(&mut env, key.as_bytes()).unwrap();
+                    newest.insert(key, None);
+                } else {
+                    set_data(&mut env, key.as_bytes(), &value).unwrap();
+                    newest.insert(key, Some(value));
+                }
+            }
+            env.retire_write_segment().unwrap();
+            let file_path = env.segments[0].file_path.clone();
+            let contents = std::fs::read(&file_path).unwrap();
+            let index = build_index(&FileStorage, &file_path, codec).unwrap();
+            assert_eq!(index.len(), newest.len());
+            for (key, offset) in index.iter() {
+                let start = *offset as usize;
+                if codec == RecordCodec::Text {
+                    assert!(
+                        start == 0 || contents[start - 1] == b'\n',
+                        "{} at {}",
+                        String::from_utf8_lossy(key),
+                        offset
+                    );
+                }
+                let frame = read_frame(&mut &contents[start..], codec).unwrap().unwrap();
+                let record = codec.decode(&frame.bytes).unwrap();
+                assert_eq!(&record.key, key);
+                let value = &newest[std::str::from_utf8(key).unwrap()];
+                assert_eq!(record.written_value(), value.as_deref().map(str::as_bytes));
+            }
+
+            // an offset off by a byte is reported, not followed
+            let segment = &mut env.segments[0];
+            let key = b"key-1".to_vec();
+            let offset = index[&key];
+            segment.index.insert(key.clone(), offset + 1);
+            assert!(matches!(
+                segment.get_record(&key, &mut OpenSegments::default()),
+                Err(KvError::Corrupt { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn watchers_receive_each_change_of_their_key() {
+        let dir = ScratchDir::new();
+        let mut store = KvStore::open(&dir.0).unwrap();
+        let first = store.watch("k");
+        let second = store.watch("k");
+        let other = store.watch("other");
+        store.set("k", "one").unwrap();
+        store.set("unwatched", "x").unwrap();
+        store.remove("k").unwrap();
+        let mut batch = WriteBatch::new();
+        batch.set("k", "two");
+        store.write(batch).unwrap();
+        store.set_bytes("k", b"\xff\0").unwrap();
+        store.rename("k", "other").unwrap();
+        let expected = [
+            ChangeEvent::Set(b"one".to_vec()),
+            ChangeEvent::Deleted,
+            ChangeEvent::Set(b"two".to_vec()),
+            ChangeEvent::Set(b"\xff\0".to_vec()),
+            ChangeEvent::Deleted,
+        ];
+        assert_eq!(first.try_iter().collect::<Vec<_>>(), expected);
+        assert_eq!(second.try_iter().collect::<Vec<_>>(), expected);
+        let moved = ChangeEvent::Set(b"\xff\0".to_vec());
+        assert_eq!(other.try_iter().collect::<Vec<_>>(), [moved]);
+
+        drop(first);
+        drop(second);
+        store.set("k", "three").unwrap();
+        assert!(
+            !store
+                .env
+                .watchers
+                .lock()
+                .unwrap()
+                .contains_key(b"k".as_slice())
+        );
+        assert_eq!(store.env.watchers.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn keys_with_delimiters_are_stored_intact() {
+        let dir = ScratchDir::new();
+        let mut env = open(&dir);
+        assert!(matches!(
+            execute(&mut env, "SET a,b value"),
+            CommandResult::Written
+        ));
+        set_data(&mut env, b"line\nbreak", "v,w").unwrap();
+        set_data(&mut env, b"a", "plain").unwrap();
+        // a carriage return and a leading header marker are kept as well
+        set_data(&mut env, b"cr\r", "\r\n").unwrap();
+        set_data(&mut env, b"\x01marked\x01", "v").unwrap();
+        env.retire_write_segment().unwrap();
+        let file_path = env.segments[0].file_path.clone();
+        let index = build_index(&FileStorage, &file_path, RecordCodec::Text).unwrap();
+        let mut keys: Vec<&Vec<u8>> = index.keys().collect();
+        keys.sort();
+        assert_eq!(
+            keys,
+            [
+                &b"\x01marked\x01"[..],
+                b"a",
+                b"a,b",
+                b"cr\r",
+                b"line\nbreak"
+            ]
+        );
+        drop(env);
+        let env = open(&dir);
+        assert_eq!(get(&env, "a,b").as_deref(), Some("value"));
+        assert_eq!(get(&env, "line\nbreak").as_deref(), Some("v,w"));
+        assert_eq!(get(&env, "a").as_deref(), Some("plain"));
+        assert_eq!(get(&env, "cr\r").as_deref(), Some("\r\n"));
+        assert_eq!(get(&env, "\x01marked\x01").as_deref(), Some("v"));
+    }
+
+    #[test]
+    fn a_store_in_memory_storage_never_touches_the_disk() {
+        let storage = Arc::new(MemoryStorage::new());
+        let every_byte: Vec<u8> = (0..=255).collect();
+        let mut store = KvStore::open_in("kvdb-in-memory", storage.clone()).unwrap();
+        store.env.segment_threshold = 64;
+        for i in 0..40 {
+            store
+                .set(format!("key-{}", i), &format!("value-{}", i))
+                .unwrap();
+        }
+        store.set_bytes("bytes", &every_byte).unwrap();
+        store.remove("key-0").unwrap();
+        assert!(store.env.segments.len() > 2);
+        // a held segment outlives the compaction that drops it
+        let live = store.env.iter_live().unwrap();
+        store.compact().unwrap();
+        assert_eq!(live.count(), 40);
+        store.close().unwrap();
+
+        let store = KvStore::open_in("kvdb-in-memory", storage.clone()).unwrap();
+        assert!(store.env.segments.iter().all(|segment| segment.from_hint));
+        assert_eq!(store.get("key-0").unwrap(), None);
+        assert_eq!(store.get("key-39").unwrap().as_deref(), Some("value-39"));
+        assert_eq!(store.get_bytes("bytes").unwrap(), Some(every_byte));
+        let mut out = Vec::new();
+        assert_eq!(sorted_export(&store.env, &mut out).unwrap(), 40);
+        assert!(verify(&store.env).unwrap().is_ok());
+        drop(store);
+        assert!(!Path::new("kvdb-in-memory").exists());
+
+        let fresh = KvStore::open_in("kvdb-in-memory", Arc::new(MemoryStorage::new())).unwrap();
+        assert_eq!(fresh.get("key-39").unwrap(), None);
+    }
+
+    #[test]
+    fn a_torn_tail_in_memory_storage_is_cut_off_on_open() {
+        let storage = Arc::new(MemoryStorage::new());
+        let mut store = KvStore::open_in("kvdb-in-memory", storage.clone()).unwrap();
+        store.env.segment_threshold = u64::MAX;
+        store.set("a", "1").unwrap();
+        store.set("b", "2").unwrap();
+        store.close().unwrap();
+        let write_segment_path = format!("kvdb-in-memory/db.{}", CURRENT_SEGMENT_SUFFIX);
+        let intact = storage.read(&write_segment_path).unwrap();
+        let mut appender = storage.append(&write_segment_path).unwrap();
+        appender.write_all(b"c,torn").unwrap();
+        drop(appender);
+
+        let store = KvStore::open_in("kvdb-in-memory", storage.clone()).unwrap();
+        assert_eq!(store.get("b").unwrap().as_deref(), Some("2"));
+        assert_eq!(store.get("c").unwrap(), None);
+        assert_eq!(storage.read(&write_segment_path).unwrap(), intact);
+    }
+
+    #[test]
+    fn doctor_and_a_read_only_open_work_on_memory_storage() {
+        let storage = Arc::new(MemoryStorage::new());
+        let data_path = String::from("kvdb-in-memory");
+        let mut store = KvStore::open_in(&data_path, storage.clone()).unwrap();
+        store.set("a", "1").unwrap();
+        store.env.retire_write_segment().unwrap();
+        store.set("b", "2").unwrap();
+        store.close().unwrap();
+        let retired = format!("{}/db.00001", data_path);
+        let intact_len = storage.size(&retired).unwrap();
+        let mut appender = storage.append(&retired).unwrap();
+        appender.write_all(b"c,torn").unwrap();
+        drop(appender);
+        let leftover = format!("{}/db.00002.tmp", data_path);
+        storage.write(&leftover, b"c,3\n").unwrap();
+
+        // a read-only open leaves the torn tail in place
+        let store = KvStore::open_read_only_in(&data_path, storage.clone()).unwrap();
+        assert_eq!(store.get("a").unwrap().as_deref(), Some("1"));
+        assert_eq!(store.get("b").unwrap().as_deref(), Some("2"));
+        drop(store);
+        let issues = doctor(&*storage, &data_path, "db", &NumericNamer, true).unwrap();
+        assert_eq!(
+            issues,
+            vec![
+                DoctorIssue::TornTail {
+                    file_path: retired.clone(),
+                    offset: intact_len
+                },
+                DoctorIssue::LeftoverTmpFile(leftover.clone()),
+            ]
+        );
+        assert_eq!(storage.size(&retired).unwrap(), intact_len);
+        assert!(!storage.exists(&leftover));
+        assert!(!Path::new(&data_path).exists());
+
+        let store = KvStore::open_in(&data_path, storage.clone()).unwrap();
+        assert!(store.env.segments[0].from_hint);
+        assert_eq!(store.get("a").unwrap().as_deref(), Some("1"));
+    }
+
+    #[test]
+    fn size_tiered_compaction_merges_one_tier() {
+        let dir = ScratchDir::new();
+        let mut env = open(&dir);
+        env.compaction_strategy = CompactionStrategy::SizeTiered;
+        env.segment_threshold = u64::MAX;
+        for i in 0..40 {
+            set_data(
+                &mut env,
+                format!("big-{:02}", i).as_bytes(),
+                &"b".repeat(50),
+            )
+            .unwrap();
+        }
+        env.retire_write_segment().unwrap();
+        env.segment_threshold = 100;
+        for round in 0..5 {
+            set_data(&mut env, format!("small-{}", round).as_bytes(), "s").unwrap();
+            env.retire_write_segment().unwrap();
+        }
+        let big = env.segments[0].file_path.clone();
+        let big_contents = std::fs::read(&big).unwrap();
+        assert!(env.size_tier(env.segments[0].size) > 0);
+        assert!(env.segments[1..].iter().all(|s| env.size_tier(s.size) == 0));
+
+        let summary = env.compact_segments().unwrap();
+        assert_eq!(summary.segments_merged, 5);
+        assert_eq!(env.segments[0].file_path, big);
+        assert_eq!(env.segments.len(), 1 + summary.segments_written);
+        // the big segment is neither rewritten nor dropped from the manifest
+        assert_eq!(std::fs::read(&big).unwrap(), big_contents);
+        let file_paths = |env: &Environment| -> Vec<String> {
+            env.segments.iter().map(|s| s.file_path.clone()).collect()
+        };
+        let compacted = file_paths(&env);
+        drop(env);
+        let mut env = open(&dir);
+        env.compaction_strategy = CompactionStrategy::SizeTiered;
+        env.segment_threshold = 100;
+        assert_eq!(file_paths(&env), compacted);
+        // the merged segment and one more are too few to make a run
+        set_data(&mut env, b"small-5", "s").unwrap();
+        env.retire_write_segment().unwrap();
+        assert_eq!(env.compact_segments().unwrap().segments_merged, 0);
+        assert_eq!(env.segments[0].file_path, big);
+        assert_eq!(get(&env, "big-39").as_deref(), Some(&*"b".repeat(50)));
+        assert_eq!(get(&env, "small-0").as_deref(), Some("s"));
+    }
+
+    #[test]
+    fn a_parallel_open_indexes_like_a_serial_one() {
+        for codec in [RecordCodec::Text, RecordCodec::Binary] {
+            let dir = ScratchDir::new();
+            let mut env = open(&dir);
+            env.set_record_codec(codec).unwrap();
+            for i in 0..600 {
+                set_data(
+                    &mut env,
+                    format!("key-{}", i % 150).as_bytes(),
+                    &"v".repeat(i % 13),
+                )
+                .unwrap();
+            }
+            env.retire_write_segment().unwrap();
+            let paths: Vec<String> = env.segments.iter().map(|s| s.file_path.clone()).collect();
+            assert!(paths.len() > 8);
+            drop(env);
+            for file_path in paths.iter() {
+                remove_index_files(&FileStorage, file_path).unwrap();
+            }
+
+            let indexes = |segments: &[Segment]| -> Vec<(String, HashMap<Vec<u8>, u64>)> {
+                segments
+                    .iter()
+                    .map(|s| {
+                        let index = s.index.iter().map(|(k, o)| (k.clone(), *o)).collect();
+                        (s.file_path.clone(), index)
+                    })
+                    .collect()
+            };
+            let env = open(&dir);
+            // rebuilt again rather than read from the hints the open wrote
+            for file_path in paths.iter() {
+                remove_index_files(&FileStorage, file_path).unwrap();
+            }
+            let serial: Vec<Segment> = paths
+                .iter()
+                .map(|file_path| Segment::open_retired(&env.storage, file_path.clone(), codec))
+                .map(Result::unwrap)
+                .collect();
+            assert_eq!(indexes(&env.segments), indexes(&serial));
+            // written last at 599, with 599 % 13 bytes
+            assert_eq!(get(&env, "key-149").as_deref(), Some("v"));
+            drop(env);
+
+            // a segment that does not index fails the open instead of a thread
+            let corrupt = &paths[paths.len() / 2];
+            remove_index_files(&FileStorage, corrupt).unwrap();
+            let mut file = OpenOptions::new().append(true).open(corrupt).unwrap();
+            file.write_all(b"\x7fgarbage\n").unwrap();
+            assert!(matches!(
+                Environment::with_namer(&dir.0, &String::from("db"), Box::new(NumericNamer)),
+                Err(KvError::Corrupt { file_path, .. }) if file_path == *corrupt
+            ));
+        }
+    }
+
+    #[test]
+    fn rename_moves_a_value_in_one_batch() {
+        for codec in [RecordCodec::Text, RecordCodec::Binary] {
+            let dir = ScratchDir::new();
+            let mut store = KvStore::open(&dir.0).unwrap();
+            store.set_record_codec(codec).unwrap();
+            store.env.segment_threshold = u64::MAX;
+            assert!(!store.rename("absent", "new").unwrap());
+            assert_eq!(store.get("new").unwrap(), None);
+            assert_eq!(
+                run(&mut store.env, "RENAME absent new"),
+                "Key [absent] not found\n"
+            );
+
+            store.set("old", "value").unwrap();
+            store.set("target", "replaced").unwrap();
+            assert_eq!(
+                run(&mut store.env, "RENAME old target"),
+                "Renamed key: [old] to: [target]\n"
+            );
+            assert_eq!(store.get("old").unwrap(), None);
+            assert_eq!(store.get("target").unwrap().as_deref(), Some("value"));
+            store.remove("target").unwrap();
+            assert!(!store.rename("target", "elsewhere").unwrap());
+
+            store.set_bytes("bytes", b"\xff\n\xfe").unwrap();
+            assert!(store.rename("bytes", "moved").unwrap());
+            assert_eq!(
+                store.get_bytes("moved").unwrap(),
+                Some(b"\xff\n\xfe".to_vec())
+            );
+            store.set("from", "moving").unwrap();
+            assert!(store.rename("from", "to").unwrap());
+            store.close().unwrap();
+
+            // the crash hit after the new key was written but before all of
+            // the tombstone of the old one was
+            let write_segment_path =
+                Path::new(&dir.0).join(format!("db.{}", CURRENT_SEGMENT_SUFFIX));
+            let contents = std::fs::read(&write_segment_path).unwrap();
+            std::fs::write(&write_segment_path, &contents[..contents.len() - 3]).unwrap();
+            let store = KvStore::open(&dir.0).unwrap();
+            assert_eq!(store.get("from").unwrap().as_deref(), Some("moving"));
+            assert_eq!(store.get("to").unwrap(), None);
+            assert_eq!(store.get("target").unwrap(), None);
+            assert_eq!(
+                store.get_bytes("moved").unwrap(),
+                Some(b"\xff\n\xfe".to_vec())
+            );
+        }
+    }
+
+    #[test]
+    fn the_disk_limit_compacts_and_then_refuses_writes() {
+        let dir = ScratchDir::new();
+        let mut store = KvStore::open(&dir.0).unwrap();
+        let limit = 2000;
+        store.set_max_disk_bytes(Some(limit));
+        let mut written = 0;
+        for i in 0..300 {
+            let value = format!("value-{}", i);
+            written += value.len() as u64;
+            store.set(format!("key-{}", i % 10), &value).unwrap();
+            // compaction keeps the retired segments under the limit
+            let retired = store.env.disk_bytes().unwrap() - store.env.write_segment.size;
+            assert!(retired <= limit, "{} after write {}", retired, i);
+        }
+        let stats = store.stats().unwrap();
+        assert!(stats.disk_bytes < written);
+        assert_eq!(stats.disk_limit, Some(limit));
+        let report = run(&mut store.env, "STATS");
+        let used = stats.disk_bytes * 100 / limit;
+        assert!(
+            report.contains(&format!("disk limit:          {} ({}% used)", limit, used)),
+            "{}",
+            report
+        );
+        assert_eq!(store.get("key-9").unwrap().as_deref(), Some("value-299"));
+
+        // distinct keys cannot be compacted away
+        let mut refused = None;
+        for i in 0..500 {
+            if let Err(e) = store.set(format!("distinct-{}", i), "value") {
+                refused = Some(e);
+                break;
+            }
+        }
+        match refused {
+            Some(KvError::DiskLimitExceeded {
+                disk_bytes,
+                limit: l,
+            }) => {
+                assert!(disk_bytes > limit);
+                assert_eq!(l, limit);
+            }
+            other => panic!("expected DiskLimitExceeded, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn offsets_hold_across_crlf_lines_and_multi_byte_text() {
+        let dir = ScratchDir::new();
+        // a segment written by hand with CRLF line endings
+        std::fs::create_dir_all(&dir.0).unwrap();
+        let hand_written = format!("{}/db.00001", dir.0);
+        std::fs::write(
+            &hand_written,
+            "añ,ü€\r\nb,x\r\nañ,zé\r\nc,\u{1F600}\r\ngone,v\r\ngone,\r\n",
+        )
+        .unwrap();
+        let index = build_index(&FileStorage, &hand_written, RecordCodec::Text).unwrap();
+        assert_eq!(index[b"b".as_slice()], "añ,ü€\r\n".len() as u64);
+        assert_eq!(index["añ".as_bytes()], "añ,ü€\r\nb,x\r\n".len() as u64);
+        let mut env = open(&dir);
+        assert_eq!(get(&env, "añ").as_deref(), Some("zé"));
+        assert_eq!(get(&env, "b").as_deref(), Some("x"));
+        assert_eq!(get(&env, "c").as_deref(), Some("\u{1F600}"));
+        // deleted by a headerless tombstone ending in CRLF
+        assert_eq!(get(&env, "gone"), None);
+        assert!(!contains_key(&env, b"gone").unwrap());
+        assert!(contains_key(&env, b"b").unwrap());
+
+        let values = ["line\r\nend", "ß\r", "\r", "日本語\n\r", "€"];
+        for (i, value) in values.iter().enumerate() {
+            set_data(&mut env, format!("k{}é", i).as_bytes(), value).unwrap();
+        }
+        env.retire_write_segment().unwrap();
+        let file_path = env.segments.last().unwrap().file_path.clone();
+        let text = std::fs::read(&file_path).unwrap();
+        for (key, offset) in build_index(&FileStorage, &file_path, RecordCodec::Text).unwrap() {
+            let start = offset as usize;
+            assert!(start == 0 || text[start - 1] == b'\n');
+            let end = start + text[start..].iter().position(|b| *b == b'\n').unwrap();
+            assert_eq!(decode_record(&text[start..end]).unwrap().key, key);
+        }
+        drop(env);
+        let env = open(&dir);
+        for (i, value) in values.iter().enumerate() {
+            assert_eq!(get(&env, &format!("k{}é", i)).as_deref(), Some(*value));
+        }
+        assert_eq!(get(&env, "añ").as_deref(), Some("zé"));
+    }
+
+    #[test]
+    fn type_tags_survive_a_roll_and_a_compaction() {
+        let dir = ScratchDir::new();
+        let mut env = open(&dir);
+        assert_eq!(
+            run(&mut env, "SET doc {} --type 7"),
+            "Written key: [doc] value: [{}] type: [7]\n"
+        );
+        assert_eq!(
+            run(&mut env, "SET doc x --type 300"),
+            "Usage: SET <key> <value> [--type <tag>]\n"
+        );
+        run(&mut env, "SET plain v");
+        run(&mut env, "SET retagged a --type 1");
+        assert_eq!(run(&mut env, "TYPE doc"), "7\n");
+        assert_eq!(run(&mut env, "TYPE plain"), "0\n");
+        assert_eq!(run(&mut env, "TYPE missing"), "Key [missing] not found\n");
+        set_with_type(&mut env, b"blob", b"\xff\x00", 9).unwrap();
+        assert_eq!(run(&mut env, "TYPE blob"), "9\n");
+
+        env.retire_write_segment().unwrap();
+        run(&mut env, "SET retagged b --type 2");
+        assert_eq!(run(&mut env, "TYPE doc"), "7\n");
+        env.retire_write_segment().unwrap();
+        env.compact_segments().unwrap();
+        drop(env);
+
+        let store = KvStore::open(&dir.0).unwrap();
+        let doc = store.get_with_meta("doc").unwrap().unwrap();
+        assert_eq!((doc.value.as_str(), doc.type_tag), ("{}", 7));
+        assert_eq!(store.get_with_meta("plain").unwrap().unwrap().type_tag, 0);
+        let retagged = store.get_with_meta("retagged").unwrap().unwrap();
+        assert_eq!((retagged.value.as_str(), retagged.type_tag), ("b", 2));
+        assert!(store.get_with_meta("missing").unwrap().is_none());
+        let blob = live_record(&store.env, b"blob").unwrap().unwrap();
+        assert_eq!(
+            (blob.value.as_slice(), record_type(&blob)),
+            (&b"\xff\x00"[..], 9)
+        );
+    }
+
+    #[test]
+    fn an_offset_past_the_end_gives_a_typed_error_and_a_new_hint() {
+        let dir = ScratchDir::new();
+        for mmap in [true, false] {
+            let mut env = open(&dir);
+            env.set_mmap(mmap);
+            set_data(&mut env, b"key", "value").unwrap();
+            env.retire_write_segment().unwrap();
+            let segment = env.segments.last_mut().unwrap();
+            let (file_path, size) = (segment.file_path.clone(), segment.size);
+            segment.index.insert(b"key".to_vec(), size + 100);
+            match get_data(&env, b"key") {
+                Err(KvError::OffsetBeyondEof {
+                    file_path: path,
+                    offset,
+                    file_len,
+                }) => assert_eq!((path, offset, file_len), (file_path, size + 100, size)),
+                other => panic!("expected OffsetBeyondEof, got {:?}", other),
+            }
+            // EXISTS reads only the start of the record, from the same offset
+            assert!(matches!(
+                contains_key(&env, b"key"),
+                Err(KvError::OffsetBeyondEof { offset, .. }) if offset == size + 100
+            ));
+        }
+
+        // a hint pointing past the end is replaced once GET reindexes
+        let mut env = open(&dir);
+        let segment = env.segments.last_mut().unwrap();
+        let file_path = segment.file_path.clone();
+        let good = read_hint(&FileStorage, &file_path).unwrap();
+        segment.index.insert(b"key".to_vec(), segment.size + 100);
+        write_hint(
+            &FileStorage,
+            &file_path,
+            &segment.index,
+            segment.record_count,
+            segment.max_sequence,
+        )
+        .unwrap();
+        drop(env);
+        let mut env = open(&dir);
+        assert!(matches!(
+            execute(&mut env, "GET key"),
+            CommandResult::Reindexed(KvError::OffsetBeyondEof { .. }, Ok(_))
+        ));
+        assert_eq!(read_hint(&FileStorage, &file_path), Some(good));
+        assert!(matches!(
+            execute(&mut env, "GET key"),
+            CommandResult::Value(Some(value)) if value == "value"
+        ));
+    }
+
+    #[test]
+    fn open_with_progress_reports_every_retired_segment() {
+        let dir = ScratchDir::new();
+        let mut calls = Vec::new();
+        drop(KvStore::open_with_progress(&dir.0, |progress| calls.push(progress)).unwrap());
+        assert!(calls.is_empty());
+
+        let mut store = KvStore::open(&dir.0).unwrap();
+        for i in 0..100 {
+            store.set(format!("key-{}", i), "value").unwrap();
+        }
+        let segments = store.env.segments.len();
+        let bytes: u64 = store
+            .env
+            .segments
+            .iter()
+            .map(|s| std::fs::metadata(&s.file_path).unwrap().len())
+            .sum();
+        assert!(segments > 1);
+        drop(store);
+
+        let store = KvStore::open_with_progress(&dir.0, |progress| calls.push(progress)).unwrap();
+        assert_eq!(calls.len(), segments);
+        for (position, progress) in calls.iter().enumerate() {
+            assert_eq!(progress.segments_loaded, position + 1);
+            assert_eq!(progress.segments_total, segments);
+            assert_eq!(progress.bytes_total, bytes);
+        }
+        let last = calls.last().unwrap();
+        assert_eq!(last.segments_loaded, last.segments_total);
+        assert_eq!(last.bytes_loaded, bytes);
+        assert_eq!(store.get("key-0").unwrap().as_deref(), Some("value"));
+
+        // sizes come from the storage the store is kept in
+        let storage = Arc::new(MemoryStorage::new());
+        let mut store = KvStore::open_in("kvdb-in-memory", storage.clone()).unwrap();
+        for i in 0..100 {
+            store.set(format!("key-{}", i), "value").unwrap();
+        }
+        let segments = store.env.segments.len();
+        let bytes: u64 = store
+            .env
+            .segments
+            .iter()
+            .map(|s| storage.size(&s.file_path).unwrap())
+            .sum();
+        drop(store);
+        let mut calls = Vec::new();
+        let progress = |progress| calls.push(progress);
+        drop(KvStore::open_in_with_progress("kvdb-in-memory", storage, progress).unwrap());
+        let last = calls.last().unwrap();
+        assert_eq!((calls.len(), last.segments_loaded), (segments, segments));
+        assert_eq!((last.bytes_loaded, last.bytes_total), (bytes, bytes));
+    }
+
+    #[test]
+    fn setnx_writes_only_absent_or_deleted_keys() {
+        let dir = ScratchDir::new();
+        let mut store = KvStore::open(&dir.0).unwrap();
+        assert!(store.set_if_absent("k", "first").unwrap());
+        assert!(!store.set_if_absent("k", "second").unwrap());
+        assert_eq!(store.get("k").unwrap().as_deref(), Some("first"));
+        store.remove("k").unwrap();
+        store.env.retire_write_segment().unwrap();
+        assert!(store.set_if_absent("k", "third").unwrap());
+        assert_eq!(store.get("k").unwrap().as_deref(), Some("third"));
+        assert!(store.set_if_absent("new", "\n").unwrap());
+        assert!(!store.set_if_absent("new", "other").unwrap());
+        store.set_bytes("blob", &[0xff, 0xfe]).unwrap();
+        assert!(!store.set_if_absent("blob", "text").unwrap());
+        assert!(!store.compare_and_swap("blob", Some("x"), "text").unwrap());
+        assert_eq!(store.get_bytes("blob").unwrap(), Some(vec![0xff, 0xfe]));
+
+        let env = &mut store.env;
+        assert_eq!(run(env, "SETNX n a"), "1\n");
+        assert_eq!(run(env, "SETNX n b"), "0\n");
+        assert_eq!(get(env, "n").as_deref(), Some("a"));
+        run(env, "DELETE n");
+        assert_eq!(run(env, "SETNX n c"), "1\n");
+        assert_eq!(
+            run(env, "GETSET n d"),
+            "Written key: [n] value: [d] replaced: [c]\n"
+        );
+    }
+
+    #[test]
+    fn segments_roll_at_whichever_limit_comes_first() {
+        let dir = ScratchDir::new();
+        let mut store = KvStore::open(&dir.0).unwrap();
+        store.env.segment_threshold = u64::MAX;
+        store.set_max_records_per_segment(Some(5));
+        for i in 0..23 {
+            store.set(format!("k{}", i), "v").unwrap();
+        }
+        let counts: Vec<usize> = store
+            .env
+            .segments
+            .iter()
+            .map(|s| s.records().unwrap().count())
+            .collect();
+        assert_eq!(counts, [5, 5, 5, 5]);
+        assert_eq!(store.env.write_segment.record_count, 3);
+        drop(store);
+        // the count of a reopened write segment carries on
+        let mut store = KvStore::open(&dir.0).unwrap();
+        store.env.segment_threshold = u64::MAX;
+        store.set_max_records_per_segment(Some(5));
+        assert_eq!(store.env.write_segment.record_count, 3);
+        for i in 23..26 {
+            store.set(format!("k{}", i), "v").unwrap();
+        }
+        assert_eq!(store.env.segments.len(), 5);
+        assert_eq!(store.env.write_segment.record_count, 1);
+        drop(store);
+
+        let dir = ScratchDir::new();
+        let mut store = KvStore::open(&dir.0).unwrap();
+        store.set_max_records_per_segment(Some(1000));
+        for i in 0..10 {
+            store.set(format!("k{}", i), &"v".repeat(200)).unwrap();
+        }
+        assert!(store.env.segments.len() >= 4);
+        for segment in store.env.segments.iter() {
+            assert!(segment.size > SEGMENT_THRESHOLD);
+            assert!(segment.records().unwrap().count() <= 2);
+        }
+    }
+
+    #[test]
+    fn verify_reports_a_clean_store_and_each_kind_of_damage() {
+        let dir = ScratchDir::new();
+        let mut store = KvStore::open(&dir.0).unwrap();
+        store.env.enable_checksums();
+        for key in ["a", "b", "c"] {
+            store.set(key, "value").unwrap();
+        }
+        store.env.retire_write_segment().unwrap();
+        store.set("a", "newer").unwrap();
+        let report = store.verify().unwrap();
+        assert!(report.is_ok());
+        let ok: Vec<u64> = report.segments.iter().map(|s| s.ok_records).collect();
+        assert_eq!(ok, [3, 1]);
+        assert!(run(&mut store.env, "VERIFY").ends_with("No issues found\n"));
+
+        // a value byte changed under its checksum, a record cut off at the tail
+        // of the write segment, and an index entry moved to another record
+        let retired = store.env.segments[0].file_path.clone();
+        let offset = *store.env.segments[0].index.get(b"b").unwrap();
+        let mut text = std::fs::read(&retired).unwrap();
+        let value_at = offset as usize
+            + text[offset as usize..]
+                .windows(5)
+                .position(|w| w == b"value")
+                .unwrap();
+        text[value_at] = b'V';
+        std::fs::write(&retired, &text).unwrap();
+        let current = store.env.write_segment.file_path.clone();
+        let tail = std::fs::metadata(&current).unwrap().len();
+        File::options()
+            .append(true)
+            .open(&current)
+            .unwrap()
+            .write_all(b"d,torn")
+            .unwrap();
+        let c_offset = *store.env.segments[0].index.get(b"c").unwrap();
+        store.env.segments[0].index.insert(b"a".to_vec(), c_offset);
+        let before = (
+            std::fs::read(&retired).unwrap(),
+            std::fs::read(&current).unwrap(),
+        );
+
+        let report = store.verify().unwrap();
+        assert!(!report.is_ok());
+        let (old, write) = (&report.segments[0], &report.segments[1]);
+        assert_eq!(
+            (old.ok_records, old.corrupt_records.clone()),
+            (2, vec![offset])
+        );
+        // the entry of `b` no longer leads to a sound record either
+        let mismatches = [(b"a".to_vec(), c_offset), (b"b".to_vec(), offset)];
+        assert_eq!(old.index_mismatches, mismatches);
+        assert_eq!((write.ok_records, write.torn_tail), (1, Some(tail)));
+        let output = run(&mut store.env, "VERIFY");
+        assert!(output.contains(&format!("  corrupt record at offset {}\n", offset)));
+        assert!(output.contains(&format!("  torn record at the tail, offset {}\n", tail)));
+        assert!(output.contains(&format!(
+            "  index entry [a] at offset {} is not the newest record of the key\n",
+            c_offset
+        )));
+        let after = (
+            std::fs::read(&retired).unwrap(),
+            std::fs::read(&current).unwrap(),
+        );
+        assert_eq!(before, after);
+    }
+
+    #[test]
+    fn records_of_a_batch_are_never_written_in_place() {
+        let dir = ScratchDir::new();
+        let mut env = open(&dir);
+        env.in_place_updates = true;
+        run(&mut env, "MSET a long-value b long-value");
+        let size = env.write_segment.size;
+        set_data(&mut env, b"a", "x").unwrap();
+        set_data(&mut env, b"b", "y").unwrap();
+        assert!(env.write_segment.size > size);
+        drop(env);
+        let env = open(&dir);
+        assert_eq!(get(&env, "a").as_deref(), Some("x"));
+        assert_eq!(get(&env, "b").as_deref(), Some("y"));
+    }
+}
@@ -1,336 +1,2191 @@
-use std::collections::HashMap;
+use kvdb_alpha::{
+    CommandResult, CompactionJob, Environment, FileStorage, KvError, SegmentNamer,
+    apply_replicated, atomic_load, command_key, compaction_strategy, doctor, handle_command,
+    handle_shared_get, key_comparator, live_keys, lookup, lookup_bytes, print_doctor_report,
+    print_verify_report, record_codec, resp, segment_namer, set_bytes, split_command, sync_policy,
+    verify,
+};
+use std::collections::{HashMap, VecDeque};
 use std::env;
-use std::fs::{File, OpenOptions, metadata, read_dir, remove_file, rename};
-use std::io::{BufReader, BufWriter, Seek, stdin};
-use std::io::{SeekFrom, prelude::*};
-use std::path::Path;
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter, stdin, stdout};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
 
-const SEGMENT_THRESHOLD: u64 = 256;
-const CURRENT_SEGMENT_SUFFIX: &str = "current";
-const DELETE_TERMINATOR: &str = "";
+#[cfg(test)]
+mod scratch;
 
-#[derive(Debug)]
-struct Segment {
-    file_path: String,
-    index: HashMap<String, u64>,
-    size: u64,
+// number of command results the interactive mode keeps for LAST
+const RESULT_HISTORY_SIZE: usize = 32;
+// how long SETSYNC waits for the follower unless --replication-timeout says otherwise
+const REPLICATION_TIMEOUT_MILLIS: u64 = 5000;
+// pause before reconnecting to a follower that could not be reached
+const REPLICATION_RETRY_INTERVAL: std::time::Duration = std::time::Duration::from_millis(100);
+// longest value a binary framed SET may announce
+const MAX_BINARY_VALUE_BYTES: usize = 512 * 1024 * 1024;
+
+// Read-only access to several logical databases (prefixes) at once.
+// Commands name the prefix they query: `GET <prefix> <key>`.
+fn handle_read_only_command(
+    envs: &HashMap<String, Environment>,
+    command_args: &[String],
+    out: &mut dyn Write,
+) -> std::io::Result<()> {
+    let command = match command_args.first() {
+        Some(command) => command,
+        None => return writeln!(out, "No command given"),
+    };
+    let usage = match command.as_str() {
+        "GET" if command_args.len() != 3 => Some("GET <prefix> <key>"),
+        "DBSIZE" if command_args.len() != 2 => Some("DBSIZE <prefix>"),
+        _ => None,
+    };
+    if let Some(usage) = usage {
+        return writeln!(out, "Usage: {}", usage);
+    }
+    let prefix = &command_args[1];
+    let env = match envs.get(prefix) {
+        Some(env) => env,
+        None => return writeln!(out, "Prefix [{}] is not open", prefix),
+    };
+    if command == "GET" {
+        let key = &command_args[2];
+        match lookup(env, key.as_bytes()) {
+            Ok(Some(value)) => writeln!(out, "Found value: [{}]", value),
+            Ok(None) => writeln!(out, "Value not found"),
+            Err(e) => writeln!(
+                out,
+                "Could not find value for key [{}]. Error: [{:?}]",
+                key, e
+            ),
+        }
+    } else if command == "DBSIZE" {
+        match live_keys(env) {
+            Ok(keys) => writeln!(out, "Live keys: [{}]", keys.len()),
+            Err(e) => writeln!(out, "Could not count keys. Error: [{}]", e),
+        }
+    } else {
+        writeln!(
+            out,
+            "Command [{}] is not available in read-only mode",
+            command
+        )
+    }
+}
+
+#[derive(Debug, Default)]
+struct Options {
+    interactive: bool,
+    data_dir: Option<String>,
+    prefix: Option<String>,
+    segment_size: Option<u64>,
+    max_segment_records: Option<u64>,
+    max_segments: Option<usize>,
+    // percentage, see `Environment::min_write_utilization`
+    min_write_utilization: Option<u64>,
+    max_key_len: Option<usize>,
+    max_value_len: Option<usize>,
+    max_read_fanout: Option<usize>,
+    track_count: bool,
+    sstable_block_size: Option<u64>,
+    // address to accept commands on over TCP instead of reading stdin
+    serve: Option<String>,
+    // speak RESP on the --serve address instead of the line protocol
+    resp: bool,
+    // token a served connection has to send with AUTH before anything else
+    require_auth: Option<String>,
+    flush_policy: FlushPolicy,
+    // address of a follower serving commands that every write is shipped to
+    replicate_to: Option<String>,
+    // in milliseconds, how long SETSYNC waits for the follower
+    replication_timeout: Option<u64>,
+    // gzip segments once they are retired
+    compress: bool,
+    // read retired segments through their files instead of memory maps
+    no_mmap: bool,
+    max_db_size: Option<u64>,
+    max_disk_bytes: Option<u64>,
+    partition_by_date: bool,
+    in_place_updates: bool,
+    segment_naming: Option<String>,
+    sync_policy: Option<String>,
+    // how a new store lays out its records, `text` or `binary`
+    record_format: Option<String>,
+    compaction_strategy: Option<String>,
+    max_line_bytes: Option<usize>,
+    cache_capacity: Option<usize>,
+    key_order: Option<String>,
+    disabled_commands: Vec<String>,
+    min_free_bytes: Option<u64>,
+    // in milliseconds
+    max_segment_age: Option<u64>,
+    // in milliseconds, how often expired keys are tombstoned in the background
+    sweep_interval: Option<u64>,
+    read_only_prefixes: Vec<String>,
+    // commands take and print keys in hex
+    binary_keys: bool,
+    checksums: bool,
 }
 
-#[derive(Debug)]
-enum SegmentError {
-    Io(std::io::Error),
-    KeyDeleted,
+// When the responses buffered for a served connection are sent.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+enum FlushPolicy {
+    // once every request the client has sent so far is answered, so that
+    // pipelined requests are answered with as few writes as possible
+    #[default]
+    Batch,
+    // after every response, for clients that wait on each one
+    Immediate,
 }
 
-impl From<std::io::Error> for SegmentError {
-    fn from(err: std::io::Error) -> SegmentError {
-        SegmentError::Io(err)
+fn parse_options(args: Vec<String>) -> Result<(Options, Vec<String>), String> {
+    let mut options = Options::default();
+    let mut args = args.into_iter().peekable();
+    while let Some(flag) = args.next_if(|arg| arg.starts_with("--")) {
+        if flag == "--interactive" {
+            options.interactive = true;
+        } else if flag == "--serve" {
+            let value = args.next().ok_or("--serve requires an address")?;
+            options.serve = Some(value);
+        } else if flag == "--protocol" {
+            let value = args.next().ok_or("--protocol requires a value")?;
+            options.resp = match value.as_str() {
+                "text" => false,
+                "resp" => true,
+                _ => return Err(format!("Invalid --protocol value [{}]", value)),
+            };
+        } else if flag == "--binary-keys" {
+            options.binary_keys = true;
+        } else if flag == "--checksums" {
+            options.checksums = true;
+        } else if flag == "--replicate-to" {
+            let value = args.next().ok_or("--replicate-to requires an address")?;
+            options.replicate_to = Some(value);
+        } else if flag == "--replication-timeout" {
+            let value = args
+                .next()
+                .ok_or("--replication-timeout requires a value")?;
+            let replication_timeout = value
+                .parse::<u64>()
+                .map_err(|_| format!("Invalid --replication-timeout value [{}]", value))?;
+            options.replication_timeout = Some(replication_timeout);
+        } else if flag == "--flush-policy" {
+            let value = args.next().ok_or("--flush-policy requires a value")?;
+            options.flush_policy = match value.as_str() {
+                "batch" => FlushPolicy::Batch,
+                "immediate" => FlushPolicy::Immediate,
+                _ => return Err(format!("Invalid --flush-policy value [{}]", value)),
+            };
+        } else if flag == "--require-auth" {
+            let value = args.next().ok_or("--require-auth requires a token")?;
+            if value.is_empty() {
+                return Err(String::from("--require-auth requires a token"));
+            }
+            options.require_auth = Some(value);
+        } else if flag == "--read-only-prefixes" {
+            let value = args.next().ok_or("--read-only-prefixes requires a value")?;
+            options.read_only_prefixes = value.split(',').map(String::from).collect();
+        } else if flag == "--disable-commands" {
+            let value = args.next().ok_or("--disable-commands requires a value")?;
+            options.disabled_commands = value.split(',').map(String::from).collect();
+        } else if flag == "--min-free-bytes" {
+            let value = args.next().ok_or("--min-free-bytes requires a value")?;
+            let min_free_bytes = value
+                .parse::<u64>()
+                .map_err(|_| format!("Invalid --min-free-bytes value [{}]", value))?;
+            options.min_free_bytes = Some(min_free_bytes);
+        } else if flag == "--max-segment-age" {
+            let value = args.next().ok_or("--max-segment-age requires a value")?;
+            let max_segment_age = value
+                .parse::<u64>()
+                .map_err(|_| format!("Invalid --max-segment-age value [{}]", value))?;
+            options.max_segment_age = Some(max_segment_age);
+        } else if flag == "--sweep-interval" {
+            let value = args.next().ok_or("--sweep-interval requires a value")?;
+            let sweep_interval = value
+                .parse::<u64>()
+                .map_err(|_| format!("Invalid --sweep-interval value [{}]", value))?;
+            options.sweep_interval = Some(sweep_interval);
+        } else if flag == "--data-dir" {
+            let value = args.next().ok_or("--data-dir requires a value")?;
+            options.data_dir = Some(value);
+        } else if flag == "--prefix" {
+            let value = args.next().ok_or("--prefix requires a value")?;
+            options.prefix = Some(value);
+        } else if flag == "--segment-size" {
+            let value = args.next().ok_or("--segment-size requires a value")?;
+            let segment_size = value
+                .parse::<u64>()
+                .map_err(|_| format!("Invalid --segment-size value [{}]", value))?;
+            options.segment_size = Some(segment_size);
+        } else if flag == "--max-segment-records" {
+            let value = args
+                .next()
+                .ok_or("--max-segment-records requires a value")?;
+            let max_segment_records = value
+                .parse::<u64>()
+                .ok()
+                .filter(|records| *records > 0)
+                .ok_or(format!("Invalid --max-segment-records value [{}]", value))?;
+            options.max_segment_records = Some(max_segment_records);
+        } else if flag == "--max-segments" {
+            let value = args.next().ok_or("--max-segments requires a value")?;
+            let max_segments = value
+                .parse::<usize>()
+                .map_err(|_| format!("Invalid --max-segments value [{}]", value))?;
+            options.max_segments = Some(max_segments);
+        } else if flag == "--min-write-utilization" {
+            let value = args
+                .next()
+                .ok_or("--min-write-utilization requires a value")?;
+            let percent = value
+                .parse::<u64>()
+                .ok()
+                .filter(|percent| *percent <= 100)
+                .ok_or(format!("Invalid --min-write-utilization value [{}]", value))?;
+            options.min_write_utilization = Some(percent);
+        } else if flag == "--max-key-len" {
+            let value = args.next().ok_or("--max-key-len requires a value")?;
+            let max_key_len = value
+                .parse::<usize>()
+                .map_err(|_| format!("Invalid --max-key-len value [{}]", value))?;
+            options.max_key_len = Some(max_key_len);
+        } else if flag == "--max-value-len" {
+            let value = args.next().ok_or("--max-value-len requires a value")?;
+            let max_value_len = value
+                .parse::<usize>()
+                .map_err(|_| format!("Invalid --max-value-len value [{}]", value))?;
+            options.max_value_len = Some(max_value_len);
+        } else if flag == "--max-db-size" {
+            let value = args.next().ok_or("--max-db-size requires a value")?;
+            let max_db_size = value
+                .parse::<u64>()
+                .map_err(|_| format!("Invalid --max-db-size value [{}]", value))?;
+            options.max_db_size = Some(max_db_size);
+        } else if flag == "--max-disk-bytes" {
+            let value = args.next().ok_or("--max-disk-bytes requires a value")?;
+            let max_disk_bytes = value
+                .parse::<u64>()
+                .map_err(|_| format!("Invalid --max-disk-bytes value [{}]", value))?;
+            options.max_disk_bytes = Some(max_disk_bytes);
+        } else if flag == "--partition-by-date" {
+            options.partition_by_date = true;
+        } else if flag == "--segment-naming" {
+            let value = args.next().ok_or("--segment-naming requires a value")?;
+            if segment_namer(&value).is_none() {
+                return Err(format!("Invalid --segment-naming value [{}]", value));
+            }
+            options.segment_naming = Some(value);
+        } else if flag == "--sync-policy" {
+            let value = args.next().ok_or("--sync-policy requires a value")?;
+            if sync_policy(&value).is_none() {
+                return Err(format!("Invalid --sync-policy value [{}]", value));
+            }
+            options.sync_policy = Some(value);
+        } else if flag == "--record-format" {
+            let value = args.next().ok_or("--record-format requires a value")?;
+            if record_codec(&value).is_none() {
+                return Err(format!("Invalid --record-format value [{}]", value));
+            }
+            options.record_format = Some(value);
+        } else if flag == "--compaction-strategy" {
+            let value = args
+                .next()
+                .ok_or("--compaction-strategy requires a value")?;
+            if compaction_strategy(&value).is_none() {
+                return Err(format!("Invalid --compaction-strategy value [{}]", value));
+            }
+            options.compaction_strategy = Some(value);
+        } else if flag == "--key-order" {
+            let value = args.next().ok_or("--key-order requires a value")?;
+            if key_comparator(&value).is_none() {
+                return Err(format!("Invalid --key-order value [{}]", value));
+            }
+            options.key_order = Some(value);
+        } else if flag == "--in-place-updates" {
+            options.in_place_updates = true;
+        } else if flag == "--track-count" {
+            options.track_count = true;
+        } else if flag == "--sstable-block-size" {
+            let value = args.next().ok_or("--sstable-block-size requires a value")?;
+            let block_size = value
+                .parse::<u64>()
+                .ok()
+                .filter(|size| *size > 0)
+                .ok_or_else(|| format!("Invalid --sstable-block-size value [{}]", value))?;
+            options.sstable_block_size = Some(block_size);
+        } else if flag == "--compress" {
+            options.compress = true;
+        } else if flag == "--no-mmap" {
+            options.no_mmap = true;
+        } else if flag == "--max-line-bytes" {
+            let value = args.next().ok_or("--max-line-bytes requires a value")?;
+            let max_line_bytes = value
+                .parse::<usize>()
+                .map_err(|_| format!("Invalid --max-line-bytes value [{}]", value))?;
+            options.max_line_bytes = Some(max_line_bytes);
+        } else if flag == "--cache-capacity" {
+            let value = args.next().ok_or("--cache-capacity requires a value")?;
+            let cache_capacity = value
+                .parse::<usize>()
+                .map_err(|_| format!("Invalid --cache-capacity value [{}]", value))?;
+            options.cache_capacity = Some(cache_capacity);
+        } else if flag == "--max-read-fanout" {
+            let value = args.next().ok_or("--max-read-fanout requires a value")?;
+            let max_read_fanout = value
+                .parse::<usize>()
+                .map_err(|_| format!("Invalid --max-read-fanout value [{}]", value))?;
+            options.max_read_fanout = Some(max_read_fanout);
+        } else {
+            return Err(format!("Unknown flag [{}]", flag));
+        }
     }
+    Ok((options, args.collect()))
 }
 
+// commands accepted over TCP with the number of arguments each needs at least
+const SERVED_COMMANDS: [(&str, usize); 5] = [
+    ("SET", 3),
+    ("GET", 2),
+    ("DELETE", 2),
+    ("COMPACT", 1),
+    ("SETSYNC", 3),
+];
 
-impl Segment {
-    pub fn new(file_path: String) -> Self {
-        let path = Path::new(&file_path);
-        if !path.exists() {
-            File::create(&path).unwrap();
-        }
-        let metadata = metadata(&file_path).unwrap();
-        return Segment {
-            file_path: file_path.clone(),
-            index: build_index(&file_path).unwrap(),
-            size: metadata.len(),
+// Compares a token sent with AUTH to the expected one in time that depends only
+// on the length of the expected token, so a client cannot guess it a byte at a time.
+fn token_matches(given: &[u8], expected: &str) -> bool {
+    let expected = expected.as_bytes();
+    let mut difference = (given.len() != expected.len()) as u8;
+    for (position, byte) in expected.iter().enumerate() {
+        difference |= byte ^ given.get(position).copied().unwrap_or(!byte);
+    }
+    std::hint::black_box(difference) == 0
+}
+
+// Accepts connections on `addr`, each served by its own thread until the client
+// disconnects. With `auth`, a connection has to send `AUTH <auth>` first.
+fn serve(
+    env: Arc<RwLock<Environment>>,
+    addr: &str,
+    resp: bool,
+    max_line_bytes: Option<usize>,
+    flush_policy: FlushPolicy,
+    replication: Option<Arc<Replication>>,
+    auth: Option<String>,
+) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    eprintln!("Listening on [{}]", listener.local_addr()?);
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Could not accept a connection. Error: [{}]", e);
+                continue;
+            }
         };
+        let env = env.clone();
+        let replication = replication.clone();
+        let auth = auth.clone();
+        std::thread::spawn(move || {
+            let peer = stream.peer_addr();
+            let result = match resp {
+                true => serve_resp_connection(
+                    &env,
+                    stream,
+                    max_line_bytes,
+                    flush_policy,
+                    auth.as_deref(),
+                ),
+                false => serve_connection(
+                    &env,
+                    stream,
+                    max_line_bytes,
+                    flush_policy,
+                    replication.as_deref(),
+                    auth.as_deref(),
+                ),
+            };
+            if let Err(e) = result {
+                eprintln!("Connection [{:?}] dropped. Error: [{}]", peer, e);
+            }
+        });
     }
+    Ok(())
+}
 
-    pub fn get_data(&self, key: &String) -> Result<String, SegmentError> {
-        let file = OpenOptions::new().read(true).open(&self.file_path)?;
-        let mut buf_reader = BufReader::new(file);
-        let mut return_value = String::new();
-        let mut found = false;
-        match self.index.get(key) {
-            Some(offset) => {
-                let _ = buf_reader.seek(SeekFrom::Start(*offset as u64));
-                let mut real_line = String::new();
-                let _ = buf_reader.read_line(&mut real_line)?;
-                let (line_key, val) = real_line.split_once(',').expect(
-                    format!(
-                        "Failed to split line [{}].\nCheck for db corruption",
-                        real_line
-                    )
-                    .as_str(),
-                );
-                if line_key == key {
-                    return_value = String::from(val);
-                    return_value.pop(); // remove endline
-                    found = true;
-                } else {
-                    panic!("index corrupted");
+// Answers every command line of a connection with its result, which is a
+// single line for the served commands. Responses go through a buffer that
+// `flush_policy` decides when to send.
+fn serve_connection(
+    env: &Arc<RwLock<Environment>>,
+    stream: TcpStream,
+    max_line_bytes: Option<usize>,
+    flush_policy: FlushPolicy,
+    replication: Option<&Replication>,
+    auth: Option<&str>,
+) -> std::io::Result<()> {
+    let mut authenticated = auth.is_none();
+    let mut binary = false;
+    let mut writer = BufWriter::new(stream.try_clone()?);
+    let mut lines = BoundedLines {
+        reader: BufReader::new(stream),
+        max_bytes: max_line_bytes,
+    };
+    while let Some(line) = lines.next() {
+        let line = match line {
+            Err(e)
+                if e.kind() == std::io::ErrorKind::InvalidInput
+                    || e.kind() == std::io::ErrorKind::InvalidData =>
+            {
+                writeln!(writer, "Line rejected: [{}]", e)?;
+                writer.flush()?;
+                continue;
+            }
+            line => line?,
+        };
+        let command_args = match split_command(&line) {
+            Ok(command_args) => command_args,
+            Err(e) => {
+                writeln!(writer, "Line rejected: [{}]", e)?;
+                writer.flush()?;
+                continue;
+            }
+        };
+        let mut response = Vec::new();
+        let result = match command_args.first().map(String::as_str) {
+            None => Ok(()),
+            Some("AUTH") => {
+                let answer = match (auth, command_args.get(1)) {
+                    (None, _) => "No authentication required",
+                    (Some(expected), Some(token)) if command_args.len() == 2 => {
+                        authenticated = token_matches(token.as_bytes(), expected);
+                        match authenticated {
+                            true => "Authenticated",
+                            false => "Invalid token",
+                        }
+                    }
+                    (Some(_), _) => "Usage: AUTH <token>",
+                };
+                writeln!(response, "{}", answer)
+            }
+            _ if !authenticated => writeln!(response, "Authentication required"),
+            Some("BINARY") => {
+                binary = true;
+                writeln!(response, "Binary framing enabled")
+            }
+            Some("SET" | "GET" | "APPLY") if binary => {
+                match serve_binary_command(env, &command_args, &mut lines.reader, &mut response) {
+                    Ok(()) => Ok(()),
+                    // the rest of the stream cannot be framed any more
+                    Err(e) => {
+                        writeln!(writer, "Framing error: [{}]", e)?;
+                        return writer.flush();
+                    }
                 }
             }
-            None => (),
+            _ => response.write_all(&serve_command(env, replication, &command_args)),
         };
-        if found && return_value == DELETE_TERMINATOR {
-            return Err(SegmentError::KeyDeleted);
+        if let Err(e) = result {
+            response.clear();
+            writeln!(response, "Failed to work with DB, [{}]", e)?;
+        }
+        writer.write_all(&response)?;
+        // a line already buffered is answered before the client hears back,
+        // a partial one may wait for these responses to be completed
+        if flush_policy == FlushPolicy::Immediate || !lines.reader.buffer().contains(&b'\n') {
+            writer.flush()?;
         }
-        Ok(return_value)
     }
+    writer.flush()
+}
 
-    pub fn save_data(&mut self, key: &String, value: &String) -> Result<(), std::io::Error> {
-        let file = OpenOptions::new()
-            .write(true)
-            .append(true)
-            .open(&self.file_path)?;
-        let mut writer = BufWriter::new(file);
-        let line = format!("{},{}", key, value);
-        writeln!(writer, "{}", line)?;
-        self.index.insert(
-            key.clone(),
-            writer.stream_position()? - line.len() as u64 - 1,
-        );
-        self.size += line.len() as u64 + 1;
-        Ok(())
+// Plain GETs share the environment, so reads run concurrently. Everything else
+// takes it exclusively, which keeps writes serialized.
+fn serve_command(
+    env: &Arc<RwLock<Environment>>,
+    replication: Option<&Replication>,
+    command_args: &[String],
+) -> Vec<u8> {
+    let mut result = Vec::new();
+    let command = command_args[0].as_str();
+    let required = match SERVED_COMMANDS.iter().find(|(name, _)| *name == command) {
+        Some((_, required)) => *required,
+        None => {
+            let _ = writeln!(result, "Command [{}] is not served", command);
+            return result;
+        }
+    };
+    if command_args.len() < required {
+        let _ = writeln!(result, "Command [{}] is missing arguments", command);
+        return result;
+    }
+    if command == "SETSYNC" {
+        return serve_set_sync(env, replication, command_args);
+    }
+    if command == "GET" && command_args.len() == 2 {
+        let shared = env.read().unwrap();
+        // evicting by size needs every access recorded
+        if shared.max_db_size.is_none() {
+            if let Err(e) = handle_shared_get(&shared, &command_args[1], &mut result) {
+                let _ = writeln!(result, "Failed to work with DB, [{}]", e);
+            }
+            return result;
+        }
+    }
+    let mut locked = env.write().unwrap();
+    if let Err(e) = run_command(&mut locked, command_args, &mut result) {
+        result.clear();
+        let _ = writeln!(result, "Failed to work with DB, [{}]", e);
+    }
+    let job = locked.take_compaction_job();
+    drop(locked);
+    if let Some(job) = job {
+        spawn_compaction(env.clone(), job);
     }
+    result
 }
 
-struct Environment {
-    data_path: String,
-    file_prefix: String,
-    segments: Vec<Segment>,
-    write_segment: Segment,
+// Answers SET, GET and APPLY on a connection that switched to BINARY framing.
+// SET is `SET <key> <length>` followed by that many raw bytes, APPLY is
+// `APPLY <length>` followed by a record a leader replicates. A value GET finds
+// comes back as its length on a line of its own, the bytes and a newline, a
+// missing one as `-1`. Other replies are a single line. Errors are those of
+// reading the framed value, after which the stream cannot be read on.
+fn serve_binary_command(
+    env: &Arc<RwLock<Environment>>,
+    command_args: &[String],
+    reader: &mut impl Read,
+    out: &mut dyn Write,
+) -> std::io::Result<()> {
+    if command_args.len() < 2 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "expected a key",
+        ));
+    }
+    if command_args[0] == "APPLY" {
+        let record = read_framed(reader, &command_args[1])?;
+        let mut locked = env.write().unwrap();
+        let result = match apply_replicated(&mut locked, &record) {
+            Ok(_) => writeln!(out, "OK"),
+            Err(e) => writeln!(out, "Could not apply record. Error: [{}]", e),
+        };
+        let job = locked.take_compaction_job();
+        drop(locked);
+        if let Some(job) = job {
+            spawn_compaction(env.clone(), job);
+        }
+        return result;
+    }
+    if command_args[0] == "GET" {
+        let shared = env.read().unwrap();
+        let value = match lookup_bytes(&shared, &command_key(&shared, &command_args[1])) {
+            Ok(value) => value,
+            Err(e) => return writeln!(out, "Could not read key. Error: [{}]", e),
+        };
+        return match value {
+            Some(value) => {
+                writeln!(out, "{}", value.len())?;
+                out.write_all(&value)?;
+                writeln!(out)
+            }
+            None => writeln!(out, "-1"),
+        };
+    }
+    let value = match command_args.get(2) {
+        Some(length) => read_framed(reader, length)?,
+        None => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "expected SET <key> <length>",
+            ));
+        }
+    };
+    let mut locked = env.write().unwrap();
+    let key = command_key(&locked, &command_args[1]);
+    locked.count_set();
+    let result = match set_bytes(&mut locked, &key, &value) {
+        Ok(_) => writeln!(out, "OK"),
+        Err(e) => writeln!(out, "Could not write key-value pair. Error: [{}]", e),
+    };
+    let job = locked.take_compaction_job();
+    drop(locked);
+    if let Some(job) = job {
+        spawn_compaction(env.clone(), job);
+    }
+    result
 }
 
-impl Environment {
-    pub fn new(data_path: &String, prefix: &String) -> Self {
-        let paths = read_dir(data_path).unwrap();
+// Reads the `length` bytes announced on a BINARY framed command line.
+fn read_framed(reader: &mut impl Read, length: &str) -> std::io::Result<Vec<u8>> {
+    let length = match length.parse::<usize>() {
+        Ok(length) if length <= MAX_BINARY_VALUE_BYTES => length,
+        _ => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("invalid length [{}]", length),
+            ));
+        }
+    };
+    let mut bytes = Vec::new();
+    reader.take(length as u64).read_to_end(&mut bytes)?;
+    if bytes.len() < length {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(bytes)
+}
 
-        let mut segments: Vec<Segment> = paths
-            .filter_map(|path| path.ok())
-            // TODO: do not build segment for CURRENT here
-            .filter(|p| p.file_name().into_string().unwrap().starts_with(prefix))
-            .map(|p| Segment::new(p.path().display().to_string()))
-            .collect();
+// How far the follower has applied the writes shipped to it, see `spawn_replication`.
+struct Replication {
+    // number of the latest write the follower answered, 0 before the first one
+    applied: Mutex<u64>,
+    advanced: Condvar,
+    // how long SETSYNC waits for its write to be applied
+    timeout: std::time::Duration,
+}
 
-        let index = segments
-            .iter()
-            .position(|s| s.file_path.ends_with(CURRENT_SEGMENT_SUFFIX));
+impl Replication {
+    // Waits until the follower applied the write numbered `position`, false if
+    // the timeout elapses first.
+    fn wait_for(&self, position: u64) -> bool {
+        let applied = self.applied.lock().unwrap();
+        let (applied, _) = self
+            .advanced
+            .wait_timeout_while(applied, self.timeout, |applied| *applied < position)
+            .unwrap();
+        *applied >= position
+    }
+}
+
+// Ships every later write to the follower serving commands at `addr`, one
+// BINARY framed APPLY at a time and in the order they were made, so values,
+// expiries and types arrive as they were written. A write is applied once the
+// follower answers it. A lost follower is reconnected to and the write in
+// flight sent again, which applying a record allows.
+fn spawn_replication(
+    env: &mut Environment,
+    addr: String,
+    timeout: std::time::Duration,
+) -> Arc<Replication> {
+    let replication = Arc::new(Replication {
+        applied: Mutex::new(env.write_position()),
+        advanced: Condvar::new(),
+        timeout,
+    });
+    let writes = env.replicate();
+    let shared = replication.clone();
+    std::thread::spawn(move || {
+        let mut follower = None;
+        for (position, record) in writes {
+            let mut command = format!("APPLY {}\n", record.len()).into_bytes();
+            command.extend_from_slice(&record);
+            while let Err(e) = ship_write(&mut follower, &addr, &command) {
+                eprintln!("Could not replicate to [{}]. Error: [{}]", addr, e);
+                follower = None;
+                std::thread::sleep(REPLICATION_RETRY_INTERVAL);
+            }
+            *shared.applied.lock().unwrap() = position;
+            shared.advanced.notify_all();
+        }
+    });
+    replication
+}
+
+// Sends one framed command to the follower, connecting and switching to
+// BINARY framing first if need be, and reads the line it answers with.
+fn ship_write(
+    follower: &mut Option<BufReader<TcpStream>>,
+    addr: &str,
+    command: &[u8],
+) -> std::io::Result<String> {
+    let follower = match follower {
+        Some(follower) => follower,
+        None => {
+            let follower = follower.insert(BufReader::new(TcpStream::connect(addr)?));
+            read_answer(follower, b"BINARY\n")?;
+            follower
+        }
+    };
+    read_answer(follower, command)
+}
+
+// Writes `command` to the follower and reads the line it answers with.
+fn read_answer(follower: &mut BufReader<TcpStream>, command: &[u8]) -> std::io::Result<String> {
+    follower.get_mut().write_all(command)?;
+    let mut answer = String::new();
+    if follower.read_line(&mut answer)? == 0 {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(answer)
+}
 
-        if index.is_some() {
-            segments.remove(index.unwrap());
+// SET that answers once the follower applied the write, or with an error once
+// the replication timeout elapses. The write stays in place either way.
+fn serve_set_sync(
+    env: &Arc<RwLock<Environment>>,
+    replication: Option<&Replication>,
+    command_args: &[String],
+) -> Vec<u8> {
+    let mut result = Vec::new();
+    let replication = match replication {
+        Some(replication) => replication,
+        None => {
+            let _ = writeln!(
+                result,
+                "Command [SETSYNC] needs a follower, see --replicate-to"
+            );
+            return result;
         }
+    };
+    if command_args.len() != 3 {
+        let _ = writeln!(result, "Usage: SETSYNC <key> <value>");
+        return result;
+    }
+    let mut locked = env.write().unwrap();
+    let position = locked.write_position();
+    let set_args = [
+        String::from("SET"),
+        command_args[1].clone(),
+        command_args[2].clone(),
+    ];
+    if let Err(e) = run_command(&mut locked, &set_args, &mut result) {
+        result.clear();
+        let _ = writeln!(result, "Failed to work with DB, [{}]", e);
+    }
+    let written = locked.write_position();
+    let job = locked.take_compaction_job();
+    drop(locked);
+    if let Some(job) = job {
+        spawn_compaction(env.clone(), job);
+    }
+    // nothing was written when the SET was refused, its result says why
+    if written > position && !replication.wait_for(written) {
+        result.clear();
+        let _ = writeln!(
+            result,
+            "Write of key [{}] not applied by the follower within [{}] ms",
+            command_args[1],
+            replication.timeout.as_millis()
+        );
+    }
+    result
+}
 
-        return Environment {
-            data_path: data_path.clone(),
-            file_prefix: prefix.clone(),
-            segments: segments,
-            write_segment: Environment::new_write_segment(&data_path, &prefix),
+// Answers RESP commands until the client disconnects. After a protocol error
+// the stream cannot be followed any more, so the connection is closed.
+fn serve_resp_connection(
+    env: &Arc<RwLock<Environment>>,
+    stream: TcpStream,
+    max_line_bytes: Option<usize>,
+    flush_policy: FlushPolicy,
+    auth: Option<&str>,
+) -> std::io::Result<()> {
+    let mut writer = BufWriter::new(stream.try_clone()?);
+    let mut reader = BufReader::new(stream);
+    let mut authenticated = auth.is_none();
+    loop {
+        let args = match resp::read_command(&mut reader, max_line_bytes) {
+            Ok(Some(args)) => args,
+            Ok(None) => return writer.flush(),
+            Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+                let reply = resp::Reply::Error(format!("ERR {}", e));
+                writer.write_all(&reply.encode())?;
+                return writer.flush();
+            }
+            Err(e) => return Err(e),
+        };
+        let is_auth = args
+            .first()
+            .is_some_and(|arg| arg.eq_ignore_ascii_case(b"AUTH"));
+        let reply = if is_auth {
+            // as Redis answers it
+            match (auth, args.get(1)) {
+                (None, _) => resp::Reply::Error(String::from(
+                    "ERR AUTH called without any password configured",
+                )),
+                (Some(expected), Some(token)) if args.len() == 2 => {
+                    authenticated = token_matches(token, expected);
+                    match authenticated {
+                        true => resp::Reply::Simple(String::from("OK")),
+                        false => resp::Reply::Error(String::from("WRONGPASS invalid password")),
+                    }
+                }
+                (Some(_), _) => resp::Reply::Error(String::from(
+                    "ERR wrong number of arguments for 'auth' command",
+                )),
+            }
+        } else if !authenticated {
+            resp::Reply::Error(String::from("NOAUTH Authentication required."))
+        } else {
+            let shared = env.read().unwrap();
+            // evicting by size needs every access recorded
+            if resp::is_read_only(&args) && shared.max_db_size.is_none() {
+                resp::execute_shared(&shared, &args)
+            } else {
+                drop(shared);
+                let mut locked = env.write().unwrap();
+                let reply = resp::execute(&mut locked, &args);
+                let job = locked.take_compaction_job();
+                drop(locked);
+                if let Some(job) = job {
+                    spawn_compaction(env.clone(), job);
+                }
+                reply
+            }
         };
+        writer.write_all(&reply.encode())?;
+        // commands are sent whole, so buffered bytes are the next ones
+        if flush_policy == FlushPolicy::Immediate || reader.buffer().is_empty() {
+            writer.flush()?;
+        }
     }
+}
 
-    pub fn next_file_name(&self) -> String {
-        let file_number = self
-            .segments
-            .iter()
-            .map(|s| u64::from_str_radix(s.file_path.split('.').last().unwrap(), 10).unwrap())
-            .max()
-            .unwrap();
-        let path_to_file =
-            Path::new(&self.data_path).join(format!("{}.{:05}", self.file_prefix, file_number + 1));
-        return path_to_file.display().to_string();
+// Lines of `reader` like `BufRead::lines`, except that a line longer than
+// `max_bytes` is skipped without being buffered and yields an InvalidInput error.
+struct BoundedLines<R> {
+    reader: R,
+    max_bytes: Option<usize>,
+}
+
+impl<R: BufRead> BoundedLines<R> {
+    fn skip_line(&mut self) -> std::io::Result<()> {
+        loop {
+            let buffer = self.reader.fill_buf()?;
+            if buffer.is_empty() {
+                return Ok(());
+            }
+            match buffer.iter().position(|byte| *byte == b'\n') {
+                Some(position) => {
+                    self.reader.consume(position + 1);
+                    return Ok(());
+                }
+                None => {
+                    let length = buffer.len();
+                    self.reader.consume(length);
+                }
+            }
+        }
     }
+}
+
+impl<R: BufRead> Iterator for BoundedLines<R> {
+    type Item = std::io::Result<String>;
 
-    fn new_write_segment(data_path: &String, file_prefix: &String) -> Segment {
-        Segment::new(
-            Path::new(data_path)
-                .join(format!("{}.{}", file_prefix, CURRENT_SEGMENT_SUFFIX))
-                .display()
-                .to_string(),
+    fn next(&mut self) -> Option<Self::Item> {
+        // room for the longest line allowed and its `\r\n`, which does not count
+        let limit = self
+            .max_bytes
+            .map_or(u64::MAX, |max_bytes| max_bytes as u64 + 2);
+        let mut line = Vec::new();
+        match (&mut self.reader).take(limit).read_until(b'\n', &mut line) {
+            Ok(0) => return None,
+            Ok(_) => (),
+            Err(e) => return Some(Err(e)),
+        }
+        let complete = line.last() == Some(&b'\n');
+        if complete {
+            line.pop();
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+        }
+        if let Some(max_bytes) = self.max_bytes
+            && line.len() > max_bytes
+        {
+            if !complete && let Err(e) = self.skip_line() {
+                return Some(Err(e));
+            }
+            return Some(Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("line longer than {} bytes", max_bytes),
+            )));
+        }
+        Some(
+            String::from_utf8(line)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e)),
         )
     }
+}
 
-    pub fn retire_write_segment(&mut self) {
-        // we have only one write thread, so this is fine
-        let next_file_name = self.next_file_name();
-        rename(&self.write_segment.file_path, &next_file_name).unwrap();
-        self.segments.push(Segment::new(next_file_name));
-        self.write_segment = Environment::new_write_segment(&self.data_path, &self.file_prefix);
-    }
-
-    pub fn compact_segments(&mut self) -> Result<(), std::io::Error> {
-        // This function is blocking an env, need to rewrite
-        let mut total_data: HashMap<String, String> = HashMap::new();
-        for segment in self.segments.iter() {
-            let file = OpenOptions::new().read(true).open(&segment.file_path)?;
-            let buf_reader = BufReader::new(file);
-            for line in buf_reader.lines() {
-                let real_line = line?;
-                let (line_key, val) = real_line.split_once(',').unwrap();
-                if val == DELETE_TERMINATOR {
-                    total_data.remove(&line_key.to_string());
-                } else {
-                    total_data.insert(line_key.to_string(), val.to_string());
-                }
+// Checks the age of the write segment in the background, a few times per
+// `max_age`, so an idle session still rotates it on time.
+fn spawn_segment_age_check(env: Arc<RwLock<Environment>>, max_age: std::time::Duration) {
+    let interval = (max_age / 4).max(std::time::Duration::from_millis(10));
+    std::thread::spawn(move || {
+        loop {
+            std::thread::sleep(interval);
+            if let Err(e) = env.write().unwrap().retire_if_older_than(max_age) {
+                eprintln!("Could not retire the write segment. Error: [{}]", e);
             }
         }
-        let mut new_segments: Vec<Segment> = Vec::new();
-        let mut current_segment = Segment::new(self.next_file_name());
-        for (key, val) in total_data {
-            if current_segment.size > SEGMENT_THRESHOLD {
-                new_segments.push(current_segment);
-                current_segment = Segment::new(self.next_file_name());
+    });
+}
+
+// Tombstones the expired keys in the background every `interval`.
+fn spawn_expiry_sweep(env: Arc<RwLock<Environment>>, interval: std::time::Duration) {
+    std::thread::spawn(move || {
+        loop {
+            std::thread::sleep(interval);
+            if let Err(e) = env.write().unwrap().sweep_expired() {
+                eprintln!("Could not sweep expired keys. Error: [{}]", e);
             }
-            current_segment.save_data(&key, &val)?;
         }
-        new_segments.push(current_segment);
-        let filenames: Vec<String> = self.segments.iter().map(|s| s.file_path.clone()).collect();
-        for file_path in filenames {
-            remove_file(file_path)?;
+    });
+}
+
+// Runs a compaction started by `COMPACT --background`, holding the environment
+// only to swap the compacted segment in at the end.
+fn spawn_compaction(
+    env: Arc<RwLock<Environment>>,
+    mut job: CompactionJob,
+) -> std::thread::JoinHandle<()> {
+    std::thread::spawn(move || {
+        let result = job.run();
+        match env.write().unwrap().finish_compaction(job, result) {
+            Ok(_) => eprintln!("Background compaction finished"),
+            Err(e) => eprintln!("Background compaction failed. Error: [{}]", e),
         }
-        self.segments = new_segments;
-        Ok(())
+    })
+}
+
+// VERIFY from the command line. The database is opened read only: opening it
+// for writes would cut off a torn tail before VERIFY could report it.
+fn verify_read_only(
+    data_path: &String,
+    prefix: &String,
+    namer: Box<dyn SegmentNamer>,
+    out: &mut dyn Write,
+) -> std::io::Result<()> {
+    match Environment::open_read_only(data_path, prefix, namer) {
+        Ok(env) => print_verify_report(out, &verify(&env)?),
+        Err(e) => writeln!(out, "Could not open database. Error: [{}]", e),
     }
 }
 
-fn build_index(file_path: &String) -> Result<HashMap<String, u64>, std::io::Error> {
-    let mut result = HashMap::new();
-    let file = OpenOptions::new().read(true).open(file_path)?;
-    let buf_reader = BufReader::new(file);
+fn open_environment(
+    data_path: &String,
+    prefix: &String,
+    options: &Options,
+) -> Result<Environment, KvError> {
+    let namer = segment_namer(options.segment_naming.as_deref().unwrap_or("numeric")).unwrap();
+    let mut env = Environment::with_namer(data_path, prefix, namer)?;
+    if let Some(format) = options.record_format.as_deref() {
+        env.set_record_codec(record_codec(format).unwrap())?;
+    }
+    env.max_read_fanout = options.max_read_fanout;
+    env.sstable_block_size = options.sstable_block_size;
+    env.binary_keys = options.binary_keys;
+    if options.checksums {
+        env.enable_checksums();
+    }
+    if let Some(segment_size) = options.segment_size {
+        env.segment_threshold = segment_size;
+    }
+    env.max_segments = options.max_segments;
+    env.max_records_per_segment = options.max_segment_records;
+    env.min_write_utilization = options.min_write_utilization;
+    env.max_key_len = options.max_key_len;
+    env.max_value_len = options.max_value_len;
+    env.compress = options.compress;
+    if options.no_mmap {
+        env.set_mmap(false);
+    }
+    env.max_db_size = options.max_db_size;
+    env.max_disk_bytes = options.max_disk_bytes;
+    env.partition_by_date = options.partition_by_date;
+    env.in_place_updates = options.in_place_updates;
+    env.min_free_bytes = options.min_free_bytes;
+    env.disabled_commands = options.disabled_commands.iter().cloned().collect();
+    env.comparator = key_comparator(options.key_order.as_deref().unwrap_or("bytes")).unwrap();
+    if let Some(cache_capacity) = options.cache_capacity {
+        env.set_value_cache_capacity(cache_capacity);
+    }
+    env.sync_policy = sync_policy(options.sync_policy.as_deref().unwrap_or("never")).unwrap();
+    let strategy = options.compaction_strategy.as_deref().unwrap_or("full");
+    env.compaction_strategy = compaction_strategy(strategy).unwrap();
+    if options.track_count {
+        env.track_live_count()?;
+    }
+    if options.sweep_interval.is_some() {
+        env.track_expiries()?;
+    }
+    Ok(env)
+}
 
-    let mut current_position: u64 = 0;
-    for line in buf_reader.lines() {
-        let real_line = line?;
-        let (line_key, _) = real_line.split_once(',').expect(
-            format!(
-                "Failed to split line [{}].\nCheck for db corruption",
-                real_line
-            )
-            .as_str(),
+// Shares an environment with the threads working on it in the background.
+fn share_environment(env: Environment, options: &Options) -> Arc<RwLock<Environment>> {
+    let env = Arc::new(RwLock::new(env));
+    if let Some(max_segment_age) = options.max_segment_age {
+        spawn_segment_age_check(
+            env.clone(),
+            std::time::Duration::from_millis(max_segment_age),
         );
-        result.insert(line_key.to_string(), current_position);
-        current_position += real_line.len() as u64 + 1; // accounting for newline here
     }
-    return Ok(result);
+    if let Some(sweep_interval) = options.sweep_interval {
+        spawn_expiry_sweep(
+            env.clone(),
+            std::time::Duration::from_millis(sweep_interval),
+        );
+    }
+    env
+}
+
+// Namespaces are file prefixes, so their names are kept clear of paths and of
+// the dots that separate a prefix from the rest of a file name.
+fn is_namespace_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+// Runs one command and writes its result the way the line protocol answers it.
+fn run_command(
+    env: &mut Environment,
+    command_args: &[String],
+    out: &mut dyn Write,
+) -> std::io::Result<()> {
+    if command_args
+        .first()
+        .is_some_and(|command| command == "GREP")
+    {
+        eprintln!("Warning: GREP reads the value of every live key");
+    }
+    let result = handle_command(env, command_args)?;
+    write_command_result(out, command_args, &result)
 }
 
-fn get_data(env: &Environment, key: &String) -> Result<String, SegmentError> {
-    match env.write_segment.get_data(key) {
-        Ok(value) => {
-            if !value.is_empty() {
-                return Ok(value);
+// Writes `result` the way the line protocol answers `command_args`.
+fn write_command_result(
+    out: &mut dyn Write,
+    command_args: &[String],
+    result: &CommandResult,
+) -> std::io::Result<()> {
+    match result {
+        CommandResult::Value(Some(value)) => writeln!(out, "Found value: [{}]", value),
+        CommandResult::Value(None) => match command_args.get(2).map(String::as_str) {
+            // tells a deleted key apart from one without any record left
+            Some("--include-tombstone") => {
+                writeln!(out, "Key [{}] has no record", command_args[1])
+            }
+            _ => writeln!(out, "Value not found"),
+        },
+        CommandResult::Written => writeln!(
+            out,
+            "Written key: [{}] value: [{}]",
+            command_args[1], command_args[2]
+        ),
+        CommandResult::Deleted => writeln!(out, "Deleted key: [{}]", command_args[1]),
+        CommandResult::Compacted(summary) => {
+            writeln!(out, "Segments compacted")?;
+            match command_args.get(1).is_some_and(|arg| arg == "--summary") {
+                true => writeln!(
+                    out,
+                    "Merged [{}] segments into [{}], reclaimed [{}] bytes, purged [{}] tombstones",
+                    summary.segments_merged,
+                    summary.segments_written,
+                    summary.bytes_reclaimed,
+                    summary.tombstones_purged
+                ),
+                false => Ok(()),
             }
         }
-        Err(e) => {
-            return Err(e);
+        CommandResult::Error(e) => write_command_error(out, command_args, e),
+        CommandResult::Reindexed(e, reindexed) => {
+            write_command_error(out, command_args, e)?;
+            let KvError::OffsetBeyondEof { file_path, .. } = e else {
+                return Ok(());
+            };
+            match reindexed {
+                Ok(_) => writeln!(out, "Segment [{}] reindexed", file_path),
+                Err(e) => writeln!(out, "Could not reindex [{}]. Error: [{}]", file_path, e),
+            }
         }
+        CommandResult::Output(lines) => out.write_all(lines),
     }
-    for segment in env.segments.iter().rev() {
-        match segment.get_data(key) {
-            Ok(value) => {
-                if !value.is_empty() {
-                    return Ok(value);
+}
+
+fn write_command_error(
+    out: &mut dyn Write,
+    command_args: &[String],
+    e: &KvError,
+) -> std::io::Result<()> {
+    match command_args[0].as_str() {
+        "COMPACT" => writeln!(out, "Failed to compact segments: [{}]", e),
+        "GET" => match e {
+            KvError::Io(e) => writeln!(
+                out,
+                "Could not find value for key [{}]. Error: [{:?}]",
+                command_args[1], e
+            ),
+            KvError::KeyDeleted { file_path }
+                if command_args
+                    .get(2)
+                    .is_some_and(|arg| arg == "--include-tombstone") =>
+            {
+                // records do not carry a write time, so there is no deletion time to report
+                writeln!(
+                    out,
+                    "Key [{}] deleted: [true] tombstone in: [{}] deleted at: [unknown]",
+                    command_args[1], file_path
+                )
+            }
+            KvError::KeyDeleted { .. } => writeln!(out, "Value not found (actually deleted)"),
+            _ => writeln!(
+                out,
+                "Could not read key [{}]. Error: [{}]",
+                command_args[1], e
+            ),
+        },
+        _ => writeln!(out, "Could not write key-value pair. Error: [{}]", e),
+    }
+}
+
+// Re-prints the results of the last `count` commands (1 by default), oldest first.
+fn print_history(
+    history: &VecDeque<Vec<u8>>,
+    count: Option<&String>,
+    out: &mut dyn Write,
+) -> std::io::Result<()> {
+    let count = match count.map(|count| count.parse::<usize>()) {
+        None => 1,
+        Some(Ok(count)) => count,
+        Some(Err(_)) => return writeln!(out, "Invalid count [{}]", count.unwrap()),
+    };
+    for result in history.iter().skip(history.len().saturating_sub(count)) {
+        out.write_all(result)?;
+    }
+    Ok(())
+}
+
+// The interactive mode: runs the command lines of `input` against `env`, with
+// LAST to repeat results. The environment is locked one command at a time, so
+// background threads get their turn in between.
+fn interactive(
+    mut env: Arc<RwLock<Environment>>,
+    data_path: &String,
+    prefix: &str,
+    options: &Options,
+    input: impl BufRead,
+    out: &mut dyn Write,
+) -> std::io::Result<()> {
+    // USE switches between prefixes of the data directory, each opened once
+    let mut namespaces: HashMap<String, Arc<RwLock<Environment>>> = HashMap::new();
+    namespaces.insert(prefix.to_string(), env.clone());
+    let mut history: VecDeque<Vec<u8>> = VecDeque::with_capacity(RESULT_HISTORY_SIZE);
+    let mut compactions = Vec::new();
+    let mut lines = BoundedLines {
+        reader: input,
+        max_bytes: options.max_line_bytes,
+    };
+    while let Some(line) = lines.next() {
+        match line {
+            Ok(real_line) => {
+                write!(out, "> ")?;
+                let command_args = match split_command(&real_line) {
+                    Ok(command_args) if command_args.is_empty() => continue,
+                    Ok(command_args) => command_args,
+                    Err(e) => {
+                        writeln!(out, "Line rejected: [{}]", e)?;
+                        continue;
+                    }
+                };
+                if command_args[0] == "LAST" {
+                    print_history(&history, command_args.get(1), out)?;
+                    continue;
+                }
+                if command_args[0] == "USE" {
+                    let namespace = match command_args.get(1) {
+                        Some(namespace) if is_namespace_name(namespace) => namespace,
+                        _ => {
+                            writeln!(
+                                out,
+                                "Usage: USE <namespace>, of letters, digits, '-' and '_'"
+                            )?;
+                            continue;
+                        }
+                    };
+                    if !namespaces.contains_key(namespace) {
+                        match open_environment(data_path, namespace, options) {
+                            Ok(opened) => {
+                                let opened = share_environment(opened, options);
+                                namespaces.insert(namespace.clone(), opened);
+                            }
+                            Err(e) => {
+                                writeln!(
+                                    out,
+                                    "Could not open namespace [{}]. Error: [{}]",
+                                    namespace, e
+                                )?;
+                                continue;
+                            }
+                        }
+                    }
+                    env = namespaces[namespace].clone();
+                    writeln!(out, "Using namespace [{}]", namespace)?;
+                    continue;
+                }
+                let mut result = Vec::new();
+                let mut locked = env.write().unwrap();
+                if command_args.len() == 1 && command_args[0] == "ATOMICLOAD" {
+                    // the block follows on the next input lines
+                    atomic_load(&mut locked, &mut lines, &mut result)?;
+                } else {
+                    run_command(&mut locked, &command_args, &mut result)?;
                 }
+                let job = locked.take_compaction_job();
+                drop(locked);
+                if let Some(job) = job {
+                    compactions.push(spawn_compaction(env.clone(), job));
+                }
+                out.write_all(&result)?;
+                if history.len() == RESULT_HISTORY_SIZE {
+                    history.pop_front();
+                }
+                history.push_back(result);
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::InvalidInput => {
+                writeln!(out, "> Line rejected: [{}]", e)?;
             }
             Err(e) => {
+                writeln!(out, "Failed to work with DB, [{}]", e)?;
                 return Err(e);
             }
         }
     }
-    Ok(String::new())
-}
-
-fn set_data(env: &mut Environment, key: &String, value: &String) -> Result<(), std::io::Error> {
-    if env.write_segment.size > SEGMENT_THRESHOLD {
-        env.retire_write_segment();
+    // finish running compactions instead of leaving their temporary files behind
+    for compaction in compactions {
+        let _ = compaction.join();
     }
-    env.write_segment.save_data(key, value)
+    for (namespace, env) in namespaces.iter() {
+        if let Err(e) = env.write().unwrap().close() {
+            writeln!(
+                out,
+                "Could not close namespace [{}]. Error: [{}]",
+                namespace, e
+            )?;
+        }
+    }
+    Ok(())
 }
 
-fn handle_command(env: &mut Environment, command_args: &Vec<String>) {
-    let command = &command_args[0];
-    if command == "SET" {
-        let key = &command_args[1];
-
-        let value = &command_args[2];
-        if value.is_empty() {
-            println!("Empty value, ignoring");
-            return;
+fn main() -> std::io::Result<()> {
+    let (options, args) = match parse_options(env::args().skip(1).collect()) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            println!("{}", e);
+            return Ok(());
         }
-        let return_value = set_data(env, key, value);
-        match return_value {
-            Ok(_) => {
-                println!("Written key: [{}] value: [{}]", key, value);
-            }
+    };
+    let data_path = options.data_dir.clone().unwrap_or(String::from("./data/"));
+    let prefix = options.prefix.clone().unwrap_or(String::from("db"));
+    let naming = options.segment_naming.as_deref().unwrap_or("numeric");
+    let namer = segment_namer(naming).unwrap();
+    // the paths below run before an environment exists to check it
+    let is_disabled = |command: &String| options.disabled_commands.contains(command);
+    if args.first().is_some_and(is_disabled) {
+        println!("Command [{}] is disabled", args[0]);
+        return Ok(());
+    }
+    if !options.interactive && args.first().is_some_and(|arg| arg == "DOCTOR") {
+        // runs before opening, a damaged directory may not open at all
+        let fix = args.get(1).is_some_and(|arg| arg == "--fix");
+        let issues = doctor(&FileStorage, &data_path, &prefix, namer.as_ref(), fix)?;
+        print_doctor_report(&mut stdout(), &issues, fix)?;
+        return Ok(());
+    }
+    if !options.interactive && args.first().is_some_and(|arg| arg == "VERIFY") {
+        return verify_read_only(&data_path, &prefix, namer, &mut stdout());
+    }
+    if !options.read_only_prefixes.is_empty() {
+        let envs: Result<HashMap<String, Environment>, _> = options
+            .read_only_prefixes
+            .iter()
+            .map(|prefix| {
+                let namer = segment_namer(naming).unwrap();
+                Environment::open_read_only(&data_path, prefix, namer)
+                    .map(|env| (prefix.clone(), env))
+            })
+            .collect();
+        let envs = match envs {
+            Ok(envs) => envs,
             Err(e) => {
-                println!("Could not write key-value pair. Error: [{}]", e);
+                println!("Could not open database. Error: [{}]", e);
+                return Ok(());
             }
+        };
+        if !options.interactive {
+            return handle_read_only_command(&envs, &args, &mut stdout());
         }
-    } else if command == "GET" {
-        let key = &command_args[1];
-
-        let return_value = get_data(env, key);
-        match return_value {
-            Ok(value) => {
-                if value.is_empty() {
-                    println!("Value not found");
-                } else {
-                    println!("Found value: [{}]", value);
+        let lines = BoundedLines {
+            reader: stdin().lock(),
+            max_bytes: options.max_line_bytes,
+        };
+        for line in lines {
+            let real_line = match line {
+                Err(e) if e.kind() == std::io::ErrorKind::InvalidInput => {
+                    println!("> Line rejected: [{}]", e);
+                    continue;
                 }
-            }
-            Err(e) => match e {
-                SegmentError::Io(e) => {
-                println!("Could not find value for key [{}]. Error: [{:?}]", key, e);
-                },
-                SegmentError::KeyDeleted => {
-                    println!("Value not found (actually deleted)");
+                line => line?,
+            };
+            print!("> ");
+            let command_args = match split_command(&real_line) {
+                Ok(command_args) if command_args.is_empty() => continue,
+                Ok(command_args) => command_args,
+                Err(e) => {
+                    println!("Line rejected: [{}]", e);
+                    continue;
                 }
+            };
+            if is_disabled(&command_args[0]) {
+                println!("Command [{}] is disabled", command_args[0]);
+                continue;
             }
+            handle_read_only_command(&envs, &command_args, &mut stdout())?;
         }
-    } else if command == "COMPACT" {
-        match env.compact_segments() {
-            Ok(_) => {
-                println!("Segments compacted");
-            }
-            Err(e) => {
+        return Ok(());
+    }
+    let mut env = match open_environment(&data_path, &prefix, &options) {
+        Ok(env) => env,
+        Err(e) => {
+            println!("Could not open database. Error: [{}]", e);
+            return Ok(());
+        }
+    };
+    if let Some(addr) = options.serve.as_ref() {
+        let timeout = options
+            .replication_timeout
+            .unwrap_or(REPLICATION_TIMEOUT_MILLIS);
+        let replication = options.replicate_to.clone().map(|follower| {
+            spawn_replication(
+                &mut env,
+                follower,
+                std::time::Duration::from_millis(timeout),
+            )
+        });
+        let env = share_environment(env, &options);
+        return serve(
+            env,
+            addr,
+            options.resp,
+            options.max_line_bytes,
+            options.flush_policy,
+            replication,
+            options.require_auth.clone(),
+        );
+    }
+    if !options.interactive {
+        if args.len() == 1 && args[0] == "ATOMICLOAD" {
+            let mut lines = BoundedLines {
+                reader: stdin().lock(),
+                max_bytes: options.max_line_bytes,
+            };
+            return atomic_load(&mut env, &mut lines, &mut stdout());
+        }
+        run_command(&mut env, &args, &mut stdout())?;
+        // nothing else runs in this mode, so there is no reason to wait in the background
+        if let Some(mut job) = env.take_compaction_job() {
+            let result = job.run();
+            if let Err(e) = env.finish_compaction(job, result) {
                 println!("Failed to compact segments: [{}]", e);
             }
         }
-    } else if command == "DELETE" {
-        let key = &command_args[1];
-        let return_value = set_data(env, key, &DELETE_TERMINATOR.to_string());
-        match return_value {
-            Ok(_) => {
-                println!("Deleted key: [{}]", key);
-            }
-            Err(e) => {
-                println!("Could not write key-value pair. Error: [{}]", e);
-            }
+        if let Err(e) = env.close() {
+            println!("Could not close the database. Error: [{}]", e);
         }
+        return Ok(());
     }
+    let env = share_environment(env, &options);
+    interactive(
+        env,
+        &data_path,
+        &prefix,
+        &options,
+        stdin().lock(),
+        &mut stdout(),
+    )
 }
 
-fn main() -> std::io::Result<()> {
-    let args: Vec<String> = env::args().skip(1).collect();
-    let is_interactive = args[0] == "--interactive";
-    // TODO: create directory if not exists
-    let mut env = Environment::new(&String::from("./data/"), &String::from("db"));
-    if !is_interactive {
-        handle_command(&mut env, &args);
-        return Ok(());
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kvdb_alpha::set_data;
+    use scratch::ScratchDir;
+    use std::net::Shutdown;
+
+    fn get(env: &Environment, key: &str) -> Option<String> {
+        lookup(env, key.as_bytes()).unwrap()
     }
-    let stdin = stdin();
-    for line in stdin.lock().lines() {
-        match line {
-            Ok(real_line) => {
-                print!("> ");
-                let command_args: Vec<String> =
-                    real_line.splitn(3, ' ').map(|x| String::from(x)).collect();
-                handle_command(&mut env, &command_args);
+
+    fn open(dir: &ScratchDir) -> Environment {
+        Environment::with_namer(
+            &dir.0,
+            &String::from("db"),
+            segment_namer("numeric").unwrap(),
+        )
+        .unwrap()
+    }
+
+    fn shared_env(dir: &ScratchDir) -> Arc<RwLock<Environment>> {
+        let env = open_environment(&dir.0, &String::from("db"), &Options::default()).unwrap();
+        Arc::new(RwLock::new(env))
+    }
+
+    fn dir_listing(dir: &ScratchDir) -> Vec<String> {
+        let mut names: Vec<String> = std::fs::read_dir(&dir.0)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    }
+
+    // Sends `request` in one write, closes the sending half and returns all
+    // that `serve_one` answered on a single connection.
+    fn exchange(
+        request: &[u8],
+        serve_one: impl FnOnce(TcpStream) -> std::io::Result<()> + Send + 'static,
+    ) -> Vec<u8> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || serve_one(listener.accept().unwrap().0));
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(request).unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).unwrap();
+        server.join().unwrap().unwrap();
+        response
+    }
+
+    #[test]
+    fn pipelined_lines_get_every_response_in_order() {
+        let request = "SET a 1\nSET b two\nGET a\nGET b\nDELETE a\nGET a\n";
+        let expected = "Written key: [a] value: [1]\n\
+                        Written key: [b] value: [two]\n\
+                        Found value: [1]\n\
+                        Found value: [two]\n\
+                        Deleted key: [a]\n\
+                        Value not found (actually deleted)\n";
+        for flush_policy in [FlushPolicy::Batch, FlushPolicy::Immediate] {
+            let dir = ScratchDir::new();
+            let env = shared_env(&dir);
+            let response = exchange(request.as_bytes(), move |stream| {
+                serve_connection(&env, stream, None, flush_policy, None, None)
+            });
+            assert_eq!(String::from_utf8(response).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn command_results_are_written_the_way_the_line_protocol_answers() {
+        let dir = ScratchDir::new();
+        std::fs::write(format!("{}/db.00001", dir.0), "").unwrap();
+        let mut env = open(&dir);
+        let run = |env: &mut Environment, line: &str| {
+            let mut out = Vec::new();
+            run_command(env, &split_command(line).unwrap(), &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(
+            run(&mut env, "SET gone 1"),
+            "Written key: [gone] value: [1]\n"
+        );
+        env.retire_write_segment().unwrap();
+        assert_eq!(run(&mut env, "DELETE gone"), "Deleted key: [gone]\n");
+        let output = run(&mut env, "GET gone --include-tombstone");
+        assert!(
+            output.starts_with("Key [gone] deleted: [true] tombstone in: [")
+                && output.ends_with("] deleted at: [unknown]\n"),
+            "{}",
+            output
+        );
+        assert_eq!(
+            run(&mut env, "GET never --include-tombstone"),
+            "Key [never] has no record\n"
+        );
+        assert_eq!(
+            run(&mut env, "GET gone"),
+            "Value not found (actually deleted)\n"
+        );
+        assert_eq!(run(&mut env, "GET never"), "Value not found\n");
+        assert_eq!(run(&mut env, "COMPACT"), "Segments compacted\n");
+        // keys are written the way they were given
+        env.binary_keys = true;
+        assert_eq!(
+            run(&mut env, "SET ff00 value"),
+            "Written key: [ff00] value: [value]\n"
+        );
+        assert_eq!(run(&mut env, "GET FF00"), "Found value: [value]\n");
+    }
+
+    #[test]
+    fn pipelined_resp_commands_get_every_reply_in_order() {
+        let request = b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\n1\r\n\
+                        *2\r\n$3\r\nGET\r\n$1\r\na\r\n\
+                        *2\r\n$3\r\nGET\r\n$1\r\nb\r\n\
+                        *1\r\n$4\r\nPING\r\n";
+        let expected = b"+OK\r\n$1\r\n1\r\n$-1\r\n+PONG\r\n";
+        for flush_policy in [FlushPolicy::Batch, FlushPolicy::Immediate] {
+            let dir = ScratchDir::new();
+            let env = shared_env(&dir);
+            let response = exchange(request, move |stream| {
+                serve_resp_connection(&env, stream, None, flush_policy, None)
+            });
+            assert_eq!(response, expected);
+        }
+    }
+
+    #[test]
+    fn read_only_prefixes_resolve_keys_from_each() {
+        let dir = ScratchDir::new();
+        for (prefix, value) in [("users", "ann"), ("orders", "42")] {
+            let mut env = Environment::with_namer(
+                &dir.0,
+                &prefix.to_string(),
+                segment_namer("numeric").unwrap(),
+            )
+            .unwrap();
+            let args: Vec<String> = ["SET", "id", value].map(String::from).to_vec();
+            handle_command(&mut env, &args).unwrap();
+        }
+        let before = dir_listing(&dir);
+        let envs: HashMap<String, Environment> = ["users", "orders"]
+            .iter()
+            .map(|prefix| {
+                let namer = segment_namer("numeric").unwrap();
+                let env = Environment::open_read_only(&dir.0, &prefix.to_string(), namer).unwrap();
+                (prefix.to_string(), env)
+            })
+            .collect();
+        let mut out = Vec::new();
+        for line in [
+            "GET users id",
+            "GET orders id",
+            "GET orders missing",
+            "GET other id",
+        ] {
+            let args = split_command(line).unwrap();
+            handle_read_only_command(&envs, &args, &mut out).unwrap();
+        }
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "Found value: [ann]\nFound value: [42]\nValue not found\nPrefix [other] is not open\n"
+        );
+        drop(envs);
+        assert_eq!(dir_listing(&dir), before);
+    }
+
+    #[test]
+    fn last_repeats_the_previous_results() {
+        let dir = ScratchDir::new();
+        let env = shared_env(&dir);
+        let input = "SET a 1\nGET a\nGET b\nLAST 2\nLAST\nLAST x\n";
+        let mut out = Vec::new();
+        interactive(
+            env,
+            &dir.0,
+            "db",
+            &Options::default(),
+            input.as_bytes(),
+            &mut out,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "> Written key: [a] value: [1]\n\
+             > Found value: [1]\n\
+             > Value not found\n\
+             > Found value: [1]\nValue not found\n\
+             > Value not found\n\
+             > Invalid count [x]\n"
+        );
+    }
+
+    #[test]
+    fn setsync_answers_once_the_follower_has_the_value() {
+        let follower_dir = ScratchDir::new();
+        let follower = shared_env(&follower_dir);
+        let follower_addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        std::thread::spawn(move || {
+            serve(
+                follower,
+                &follower_addr.to_string(),
+                false,
+                None,
+                FlushPolicy::Batch,
+                None,
+                None,
+            )
+        });
+        let dir = ScratchDir::new();
+        let mut env = open(&dir);
+        let timeout = std::time::Duration::from_secs(10);
+        let replication = spawn_replication(&mut env, follower_addr.to_string(), timeout);
+        let env = Arc::new(RwLock::new(env));
+        let request = "SET a 1\nDELETE a\nSETSYNC b \"two words\"\nSETSYNC b\n";
+        let response = exchange(request.as_bytes(), move |stream| {
+            serve_connection(
+                &env,
+                stream,
+                None,
+                FlushPolicy::Batch,
+                Some(&replication),
+                None,
+            )
+        });
+        assert_eq!(
+            String::from_utf8(response).unwrap(),
+            "Written key: [a] value: [1]\n\
+             Deleted key: [a]\n\
+             Written key: [b] value: [two words]\n\
+             Command [SETSYNC] is missing arguments\n"
+        );
+        // the writes before it were shipped first, in order
+        let namer = segment_namer("numeric").unwrap();
+        let follower =
+            Environment::open_read_only(&follower_dir.0, &String::from("db"), namer).unwrap();
+        assert_eq!(get(&follower, "b"), Some(String::from("two words")));
+        assert_eq!(get(&follower, "a"), None);
+    }
+
+    #[test]
+    fn replicated_records_keep_their_bytes_and_expiry() {
+        let follower_dir = ScratchDir::new();
+        let follower = shared_env(&follower_dir);
+        let follower_addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        std::thread::spawn(move || {
+            serve(
+                follower,
+                &follower_addr.to_string(),
+                false,
+                None,
+                FlushPolicy::Batch,
+                None,
+                None,
+            )
+        });
+        let dir = ScratchDir::new();
+        let mut env = open(&dir);
+        let timeout = std::time::Duration::from_secs(10);
+        let replication = spawn_replication(&mut env, follower_addr.to_string(), timeout);
+        let raw = [0xff, 0xfe, b'\n', b',', b'\\'];
+        set_bytes(&mut env, b"raw", &raw).unwrap();
+        let args = ["SETEX", "brief", "1", "soon"].map(String::from);
+        handle_command(&mut env, &args).unwrap();
+        assert!(replication.wait_for(env.write_position()));
+
+        let namer = segment_namer("numeric").unwrap();
+        let follower =
+            Environment::open_read_only(&follower_dir.0, &String::from("db"), namer).unwrap();
+        assert_eq!(lookup_bytes(&follower, b"raw").unwrap(), Some(raw.to_vec()));
+        assert_eq!(get(&follower, "brief"), Some(String::from("soon")));
+        std::thread::sleep(std::time::Duration::from_millis(1100));
+        assert_eq!(get(&follower, "brief"), None);
+    }
+
+    #[test]
+    fn setsync_times_out_without_a_follower_to_apply_it() {
+        let unreachable = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let dir = ScratchDir::new();
+        let mut env = open(&dir);
+        let timeout = std::time::Duration::from_millis(100);
+        let replication = spawn_replication(&mut env, unreachable.to_string(), timeout);
+        let env = Arc::new(RwLock::new(env));
+        let args = ["SETSYNC", "a", "1"].map(String::from);
+        let out = serve_set_sync(&env, Some(&replication), &args);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "Write of key [a] not applied by the follower within [100] ms\n"
+        );
+        // kept locally all the same
+        assert_eq!(get(&env.read().unwrap(), "a"), Some(String::from("1")));
+        let out = serve_set_sync(&env, None, &args);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "Command [SETSYNC] needs a follower, see --replicate-to\n"
+        );
+    }
+
+    #[test]
+    fn a_line_over_the_limit_is_rejected_and_the_next_one_served() {
+        let mut lines = BoundedLines {
+            reader: BufReader::with_capacity(
+                16,
+                std::io::Cursor::new(format!("{}\nGET a\n", "x".repeat(100_000))),
+            ),
+            max_bytes: Some(64),
+        };
+        let error = lines.next().unwrap().unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(error.to_string(), "line longer than 64 bytes");
+        assert_eq!(lines.next().unwrap().unwrap(), "GET a");
+        assert!(lines.next().is_none());
+
+        let dir = ScratchDir::new();
+        let env = shared_env(&dir);
+        let request = format!("SET a {}\nSET a 1\nGET a\n", "x".repeat(1 << 20));
+        let response = exchange(request.as_bytes(), move |stream| {
+            serve_connection(&env, stream, Some(64), FlushPolicy::Batch, None, None)
+        });
+        assert_eq!(
+            String::from_utf8(response).unwrap(),
+            "Line rejected: [line longer than 64 bytes]\n\
+             Written key: [a] value: [1]\n\
+             Found value: [1]\n"
+        );
+    }
+
+    #[test]
+    fn resp_commands_over_the_limit_are_refused() {
+        let inline = format!("GET a\r\nSET a {}\r\nGET a\r\n", "x".repeat(100));
+        let bulk = format!(
+            "*2\r\n$3\r\nGET\r\n$1\r\na\r\n*3\r\n$3\r\nSET\r\n$1\r\na\r\n$100\r\n{}\r\n",
+            "x".repeat(100)
+        );
+        for (request, error) in [(inline, "too big inline request"), (bulk, "invalid length")] {
+            let dir = ScratchDir::new();
+            let env = shared_env(&dir);
+            let response = exchange(request.as_bytes(), move |stream| {
+                serve_resp_connection(&env, stream, Some(64), FlushPolicy::Batch, None)
+            });
+            // the stream cannot be followed past the refused command
+            assert_eq!(
+                String::from_utf8(response).unwrap(),
+                format!("$-1\r\n-ERR Protocol error: {}\r\n", error)
+            );
+        }
+    }
+
+    #[test]
+    fn the_line_terminator_does_not_count_towards_the_limit() {
+        let input = "abcd\nefgh\r\nijklm\r\nnop\n";
+        let lines = BoundedLines {
+            reader: input.as_bytes(),
+            max_bytes: Some(4),
+        };
+        let lines: Vec<Result<String, std::io::ErrorKind>> =
+            lines.map(|line| line.map_err(|e| e.kind())).collect();
+        assert_eq!(
+            lines,
+            vec![
+                Ok(String::from("abcd")),
+                Ok(String::from("efgh")),
+                Err(std::io::ErrorKind::InvalidInput),
+                Ok(String::from("nop")),
+            ]
+        );
+    }
+
+    #[test]
+    fn disabled_commands_are_rejected_in_every_mode() {
+        let dir = ScratchDir::new();
+        let mut env = open(&dir);
+        env.disabled_commands = ["DELETE", "GET"].into_iter().map(String::from).collect();
+
+        let env = Arc::new(RwLock::new(env));
+        let mut out = Vec::new();
+        let input = "SET a 1\nDELETE a\nGET a\nSET b 2\n";
+        interactive(
+            env.clone(),
+            &dir.0,
+            "db",
+            &Options::default(),
+            input.as_bytes(),
+            &mut out,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "> Written key: [a] value: [1]\n\
+             > Command [DELETE] is disabled\n\
+             > Command [GET] is disabled\n\
+             > Written key: [b] value: [2]\n"
+        );
+
+        let response = exchange(b"DELETE a\nGET a\nSET a 2\n", move |stream| {
+            serve_connection(&env, stream, None, FlushPolicy::Batch, None, None)
+        });
+        assert_eq!(
+            String::from_utf8(response).unwrap(),
+            "Command [DELETE] is disabled\n\
+             Command [GET] is disabled\n\
+             Written key: [a] value: [2]\n"
+        );
+    }
+
+    #[test]
+    fn served_commands_wait_for_a_correct_auth() {
+        let dir = ScratchDir::new();
+        let env = shared_env(&dir);
+        let request = "SET a 1\nAUTH wrong\nGET a\nAUTH secret\nSET a 1\nGET a\n";
+        let response = exchange(request.as_bytes(), move |stream| {
+            serve_connection(&env, stream, None, FlushPolicy::Batch, None, Some("secret"))
+        });
+        assert_eq!(
+            String::from_utf8(response).unwrap(),
+            "Authentication required\n\
+             Invalid token\n\
+             Authentication required\n\
+             Authenticated\n\
+             Written key: [a] value: [1]\n\
+             Found value: [1]\n"
+        );
+    }
+
+    #[test]
+    fn tokens_only_match_exactly() {
+        assert!(token_matches(b"secret", "secret"));
+        for given in ["", "secre", "secrets", "Secret", "sec\0et"] {
+            assert!(!token_matches(given.as_bytes(), "secret"), "{}", given);
+        }
+    }
+
+    #[test]
+    fn an_old_write_segment_is_retired_in_the_background() {
+        let dir = ScratchDir::new();
+        std::fs::write(format!("{}/db.00001", dir.0), "").unwrap();
+        let env = shared_env(&dir);
+        spawn_segment_age_check(env.clone(), std::time::Duration::from_millis(20));
+        set_data(&mut env.write().unwrap(), b"a", "1").unwrap();
+
+        let started = std::time::Instant::now();
+        let retired = |dir: &ScratchDir, name: &str| dir_listing(dir).contains(&name.to_string());
+        while !retired(&dir, "db.00002") {
+            assert!(started.elapsed() < std::time::Duration::from_secs(5));
+            std::thread::sleep(std::time::Duration::from_millis(5));
+        }
+        // an empty write segment is never retired
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        assert!(!retired(&dir, "db.00003"));
+        let env = env.read().unwrap();
+        assert_eq!(get(&env, "a").as_deref(), Some("1"));
+    }
+
+    #[test]
+    fn binary_framing_carries_raw_bytes() {
+        let dir = ScratchDir::new();
+        let env = shared_env(&dir);
+        // not UTF-8 either
+        let value = b"tab\tspace ,comma\0nul\xff\xfe";
+        let mut request = format!("BINARY\nSET \"a key\" {}\n", value.len()).into_bytes();
+        request.extend_from_slice(value);
+        request
+            .extend_from_slice(b"GET \"a key\"\nGET missing\nSET lines 7\nab\ncd\r\nGET lines\n");
+        request.extend_from_slice(b"DELETE \"a key\"\nGET \"a key\"\n");
+        let response = exchange(&request, move |stream| {
+            serve_connection(&env, stream, None, FlushPolicy::Batch, None, None)
+        });
+        let mut expected = b"Binary framing enabled\nOK\n".to_vec();
+        expected.extend_from_slice(format!("{}\n", value.len()).as_bytes());
+        expected.extend_from_slice(value);
+        expected.extend_from_slice(b"\n-1\nOK\n7\nab\ncd\r\n\n");
+        expected.extend_from_slice(b"Deleted key: [a key]\n-1\n");
+        assert_eq!(response, expected);
+
+        // a value cut short ends the connection
+        let env = shared_env(&dir);
+        let response = exchange(b"BINARY\nSET b 10\nshort", move |stream| {
+            serve_connection(&env, stream, None, FlushPolicy::Batch, None, None)
+        });
+        assert_eq!(
+            String::from_utf8(response).unwrap(),
+            "Binary framing enabled\nFraming error: [unexpected end of file]\n"
+        );
+    }
+
+    #[test]
+    fn segment_size_sets_the_byte_count_a_segment_rolls_at() {
+        let dir = ScratchDir::new();
+        let args = ["--segment-size", "1000", "GET", "a"]
+            .map(String::from)
+            .to_vec();
+        let (options, rest) = parse_options(args).unwrap();
+        assert_eq!(rest, ["GET", "a"]);
+        let mut env = open(&dir);
+        env.segment_threshold = options.segment_size.unwrap();
+        let file_size = |name: &str| {
+            std::fs::metadata(format!("{}/{}", dir.0, name))
+                .unwrap()
+                .len()
+        };
+
+        let mut sizes = Vec::new();
+        for i in 0..100 {
+            let command_args = ["SET", &format!("key-{}", i), "value"].map(String::from);
+            handle_command(&mut env, &command_args).unwrap();
+            if dir_listing(&dir).contains(&String::from("db.00001")) {
+                break;
             }
-            Err(e) => {
-                println!("Failed to work with DB, [{}]", e);
-                return Err(e);
+            sizes.push(file_size("db.current"));
+        }
+        // retired by the write following the one that took it past 1000 bytes
+        let [.., below, above] = sizes[..] else {
+            panic!("{:?}", sizes)
+        };
+        assert!(below <= 1000 && above > 1000, "{:?}", sizes);
+        assert_eq!(file_size("db.00001"), above);
+        assert!(!dir_listing(&dir).contains(&String::from("db.00002")));
+    }
+
+    #[test]
+    fn a_served_client_round_trips_commands_and_survives_bad_ones() {
+        let dir = ScratchDir::new();
+        let env = shared_env(&dir);
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        std::thread::spawn(move || {
+            serve(
+                env,
+                &addr.to_string(),
+                false,
+                None,
+                FlushPolicy::Batch,
+                None,
+                None,
+            )
+        });
+        let connect = || loop {
+            match TcpStream::connect(addr) {
+                Ok(stream) => return stream,
+                Err(_) => std::thread::sleep(std::time::Duration::from_millis(10)),
             }
+        };
+        let ask = |reader: &mut BufReader<TcpStream>, line: &[u8]| {
+            reader.get_mut().write_all(line).unwrap();
+            reader.get_mut().write_all(b"\n").unwrap();
+            let mut response = String::new();
+            reader.read_line(&mut response).unwrap();
+            response
+        };
+
+        let mut client = BufReader::new(connect());
+        assert_eq!(
+            ask(&mut client, b"SET a \"one two\""),
+            "Written key: [a] value: [one two]\n"
+        );
+        assert_eq!(ask(&mut client, b"GET a"), "Found value: [one two]\n");
+        assert!(ask(&mut client, b"SET \xff 1").starts_with("Line rejected: ["));
+        assert_eq!(
+            ask(&mut client, b"FLUSHALL"),
+            "Command [FLUSHALL] is not served\n"
+        );
+        assert_eq!(
+            ask(&mut client, b"GET"),
+            "Command [GET] is missing arguments\n"
+        );
+        // gone halfway through a line
+        client.get_mut().write_all(b"SET b").unwrap();
+        drop(client);
+
+        let mut client = BufReader::new(connect());
+        assert_eq!(ask(&mut client, b"GET a"), "Found value: [one two]\n");
+        assert_eq!(ask(&mut client, b"GET b"), "Value not found\n");
+        assert_eq!(ask(&mut client, b"DELETE a"), "Deleted key: [a]\n");
+        assert_eq!(
+            ask(&mut client, b"GET a"),
+            "Value not found (actually deleted)\n"
+        );
+    }
+
+    #[test]
+    fn resp_replies_match_byte_for_byte() {
+        let request: &[u8] = b"SET empty \"\"\r\n\
+                               *2\r\n$3\r\nGET\r\n$5\r\nempty\r\n\
+                               *3\r\n$3\r\nSET\r\n$1\r\nk\r\n$4\r\na\r\nb\r\n\
+                               get k\r\n\
+                               *3\r\n$3\r\nSET\r\n$3\r\nraw\r\n$2\r\n\xff\x00\r\n\
+                               *2\r\n$3\r\nGET\r\n$3\r\nraw\r\n\
+                               *3\r\n$3\r\nDEL\r\n$1\r\nk\r\n$7\r\nmissing\r\n\
+                               GET k\r\n\
+                               PING \"hello there\"\r\n\
+                               FLUSHALL\r\n\
+                               *1\r\n$3\r\nGET\r\n\
+                               \r\n\
+                               *1\r\n$x\r\nGET\r\n\
+                               PING\r\n";
+        let expected: &[u8] = b"+OK\r\n\
+                                $0\r\n\r\n\
+                                +OK\r\n\
+                                $4\r\na\r\nb\r\n\
+                                +OK\r\n\
+                                $2\r\n\xff\x00\r\n\
+                                :1\r\n\
+                                $-1\r\n\
+                                $11\r\nhello there\r\n\
+                                -ERR unknown command 'FLUSHALL'\r\n\
+                                -ERR wrong number of arguments for 'get' command\r\n\
+                                -ERR Protocol error: invalid length\r\n";
+        let dir = ScratchDir::new();
+        let env = shared_env(&dir);
+        let response = exchange(request, move |stream| {
+            serve_resp_connection(&env, stream, None, FlushPolicy::Batch, None)
+        });
+        assert_eq!(response, expected);
+    }
+
+    #[test]
+    fn use_switches_between_independent_namespaces() {
+        let dir = ScratchDir::new();
+        let env = shared_env(&dir);
+        let mut input = String::from("SET k main\nUSE cache\nGET k\nSET k cached\n");
+        for i in 0..20 {
+            input.push_str(&format!("SET filler-{} value\n", i));
+        }
+        input.push_str("USE db\nGET k\nUSE cache\nGET k\nUSE ../db\n");
+        let mut out = Vec::new();
+        interactive(
+            env,
+            &dir.0,
+            "db",
+            &Options::default(),
+            input.as_bytes(),
+            &mut out,
+        )
+        .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out
+            .lines()
+            .filter(|line| !line.contains("filler"))
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                "> Written key: [k] value: [main]",
+                "> Using namespace [cache]",
+                "> Value not found",
+                "> Written key: [k] value: [cached]",
+                "> Using namespace [db]",
+                "> Found value: [main]",
+                "> Using namespace [cache]",
+                "> Found value: [cached]",
+                "> Usage: USE <namespace>, of letters, digits, '-' and '_'",
+            ]
+        );
+        // only the namespace written to rolled its segments
+        let listing = dir_listing(&dir);
+        assert!(
+            listing.contains(&String::from("cache.00001")),
+            "{:?}",
+            listing
+        );
+        assert!(
+            !listing.iter().any(|name| name.starts_with("db.0")),
+            "{:?}",
+            listing
+        );
+    }
+
+    #[test]
+    fn max_disk_bytes_keeps_the_retired_segments_under_the_limit() {
+        let args = ["--max-disk-bytes", "many"].map(String::from).to_vec();
+        assert_eq!(
+            parse_options(args).unwrap_err(),
+            "Invalid --max-disk-bytes value [many]"
+        );
+        let dir = ScratchDir::new();
+        let args = ["--max-disk-bytes", "2000"].map(String::from).to_vec();
+        let (options, _) = parse_options(args).unwrap();
+        let mut env = open_environment(&dir.0, &String::from("db"), &options).unwrap();
+        let retired_bytes = || -> u64 {
+            dir_listing(&dir)
+                .iter()
+                .filter(|name| {
+                    name.strip_prefix("db.")
+                        .is_some_and(|number| number.bytes().all(|b| b.is_ascii_digit()))
+                })
+                .map(|name| {
+                    std::fs::metadata(format!("{}/{}", dir.0, name))
+                        .unwrap()
+                        .len()
+                })
+                .sum()
+        };
+        let mut written = 0;
+        for i in 0..300 {
+            let value = format!("value-{}", i);
+            written += value.len() as u64;
+            let command_args = ["SET", &format!("key-{}", i % 10), &value].map(String::from);
+            handle_command(&mut env, &command_args).unwrap();
+            assert!(retired_bytes() <= 2000, "after write {}", i);
         }
+        assert!(retired_bytes() < written);
+        let mut out = Vec::new();
+        run_command(&mut env, &[String::from("STATS")], &mut out).unwrap();
+        assert!(
+            String::from_utf8(out)
+                .unwrap()
+                .contains("disk limit:          2000")
+        );
+    }
+
+    #[test]
+    fn max_segment_records_rolls_by_count_not_size() {
+        for value in ["0", "few"] {
+            let args = ["--max-segment-records", value].map(String::from).to_vec();
+            assert_eq!(
+                parse_options(args).unwrap_err(),
+                format!("Invalid --max-segment-records value [{}]", value)
+            );
+        }
+        let dir = ScratchDir::new();
+        let args = ["--max-segment-records", "5", "--segment-size", "1000000"]
+            .map(String::from)
+            .to_vec();
+        let (options, _) = parse_options(args).unwrap();
+        let mut env = open_environment(&dir.0, &String::from("db"), &options).unwrap();
+        for i in 0..12 {
+            let command_args = ["SET", &format!("key-{}", i), "v"].map(String::from);
+            handle_command(&mut env, &command_args).unwrap();
+        }
+        let retired = dir_listing(&dir)
+            .iter()
+            .filter(|name| {
+                name.strip_prefix("db.")
+                    .is_some_and(|number| number.bytes().all(|b| b.is_ascii_digit()))
+            })
+            .count();
+        assert_eq!(retired, 2);
+    }
+
+    #[test]
+    fn verify_from_the_command_line_reports_a_torn_tail_it_leaves_alone() {
+        let dir = ScratchDir::new();
+        let options = Options::default();
+        let mut env = open_environment(&dir.0, &String::from("db"), &options).unwrap();
+        let command_args = ["SET", "a", "value"].map(String::from);
+        handle_command(&mut env, &command_args).unwrap();
+        drop(env);
+        let current = format!("{}/db.current", dir.0);
+        let tail = std::fs::metadata(&current).unwrap().len();
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&current)
+            .unwrap()
+            .write_all(b"b,torn")
+            .unwrap();
+        let before = std::fs::read(&current).unwrap();
+
+        let mut out = Vec::new();
+        let namer = segment_namer("numeric").unwrap();
+        verify_read_only(&dir.0, &String::from("db"), namer, &mut out).unwrap();
+        let output = String::from_utf8(out).unwrap();
+        assert!(output.contains(&format!("  torn record at the tail, offset {}\n", tail)));
+        assert!(output.ends_with("Found issues in [1] segment(s)\n"));
+        assert_eq!(std::fs::read(&current).unwrap(), before);
     }
-    Ok(())
 }
@@ -0,0 +1,99 @@
+// Read-only memory maps of retired segments, so a lookup reads its record from
+// memory instead of opening and seeking the file. Only unix systems map files,
+// elsewhere `map` returns None and segments are read through the file.
+use std::fs::File;
+use std::sync::Arc;
+
+#[derive(Debug, Clone)]
+pub(crate) struct Mmap(Arc<Mapping>);
+
+#[derive(Debug)]
+struct Mapping {
+    address: *const u8,
+    len: usize,
+}
+
+// the mapping is read-only and never changes after it is made
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+impl std::ops::Deref for Mmap {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: the address and length are those of a live mapping
+        unsafe { std::slice::from_raw_parts(self.0.address, self.0.len) }
+    }
+}
+
+impl AsRef<[u8]> for Mmap {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+#[cfg(unix)]
+mod sys {
+    use std::ffi::c_void;
+
+    pub const PROT_READ: i32 = 1;
+    pub const MAP_PRIVATE: i32 = 2;
+    pub const MAP_FAILED: *mut c_void = !0 as *mut c_void;
+
+    unsafe extern "C" {
+        pub fn mmap(
+            address: *mut c_void,
+            len: usize,
+            protection: i32,
+            flags: i32,
+            fd: i32,
+            offset: i64,
+        ) -> *mut c_void;
+        pub fn munmap(address: *mut c_void, len: usize) -> i32;
+    }
+}
+
+// Maps the whole of `file`, which must not be truncated while it is mapped.
+// None for an empty file, which cannot be mapped, and off unix.
+#[cfg(unix)]
+pub(crate) fn map(file: &File) -> Result<Option<Mmap>, std::io::Error> {
+    use std::os::fd::AsRawFd;
+
+    let len = file.metadata()?.len() as usize;
+    if len == 0 {
+        return Ok(None);
+    }
+    // SAFETY: a private read-only mapping of an open file, checked below
+    let address = unsafe {
+        sys::mmap(
+            std::ptr::null_mut(),
+            len,
+            sys::PROT_READ,
+            sys::MAP_PRIVATE,
+            file.as_raw_fd(),
+            0,
+        )
+    };
+    if address == sys::MAP_FAILED {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(Some(Mmap(Arc::new(Mapping {
+        address: address as *const u8,
+        len,
+    }))))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn map(_file: &File) -> Result<Option<Mmap>, std::io::Error> {
+    Ok(None)
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        // SAFETY: the mapping was made by `map` and nothing refers to it anymore
+        unsafe {
+            sys::munmap(self.address as *mut std::ffi::c_void, self.len);
+        }
+    }
+}
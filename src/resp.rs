@@ -0,0 +1,296 @@
+// RESP2, the protocol of Redis, so that Redis clients can be pointed at the
+// store. Only GET, SET, DEL and PING are understood, anything else is answered
+// with an error reply.
+use crate::{Environment, KvError, contains_key, get_bytes, set_bytes, write_batch};
+use std::io::prelude::*;
+use std::sync::atomic::Ordering;
+
+// longest inline command and bulk string accepted, as in Redis
+const MAX_INLINE_LENGTH: usize = 64 * 1024;
+const MAX_BULK_LENGTH: usize = 512 * 1024 * 1024;
+// most arguments a command array may announce
+const MAX_ARRAY_LENGTH: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reply {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    // None is the null bulk string, the reply for a missing key
+    Bulk(Option<Vec<u8>>),
+}
+
+impl Reply {
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            Reply::Simple(line) => format!("+{}\r\n", single_line(line)).into_bytes(),
+            Reply::Error(message) => format!("-{}\r\n", single_line(message)).into_bytes(),
+            Reply::Integer(number) => format!(":{}\r\n", number).into_bytes(),
+            Reply::Bulk(None) => b"$-1\r\n".to_vec(),
+            Reply::Bulk(Some(value)) => {
+                let mut encoded = format!("${}\r\n", value.len()).into_bytes();
+                encoded.extend_from_slice(value);
+                encoded.extend_from_slice(b"\r\n");
+                encoded
+            }
+        }
+    }
+}
+
+// simple strings and errors cannot carry line breaks
+fn single_line(line: &str) -> String {
+    line.replace(['\r', '\n'], " ")
+}
+
+fn protocol_error(message: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("Protocol error: {}", message),
+    )
+}
+
+// A line without its `\r\n` (or bare `\n`), None at the end of input.
+fn read_line(reader: &mut impl BufRead) -> std::io::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    let limit = MAX_INLINE_LENGTH + 2;
+    let read = reader.take(limit as u64).read_until(b'\n', &mut line)?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return match read == limit {
+            true => Err(protocol_error("too big inline request")),
+            false => Err(std::io::ErrorKind::UnexpectedEof.into()),
+        };
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_length(digits: &[u8], max: usize) -> std::io::Result<usize> {
+    std::str::from_utf8(digits)
+        .ok()
+        .and_then(|digits| digits.parse::<usize>().ok())
+        .filter(|length| *length <= max)
+        .ok_or_else(|| protocol_error("invalid length"))
+}
+
+// Splits an inline command into words. A word in double quotes may hold
+// spaces and `\"` or `\\` escapes, `""` being the empty argument.
+pub(crate) fn split_inline(line: &[u8]) -> std::io::Result<Vec<Vec<u8>>> {
+    let mut args = Vec::new();
+    let mut bytes = line.iter().copied().peekable();
+    loop {
+        while bytes
+            .next_if(|byte| *byte == b' ' || *byte == b'\t')
+            .is_some()
+        {}
+        let first = match bytes.next() {
+            Some(first) => first,
+            None => return Ok(args),
+        };
+        let mut arg = Vec::new();
+        if first == b'"' {
+            loop {
+                match bytes.next() {
+                    Some(b'"') => break,
+                    Some(b'\\') => match bytes.next() {
+                        Some(escaped) => arg.push(escaped),
+                        None => return Err(protocol_error("unbalanced quotes in request")),
+                    },
+                    Some(byte) => arg.push(byte),
+                    None => return Err(protocol_error("unbalanced quotes in request")),
+                }
+            }
+            if bytes
+                .peek()
+                .is_some_and(|byte| *byte != b' ' && *byte != b'\t')
+            {
+                return Err(protocol_error("unbalanced quotes in request"));
+            }
+        } else {
+            arg.push(first);
+            while let Some(byte) = bytes.next_if(|byte| *byte != b' ' && *byte != b'\t') {
+                arg.push(byte);
+            }
+        }
+        args.push(arg);
+    }
+}
+
+// Reads the next command, sent either as an array of bulk strings or inline as
+// a line of words. None at the end of input. Malformed input is an InvalidData
+// error, past which the stream cannot be read any further. `max_bytes` lowers
+// the limits on an inline command and on each bulk string.
+pub fn read_command(
+    reader: &mut impl BufRead,
+    max_bytes: Option<usize>,
+) -> std::io::Result<Option<Vec<Vec<u8>>>> {
+    let max_inline = max_bytes.map_or(MAX_INLINE_LENGTH, |max| max.min(MAX_INLINE_LENGTH));
+    let max_bulk = max_bytes.map_or(MAX_BULK_LENGTH, |max| max.min(MAX_BULK_LENGTH));
+    loop {
+        let line = match read_line(reader)? {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+        let count = match line.strip_prefix(b"*") {
+            Some(count) => parse_length(count, MAX_ARRAY_LENGTH)?,
+            None if line.len() > max_inline => {
+                return Err(protocol_error("too big inline request"));
+            }
+            None => {
+                let args = split_inline(&line)?;
+                // empty lines are skipped, as in Redis
+                if args.is_empty() {
+                    continue;
+                }
+                return Ok(Some(args));
+            }
+        };
+        if count == 0 {
+            continue;
+        }
+        let mut args = Vec::with_capacity(count.min(64));
+        for _ in 0..count {
+            let header = read_line(reader)?.ok_or(std::io::ErrorKind::UnexpectedEof)?;
+            let length = header
+                .strip_prefix(b"$")
+                .ok_or_else(|| protocol_error("expected '$'"))?;
+            let length = parse_length(length, max_bulk)?;
+            let mut arg = vec![0; length + 2];
+            reader.read_exact(&mut arg)?;
+            if !arg.ends_with(b"\r\n") {
+                return Err(protocol_error("bulk string not terminated by CRLF"));
+            }
+            arg.truncate(length);
+            args.push(arg);
+        }
+        return Ok(Some(args));
+    }
+}
+
+enum Command {
+    Ping(Option<Vec<u8>>),
+    Get(Vec<u8>),
+    Set(Vec<u8>, Vec<u8>),
+    Del(Vec<Vec<u8>>),
+}
+
+// Arguments are taken as they are, any bytes.
+fn parse_command(args: &[Vec<u8>]) -> Result<Command, Reply> {
+    let name = String::from_utf8_lossy(&args[0]).to_ascii_uppercase();
+    let wrong_arity = || {
+        Reply::Error(format!(
+            "ERR wrong number of arguments for '{}' command",
+            name.to_ascii_lowercase()
+        ))
+    };
+    let rest = &args[1..];
+    match (name.as_str(), rest.len()) {
+        ("PING", 0) => Ok(Command::Ping(None)),
+        ("PING", 1) => Ok(Command::Ping(Some(rest[0].clone()))),
+        ("GET", 1) => Ok(Command::Get(rest[0].clone())),
+        ("SET", 2) => Ok(Command::Set(rest[0].clone(), rest[1].clone())),
+        ("DEL", 1..) => Ok(Command::Del(rest.to_vec())),
+        ("PING" | "GET" | "SET" | "DEL", _) => Err(wrong_arity()),
+        _ => Err(Reply::Error(format!(
+            "ERR unknown command '{}'",
+            String::from_utf8_lossy(&args[0])
+        ))),
+    }
+}
+
+// An error reply if the line protocol command matching `command` is disabled.
+fn disabled_reply(env: &Environment, command: &Command) -> Option<Reply> {
+    let name = match command {
+        Command::Ping(_) => return None,
+        Command::Get(_) => "GET",
+        Command::Set(..) => "SET",
+        Command::Del(_) => "DELETE",
+    };
+    env.disabled_commands
+        .contains(name)
+        .then(|| Reply::Error(format!("ERR command '{}' is disabled", name)))
+}
+
+fn error_reply(error: impl std::fmt::Display) -> Reply {
+    Reply::Error(format!("ERR {}", error))
+}
+
+// Whether `args` only reads, so that it can be run by `execute_shared`.
+pub fn is_read_only(args: &[Vec<u8>]) -> bool {
+    let name = args[0].to_ascii_uppercase();
+    name == b"GET" || name == b"PING"
+}
+
+// Runs a command `is_read_only` accepts with the environment shared. Unlike
+// through `execute` the access is not recorded for eviction.
+pub fn execute_shared(env: &Environment, args: &[Vec<u8>]) -> Reply {
+    let command = match parse_command(args) {
+        Ok(command) => command,
+        Err(reply) => return reply,
+    };
+    if let Some(reply) = disabled_reply(env, &command) {
+        return reply;
+    }
+    match command {
+        Command::Ping(None) => Reply::Simple("PONG".to_string()),
+        Command::Ping(message) => Reply::Bulk(message),
+        Command::Get(key) => {
+            env.metrics.gets.fetch_add(1, Ordering::Relaxed);
+            match get_bytes(env, &key) {
+                Ok(value) => Reply::Bulk(value),
+                Err(KvError::KeyDeleted { .. }) => Reply::Bulk(None),
+                Err(e) => error_reply(e),
+            }
+        }
+        Command::Set(..) | Command::Del(_) => {
+            Reply::Error("ERR write commands need exclusive access".to_string())
+        }
+    }
+}
+
+pub fn execute(env: &mut Environment, args: &[Vec<u8>]) -> Reply {
+    let command = match parse_command(args) {
+        Ok(command) => command,
+        Err(reply) => return reply,
+    };
+    if let Some(reply) = disabled_reply(env, &command) {
+        return reply;
+    }
+    match command {
+        Command::Set(key, value) => {
+            env.metrics.sets.fetch_add(1, Ordering::Relaxed);
+            match set_bytes(env, &key, &value) {
+                Ok(_) => Reply::Simple("OK".to_string()),
+                Err(e) => error_reply(e),
+            }
+        }
+        Command::Del(keys) => {
+            let mut tombstones = Vec::new();
+            for key in keys {
+                match contains_key(env, &key) {
+                    Ok(true) if !tombstones.iter().any(|(deleted, _)| *deleted == key) => {
+                        tombstones.push((key, None))
+                    }
+                    Ok(_) => (),
+                    Err(e) => return error_reply(e),
+                }
+            }
+            env.metrics
+                .deletes
+                .fetch_add(tombstones.len() as u64, Ordering::Relaxed);
+            match write_batch(env, &tombstones) {
+                Ok(_) => Reply::Integer(tombstones.len() as i64),
+                Err(e) => error_reply(e),
+            }
+        }
+        Command::Get(key) => {
+            env.touch(&key);
+            execute_shared(env, args)
+        }
+        Command::Ping(_) => execute_shared(env, args),
+    }
+}
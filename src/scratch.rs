@@ -0,0 +1,30 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// A directory under the system temp dir, removed with everything in it when
+// dropped. Each one is unique to the process and the call. The tests of the
+// library and of the CLI share it, as do the dev commands that work in a
+// scratch environment.
+pub(crate) struct ScratchDir(pub(crate) String);
+
+impl ScratchDir {
+    pub(crate) fn new() -> Self {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir()
+            .join(format!(
+                "kvdb-scratch-{}-{}",
+                std::process::id(),
+                NEXT.fetch_add(1, Ordering::Relaxed)
+            ))
+            .display()
+            .to_string();
+        let _ = std::fs::remove_dir_all(&path);
+        let _ = std::fs::create_dir_all(&path);
+        ScratchDir(path)
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,413 @@
+// Where a store keeps its files: the segments and the manifest, hints and
+// filters next to them. Paths are the ones the store builds under its data
+// path. `FileStorage` takes them as file system paths, `MemoryStorage` only
+// as names, so tests can run a store without touching the disk.
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+pub trait Storage: fmt::Debug + Send + Sync {
+    // `path` for reading from any offset.
+    fn open(&self, path: &str) -> std::io::Result<Box<dyn StorageFile>>;
+    // `path` for reading and writing at any offset.
+    fn open_writable(&self, path: &str) -> std::io::Result<Box<dyn StorageFile>>;
+    // `path` for appending to, which has to exist.
+    fn append(&self, path: &str) -> std::io::Result<Box<dyn Appender>>;
+    // Creates `path` holding `contents`, or replaces what it holds, and syncs it.
+    fn write(&self, path: &str, contents: &[u8]) -> std::io::Result<()>;
+    fn rename(&self, from: &str, to: &str) -> std::io::Result<()>;
+    // Removes the file at `path`, or the directory if it is empty.
+    fn remove(&self, path: &str) -> std::io::Result<()>;
+    // The paths of the files and directories in `dir`, in no particular order.
+    fn list(&self, dir: &str) -> std::io::Result<Vec<String>>;
+    // Creates `dir` along with the directories above it that are missing.
+    fn create_dir(&self, dir: &str) -> std::io::Result<()>;
+    fn exists(&self, path: &str) -> bool;
+    fn is_dir(&self, path: &str) -> bool;
+    fn modified(&self, path: &str) -> std::io::Result<SystemTime>;
+    fn size(&self, path: &str) -> std::io::Result<u64>;
+
+    fn read(&self, path: &str) -> std::io::Result<Vec<u8>> {
+        let mut contents = Vec::new();
+        self.open(path)?.read_to_end(&mut contents)?;
+        Ok(contents)
+    }
+
+    // The file behind `path` in the file system, which a memory map needs.
+    // None where files are kept elsewhere, their segments are read as they are.
+    fn local_path(&self, _path: &str) -> Option<PathBuf> {
+        None
+    }
+}
+
+// A file opened through a `Storage`, read and written from where it is sought to.
+pub trait StorageFile: Read + Write + Seek + Send + Sync {
+    fn size(&self) -> std::io::Result<u64>;
+    fn set_len(&self, len: u64) -> std::io::Result<()>;
+    // Makes what was written to the file durable.
+    fn sync(&self) -> std::io::Result<()>;
+}
+
+// Where a segment appends its records: a file, or in tests a stand-in that
+// fails partway through a write.
+pub trait Appender: Write + fmt::Debug + Send + Sync {
+    fn set_len(&self, len: u64) -> std::io::Result<()>;
+}
+
+impl StorageFile for File {
+    fn size(&self) -> std::io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn set_len(&self, len: u64) -> std::io::Result<()> {
+        File::set_len(self, len)
+    }
+
+    fn sync(&self) -> std::io::Result<()> {
+        self.sync_data()
+    }
+}
+
+impl Appender for File {
+    fn set_len(&self, len: u64) -> std::io::Result<()> {
+        File::set_len(self, len)
+    }
+}
+
+// The file system, what a store uses unless it is given another storage.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FileStorage;
+
+impl Storage for FileStorage {
+    fn open(&self, path: &str) -> std::io::Result<Box<dyn StorageFile>> {
+        Ok(Box::new(File::open(path)?))
+    }
+
+    fn open_writable(&self, path: &str) -> std::io::Result<Box<dyn StorageFile>> {
+        Ok(Box::new(
+            OpenOptions::new().read(true).write(true).open(path)?,
+        ))
+    }
+
+    fn append(&self, path: &str) -> std::io::Result<Box<dyn Appender>> {
+        Ok(Box::new(OpenOptions::new().append(true).open(path)?))
+    }
+
+    fn write(&self, path: &str, contents: &[u8]) -> std::io::Result<()> {
+        let mut file = File::create(path)?;
+        file.write_all(contents)?;
+        file.sync_all()
+    }
+
+    fn rename(&self, from: &str, to: &str) -> std::io::Result<()> {
+        std::fs::rename(from, to)
+    }
+
+    fn remove(&self, path: &str) -> std::io::Result<()> {
+        match self.is_dir(path) {
+            true => std::fs::remove_dir(path),
+            false => std::fs::remove_file(path),
+        }
+    }
+
+    fn list(&self, dir: &str) -> std::io::Result<Vec<String>> {
+        Ok(std::fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path().display().to_string())
+            .collect())
+    }
+
+    fn create_dir(&self, dir: &str) -> std::io::Result<()> {
+        std::fs::create_dir_all(dir)
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        Path::new(path).exists()
+    }
+
+    fn is_dir(&self, path: &str) -> bool {
+        Path::new(path).is_dir()
+    }
+
+    fn modified(&self, path: &str) -> std::io::Result<SystemTime> {
+        std::fs::metadata(path)?.modified()
+    }
+
+    fn size(&self, path: &str) -> std::io::Result<u64> {
+        Ok(std::fs::metadata(path)?.len())
+    }
+
+    fn read(&self, path: &str) -> std::io::Result<Vec<u8>> {
+        std::fs::read(path)
+    }
+
+    fn local_path(&self, path: &str) -> Option<PathBuf> {
+        Some(PathBuf::from(path))
+    }
+}
+
+// Files kept in memory, gone with the storage. As on unix, a file stays
+// readable through the handles open on it after it is renamed or removed.
+// Modification times come from a counter that every change moves on, so they
+// tell apart changes a coarse clock would give the same time.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    entries: Mutex<MemoryEntries>,
+    clock: Arc<AtomicU64>,
+}
+
+#[derive(Debug, Default)]
+struct MemoryEntries {
+    files: HashMap<PathBuf, Arc<MemoryFile>>,
+    dirs: HashSet<PathBuf>,
+}
+
+#[derive(Debug, Default)]
+struct MemoryFile {
+    contents: Mutex<Vec<u8>>,
+    // tick of the clock at the last change
+    modified: AtomicU64,
+}
+
+impl MemoryFile {
+    fn touch(&self, clock: &AtomicU64) {
+        let tick = clock.fetch_add(1, Ordering::Relaxed) + 1;
+        self.modified.store(tick, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug)]
+struct MemoryHandle {
+    file: Arc<MemoryFile>,
+    clock: Arc<AtomicU64>,
+    position: u64,
+    // writes go to the end whatever the position
+    append: bool,
+}
+
+fn not_found(path: &Path) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::NotFound,
+        format!("[{}] not found", path.display()),
+    )
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        MemoryStorage::default()
+    }
+
+    fn file(&self, path: &str) -> std::io::Result<Arc<MemoryFile>> {
+        let path = Path::new(path);
+        let entries = self.entries.lock().unwrap();
+        entries
+            .files
+            .get(path)
+            .cloned()
+            .ok_or_else(|| not_found(path))
+    }
+
+    fn handle(&self, path: &str, append: bool) -> std::io::Result<MemoryHandle> {
+        Ok(MemoryHandle {
+            file: self.file(path)?,
+            clock: self.clock.clone(),
+            position: 0,
+            append,
+        })
+    }
+}
+
+impl MemoryEntries {
+    // a file can only go in a directory that exists
+    fn check_parent(&self, path: &Path) -> std::io::Result<()> {
+        match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() && !self.dirs.contains(parent) => {
+                Err(not_found(parent))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn open(&self, path: &str) -> std::io::Result<Box<dyn StorageFile>> {
+        Ok(Box::new(self.handle(path, false)?))
+    }
+
+    fn open_writable(&self, path: &str) -> std::io::Result<Box<dyn StorageFile>> {
+        Ok(Box::new(self.handle(path, false)?))
+    }
+
+    fn append(&self, path: &str) -> std::io::Result<Box<dyn Appender>> {
+        Ok(Box::new(self.handle(path, true)?))
+    }
+
+    fn write(&self, path: &str, contents: &[u8]) -> std::io::Result<()> {
+        let path = Path::new(path);
+        let mut entries = self.entries.lock().unwrap();
+        entries.check_parent(path)?;
+        let file = entries.files.entry(path.to_path_buf()).or_default();
+        *file.contents.lock().unwrap() = contents.to_vec();
+        file.touch(&self.clock);
+        Ok(())
+    }
+
+    fn rename(&self, from: &str, to: &str) -> std::io::Result<()> {
+        let (from, to) = (Path::new(from), Path::new(to));
+        let mut entries = self.entries.lock().unwrap();
+        entries.check_parent(to)?;
+        let file = entries.files.remove(from).ok_or_else(|| not_found(from))?;
+        entries.files.insert(to.to_path_buf(), file);
+        Ok(())
+    }
+
+    fn remove(&self, path: &str) -> std::io::Result<()> {
+        let path = Path::new(path);
+        let mut entries = self.entries.lock().unwrap();
+        if entries.files.remove(path).is_some() {
+            return Ok(());
+        }
+        if !entries.dirs.contains(path) {
+            return Err(not_found(path));
+        }
+        let in_use = entries
+            .files
+            .keys()
+            .chain(entries.dirs.iter())
+            .any(|entry| entry.parent() == Some(path));
+        if in_use {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::DirectoryNotEmpty,
+                format!("[{}] is not empty", path.display()),
+            ));
+        }
+        entries.dirs.remove(path);
+        Ok(())
+    }
+
+    fn list(&self, dir: &str) -> std::io::Result<Vec<String>> {
+        let dir = Path::new(dir);
+        let entries = self.entries.lock().unwrap();
+        if !entries.dirs.contains(dir) {
+            return Err(not_found(dir));
+        }
+        Ok(entries
+            .files
+            .keys()
+            .chain(entries.dirs.iter())
+            .filter(|entry| entry.parent() == Some(dir))
+            .map(|entry| entry.display().to_string())
+            .collect())
+    }
+
+    fn create_dir(&self, dir: &str) -> std::io::Result<()> {
+        let dir = Path::new(dir);
+        let mut entries = self.entries.lock().unwrap();
+        for ancestor in dir.ancestors().filter(|a| !a.as_os_str().is_empty()) {
+            if entries.files.contains_key(ancestor) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::AlreadyExists,
+                    format!("[{}] is a file", ancestor.display()),
+                ));
+            }
+            entries.dirs.insert(ancestor.to_path_buf());
+        }
+        Ok(())
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        let path = Path::new(path);
+        let entries = self.entries.lock().unwrap();
+        entries.files.contains_key(path) || entries.dirs.contains(path)
+    }
+
+    fn is_dir(&self, path: &str) -> bool {
+        self.entries.lock().unwrap().dirs.contains(Path::new(path))
+    }
+
+    fn modified(&self, path: &str) -> std::io::Result<SystemTime> {
+        let tick = self.file(path)?.modified.load(Ordering::Relaxed);
+        Ok(SystemTime::UNIX_EPOCH + Duration::from_nanos(tick))
+    }
+
+    fn size(&self, path: &str) -> std::io::Result<u64> {
+        Ok(self.file(path)?.contents.lock().unwrap().len() as u64)
+    }
+}
+
+impl Read for MemoryHandle {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let contents = self.file.contents.lock().unwrap();
+        let start = (self.position as usize).min(contents.len());
+        let read = buf.len().min(contents.len() - start);
+        buf[..read].copy_from_slice(&contents[start..start + read]);
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Write for MemoryHandle {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut contents = self.file.contents.lock().unwrap();
+        if self.append {
+            self.position = contents.len() as u64;
+        }
+        let start = self.position as usize;
+        let end = start + buf.len();
+        if contents.len() < end {
+            contents.resize(end, 0);
+        }
+        contents[start..end].copy_from_slice(buf);
+        self.position = end as u64;
+        self.file.touch(&self.clock);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemoryHandle {
+    fn seek(&mut self, position: SeekFrom) -> std::io::Result<u64> {
+        let position = match position {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => self.size()?.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.position.checked_add_signed(delta),
+        };
+        self.position = position.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "seek to a negative offset",
+            )
+        })?;
+        Ok(self.position)
+    }
+}
+
+impl StorageFile for MemoryHandle {
+    fn size(&self) -> std::io::Result<u64> {
+        Ok(self.file.contents.lock().unwrap().len() as u64)
+    }
+
+    fn set_len(&self, len: u64) -> std::io::Result<()> {
+        self.file.contents.lock().unwrap().resize(len as usize, 0);
+        self.file.touch(&self.clock);
+        Ok(())
+    }
+
+    fn sync(&self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Appender for MemoryHandle {
+    fn set_len(&self, len: u64) -> std::io::Result<()> {
+        StorageFile::set_len(self, len)
+    }
+}